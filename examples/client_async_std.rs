@@ -0,0 +1,52 @@
+use std::time::Duration;
+
+use log::{error, info};
+use udis::Udis;
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("trace")).init();
+
+    async_std::task::block_on(async {
+        // Build the udis endpoint.
+        //
+        // This is the same as `client_async.rs`, but the background task is spawned on the
+        // async-std runtime rather than tokio.
+        let mut udis = Udis::new("client").search("hello").build_async_std()?;
+
+        // Vector to collect our found services into
+        let mut services = Vec::new();
+
+        info!("Discovering services");
+
+        // Look for services until we reach the given timeout
+        async_std::future::timeout(Duration::from_secs(5), async {
+            loop {
+                match udis.find_service().await {
+                    Ok(serv_info) => {
+                        info!(
+                            "Found service `{}` hosted by `{}` at {}:{}",
+                            serv_info.kind, serv_info.name, serv_info.addr, serv_info.port
+                        );
+
+                        services.push(serv_info);
+                    }
+                    Err(e) => {
+                        error!("err: {e}");
+                        break;
+                    }
+                }
+            }
+        })
+        .await
+        .ok();
+
+        if services.is_empty() {
+            info!("Found no services");
+        }
+
+        // Gracefully shutdown the udis endpoint
+        udis.shutdown().await?;
+
+        Ok(())
+    })
+}
@@ -11,7 +11,10 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     // Here we build our endpoint by giving it a name ("server"), and telling it that we are
     // hosting a service, in this case one with the kind of "hello" which we will make available on
     // port 4112.
-    let udis = Udis::new("server").host("hello", 4112)?.build_async()?;
+    let mut udis = Udis::new("server").host("hello", 4112)?.build_async()?;
+
+    // Wait until the endpoint has joined the discovery network and announced itself
+    udis.ready().await?;
 
     // Wait for receipt
     tokio::time::sleep(Duration::from_secs(10)).await;
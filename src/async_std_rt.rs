@@ -0,0 +1,1180 @@
+use std::{
+    collections::HashMap,
+    future::{pending, poll_fn, Future},
+    io::{self, ErrorKind},
+    net::{IpAddr, SocketAddr, SocketAddrV4},
+    pin::Pin,
+    task::Poll,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    builder::BindMode,
+    config::{Config, ShutdownSignal, ANNOUNCE_REPEAT_INTERVAL, VERIFY_MULTICAST_TIMEOUT},
+    core::{Output, Processor},
+    diagnostics::{Diagnostics, SharedDiagnostics},
+    error::Error,
+    net::{
+        build_group_socket, build_unicast_socket, discovery_socket, ignore_transient_send_error,
+        send_addr, verify_discovery, MULTICAST_PORT, RX_LOG_TARGET, TX_LOG_TARGET,
+    },
+    relay::Relay,
+    sync::POLL_PERIOD,
+    transport::Transport,
+    HostEvent, ServiceEvent, ServiceInfo, Udis,
+};
+use async_std::{
+    channel::{bounded, unbounded, Receiver, Sender},
+    net::UdpSocket,
+    task::{self, JoinHandle},
+};
+use futures_lite::FutureExt;
+use log::{error, trace};
+
+/// An asynchronous udis endpoint running on the async-std runtime.
+///
+/// This endpoint works by starting a background async-std task that handles the udis network
+/// logic, and communicates discovered services to the main task with channels.
+///
+/// To retrieve services found by this endpoint use the [`AsyncUdis::find_service`] function.
+///
+/// When finished using the endpoint be sure to call [`AsyncUdis::shutdown`] to close the background
+/// task.
+///
+/// If the endpoint is dropped without being shut down the background task stops by itself, as
+/// nobody is listening for services any more. This is not treated as an error.
+#[derive(Debug)]
+pub struct AsyncUdis {
+    _udis: Udis,
+
+    // Task join handle
+    bg_task_jh: JoinHandle<Result<(), Error>>,
+
+    // Sender for commands
+    cmd_tx: Sender<Cmd>,
+
+    // Receiver for getting service infos from the udis task
+    serv_info_rx: Receiver<ServiceInfo>,
+
+    // Receiver for getting host events from the udis task
+    host_event_rx: Receiver<HostEvent>,
+
+    // Whether the udis task passes on host events
+    host_events: bool,
+
+    // Receiver for getting service events from the udis task
+    service_event_rx: Receiver<ServiceEvent>,
+
+    // Diagnostics updated by the udis task
+    diag: SharedDiagnostics,
+
+    // Receiver signalled once the udis task has made its first announcement, cleared once it has
+    ready_rx: Option<Receiver<()>>,
+}
+
+/// A cheap, cloneable handle for controlling an [`AsyncUdis`] endpoint from other tasks.
+///
+/// Get a handle with [`AsyncUdis::handle`]. Handles can be used concurrently from any number of
+/// tasks, while found services and host events are still only received by the [`AsyncUdis`]
+/// itself.
+#[derive(Debug, Clone)]
+pub struct Handle {
+    // Sender for commands
+    cmd_tx: Sender<Cmd>,
+}
+
+enum Cmd {
+    /// Wait for the shutdown grace so messages already sent leave the machine
+    Flush,
+    Shutdown,
+    Rename(String),
+    Refresh,
+    Pause,
+    Resume,
+    GetPayload(Sender<Vec<u8>>),
+    Probe {
+        addr: IpAddr,
+        reply_tx: Sender<Vec<ServiceInfo>>,
+    },
+}
+
+impl AsyncUdis {
+    pub(crate) fn build(
+        udis: Udis,
+        mut config: Config,
+        transport: Option<Box<dyn Transport>>,
+    ) -> Result<Self, Error> {
+        let (cmd_tx, cmd_rx) = unbounded();
+        let (serv_info_tx, serv_info_rx) = unbounded();
+        let (ready_tx, ready_rx) = bounded(1);
+        let (host_event_tx, host_event_rx) = unbounded();
+        let (service_event_tx, service_event_rx) = unbounded();
+
+        let diag = SharedDiagnostics::default();
+
+        // Connect to the multicast network here rather than in the task, so that any errors are
+        // returned when building the endpoint
+        let transport = match transport {
+            Some(transport) => AsyncTransport::Custom(transport),
+            None => AsyncTransport::udp(&mut config, &diag)?,
+        };
+
+        let host_events = config.host_events;
+        let udis_bg = udis.clone();
+        let diag_bg = diag.clone();
+
+        let bg_task_jh = task::Builder::new()
+            .name(udis.worker_name())
+            .spawn(async move {
+                async_task(
+                    udis_bg,
+                    config,
+                    transport,
+                    diag_bg,
+                    cmd_rx,
+                    serv_info_tx,
+                    host_event_tx,
+                    service_event_tx,
+                    ready_tx,
+                )
+                .await
+            })?;
+
+        Ok(Self {
+            _udis: udis,
+            bg_task_jh,
+            cmd_tx,
+            serv_info_rx,
+            host_event_rx,
+            host_events,
+            service_event_rx,
+            diag,
+            ready_rx: Some(ready_rx),
+        })
+    }
+
+    /// Wait until the background task has joined the discovery network and sent its first
+    /// announcement.
+    ///
+    /// Any endpoint started after this returns is guaranteed to be able to see this one, which is
+    /// useful for ordering endpoints in tests without arbitrary sleeps. Once the endpoint is ready
+    /// this returns immediately.
+    ///
+    /// # Errors
+    ///
+    /// This function may return an error if the background task closed before it was ready.
+    pub async fn ready(&mut self) -> Result<(), Error> {
+        if let Some(ready_rx) = &self.ready_rx {
+            ready_rx
+                .recv()
+                .await
+                .map_err(|_| Error::BackgroundThreadShutdown)?;
+            self.ready_rx = None;
+        }
+
+        Ok(())
+    }
+
+    /// Find the next service discovered by this udis endpoint.
+    ///
+    /// # Errors
+    ///
+    /// This function may return an error if the background task has closed for any reason.
+    pub async fn find_service(&mut self) -> Result<ServiceInfo, Error> {
+        self.serv_info_rx
+            .recv()
+            .await
+            .map_err(|_| Error::ServiceInfoChannelClosed)
+    }
+
+    /// Get the next event relating to the services hosted by this endpoint, for example a peer
+    /// acknowledging that it has received one of our services.
+    ///
+    /// # Errors
+    ///
+    /// This function may return an error if the endpoint wasn't built with
+    /// [`crate::builder::Builder::host_events`], or if the background task has closed for any
+    /// reason.
+    pub async fn next_host_event(&mut self) -> Result<HostEvent, Error> {
+        if !self.host_events {
+            return Err(Error::HostEventsDisabled);
+        }
+
+        self.host_event_rx
+            .recv()
+            .await
+            .map_err(|_| Error::BackgroundThreadShutdown)
+    }
+
+    /// Get the next event relating to the services found by this endpoint, for example a service
+    /// expiring because its host didn't announce it again within its TTL.
+    ///
+    /// # Errors
+    ///
+    /// This function may return an error if the background task has closed for any reason.
+    pub async fn next_service_event(&mut self) -> Result<ServiceEvent, Error> {
+        self.service_event_rx
+            .recv()
+            .await
+            .map_err(|_| Error::BackgroundThreadShutdown)
+    }
+
+    /// Get diagnostic information about this endpoint's connection to the discovery network.
+    ///
+    /// This is useful for troubleshooting an endpoint which isn't finding any services, see
+    /// [`Diagnostics`] for the information available.
+    pub fn diagnostics(&self) -> Diagnostics {
+        self.diag.get()
+    }
+
+    /// Take the most recent error the background task logged and carried on from, such as a
+    /// failure to receive a packet or a packet which couldn't be deserialised.
+    ///
+    /// The error is cleared once taken, so this returns `None` until the task hits another error,
+    /// and only the latest is kept if there were several. Errors which stop the task are returned
+    /// by [`AsyncUdis::shutdown`] instead.
+    pub fn last_error(&self) -> Option<Error> {
+        self.diag.take_error()
+    }
+
+    /// Change the name this endpoint advertises to the discovery network.
+    ///
+    /// The background task keeps its membership of the discovery network and immediately
+    /// re-announces itself under the new name. Peers will see the renamed endpoint as a new peer.
+    ///
+    /// # Errors
+    ///
+    /// This function may return an error if the background task has closed for any reason.
+    pub fn rename<S: Into<String>>(&self, name: S) -> Result<(), Error> {
+        self.handle().rename(name)
+    }
+
+    /// Find every service again straight away, for example after the network has changed.
+    ///
+    /// The background task forgets the peers and services it knows about, then queries the
+    /// discovery network so that hosts reply to it again. Services found before the refresh are
+    /// found again, so the same [`ServiceInfo`] may be returned more than once. Peers don't forget
+    /// the services this endpoint hosts.
+    ///
+    /// While paused the hosts aren't queried until the endpoint is resumed.
+    ///
+    /// # Errors
+    ///
+    /// This function may return an error if the background task has closed for any reason.
+    pub fn refresh(&self) -> Result<(), Error> {
+        self.handle().refresh()
+    }
+
+    /// Pause the endpoint without shutting it down, for example during maintenance.
+    ///
+    /// While paused the endpoint doesn't announce itself, reply to peers or find any services,
+    /// though the background task keeps receiving packets so they don't build up. Services which
+    /// peers announce while the endpoint is paused are missed.
+    ///
+    /// # Errors
+    ///
+    /// This function may return an error if the background task has closed for any reason.
+    pub fn pause(&self) -> Result<(), Error> {
+        self.handle().pause()
+    }
+
+    /// Resume the endpoint after [`AsyncUdis::pause`], re-announcing it to the discovery network.
+    ///
+    /// # Errors
+    ///
+    /// This function may return an error if the background task has closed for any reason.
+    pub fn resume(&self) -> Result<(), Error> {
+        self.handle().resume()
+    }
+
+    /// Ask the peer at `addr` directly which services it hosts, waiting up to `timeout` for it to
+    /// reply.
+    ///
+    /// The probe is sent straight to `addr` on the udis port as well as to the discovery network,
+    /// which is useful when the peer's address is known some other way but multicast doesn't
+    /// reach it, and reaches every endpoint at `addr` where multicast does. Every service the peer
+    /// hosts is returned, not just the kinds this endpoint searches for, and the services aren't
+    /// also returned by [`AsyncUdis::find_service`] unless they're newly found. If several endpoints
+    /// at `addr` host services, the services of whichever replies first are returned. An empty
+    /// list is returned if the peer doesn't reply in time, and straight away without probing
+    /// anything while the endpoint is paused.
+    ///
+    /// The peer replies directly if it has a unicast port, otherwise to the whole discovery
+    /// network. Peers running older versions of udis don't reply to probes.
+    ///
+    /// # Errors
+    ///
+    /// This function may return an error if the background task has closed for any reason.
+    pub async fn probe(&self, addr: IpAddr, timeout: Duration) -> Result<Vec<ServiceInfo>, Error> {
+        self.handle().probe(addr, timeout).await
+    }
+
+    /// Get the notify message payload this endpoint broadcasts to the discovery network.
+    ///
+    /// These are the exact bytes sent by the background task, which is useful for asserting on the
+    /// wire format in tests.
+    ///
+    /// # Errors
+    ///
+    /// This function may return an error if the background task has closed for any reason.
+    pub async fn notify_payload(&self) -> Result<Vec<u8>, Error> {
+        self.handle().notify_payload().await
+    }
+
+    /// Get a [`Handle`] for controlling this endpoint from other tasks.
+    pub fn handle(&self) -> Handle {
+        Handle {
+            cmd_tx: self.cmd_tx.clone(),
+        }
+    }
+
+    /// Shutdown this endpoint
+    ///
+    /// If the endpoint was built with a [`crate::builder::Builder::shutdown_grace`] the background
+    /// task waits for it before stopping, so messages it has sent can leave the machine.
+    ///
+    /// # Errors
+    ///
+    /// This function may return an error if the background task has closed for any reason.
+    pub async fn shutdown(self) -> Result<(), Error> {
+        // If the task has already stopped, e.g. after finding a service with
+        // `Builder::find_once`, there's nothing to tell it
+        let _ = self.cmd_tx.try_send(Cmd::Flush);
+        let _ = self.cmd_tx.try_send(Cmd::Shutdown);
+
+        self.bg_task_jh.await
+    }
+}
+
+impl Handle {
+    /// Change the name the endpoint advertises to the discovery network, see
+    /// [`AsyncUdis::rename`].
+    ///
+    /// # Errors
+    ///
+    /// This function may return an error if the background task has closed for any reason.
+    pub fn rename<S: Into<String>>(&self, name: S) -> Result<(), Error> {
+        self.cmd_tx
+            .try_send(Cmd::Rename(name.into()))
+            .map_err(|_| Error::BackgroundThreadShutdown)
+    }
+
+    /// Find every service again, see [`AsyncUdis::refresh`].
+    ///
+    /// # Errors
+    ///
+    /// This function may return an error if the background task has closed for any reason.
+    pub fn refresh(&self) -> Result<(), Error> {
+        self.cmd_tx
+            .try_send(Cmd::Refresh)
+            .map_err(|_| Error::BackgroundThreadShutdown)
+    }
+
+    /// Pause the endpoint, see [`AsyncUdis::pause`].
+    ///
+    /// # Errors
+    ///
+    /// This function may return an error if the background task has closed for any reason.
+    pub fn pause(&self) -> Result<(), Error> {
+        self.cmd_tx
+            .try_send(Cmd::Pause)
+            .map_err(|_| Error::BackgroundThreadShutdown)
+    }
+
+    /// Resume the endpoint after pausing it, see [`AsyncUdis::resume`].
+    ///
+    /// # Errors
+    ///
+    /// This function may return an error if the background task has closed for any reason.
+    pub fn resume(&self) -> Result<(), Error> {
+        self.cmd_tx
+            .try_send(Cmd::Resume)
+            .map_err(|_| Error::BackgroundThreadShutdown)
+    }
+
+    /// Ask the peer at `addr` directly which services it hosts, see [`AsyncUdis::probe`].
+    ///
+    /// # Errors
+    ///
+    /// This function may return an error if the background task has closed for any reason.
+    pub async fn probe(&self, addr: IpAddr, timeout: Duration) -> Result<Vec<ServiceInfo>, Error> {
+        let (reply_tx, reply_rx) = bounded(1);
+
+        self.cmd_tx
+            .try_send(Cmd::Probe { addr, reply_tx })
+            .map_err(|_| Error::BackgroundThreadShutdown)?;
+
+        match async_std::future::timeout(timeout, reply_rx.recv()).await {
+            Ok(reply) => reply.map_err(|_| Error::BackgroundThreadShutdown),
+            Err(_) => Ok(Vec::new()),
+        }
+    }
+
+    /// Get the notify message payload the endpoint broadcasts to the discovery network, see
+    /// [`AsyncUdis::notify_payload`].
+    ///
+    /// # Errors
+    ///
+    /// This function may return an error if the background task has closed for any reason.
+    pub async fn notify_payload(&self) -> Result<Vec<u8>, Error> {
+        let (payload_tx, payload_rx) = bounded(1);
+
+        self.cmd_tx
+            .try_send(Cmd::GetPayload(payload_tx))
+            .map_err(|_| Error::BackgroundThreadShutdown)?;
+
+        payload_rx
+            .recv()
+            .await
+            .map_err(|_| Error::BackgroundThreadShutdown)
+    }
+
+    /// Ask the endpoint to shut down.
+    ///
+    /// Unlike [`AsyncUdis::shutdown`] this doesn't wait for the background task to finish. Once it
+    /// has, [`AsyncUdis::find_service`] returns an error on the task owning the endpoint, which
+    /// should still call [`AsyncUdis::shutdown`] to collect any error the task stopped with.
+    ///
+    /// # Errors
+    ///
+    /// This function may return an error if the background task has already closed.
+    pub fn shutdown(&self) -> Result<(), Error> {
+        self.cmd_tx
+            .try_send(Cmd::Flush)
+            .and_then(|()| self.cmd_tx.try_send(Cmd::Shutdown))
+            .map_err(|_| Error::BackgroundThreadShutdown)
+    }
+}
+
+/// Something that woke the background task up
+enum Event {
+    Cmd(Option<Cmd>),
+    Signalled,
+    Repeat,
+    Reannounce,
+    Expired,
+    ServiceExpiry,
+    ReplyFlush,
+    Metrics,
+    Recv(io::Result<(SocketAddr, Option<SocketAddrV4>)>),
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn async_task(
+    udis: Udis,
+    config: Config,
+    mut transport: AsyncTransport,
+    diag: SharedDiagnostics,
+    cmd_rx: Receiver<Cmd>,
+    serv_info_tx: Sender<ServiceInfo>,
+    host_event_tx: Sender<HostEvent>,
+    service_event_tx: Sender<ServiceEvent>,
+    ready_tx: Sender<()>,
+) -> Result<(), Error> {
+    let startup_delay = config.startup_delay();
+    let deadline = config.lifetime.map(|lifetime| Instant::now() + lifetime);
+    let mut repeats_left = config.announce_repeat() - 1;
+    let shutdown_grace = config.shutdown_grace;
+    let mut shutdown_signal = config.take_shutdown_signal();
+
+    let mut processor = Processor::new(udis, config)?;
+
+    // Wait a random amount of time so endpoints starting together don't all announce at once
+    if !startup_delay.is_zero() {
+        trace!(target: TX_LOG_TARGET, "delaying initial announcement by {startup_delay:?}");
+        task::sleep(startup_delay).await;
+    }
+
+    // Send our notify message as we're joining the network, unless we're a silent client in
+    // which case hosts find out about us when they announce themselves
+    if processor.is_silent() {
+        trace!(target: TX_LOG_TARGET, "silent client, not announcing `{}`", processor.name());
+        repeats_left = 0;
+    } else {
+        trace!(target: TX_LOG_TARGET, "announcing `{}`", processor.name());
+        send_notify(&mut transport, processor.notify_message(), &diag).await?;
+        processor.announced();
+    }
+    let _ = ready_tx.try_send(());
+    let mut next_repeat = Instant::now() + ANNOUNCE_REPEAT_INTERVAL;
+
+    // Receive buffer, reused for every packet
+    let mut buf = Vec::with_capacity(processor.recv_buffer_size());
+
+    // Whoever is waiting for a reply from each probed peer, by the peer's address
+    let mut probes: HashMap<IpAddr, Vec<Sender<Vec<ServiceInfo>>>> = HashMap::new();
+
+    // When metrics should next be reported, if the user wants them
+    let metrics_period = processor
+        .metrics_interval()
+        .map(|period| period.max(Duration::from_millis(1)));
+    let mut next_metrics = metrics_period.map(|period| Instant::now() + period);
+
+    // Main loop
+    'main: loop {
+        // Wait for a command from the main task, a timer, or some data on the socket
+        let event = {
+            let cmd = async { Event::Cmd(cmd_rx.recv().await.ok()) };
+
+            // Stop when the user's shutdown signal fires, as if the endpoint had been shut down
+            let signalled = async {
+                shutdown_signalled(&mut shutdown_signal).await;
+                Event::Signalled
+            };
+
+            // Repeat our initial announcement in case it was lost
+            let repeat = async {
+                if repeats_left == 0 {
+                    pending::<()>().await;
+                }
+                sleep_until(next_repeat).await;
+                Event::Repeat
+            };
+
+            // Announce ourselves again before searchers forget the services we host with a TTL
+            let next_reannounce = processor.next_reannounce_check();
+            let reannounce = async {
+                match next_reannounce {
+                    Some(at) => sleep_until(at).await,
+                    None => pending().await,
+                }
+                Event::Reannounce
+            };
+
+            // Leave the discovery network once our lifetime is up
+            let expired = async {
+                match deadline {
+                    Some(at) => sleep_until(at).await,
+                    None => pending().await,
+                }
+                Event::Expired
+            };
+
+            // Tell the user about found services whose TTL has run out
+            let next_sweep = processor.next_sweep();
+            let service_expiry = async {
+                match next_sweep {
+                    Some(at) => sleep_until(at).await,
+                    None => pending().await,
+                }
+                Event::ServiceExpiry
+            };
+
+            // Send replies held back to be coalesced once their window is up
+            let next_reply_flush = processor.next_reply_flush_check();
+            let reply_flush = async {
+                match next_reply_flush {
+                    Some(at) => sleep_until(at).await,
+                    None => pending().await,
+                }
+                Event::ReplyFlush
+            };
+
+            // Periodically report metrics
+            let metrics = async {
+                match next_metrics {
+                    Some(at) => sleep_until(at).await,
+                    None => pending().await,
+                }
+                Event::Metrics
+            };
+
+            let recv = async { Event::Recv(transport.recv(&mut buf).await) };
+
+            cmd.or(signalled)
+                .or(repeat)
+                .or(reannounce)
+                .or(expired)
+                .or(service_expiry)
+                .or(reply_flush)
+                .or(metrics)
+                .or(recv)
+                .await
+        };
+
+        match event {
+            // On command receipt handle it
+            Event::Cmd(cmd) => match cmd {
+                Some(cmd) => match cmd {
+                    Cmd::Flush => {
+                        // Replies held back are still owed to the peers that asked for them
+                        if !send_held_replies(&mut processor, &mut transport, &diag, &host_event_tx)
+                            .await?
+                        {
+                            break;
+                        }
+                        if !shutdown_grace.is_zero() {
+                            trace!("waiting {shutdown_grace:?} for sent messages to leave");
+                            task::sleep(shutdown_grace).await;
+                        }
+                    }
+                    Cmd::Shutdown => break,
+                    Cmd::Rename(name) => {
+                        processor.rename(name)?;
+
+                        // If paused the new name is announced on resuming
+                        if !processor.is_paused() {
+                            trace!(target: TX_LOG_TARGET, "announcing `{}`", processor.name());
+                            send_notify(&mut transport, processor.notify_message(), &diag).await?;
+                            processor.announced();
+                        }
+                    }
+                    Cmd::Refresh => {
+                        processor.refresh();
+
+                        // If paused hosts are queried on resuming
+                        if !processor.is_paused() {
+                            trace!(target: TX_LOG_TARGET, "querying hosts for `{}`", processor.name());
+                            send_notify(&mut transport, processor.query_message(), &diag).await?;
+                            processor.announced();
+                        }
+                    }
+                    Cmd::Pause => {
+                        if !send_held_replies(&mut processor, &mut transport, &diag, &host_event_tx)
+                            .await?
+                        {
+                            break;
+                        }
+                        processor.pause();
+                    }
+                    Cmd::Resume => {
+                        trace!(target: TX_LOG_TARGET, "announcing `{}`", processor.name());
+                        send_notify(&mut transport, processor.resume(), &diag).await?;
+                        processor.announced();
+                    }
+                    Cmd::GetPayload(payload_tx) => {
+                        // If the requester has gone away there's nobody to tell
+                        let _ = payload_tx.try_send(processor.notify_message().to_vec());
+                    }
+                    Cmd::Probe { addr, reply_tx } => {
+                        // A paused endpoint mustn't make itself known, so has nobody to ask
+                        if processor.is_paused() {
+                            let _ = reply_tx.try_send(Vec::new());
+                            continue;
+                        }
+
+                        let probe = processor.probe(addr)?;
+                        send_notify(&mut transport, &probe, &diag).await?;
+                        let to = SocketAddr::new(addr, MULTICAST_PORT);
+                        send_reply(&mut transport, &probe, to, &diag).await?;
+
+                        // Forget anyone whose earlier probe timed out, and any probe nobody is
+                        // waiting for any more
+                        probes.retain(|&addr, waiting| {
+                            waiting.retain(|tx| !tx.is_closed());
+                            if waiting.is_empty() {
+                                processor.forget_probe(addr);
+                            }
+                            !waiting.is_empty()
+                        });
+                        probes.entry(addr).or_default().push(reply_tx);
+                    }
+                },
+                None => break,
+            },
+
+            Event::Signalled => {
+                trace!("shutdown signal fired");
+                break;
+            }
+
+            Event::Repeat => {
+                repeats_left -= 1;
+                next_repeat += ANNOUNCE_REPEAT_INTERVAL;
+
+                if processor.is_paused() {
+                    trace!(target: TX_LOG_TARGET, "paused, skipping repeat announcement");
+                } else if processor.announcement_is_redundant() {
+                    trace!(target: TX_LOG_TARGET, "skipping redundant repeat announcement");
+                } else {
+                    trace!(
+                        target: TX_LOG_TARGET,
+                        "repeating announcement of `{}`",
+                        processor.name()
+                    );
+                    send_notify(&mut transport, processor.notify_message(), &diag).await?;
+                    processor.announced();
+                }
+            }
+
+            Event::Reannounce => {
+                if processor.reannounce_due() {
+                    trace!(
+                        target: TX_LOG_TARGET,
+                        "re-announcing `{}` within its TTL",
+                        processor.name()
+                    );
+                    send_notify(&mut transport, processor.notify_message(), &diag).await?;
+                    processor.announced();
+                }
+            }
+
+            Event::Expired => {
+                trace!(
+                    target: TX_LOG_TARGET,
+                    "lifetime expired, saying goodbye from `{}`",
+                    processor.name()
+                );
+                send_notify(&mut transport, &processor.goodbye_message()?, &diag).await?;
+                break;
+            }
+
+            Event::ServiceExpiry => {
+                for event in processor.expire_services() {
+                    if service_event_tx.send(event).await.is_err() {
+                        trace!("service event receiver dropped, stopping");
+                        break 'main;
+                    }
+                }
+            }
+
+            Event::ReplyFlush => {
+                if processor.replies_due()
+                    && !send_held_replies(&mut processor, &mut transport, &diag, &host_event_tx)
+                        .await?
+                {
+                    break 'main;
+                }
+            }
+
+            Event::Metrics => {
+                processor.report_metrics(&diag);
+                next_metrics = metrics_period.map(|period| Instant::now() + period);
+            }
+
+            // On some data from the transport process it
+            Event::Recv(recv_res) => {
+                let (src, group) = match recv_res {
+                    Ok(received) => {
+                        diag.record_recv();
+                        received
+                    }
+                    Err(e) => {
+                        match e.kind() {
+                            ErrorKind::TimedOut | ErrorKind::WouldBlock => (),
+                            k => {
+                                error!(
+                                    target: RX_LOG_TARGET,
+                                    "Error while receiving udis notify messages (will continue): \
+                                    ({k:?}) {e}"
+                                );
+                                diag.record_error(e.into());
+                            }
+                        }
+                        continue;
+                    }
+                };
+
+                let outputs = processor.process_packet(&buf, src, group)?;
+                if let Some(e) = processor.take_error() {
+                    diag.record_error(e);
+                }
+
+                for output in outputs {
+                    match output {
+                        Output::Send(msg) => send_notify(&mut transport, &msg, &diag).await?,
+                        Output::Reply(msg, addr) => {
+                            send_reply(&mut transport, &msg, addr, &diag).await?
+                        }
+                        // A closed channel means the endpoint was dropped without being shut down,
+                        // so nobody is listening any more and the task should just stop
+                        Output::Service(serv_info) => {
+                            if serv_info_tx.send(*serv_info).await.is_err() {
+                                trace!("service receiver dropped, stopping");
+                                break 'main;
+                            }
+                        }
+                        Output::HostEvent(event) => {
+                            if host_event_tx.send(event).await.is_err() {
+                                trace!("host event receiver dropped, stopping");
+                                break 'main;
+                            }
+                        }
+                        Output::ProbeReply(addr, hosted) => {
+                            for reply_tx in probes.remove(&addr).unwrap_or_default() {
+                                let _ = reply_tx.try_send(hosted.clone());
+                            }
+                        }
+                        Output::Shutdown => break 'main,
+                    }
+                }
+            }
+        }
+    }
+
+    trace!("udis background task shutting down");
+
+    Ok(())
+}
+
+/// Wait for the user's shutdown signal to fire, or forever if there isn't one
+async fn shutdown_signalled(signal: &mut Option<ShutdownSignal>) {
+    match signal {
+        Some(signal) => signal.await,
+        None => pending().await,
+    }
+}
+
+/// Sleep until the given instant, returning immediately if it has already passed
+async fn sleep_until(at: Instant) {
+    task::sleep(at.saturating_duration_since(Instant::now())).await;
+}
+
+/// Send a message directly to a peer
+async fn send_reply(
+    transport: &mut AsyncTransport,
+    message: &[u8],
+    addr: SocketAddr,
+    diag: &SharedDiagnostics,
+) -> Result<(), Error> {
+    match transport.send_to(message, addr).await {
+        Ok(()) => diag.record_send(),
+        Err(e) => ignore_transient_send_error(e, diag)?,
+    }
+
+    Ok(())
+}
+
+/// Send the replies held back to be coalesced, returning false if the host event receiver has been
+/// dropped so the task should stop
+async fn send_held_replies(
+    processor: &mut Processor,
+    transport: &mut AsyncTransport,
+    diag: &SharedDiagnostics,
+    host_event_tx: &Sender<HostEvent>,
+) -> Result<bool, Error> {
+    for output in processor.flush_replies() {
+        match output {
+            Output::Send(msg) => send_notify(transport, &msg, diag).await?,
+            Output::Reply(msg, addr) => send_reply(transport, &msg, addr, diag).await?,
+            Output::HostEvent(event) => {
+                if host_event_tx.send(event).await.is_err() {
+                    trace!("host event receiver dropped, stopping");
+                    return Ok(false);
+                }
+            }
+            // Only replies and the events for them are held back
+            Output::Service(_) | Output::ProbeReply(..) | Output::Shutdown => (),
+        }
+    }
+
+    Ok(true)
+}
+
+/// Send the notify message to the discovery network
+async fn send_notify(
+    transport: &mut AsyncTransport,
+    notify_message: &[u8],
+    diag: &SharedDiagnostics,
+) -> Result<(), Error> {
+    match transport.send(notify_message).await {
+        Ok(()) => diag.record_send(),
+        Err(e) => ignore_transient_send_error(e, diag)?,
+    }
+
+    Ok(())
+}
+
+/// Which socket of the UDP transport a packet was received on
+#[derive(Debug, Clone, Copy)]
+enum Source {
+    Multicast,
+    Unicast,
+
+    /// The socket of the extra group at this index
+    Group(usize),
+}
+
+/// A packet received on one of the sockets of the UDP transport, with its length and source
+type Received = io::Result<((usize, SocketAddr), Source)>;
+
+/// Wait for any of the extra group sockets to receive a packet, receiving into the group's buffer
+/// after growing it to `len`. This never finishes if there are no groups.
+async fn recv_any_group(groups: &mut [(SocketAddrV4, UdpSocket, Vec<u8>)], len: usize) -> Received {
+    let mut recvs: Vec<Pin<Box<dyn Future<Output = Received> + Send + '_>>> = groups
+        .iter_mut()
+        .enumerate()
+        .map(|(i, (_, socket, group_buf))| {
+            group_buf.resize(len, 0);
+            Box::pin(async move {
+                let received = socket.recv_from(group_buf).await?;
+                Ok((received, Source::Group(i)))
+            }) as Pin<Box<dyn Future<Output = Received> + Send + '_>>
+        })
+        .collect();
+
+    poll_fn(|cx| {
+        for recv in &mut recvs {
+            if let Poll::Ready(res) = recv.as_mut().poll(cx) {
+                return Poll::Ready(res);
+            }
+        }
+        Poll::Pending
+    })
+    .await
+}
+
+/// The transport used by the async background task
+enum AsyncTransport {
+    /// The default UDP multicast transport
+    Udp {
+        socket: UdpSocket,
+
+        /// Socket bound to the unicast port, if there is one, which all messages are sent from,
+        /// along with a buffer to receive into while also waiting on the multicast socket
+        unicast: Option<(UdpSocket, Vec<u8>)>,
+
+        /// Addresses of the discovery network groups messages are sent to
+        disc_addrs: Vec<SocketAddr>,
+
+        /// Extra multicast groups messages are also sent to, and the sockets receiving from them
+        /// along with buffers to receive into
+        groups: Vec<(SocketAddrV4, UdpSocket, Vec<u8>)>,
+
+        bind_mode: BindMode,
+
+        /// Relays messages between interfaces while the transport is in use, if asked to
+        _relay: Option<Relay>,
+    },
+
+    /// A transport given by the user
+    Custom(Box<dyn Transport>),
+}
+
+impl AsyncTransport {
+    /// Build the multicast socket described by the config, or use the one the user gave us
+    fn udp(config: &mut Config, diag: &SharedDiagnostics) -> Result<Self, Error> {
+        let (disc_addrs, socket) = discovery_socket(
+            config.socket.take(),
+            config.bind_mode(),
+            config.delivery,
+            config.device.as_deref(),
+            config.dscp,
+        )?;
+        trace!("joined udis notify network on {disc_addrs:?}");
+        diag.record_socket(&disc_addrs, &socket);
+
+        let unicast = config
+            .unicast_port
+            .map(|port| {
+                trace!("using unicast port {port}");
+                build_unicast_socket(
+                    port,
+                    config.bind_mode(),
+                    config.delivery,
+                    config.device.as_deref(),
+                    config.dscp,
+                )
+            })
+            .transpose()?;
+
+        if config.verify_multicast {
+            trace!("verifying the udis notify network");
+            verify_discovery(
+                &socket,
+                unicast.as_ref().unwrap_or(&socket),
+                &disc_addrs,
+                VERIFY_MULTICAST_TIMEOUT,
+            )?;
+        }
+
+        let groups = config
+            .groups
+            .iter()
+            .map(|&group| {
+                trace!("joining extra group {group}");
+                build_group_socket(group, config.device.as_deref()).map(|socket| (group, socket))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        // Convert the sockets to async-std ones
+        let socket = std::net::UdpSocket::from(socket).into();
+        let unicast =
+            unicast.map(|unicast| (std::net::UdpSocket::from(unicast).into(), Vec::new()));
+        let groups = groups
+            .into_iter()
+            .map(|(group, socket)| (group, std::net::UdpSocket::from(socket).into(), Vec::new()))
+            .collect();
+
+        Ok(Self::Udp {
+            socket,
+            unicast,
+            disc_addrs,
+            groups,
+            bind_mode: config.bind_mode(),
+            _relay: Relay::start(config)?,
+        })
+    }
+
+    /// Send a message to the discovery network
+    async fn send(&mut self, msg: &[u8]) -> io::Result<()> {
+        match self {
+            Self::Udp {
+                socket,
+                unicast,
+                disc_addrs,
+                groups,
+                bind_mode,
+                ..
+            } => {
+                let socket = unicast.as_ref().map_or(&*socket, |(unicast, _)| unicast);
+                for disc_addr in disc_addrs.iter() {
+                    socket.send_to(msg, disc_addr).await?;
+                }
+                for (group, ..) in groups.iter() {
+                    socket
+                        .send_to(msg, send_addr((*group).into(), *bind_mode))
+                        .await?;
+                }
+                Ok(())
+            }
+            Self::Custom(transport) => transport.send(msg),
+        }
+    }
+
+    /// Send a message directly to the endpoint at `addr`
+    async fn send_to(&mut self, msg: &[u8], addr: SocketAddr) -> io::Result<()> {
+        match self {
+            Self::Udp {
+                socket,
+                unicast,
+                bind_mode,
+                ..
+            } => {
+                let socket = unicast.as_ref().map_or(&*socket, |(unicast, _)| unicast);
+                socket.send_to(msg, send_addr(addr, *bind_mode)).await?;
+                Ok(())
+            }
+            Self::Custom(transport) => transport.send_to(msg, addr),
+        }
+    }
+
+    /// Receive the next message from the discovery network into `buf`, along with the extra group
+    /// it was received on if any
+    async fn recv(&mut self, buf: &mut Vec<u8>) -> io::Result<(SocketAddr, Option<SocketAddrV4>)> {
+        match self {
+            Self::Udp {
+                socket,
+                unicast,
+                groups,
+                ..
+            } => {
+                buf.resize(buf.capacity(), 0);
+                let len = buf.len();
+
+                // Wait for any socket to receive a packet, copying anything received on the
+                // unicast or group sockets into the caller's buffer
+                let multicast = async {
+                    let received = socket.recv_from(buf).await?;
+                    Ok((received, Source::Multicast))
+                };
+                let unicast_recv = async {
+                    let Some((unicast, unicast_buf)) = unicast else {
+                        return pending().await;
+                    };
+                    unicast_buf.resize(len, 0);
+                    let received = unicast.recv_from(unicast_buf).await?;
+                    Ok((received, Source::Unicast))
+                };
+                let group_recv = recv_any_group(groups, len);
+
+                let ((received, src), source) = multicast.or(unicast_recv).or(group_recv).await?;
+                let group = match source {
+                    Source::Multicast => None,
+                    Source::Unicast => {
+                        if let Some((_, unicast_buf)) = unicast {
+                            buf[..received].copy_from_slice(&unicast_buf[..received]);
+                        }
+                        None
+                    }
+                    Source::Group(i) => {
+                        let (group, _, group_buf) = &groups[i];
+                        buf[..received].copy_from_slice(&group_buf[..received]);
+                        Some(*group)
+                    }
+                };
+
+                buf.truncate(received);
+                Ok((src, group))
+            }
+            Self::Custom(transport) => loop {
+                match transport.recv_with_group(buf) {
+                    Err(e) if e.kind() == ErrorKind::WouldBlock => {
+                        task::sleep(POLL_PERIOD).await;
+                    }
+                    res => return res,
+                }
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::Ipv4Addr, time::Duration};
+
+    use async_std::{future::timeout, task};
+
+    use crate::{
+        clock::MockClock,
+        tcp_host,
+        transport::{ChannelNetwork, Transport},
+        Message, Service, ServiceEvent, Udis,
+    };
+
+    #[test]
+    fn test_find_and_expire() {
+        task::block_on(async {
+            let network = ChannelNetwork::new();
+            let clock = MockClock::new();
+            let server = Udis::new("server")
+                .addr(Ipv4Addr::new(10, 0, 0, 1))
+                .host("hello", 4112)
+                .unwrap()
+                .build_async_std_with_transport(
+                    network.transport((Ipv4Addr::new(10, 0, 0, 1), 8787)),
+                )
+                .unwrap();
+            let mut client = Udis::new("client")
+                .addr(Ipv4Addr::new(10, 0, 0, 2))
+                .search("hello")
+                .search("leased")
+                .clock(clock.clone())
+                .build_async_std_with_transport(
+                    network.transport((Ipv4Addr::new(10, 0, 0, 2), 8787)),
+                )
+                .unwrap();
+
+            let wait = Duration::from_secs(2);
+            let found = timeout(wait, client.find_service()).await.unwrap().unwrap();
+            assert_eq!((found.name.as_str(), found.port), ("server", 4112));
+
+            // A service whose host goes quiet should expire once its TTL is up
+            let mut leased = tcp_host("leased", 4113);
+            if let Service::Host { ttl, .. } = &mut leased {
+                *ttl = 1;
+            }
+            let peer = Udis::build(
+                "leaser".into(),
+                Ipv4Addr::new(10, 0, 0, 3).into(),
+                vec![leased],
+            );
+            network
+                .transport((Ipv4Addr::new(10, 0, 0, 3), 8787))
+                .send(&Message::Notify(peer).encode().unwrap())
+                .unwrap();
+            let found = timeout(wait, client.find_service()).await.unwrap().unwrap();
+            assert_eq!(found.port, 4113);
+
+            clock.advance(Duration::from_secs(2));
+            let event = timeout(wait, client.next_service_event())
+                .await
+                .unwrap()
+                .unwrap();
+            assert!(matches!(event, ServiceEvent::Expired(s) if s.port == 4113));
+
+            client.shutdown().await.unwrap();
+            server.shutdown().await.unwrap();
+        });
+    }
+}
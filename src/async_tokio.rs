@@ -1,10 +1,37 @@
-use std::collections::HashSet;
+use std::{
+    collections::HashMap,
+    future::poll_fn,
+    io::{self, ErrorKind},
+    iter,
+    net::{IpAddr, SocketAddr, SocketAddrV4},
+    task::Poll,
+    time::Duration,
+};
 
-use crate::{error::Error, net::build_multicast_socket, Service, ServiceInfo, Udis};
+use crate::{
+    builder::BindMode,
+    config::{Config, ShutdownSignal, ANNOUNCE_REPEAT_INTERVAL, VERIFY_MULTICAST_TIMEOUT},
+    core::{Output, Processor},
+    diagnostics::{Diagnostics, SharedDiagnostics},
+    error::Error,
+    net::{
+        build_group_socket, build_unicast_socket, discovery_socket, ignore_transient_send_error,
+        send_addr, verify_discovery, MULTICAST_PORT, RX_LOG_TARGET, TX_LOG_TARGET,
+    },
+    relay::Relay,
+    sync::POLL_PERIOD,
+    transport::Transport,
+    HostEvent, ServiceEvent, ServiceInfo, Udis,
+};
 use log::{error, trace};
 use tokio::{
-    sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender},
+    net::UdpSocket,
+    sync::{
+        mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender},
+        oneshot,
+    },
     task::JoinHandle,
+    time::{Instant, Interval, MissedTickBehavior},
 };
 
 /// An asynchronous udis endpoint.
@@ -16,6 +43,9 @@ use tokio::{
 ///
 /// When finished using the endpoint be sure to call [`AsyncUdis::shutdown`] to close the background
 /// task.
+///
+/// If the endpoint is dropped without being shut down the background task stops by itself, as
+/// nobody is listening for services any more. This is not treated as an error.
 #[derive(Debug)]
 pub struct AsyncUdis {
     _udis: Udis,
@@ -28,28 +58,128 @@ pub struct AsyncUdis {
 
     // Receiver for getting service infos from the udis task
     serv_info_rx: UnboundedReceiver<ServiceInfo>,
+
+    // Receiver for getting host events from the udis task
+    host_event_rx: UnboundedReceiver<HostEvent>,
+
+    // Whether the udis task passes on host events
+    host_events: bool,
+
+    // Receiver for getting service events from the udis task
+    service_event_rx: UnboundedReceiver<ServiceEvent>,
+
+    // Diagnostics updated by the udis task
+    diag: SharedDiagnostics,
+
+    // Receiver signalled once the udis task has made its first announcement, cleared once it has
+    ready_rx: Option<oneshot::Receiver<()>>,
+}
+
+/// A cheap, cloneable handle for controlling an [`AsyncUdis`] endpoint from other tasks.
+///
+/// Get a handle with [`AsyncUdis::handle`]. Handles can be used concurrently from any number of
+/// tasks, while found services and host events are still only received by the [`AsyncUdis`]
+/// itself.
+#[derive(Debug, Clone)]
+pub struct Handle {
+    // Sender for commands
+    cmd_tx: UnboundedSender<Cmd>,
 }
 
 enum Cmd {
+    /// Wait for the shutdown grace so messages already sent leave the machine
+    Flush,
     Shutdown,
+    Rename(String),
+    Refresh,
+    Pause,
+    Resume,
+    GetPayload(oneshot::Sender<Vec<u8>>),
+    Probe {
+        addr: IpAddr,
+        reply_tx: oneshot::Sender<Vec<ServiceInfo>>,
+    },
 }
 
 impl AsyncUdis {
-    pub(crate) fn build(udis: Udis) -> Self {
+    pub(crate) fn build(
+        udis: Udis,
+        mut config: Config,
+        transport: Option<Box<dyn Transport>>,
+        handle: &tokio::runtime::Handle,
+    ) -> Result<Self, Error> {
         let (cmd_tx, cmd_rx) = unbounded_channel();
         let (serv_info_tx, serv_info_rx) = unbounded_channel();
+        let (ready_tx, ready_rx) = oneshot::channel();
+        let (host_event_tx, host_event_rx) = unbounded_channel();
+        let (service_event_tx, service_event_rx) = unbounded_channel();
 
+        let diag = SharedDiagnostics::default();
+
+        // Connect to the multicast network here rather than in the task, so that any errors are
+        // returned when building the endpoint. The runtime must be entered to register the socket
+        // with it.
+        let transport = match transport {
+            Some(transport) => AsyncTransport::Custom(transport),
+            None => {
+                let _guard = handle.enter();
+                AsyncTransport::udp(&mut config, &diag)?
+            }
+        };
+
+        let host_events = config.host_events;
         let udis_bg = udis.clone();
+        let diag_bg = diag.clone();
 
-        let bg_task_jh =
-            tokio::task::spawn(async move { async_task(udis_bg, cmd_rx, serv_info_tx).await });
+        // Naming tasks needs tokio's unstable task builder, so unlike the sync worker thread the
+        // task is left anonymous
+        let bg_task_jh = handle.spawn(async move {
+            async_task(
+                udis_bg,
+                config,
+                transport,
+                diag_bg,
+                cmd_rx,
+                serv_info_tx,
+                host_event_tx,
+                service_event_tx,
+                ready_tx,
+            )
+            .await
+        });
 
-        Self {
+        Ok(Self {
             _udis: udis,
             bg_task_jh,
             cmd_tx,
             serv_info_rx,
+            host_event_rx,
+            host_events,
+            service_event_rx,
+            diag,
+            ready_rx: Some(ready_rx),
+        })
+    }
+
+    /// Wait until the background task has joined the discovery network and sent its first
+    /// announcement.
+    ///
+    /// Any endpoint started after this returns is guaranteed to be able to see this one, which is
+    /// useful for ordering endpoints in tests without arbitrary sleeps. Once the endpoint is ready
+    /// this returns immediately.
+    ///
+    /// # Errors
+    ///
+    /// This function may return an error if the background task closed before it was ready.
+    pub async fn ready(&mut self) -> Result<(), Error> {
+        if let Some(ready_rx) = &mut self.ready_rx {
+            ready_rx
+                .await
+                .map_err(|_| Error::BackgroundThreadShutdown)?;
+            self.ready_rx = None;
         }
+
+        Ok(())
     }
 
     /// Find the next service discovered by this udis endpoint.
@@ -65,15 +195,160 @@ impl AsyncUdis {
         }
     }
 
+    /// Get the next event relating to the services hosted by this endpoint, for example a peer
+    /// acknowledging that it has received one of our services.
+    ///
+    /// # Errors
+    ///
+    /// This function may return an error if the endpoint wasn't built with
+    /// [`crate::builder::Builder::host_events`], or if the background task has closed for any
+    /// reason.
+    pub async fn next_host_event(&mut self) -> Result<HostEvent, Error> {
+        if !self.host_events {
+            return Err(Error::HostEventsDisabled);
+        }
+
+        self.host_event_rx
+            .recv()
+            .await
+            .ok_or(Error::BackgroundThreadShutdown)
+    }
+
+    /// Get the next event relating to the services found by this endpoint, for example a service
+    /// expiring because its host didn't announce it again within its TTL.
+    ///
+    /// # Errors
+    ///
+    /// This function may return an error if the background task has closed for any reason.
+    pub async fn next_service_event(&mut self) -> Result<ServiceEvent, Error> {
+        self.service_event_rx
+            .recv()
+            .await
+            .ok_or(Error::BackgroundThreadShutdown)
+    }
+
+    /// Get diagnostic information about this endpoint's connection to the discovery network.
+    ///
+    /// This is useful for troubleshooting an endpoint which isn't finding any services, see
+    /// [`Diagnostics`] for the information available.
+    pub fn diagnostics(&self) -> Diagnostics {
+        self.diag.get()
+    }
+
+    /// Take the most recent error the background task logged and carried on from, such as a
+    /// failure to receive a packet or a packet which couldn't be deserialised.
+    ///
+    /// The error is cleared once taken, so this returns `None` until the task hits another error,
+    /// and only the latest is kept if there were several. Errors which stop the task are returned
+    /// by [`AsyncUdis::shutdown`] instead.
+    pub fn last_error(&self) -> Option<Error> {
+        self.diag.take_error()
+    }
+
+    /// Change the name this endpoint advertises to the discovery network.
+    ///
+    /// The background task keeps its membership of the discovery network and immediately
+    /// re-announces itself under the new name. Peers will see the renamed endpoint as a new peer.
+    ///
+    /// # Errors
+    ///
+    /// This function may return an error if the background task has closed for any reason.
+    pub fn rename<S: Into<String>>(&self, name: S) -> Result<(), Error> {
+        self.handle().rename(name)
+    }
+
+    /// Find every service again straight away, for example after the network has changed.
+    ///
+    /// The background task forgets the peers and services it knows about, then queries the
+    /// discovery network so that hosts reply to it again. Services found before the refresh are
+    /// found again, so the same [`ServiceInfo`] may be returned more than once. Peers don't forget
+    /// the services this endpoint hosts.
+    ///
+    /// While paused the hosts aren't queried until the endpoint is resumed.
+    ///
+    /// # Errors
+    ///
+    /// This function may return an error if the background task has closed for any reason.
+    pub fn refresh(&self) -> Result<(), Error> {
+        self.handle().refresh()
+    }
+
+    /// Pause the endpoint without shutting it down, for example during maintenance.
+    ///
+    /// While paused the endpoint doesn't announce itself, reply to peers or find any services,
+    /// though the background task keeps receiving packets so they don't build up. Services which
+    /// peers announce while the endpoint is paused are missed.
+    ///
+    /// # Errors
+    ///
+    /// This function may return an error if the background task has closed for any reason.
+    pub fn pause(&self) -> Result<(), Error> {
+        self.handle().pause()
+    }
+
+    /// Resume the endpoint after [`AsyncUdis::pause`], re-announcing it to the discovery network.
+    ///
+    /// # Errors
+    ///
+    /// This function may return an error if the background task has closed for any reason.
+    pub fn resume(&self) -> Result<(), Error> {
+        self.handle().resume()
+    }
+
+    /// Ask the peer at `addr` directly which services it hosts, waiting up to `timeout` for it to
+    /// reply.
+    ///
+    /// The probe is sent straight to `addr` on the udis port as well as to the discovery network,
+    /// which is useful when the peer's address is known some other way but multicast doesn't
+    /// reach it, and reaches every endpoint at `addr` where multicast does. Every service the peer
+    /// hosts is returned, not just the kinds this endpoint searches for, and the services aren't
+    /// also returned by [`AsyncUdis::find_service`] unless they're newly found. If several endpoints
+    /// at `addr` host services, the services of whichever replies first are returned. An empty
+    /// list is returned if the peer doesn't reply in time, and straight away without probing
+    /// anything while the endpoint is paused.
+    ///
+    /// The peer replies directly if it has a unicast port, otherwise to the whole discovery
+    /// network. Peers running older versions of udis don't reply to probes.
+    ///
+    /// # Errors
+    ///
+    /// This function may return an error if the background task has closed for any reason.
+    pub async fn probe(&self, addr: IpAddr, timeout: Duration) -> Result<Vec<ServiceInfo>, Error> {
+        self.handle().probe(addr, timeout).await
+    }
+
+    /// Get the notify message payload this endpoint broadcasts to the discovery network.
+    ///
+    /// These are the exact bytes sent by the background task, which is useful for asserting on the
+    /// wire format in tests.
+    ///
+    /// # Errors
+    ///
+    /// This function may return an error if the background task has closed for any reason.
+    pub async fn notify_payload(&self) -> Result<Vec<u8>, Error> {
+        self.handle().notify_payload().await
+    }
+
+    /// Get a [`Handle`] for controlling this endpoint from other tasks.
+    pub fn handle(&self) -> Handle {
+        Handle {
+            cmd_tx: self.cmd_tx.clone(),
+        }
+    }
+
     /// Shutdown this endpoint
     ///
+    /// If the endpoint was built with a [`crate::builder::Builder::shutdown_grace`] the background
+    /// task waits for it before stopping, so messages it has sent can leave the machine.
+    ///
     /// # Errors
     ///
     /// This function may return an error if the background task has closed for any reason.
     pub async fn shutdown(self) -> Result<(), Error> {
-        self.cmd_tx
-            .send(Cmd::Shutdown)
-            .map_err(|_| Error::FailedToShutdownUdisTask)?;
+        // If the task has already stopped, e.g. after finding a service with
+        // `Builder::find_once`, there's nothing to tell it
+        let _ = self.cmd_tx.send(Cmd::Flush);
+        let _ = self.cmd_tx.send(Cmd::Shutdown);
 
         self.bg_task_jh.await??;
 
@@ -81,124 +356,756 @@ impl AsyncUdis {
     }
 }
 
+impl Handle {
+    /// Change the name the endpoint advertises to the discovery network, see
+    /// [`AsyncUdis::rename`].
+    ///
+    /// # Errors
+    ///
+    /// This function may return an error if the background task has closed for any reason.
+    pub fn rename<S: Into<String>>(&self, name: S) -> Result<(), Error> {
+        self.cmd_tx
+            .send(Cmd::Rename(name.into()))
+            .map_err(|_| Error::BackgroundThreadShutdown)
+    }
+
+    /// Find every service again, see [`AsyncUdis::refresh`].
+    ///
+    /// # Errors
+    ///
+    /// This function may return an error if the background task has closed for any reason.
+    pub fn refresh(&self) -> Result<(), Error> {
+        self.cmd_tx
+            .send(Cmd::Refresh)
+            .map_err(|_| Error::BackgroundThreadShutdown)
+    }
+
+    /// Pause the endpoint, see [`AsyncUdis::pause`].
+    ///
+    /// # Errors
+    ///
+    /// This function may return an error if the background task has closed for any reason.
+    pub fn pause(&self) -> Result<(), Error> {
+        self.cmd_tx
+            .send(Cmd::Pause)
+            .map_err(|_| Error::BackgroundThreadShutdown)
+    }
+
+    /// Resume the endpoint after pausing it, see [`AsyncUdis::resume`].
+    ///
+    /// # Errors
+    ///
+    /// This function may return an error if the background task has closed for any reason.
+    pub fn resume(&self) -> Result<(), Error> {
+        self.cmd_tx
+            .send(Cmd::Resume)
+            .map_err(|_| Error::BackgroundThreadShutdown)
+    }
+
+    /// Ask the peer at `addr` directly which services it hosts, see [`AsyncUdis::probe`].
+    ///
+    /// # Errors
+    ///
+    /// This function may return an error if the background task has closed for any reason.
+    pub async fn probe(&self, addr: IpAddr, timeout: Duration) -> Result<Vec<ServiceInfo>, Error> {
+        let (reply_tx, reply_rx) = oneshot::channel();
+
+        self.cmd_tx
+            .send(Cmd::Probe { addr, reply_tx })
+            .map_err(|_| Error::BackgroundThreadShutdown)?;
+
+        match tokio::time::timeout(timeout, reply_rx).await {
+            Ok(reply) => reply.map_err(|_| Error::BackgroundThreadShutdown),
+            Err(_) => Ok(Vec::new()),
+        }
+    }
+
+    /// Get the notify message payload the endpoint broadcasts to the discovery network, see
+    /// [`AsyncUdis::notify_payload`].
+    ///
+    /// # Errors
+    ///
+    /// This function may return an error if the background task has closed for any reason.
+    pub async fn notify_payload(&self) -> Result<Vec<u8>, Error> {
+        let (payload_tx, payload_rx) = oneshot::channel();
+
+        self.cmd_tx
+            .send(Cmd::GetPayload(payload_tx))
+            .map_err(|_| Error::BackgroundThreadShutdown)?;
+
+        payload_rx
+            .await
+            .map_err(|_| Error::BackgroundThreadShutdown)
+    }
+
+    /// Ask the endpoint to shut down.
+    ///
+    /// Unlike [`AsyncUdis::shutdown`] this doesn't wait for the background task to finish. Once it
+    /// has, [`AsyncUdis::find_service`] returns an error on the task owning the endpoint, which
+    /// should still call [`AsyncUdis::shutdown`] to collect any error the task stopped with.
+    ///
+    /// # Errors
+    ///
+    /// This function may return an error if the background task has already closed.
+    pub fn shutdown(&self) -> Result<(), Error> {
+        self.cmd_tx
+            .send(Cmd::Flush)
+            .and_then(|()| self.cmd_tx.send(Cmd::Shutdown))
+            .map_err(|_| Error::BackgroundThreadShutdown)
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
 async fn async_task(
     udis: Udis,
+    config: Config,
+    mut transport: AsyncTransport,
+    diag: SharedDiagnostics,
     mut cmd_rx: UnboundedReceiver<Cmd>,
     serv_info_tx: UnboundedSender<ServiceInfo>,
+    host_event_tx: UnboundedSender<HostEvent>,
+    service_event_tx: UnboundedSender<ServiceEvent>,
+    ready_tx: oneshot::Sender<()>,
 ) -> Result<(), Error> {
-    // Build the multicast socket
-    let (disc_addr, socket) = build_multicast_socket()?;
-    trace!("joined udis notify network on {disc_addr}");
-
-    for service in &udis.services {
-        match service {
-            Service::Host { kind, port } => {
-                trace!("hosting service `{}` on port {}", kind, port);
-            }
-            Service::Search { kind } => {
-                trace!("searching for service `{}`", kind);
-            }
-        }
+    let startup_delay = config.startup_delay();
+    let deadline = config.lifetime.map(|lifetime| Instant::now() + lifetime);
+    let mut repeats_left = config.announce_repeat() - 1;
+    let shutdown_grace = config.shutdown_grace;
+    let mut shutdown_signal = config.take_shutdown_signal();
+
+    let mut processor = Processor::new(udis, config)?;
+
+    // Wait a random amount of time so endpoints starting together don't all announce at once
+    if !startup_delay.is_zero() {
+        trace!(target: TX_LOG_TARGET, "delaying initial announcement by {startup_delay:?}");
+        tokio::time::sleep(startup_delay).await;
     }
 
-    // Convert the socket to a tokio one
-    let socket: tokio::net::UdpSocket = tokio::net::UdpSocket::from_std(socket.into())?;
+    // Send our notify message as we're joining the network, unless we're a silent client in
+    // which case hosts find out about us when they announce themselves
+    if processor.is_silent() {
+        trace!(target: TX_LOG_TARGET, "silent client, not announcing `{}`", processor.name());
+        repeats_left = 0;
+    } else {
+        trace!(target: TX_LOG_TARGET, "announcing `{}`", processor.name());
+        send_notify(&mut transport, processor.notify_message(), &diag).await?;
+        processor.announced();
+    }
+    let _ = ready_tx.send(());
+    let mut next_repeat = Instant::now() + ANNOUNCE_REPEAT_INTERVAL;
 
-    // Build the registry of udis peers
-    let mut registry = HashSet::<Udis>::new();
+    // Receive buffer, reused for every packet
+    let mut buf = Vec::with_capacity(processor.recv_buffer_size());
 
-    // Build the notify message
-    let notify_message = serde_json::to_vec(&udis).map_err(Error::FailedToSerialiseNotifyMsg)?;
+    // Whoever is waiting for a reply from each probed peer, by the peer's address
+    let mut probes: HashMap<IpAddr, Vec<oneshot::Sender<Vec<ServiceInfo>>>> = HashMap::new();
 
-    // Send our notify message as we're joining the network
-    socket.send_to(&notify_message[..], &disc_addr).await?;
+    // Timer for reporting metrics, if the user wants them
+    let mut metrics_interval = processor.metrics_interval().map(|period| {
+        // Tokio panics if the period is zero
+        let period = period.max(Duration::from_millis(1));
 
-    // Buffer
-    let mut buf = [0; 1024];
+        let mut interval = tokio::time::interval_at(Instant::now() + period, period);
+        interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        interval
+    });
 
     // Main loop
-    loop {
+    'main: loop {
         // Either receive some data on the socket or a command from the main task
         tokio::select! {
             // On command receipt handle it
             cmd = cmd_rx.recv() => {
                 match cmd {
                     Some(cmd) => match cmd {
+                        Cmd::Flush => {
+                            // Replies held back are still owed to the peers that asked for them
+                            if !send_held_replies(&mut processor, &mut transport, &diag, &host_event_tx)
+                                .await?
+                            {
+                                break;
+                            }
+                            if !shutdown_grace.is_zero() {
+                                trace!("waiting {shutdown_grace:?} for sent messages to leave");
+                                tokio::time::sleep(shutdown_grace).await;
+                            }
+                        }
                         Cmd::Shutdown => break,
+                        Cmd::Rename(name) => {
+                            processor.rename(name)?;
+
+                            // If paused the new name is announced on resuming
+                            if !processor.is_paused() {
+                                trace!(
+                                    target: TX_LOG_TARGET,
+                                    "announcing `{}`",
+                                    processor.name()
+                                );
+                                send_notify(&mut transport, processor.notify_message(), &diag)
+                                    .await?;
+                                processor.announced();
+                            }
+                        }
+                        Cmd::Refresh => {
+                            processor.refresh();
+
+                            // If paused hosts are queried on resuming
+                            if !processor.is_paused() {
+                                trace!(
+                                    target: TX_LOG_TARGET,
+                                    "querying hosts for `{}`",
+                                    processor.name()
+                                );
+                                send_notify(&mut transport, processor.query_message(), &diag)
+                                    .await?;
+                                processor.announced();
+                            }
+                        }
+                        Cmd::Pause => {
+                            if !send_held_replies(&mut processor, &mut transport, &diag, &host_event_tx)
+                                .await?
+                            {
+                                break;
+                            }
+                            processor.pause();
+                        }
+                        Cmd::Resume => {
+                            trace!(target: TX_LOG_TARGET, "announcing `{}`", processor.name());
+                            send_notify(&mut transport, processor.resume(), &diag).await?;
+                            processor.announced();
+                        }
+                        Cmd::GetPayload(payload_tx) => {
+                            // If the requester has gone away there's nobody to tell
+                            let _ = payload_tx.send(processor.notify_message().to_vec());
+                        }
+                        Cmd::Probe { addr, reply_tx } => {
+                            // A paused endpoint mustn't make itself known, so has nobody to ask
+                            if processor.is_paused() {
+                                let _ = reply_tx.send(Vec::new());
+                                continue;
+                            }
+
+                            let probe = processor.probe(addr)?;
+                            send_notify(&mut transport, &probe, &diag).await?;
+                            let to = SocketAddr::new(addr, MULTICAST_PORT);
+                            send_reply(&mut transport, &probe, to, &diag).await?;
+
+                            // Forget anyone whose earlier probe timed out, and any probe nobody is
+                            // waiting for any more
+                            probes.retain(|&addr, waiting| {
+                                waiting.retain(|tx| !tx.is_closed());
+                                if waiting.is_empty() {
+                                    processor.forget_probe(addr);
+                                }
+                                !waiting.is_empty()
+                            });
+                            probes.entry(addr).or_default().push(reply_tx);
+                        }
                     }
                     None => break,
                 }
             },
 
-            // On some data from the socket process it
-            recv_res = socket.recv(&mut buf) => {
-                let received = match recv_res {
-                    Ok(r) => r,
+            // Repeat our initial announcement in case it was lost
+            _ = tokio::time::sleep_until(next_repeat), if repeats_left > 0 => {
+                repeats_left -= 1;
+                next_repeat += ANNOUNCE_REPEAT_INTERVAL;
+
+                if processor.is_paused() {
+                    trace!(target: TX_LOG_TARGET, "paused, skipping repeat announcement");
+                } else if processor.announcement_is_redundant() {
+                    trace!(target: TX_LOG_TARGET, "skipping redundant repeat announcement");
+                } else {
+                    trace!(
+                        target: TX_LOG_TARGET,
+                        "repeating announcement of `{}`",
+                        processor.name()
+                    );
+                    send_notify(&mut transport, processor.notify_message(), &diag).await?;
+                    processor.announced();
+                }
+            },
+
+            // Announce ourselves again before searchers forget the services we host with a TTL
+            _ = sleep_until_deadline(processor.next_reannounce_check().map(Instant::from_std)) => {
+                if processor.reannounce_due() {
+                    trace!(
+                        target: TX_LOG_TARGET,
+                        "re-announcing `{}` within its TTL",
+                        processor.name()
+                    );
+                    send_notify(&mut transport, processor.notify_message(), &diag).await?;
+                    processor.announced();
+                }
+            },
+
+            // Stop when the user's shutdown signal fires, as if the endpoint had been shut down
+            _ = shutdown_signalled(&mut shutdown_signal) => {
+                trace!("shutdown signal fired");
+                break;
+            },
+
+            // Leave the discovery network once our lifetime is up
+            _ = sleep_until_deadline(deadline) => {
+                trace!(
+                    target: TX_LOG_TARGET,
+                    "lifetime expired, saying goodbye from `{}`",
+                    processor.name()
+                );
+                send_notify(&mut transport, &processor.goodbye_message()?, &diag).await?;
+                break;
+            },
+
+            // Tell the user about found services whose TTL has run out
+            _ = sleep_until_deadline(processor.next_sweep().map(Instant::from_std)) => {
+                for event in processor.expire_services() {
+                    if service_event_tx.send(event).is_err() {
+                        trace!("service event receiver dropped, stopping");
+                        break 'main;
+                    }
+                }
+            },
+
+            // Send replies held back to be coalesced once their window is up
+            _ = sleep_until_deadline(processor.next_reply_flush_check().map(Instant::from_std)) => {
+                if processor.replies_due()
+                    && !send_held_replies(&mut processor, &mut transport, &diag, &host_event_tx)
+                        .await?
+                {
+                    break 'main;
+                }
+            },
+
+            // Periodically report metrics
+            _ = metrics_tick(&mut metrics_interval) => {
+                processor.report_metrics(&diag);
+            },
+
+            // On some data from the transport process it
+            recv_res = transport.recv(&mut buf) => {
+                let (src, group) = match recv_res {
+                    Ok(received) => {
+                        diag.record_recv();
+                        received
+                    }
                     Err(e) => {
-                        error!("Error while receiving udis notify messages (will continue): {e}");
+                        match e.kind() {
+                            ErrorKind::TimedOut | ErrorKind::WouldBlock => (),
+                            k => {
+                                error!(
+                                    target: RX_LOG_TARGET,
+                                    "Error while receiving udis notify messages (will continue): \
+                                    ({k:?}) {e}"
+                                );
+                                diag.record_error(e.into());
+                            }
+                        }
                         continue;
                     }
                 };
 
-                // Decode into a udis struct
-                let peer: Udis =
-                serde_json::from_slice(&buf[..received])
-                    .map_err(Error::FailedToDeserialiseNotifyMsg)?;
-
-                // If its our own notify message ignore it
-                if peer == udis {
-                    continue;
+                let outputs = processor.process_packet(&buf, src, group)?;
+                if let Some(e) = processor.take_error() {
+                    diag.record_error(e);
                 }
 
-                // If its already in the registry ignore it
-                if registry.contains(&peer) {
-                    continue;
+                for output in outputs {
+                    match output {
+                        Output::Send(msg) => send_notify(&mut transport, &msg, &diag).await?,
+                        Output::Reply(msg, addr) => {
+                            send_reply(&mut transport, &msg, addr, &diag).await?
+                        }
+                        // A closed channel means the endpoint was dropped without being shut down,
+                        // so nobody is listening any more and the task should just stop
+                        Output::Service(serv_info) => {
+                            if serv_info_tx.send(*serv_info).is_err() {
+                                trace!("service receiver dropped, stopping");
+                                break 'main;
+                            }
+                        }
+                        Output::HostEvent(event) => {
+                            if host_event_tx.send(event).is_err() {
+                                trace!("host event receiver dropped, stopping");
+                                break 'main;
+                            }
+                        }
+                        Output::ProbeReply(addr, hosted) => {
+                            for reply_tx in probes.remove(&addr).unwrap_or_default() {
+                                let _ = reply_tx.send(hosted.clone());
+                            }
+                        }
+                        Output::Shutdown => break 'main,
+                    }
                 }
+            }
+        }
+    }
 
-                // Add the peer to the registry
-                registry.insert(peer.clone());
+    trace!("udis background task shutting down");
 
-                // If the peer is interested in one of the services we're offering notify it
-                if udis.get_wanted_services(&peer).count() > 0 {
-                    trace!(
-                        "notified of peer `{}` that wants one of our services",
-                        peer.name
-                    );
+    Ok(())
+}
 
-                    socket.send_to(&notify_message[..], &disc_addr).await?;
-                }
+/// Send a message directly to a peer
+async fn send_reply(
+    transport: &mut AsyncTransport,
+    message: &[u8],
+    addr: SocketAddr,
+    diag: &SharedDiagnostics,
+) -> Result<(), Error> {
+    match transport.send_to(message, addr).await {
+        Ok(()) => diag.record_send(),
+        Err(e) => ignore_transient_send_error(e, diag)?,
+    }
 
-                // If the peer has one of the services we're interested in
-                for service in peer.get_wanted_services(&udis) {
-                    let Service::Host { kind, port } = service else {
-                        trace!("Non-host service returned by get_wanted_services, skipping");
-                        continue;
-                    };
+    Ok(())
+}
 
-                    trace!(
-                        "found peer `{}` that hosts a service we want `{}` at {}:{}",
-                        peer.name,
-                        kind,
-                        peer.addr,
-                        port
-                    );
+/// Wait for the next metrics report, or forever if metrics aren't wanted
+async fn metrics_tick(interval: &mut Option<Interval>) {
+    match interval {
+        Some(interval) => {
+            interval.tick().await;
+        }
+        None => std::future::pending().await,
+    }
+}
 
-                    // Build service info struct
-                    let serv_info = ServiceInfo {
-                        name: peer.name.clone(),
-                        kind: kind.clone(),
-                        addr: peer.addr,
-                        port: *port,
-                    };
+/// Wait for the user's shutdown signal to fire, or forever if there isn't one
+async fn shutdown_signalled(signal: &mut Option<ShutdownSignal>) {
+    match signal {
+        Some(signal) => signal.await,
+        None => std::future::pending().await,
+    }
+}
 
-                    // Send to the main thread
-                    serv_info_tx.send(serv_info)?;
+/// Wait until the deadline, or forever if there isn't one
+async fn sleep_until_deadline(deadline: Option<Instant>) {
+    match deadline {
+        Some(deadline) => tokio::time::sleep_until(deadline).await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Send the replies held back to be coalesced, returning false if the host event receiver has been
+/// dropped so the task should stop
+async fn send_held_replies(
+    processor: &mut Processor,
+    transport: &mut AsyncTransport,
+    diag: &SharedDiagnostics,
+    host_event_tx: &UnboundedSender<HostEvent>,
+) -> Result<bool, Error> {
+    for output in processor.flush_replies() {
+        match output {
+            Output::Send(msg) => send_notify(transport, &msg, diag).await?,
+            Output::Reply(msg, addr) => send_reply(transport, &msg, addr, diag).await?,
+            Output::HostEvent(event) => {
+                if host_event_tx.send(event).is_err() {
+                    trace!("host event receiver dropped, stopping");
+                    return Ok(false);
                 }
             }
+            // Only replies and the events for them are held back
+            Output::Service(_) | Output::ProbeReply(..) | Output::Shutdown => (),
         }
     }
 
-    trace!("udis background task shutting down");
+    Ok(true)
+}
+
+/// Send the notify message to the discovery network
+async fn send_notify(
+    transport: &mut AsyncTransport,
+    notify_message: &[u8],
+    diag: &SharedDiagnostics,
+) -> Result<(), Error> {
+    match transport.send(notify_message).await {
+        Ok(()) => diag.record_send(),
+        Err(e) => ignore_transient_send_error(e, diag)?,
+    }
 
     Ok(())
 }
+
+/// The transport used by the async background task
+enum AsyncTransport {
+    /// The default UDP multicast transport
+    Udp {
+        socket: UdpSocket,
+
+        /// Socket bound to the unicast port, if there is one, which all messages are sent from
+        unicast: Option<UdpSocket>,
+
+        /// Addresses of the discovery network groups messages are sent to
+        disc_addrs: Vec<SocketAddr>,
+
+        /// Extra multicast groups messages are also sent to, and the sockets receiving from them
+        groups: Vec<(SocketAddrV4, UdpSocket)>,
+
+        bind_mode: BindMode,
+
+        /// Relays messages between interfaces while the transport is in use, if asked to
+        _relay: Option<Relay>,
+    },
+
+    /// A transport given by the user
+    Custom(Box<dyn Transport>),
+}
+
+impl AsyncTransport {
+    /// Build the multicast socket described by the config, or use the one the user gave us
+    fn udp(config: &mut Config, diag: &SharedDiagnostics) -> Result<Self, Error> {
+        let (disc_addrs, socket) = discovery_socket(
+            config.socket.take(),
+            config.bind_mode(),
+            config.delivery,
+            config.device.as_deref(),
+            config.dscp,
+        )?;
+        trace!("joined udis notify network on {disc_addrs:?}");
+        diag.record_socket(&disc_addrs, &socket);
+
+        let unicast = config
+            .unicast_port
+            .map(|port| {
+                trace!("using unicast port {port}");
+                build_unicast_socket(
+                    port,
+                    config.bind_mode(),
+                    config.delivery,
+                    config.device.as_deref(),
+                    config.dscp,
+                )
+            })
+            .transpose()?;
+
+        if config.verify_multicast {
+            trace!("verifying the udis notify network");
+            verify_discovery(
+                &socket,
+                unicast.as_ref().unwrap_or(&socket),
+                &disc_addrs,
+                VERIFY_MULTICAST_TIMEOUT,
+            )?;
+        }
+
+        let groups = config
+            .groups
+            .iter()
+            .map(|&group| {
+                trace!("joining extra group {group}");
+                build_group_socket(group, config.device.as_deref()).map(|socket| (group, socket))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        // Convert the sockets to tokio ones
+        let socket = UdpSocket::from_std(socket.into())?;
+        let unicast = unicast
+            .map(|unicast| UdpSocket::from_std(unicast.into()))
+            .transpose()?;
+        let groups = groups
+            .into_iter()
+            .map(|(group, socket)| Ok((group, UdpSocket::from_std(socket.into())?)))
+            .collect::<io::Result<Vec<_>>>()?;
+
+        Ok(Self::Udp {
+            socket,
+            unicast,
+            disc_addrs,
+            groups,
+            bind_mode: config.bind_mode(),
+            _relay: Relay::start(config)?,
+        })
+    }
+
+    /// Send a message to the discovery network
+    async fn send(&mut self, msg: &[u8]) -> io::Result<()> {
+        match self {
+            Self::Udp {
+                socket,
+                unicast,
+                disc_addrs,
+                groups,
+                bind_mode,
+                ..
+            } => {
+                let socket = unicast.as_ref().unwrap_or(socket);
+                for disc_addr in disc_addrs.iter() {
+                    socket.send_to(msg, disc_addr).await?;
+                }
+                for (group, _) in groups.iter() {
+                    socket
+                        .send_to(msg, send_addr((*group).into(), *bind_mode))
+                        .await?;
+                }
+                Ok(())
+            }
+            Self::Custom(transport) => transport.send(msg),
+        }
+    }
+
+    /// Send a message directly to the endpoint at `addr`
+    async fn send_to(&mut self, msg: &[u8], addr: SocketAddr) -> io::Result<()> {
+        match self {
+            Self::Udp {
+                socket,
+                unicast,
+                bind_mode,
+                ..
+            } => {
+                let socket = unicast.as_ref().unwrap_or(socket);
+                socket.send_to(msg, send_addr(addr, *bind_mode)).await?;
+                Ok(())
+            }
+            Self::Custom(transport) => transport.send_to(msg, addr),
+        }
+    }
+
+    /// Receive the next message from the discovery network into `buf`, along with the extra group
+    /// it was received on if any
+    async fn recv(&mut self, buf: &mut Vec<u8>) -> io::Result<(SocketAddr, Option<SocketAddrV4>)> {
+        match self {
+            Self::Udp {
+                socket,
+                unicast,
+                groups,
+                ..
+            } => loop {
+                // Wait for any of the sockets to have a packet waiting
+                let sockets = iter::once((&*socket, None))
+                    .chain(unicast.as_ref().map(|unicast| (unicast, None)))
+                    .chain(groups.iter().map(|(group, socket)| (socket, Some(*group))));
+                let (ready, group) = poll_fn(|cx| {
+                    for (socket, group) in sockets.clone() {
+                        if let Poll::Ready(res) = socket.poll_recv_ready(cx) {
+                            return Poll::Ready(res.map(|_| (socket, group)));
+                        }
+                    }
+                    Poll::Pending
+                })
+                .await?;
+
+                buf.resize(buf.capacity(), 0);
+                match ready.try_recv_from(buf) {
+                    Ok((received, src)) => {
+                        buf.truncate(received);
+                        return Ok((src, group));
+                    }
+                    // The readiness can be a false positive, in which case wait again
+                    Err(e) if e.kind() == ErrorKind::WouldBlock => buf.clear(),
+                    Err(e) => return Err(e),
+                }
+            },
+            Self::Custom(transport) => loop {
+                match transport.recv_with_group(buf) {
+                    Err(e) if e.kind() == ErrorKind::WouldBlock => {
+                        tokio::time::sleep(POLL_PERIOD).await;
+                    }
+                    res => return res,
+                }
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{self, ErrorKind},
+        net::{Ipv4Addr, SocketAddr, UdpSocket},
+        time::Duration,
+    };
+
+    use tokio::sync::mpsc::{unbounded_channel, UnboundedSender};
+
+    use crate::{
+        error::Error,
+        net::{MULTICAST_ADDR, MULTICAST_PORT},
+        tcp_host,
+        transport::Transport,
+        Message, Service, Udis,
+    };
+
+    /// A transport which fails to send every message with the given kind of error, letting the
+    /// test know each time it tries
+    struct FailingTransport {
+        kind: ErrorKind,
+        attempts_tx: UnboundedSender<()>,
+    }
+
+    impl Transport for FailingTransport {
+        fn send(&mut self, _msg: &[u8]) -> io::Result<()> {
+            let _ = self.attempts_tx.send(());
+            Err(self.kind.into())
+        }
+
+        fn recv(&mut self, _buf: &mut Vec<u8>) -> io::Result<SocketAddr> {
+            Err(ErrorKind::WouldBlock.into())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_send_failures() {
+        // A transient failure to send shouldn't stop the background task
+        let (attempts_tx, mut attempts_rx) = unbounded_channel();
+        let udis = Udis::new("flaky")
+            .addr(Ipv4Addr::LOCALHOST)
+            .build_async_with_transport(FailingTransport {
+                kind: ErrorKind::NetworkUnreachable,
+                attempts_tx,
+            })
+            .unwrap();
+        attempts_rx.recv().await.unwrap();
+        assert!(udis.notify_payload().await.is_ok());
+        udis.shutdown().await.unwrap();
+
+        // But any other failure should
+        let (attempts_tx, mut attempts_rx) = unbounded_channel();
+        let udis = Udis::new("broken")
+            .addr(Ipv4Addr::LOCALHOST)
+            .build_async_with_transport(FailingTransport {
+                kind: ErrorKind::InvalidInput,
+                attempts_tx,
+            })
+            .unwrap();
+        attempts_rx.recv().await.unwrap();
+        assert!(matches!(udis.shutdown().await, Err(Error::IoError(_))));
+    }
+
+    #[tokio::test]
+    #[ignore = "uses the real multicast network"]
+    async fn test_large_announcement() {
+        let mut udis = Udis::new("large-client")
+            .addr(Ipv4Addr::LOCALHOST)
+            .search("large-test")
+            .build_async()
+            .unwrap();
+        udis.ready().await.unwrap();
+
+        // An announcement much larger than a typical packet should still be received whole
+        let mut large = tcp_host("large-test", 4112);
+        if let Service::Host { description, .. } = &mut large {
+            *description = Some("x".repeat(4096));
+        }
+        let peer = Udis::build(
+            "large-server".into(),
+            Ipv4Addr::LOCALHOST.into(),
+            vec![large],
+        );
+        let notify = Message::Notify(peer).encode().unwrap();
+        assert!(notify.len() > 4096);
+
+        let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).unwrap();
+        socket
+            .send_to(&notify, (MULTICAST_ADDR, MULTICAST_PORT))
+            .unwrap();
+
+        let found = tokio::time::timeout(Duration::from_secs(2), udis.find_service())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(found.description.map(|d| d.len()), Some(4096));
+
+        udis.shutdown().await.unwrap();
+    }
+}
@@ -0,0 +1,493 @@
+use std::{
+    fmt,
+    io::ErrorKind,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use log::{error, trace};
+use polling::{Events, Poller};
+
+use crate::{
+    clock::Clock,
+    config::{Config, ANNOUNCE_REPEAT_INTERVAL},
+    core::{Output, Processor},
+    diagnostics::{Diagnostics, SharedDiagnostics},
+    error::Error,
+    net::{RX_LOG_TARGET, TX_LOG_TARGET},
+    sync::{poll_period, send_notify, send_reply},
+    transport::{Transport, UdpTransport},
+    ServiceInfo, Udis,
+};
+
+/// A blocking udis endpoint, which runs on the caller's thread rather than starting a background
+/// thread.
+///
+/// Nothing happens unless [`BlockingUdis::run_once`] is called, which announces the endpoint,
+/// replies to peers and finds services, so it should be called regularly. This gives full control
+/// over scheduling, for example in single threaded programs or where spawning threads isn't
+/// allowed.
+///
+/// Events about hosted or found services, such as [`crate::HostEvent::Acknowledged`], aren't
+/// reported by blocking endpoints, even if they're built with
+/// [`crate::builder::Builder::host_events`], as there's nowhere to queue them. To leave the
+/// discovery network just drop the endpoint.
+pub struct BlockingUdis {
+    processor: Processor,
+
+    transport: Box<dyn Transport>,
+
+    /// Waits for packets to arrive on the transport
+    poller: Arc<Poller>,
+
+    /// How often the endpoint must wake up by itself while waiting, if packets or timers can't wake
+    /// it
+    poll_period: Option<Duration>,
+
+    clock: Arc<dyn Clock>,
+
+    diag: SharedDiagnostics,
+
+    /// Receive buffer, reused for every packet
+    buf: Vec<u8>,
+
+    repeats_left: u8,
+
+    next_repeat: Instant,
+
+    /// When the endpoint's lifetime is up, if it has one
+    deadline: Option<Instant>,
+
+    /// When metrics were last reported to the user
+    last_metrics: Instant,
+
+    /// Whether the endpoint has left the discovery network
+    finished: bool,
+}
+
+impl fmt::Debug for BlockingUdis {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("BlockingUdis")
+            .field("name", &self.processor.name())
+            .field("finished", &self.finished)
+            .finish_non_exhaustive()
+    }
+}
+
+impl BlockingUdis {
+    pub(crate) fn build(
+        udis: Udis,
+        mut config: Config,
+        transport: Option<Box<dyn Transport>>,
+    ) -> Result<Self, Error> {
+        let diag = SharedDiagnostics::default();
+        let poller = Arc::new(Poller::new()?);
+
+        let (mut transport, waits_on_transport): (Box<dyn Transport>, _) = match transport {
+            // Custom transports can't wake the poller, so they must be polled
+            Some(transport) => (transport, false),
+            None => {
+                let mut transport = UdpTransport::from_config(&mut config, &diag)?;
+                let registered = transport.register(poller.clone())?;
+                (Box::new(transport), registered)
+            }
+        };
+
+        let clock = config.clock();
+        let startup_delay = config.startup_delay();
+        let deadline = config.lifetime.map(|lifetime| clock.now() + lifetime);
+
+        let poll_period = poll_period(&config, waits_on_transport);
+        let mut repeats_left = config.announce_repeat() - 1;
+
+        let mut processor = Processor::new(udis, config)?;
+
+        // Wait a random amount of time so endpoints starting together don't all announce at once
+        if !startup_delay.is_zero() {
+            trace!(target: TX_LOG_TARGET, "delaying initial announcement by {startup_delay:?}");
+            std::thread::sleep(startup_delay);
+        }
+
+        // Send our notify message as we're joining the network, unless we're a silent client in
+        // which case hosts find out about us when they announce themselves
+        if processor.is_silent() {
+            trace!(target: TX_LOG_TARGET, "silent client, not announcing `{}`", processor.name());
+            repeats_left = 0;
+        } else {
+            trace!(target: TX_LOG_TARGET, "announcing `{}`", processor.name());
+            send_notify(&mut *transport, processor.notify_message(), &diag)?;
+            processor.announced();
+        }
+
+        Ok(Self {
+            buf: Vec::with_capacity(processor.recv_buffer_size()),
+            processor,
+            transport,
+            poller,
+            poll_period,
+            diag,
+            repeats_left,
+            next_repeat: clock.now() + ANNOUNCE_REPEAT_INTERVAL,
+            deadline,
+            last_metrics: clock.now(),
+            clock,
+            finished: false,
+        })
+    }
+
+    /// Handle everything the endpoint needs to do, waiting up to `timeout` for a service to be
+    /// found, and return the services found.
+    ///
+    /// This returns as soon as any services are found, so may return before the timeout, and a
+    /// timeout of zero handles anything already waiting without blocking. Once the endpoint has
+    /// left the discovery network, for example when its lifetime is up, this always returns no
+    /// services, see [`BlockingUdis::is_finished`].
+    ///
+    /// # Errors
+    ///
+    /// This function can return an error if the endpoint fails to send a message to the discovery
+    /// network, or fails to wait for packets to arrive.
+    pub fn run_once(&mut self, timeout: Duration) -> Result<Vec<ServiceInfo>, Error> {
+        let until = Instant::now() + timeout;
+        let mut found = Vec::new();
+
+        // Events the poller woke up for, which aren't needed as everything is checked on each wake
+        let mut events = Events::new();
+
+        while !self.finished {
+            self.handle_timers()?;
+            self.receive(&mut found)?;
+
+            let now = Instant::now();
+            if !found.is_empty() || self.finished || now >= until {
+                break;
+            }
+
+            // Sleep until a packet arrives, the next thing is due to happen, or the timeout is up
+            let next_wake = [
+                (self.repeats_left > 0).then_some(self.next_repeat),
+                self.processor.next_reannounce(),
+                self.deadline,
+                self.processor
+                    .metrics_interval()
+                    .map(|interval| self.last_metrics + interval),
+                self.processor.next_expiry(),
+                self.processor.next_reply_flush(),
+            ]
+            .into_iter()
+            .flatten()
+            .map(|at| at.saturating_duration_since(self.clock.now()))
+            .min();
+
+            let mut wait = until - now;
+            if let Some(next_wake) = next_wake {
+                wait = wait.min(next_wake);
+            }
+
+            if let Some(period) = self.poll_period {
+                wait = wait.min(period);
+            }
+
+            events.clear();
+            match self.poller.wait(&mut events, Some(wait)) {
+                Ok(_) => (),
+                Err(e) if e.kind() == ErrorKind::Interrupted => (),
+                Err(e) => return Err(e.into()),
+            }
+        }
+
+        Ok(found)
+    }
+
+    /// Whether the endpoint has left the discovery network, after which it won't find any more
+    /// services.
+    ///
+    /// This happens once the endpoint's [`crate::builder::Builder::lifetime`] is up, or after it
+    /// finds its first service if built with [`crate::builder::Builder::find_once`].
+    pub fn is_finished(&self) -> bool {
+        self.finished
+    }
+
+    /// Find every service again, for example after the network has changed.
+    ///
+    /// The endpoint forgets the peers and services it knows about, then queries the discovery
+    /// network so that hosts reply to it again. Services found before the refresh are
+    /// returned again by [`BlockingUdis::run_once`].
+    ///
+    /// # Errors
+    ///
+    /// This function can return an error if the endpoint fails to send a message to the discovery
+    /// network.
+    pub fn refresh(&mut self) -> Result<(), Error> {
+        if self.finished {
+            return Ok(());
+        }
+
+        self.processor.refresh();
+
+        trace!(target: TX_LOG_TARGET, "querying hosts for `{}`", self.processor.name());
+        send_notify(
+            &mut *self.transport,
+            self.processor.query_message(),
+            &self.diag,
+        )?;
+        self.processor.announced();
+
+        Ok(())
+    }
+
+    /// Get diagnostic information about this endpoint's connection to the discovery network.
+    ///
+    /// This is useful for troubleshooting an endpoint which isn't finding any services, see
+    /// [`Diagnostics`] for the information available.
+    pub fn diagnostics(&self) -> Diagnostics {
+        self.diag.get()
+    }
+
+    /// Take the most recent error the endpoint logged and carried on from, such as a failure to
+    /// receive a packet or a packet which couldn't be deserialised.
+    ///
+    /// The error is cleared once taken, so this returns `None` until the endpoint hits another
+    /// error, and only the latest is kept if there were several. Errors which the endpoint can't
+    /// carry on from are returned by [`BlockingUdis::run_once`] instead.
+    pub fn last_error(&self) -> Option<Error> {
+        self.diag.take_error()
+    }
+
+    /// Do anything which is due to happen, such as repeating our announcement
+    fn handle_timers(&mut self) -> Result<(), Error> {
+        let transport = &mut *self.transport;
+
+        // Repeat our initial announcement in case it was lost
+        if self.repeats_left > 0 && self.clock.now() >= self.next_repeat {
+            self.repeats_left -= 1;
+            self.next_repeat += ANNOUNCE_REPEAT_INTERVAL;
+
+            if self.processor.announcement_is_redundant() {
+                trace!(target: TX_LOG_TARGET, "skipping redundant repeat announcement");
+            } else {
+                trace!(
+                    target: TX_LOG_TARGET,
+                    "repeating announcement of `{}`",
+                    self.processor.name()
+                );
+                send_notify(transport, self.processor.notify_message(), &self.diag)?;
+                self.processor.announced();
+            }
+        }
+
+        // Announce ourselves again before searchers forget the services we host with a TTL
+        if self.processor.reannounce_due() {
+            trace!(
+                target: TX_LOG_TARGET,
+                "re-announcing `{}` within its TTL",
+                self.processor.name()
+            );
+            send_notify(transport, self.processor.notify_message(), &self.diag)?;
+            self.processor.announced();
+        }
+
+        // Leave the discovery network once our lifetime is up
+        if self
+            .deadline
+            .is_some_and(|deadline| self.clock.now() >= deadline)
+        {
+            trace!(
+                target: TX_LOG_TARGET,
+                "lifetime expired, saying goodbye from `{}`",
+                self.processor.name()
+            );
+            send_notify(transport, &self.processor.goodbye_message()?, &self.diag)?;
+            self.finished = true;
+            return Ok(());
+        }
+
+        // Report metrics if it's time to
+        if self.processor.metrics_interval().is_some_and(|interval| {
+            self.clock
+                .now()
+                .saturating_duration_since(self.last_metrics)
+                >= interval
+        }) {
+            self.processor.report_metrics(&self.diag);
+            self.last_metrics = self.clock.now();
+        }
+
+        // Forget found services whose TTL has run out
+        if self
+            .processor
+            .next_expiry()
+            .is_some_and(|expiry| self.clock.now() >= expiry)
+        {
+            for event in self.processor.expire_services() {
+                trace!("not reporting service event {event:?} from a blocking endpoint");
+            }
+        }
+
+        // Send replies held back to be coalesced once their window is up
+        if self.processor.replies_due() {
+            for output in self.processor.flush_replies() {
+                match output {
+                    Output::Send(msg) => send_notify(transport, &msg, &self.diag)?,
+                    Output::Reply(msg, addr) => send_reply(transport, &msg, addr, &self.diag)?,
+                    Output::HostEvent(event) => {
+                        trace!("not reporting host event {event:?} from a blocking endpoint");
+                    }
+                    // Only replies and the events for them are held back
+                    Output::Service(_) | Output::ProbeReply(..) | Output::Shutdown => (),
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Handle every packet waiting on the transport, adding any services found to `found`
+    fn receive(&mut self, found: &mut Vec<ServiceInfo>) -> Result<(), Error> {
+        while !self.finished {
+            let (src, group) = match self.transport.recv_with_group(&mut self.buf) {
+                Ok(received) => {
+                    self.diag.record_recv();
+                    received
+                }
+                Err(e) => {
+                    match e.kind() {
+                        ErrorKind::TimedOut | ErrorKind::WouldBlock => (),
+                        k => {
+                            error!(
+                                target: RX_LOG_TARGET,
+                                "Error while receiving udis notify messages (will continue): ({k:?}) {e}"
+                            );
+                            self.diag.record_error(e.into());
+                        }
+                    }
+                    return Ok(());
+                }
+            };
+
+            let outputs = self.processor.process_packet(&self.buf, src, group)?;
+            if let Some(e) = self.processor.take_error() {
+                self.diag.record_error(e);
+            }
+
+            for output in outputs {
+                let transport = &mut *self.transport;
+                match output {
+                    Output::Send(msg) => send_notify(transport, &msg, &self.diag)?,
+                    Output::Reply(msg, addr) => send_reply(transport, &msg, addr, &self.diag)?,
+                    Output::Service(serv_info) => found.push(*serv_info),
+                    Output::HostEvent(event) => {
+                        trace!("not reporting host event {event:?} from a blocking endpoint");
+                    }
+                    // Blocking endpoints never probe peers
+                    Output::ProbeReply(..) => (),
+                    Output::Shutdown => self.finished = true,
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::Ipv4Addr, time::Duration};
+
+    use crate::{
+        clock::MockClock,
+        error::Error,
+        tcp_host,
+        transport::{ChannelNetwork, Transport},
+        Message, Service, Udis,
+    };
+
+    #[test]
+    fn test_find_and_reply() {
+        let network = ChannelNetwork::new();
+        let server_transport = network.transport((Ipv4Addr::new(10, 0, 0, 1), 8787));
+        let client_transport = network.transport((Ipv4Addr::new(10, 0, 0, 2), 8787));
+
+        let mut server = Udis::new("server")
+            .addr(Ipv4Addr::new(10, 0, 0, 1))
+            .host("hello", 4112)
+            .unwrap()
+            .build_blocking_with_transport(server_transport)
+            .unwrap();
+        let mut client = Udis::new("client")
+            .addr(Ipv4Addr::new(10, 0, 0, 2))
+            .search("hello")
+            .build_blocking_with_transport(client_transport)
+            .unwrap();
+
+        // The client should find the server from its announcement
+        let found = client.run_once(Duration::from_secs(1)).unwrap();
+        assert!(matches!(&found[..], [s] if s.name == "server" && s.port == 4112));
+
+        // Once the client forgets the server it should only find it again when the server replies
+        client.refresh().unwrap();
+        assert!(client.run_once(Duration::ZERO).unwrap().is_empty());
+        assert!(server.run_once(Duration::ZERO).unwrap().is_empty());
+        let found = client.run_once(Duration::from_secs(1)).unwrap();
+        assert!(matches!(&found[..], [s] if s.name == "server"));
+    }
+
+    #[test]
+    fn test_last_error() {
+        let network = ChannelNetwork::new();
+        let mut client = Udis::new("client")
+            .addr(Ipv4Addr::new(10, 0, 0, 2))
+            .search("hello")
+            .build_blocking_with_transport(network.transport((Ipv4Addr::new(10, 0, 0, 2), 8787)))
+            .unwrap();
+
+        // A packet which isn't a udis message should be kept for the user, until they've seen it
+        let mut other = network.transport((Ipv4Addr::new(10, 0, 0, 3), 8787));
+        other.send(b"not udis").unwrap();
+        assert!(client.run_once(Duration::from_secs(1)).unwrap().is_empty());
+        assert!(matches!(
+            client.last_error(),
+            Some(Error::FailedToDeserialiseNotifyMsg { .. })
+        ));
+        assert!(client.last_error().is_none());
+    }
+
+    #[test]
+    fn test_expiry() {
+        let network = ChannelNetwork::new();
+        let clock = MockClock::new();
+        let mut client = Udis::new("client")
+            .addr(Ipv4Addr::new(10, 0, 0, 2))
+            .search("leased")
+            .clock(clock.clone())
+            .build_blocking_with_transport(network.transport((Ipv4Addr::new(10, 0, 0, 2), 8787)))
+            .unwrap();
+
+        let mut leased = tcp_host("leased", 4113);
+        if let Service::Host { ttl, .. } = &mut leased {
+            *ttl = 1;
+        }
+        let peer = Udis::build(
+            "leaser".into(),
+            Ipv4Addr::new(10, 0, 0, 3).into(),
+            vec![leased],
+        );
+        let notify = Message::Notify(peer).encode().unwrap();
+        let mut peer_transport = network.transport((Ipv4Addr::new(10, 0, 0, 3), 8787));
+
+        peer_transport.send(&notify).unwrap();
+        let found = client.run_once(Duration::from_secs(1)).unwrap();
+        assert!(matches!(&found[..], [s] if s.port == 4113));
+
+        // Hearing the same announcement within the TTL finds nothing new
+        peer_transport.send(&notify).unwrap();
+        assert!(client.run_once(Duration::ZERO).unwrap().is_empty());
+
+        // Once the TTL is up the service is forgotten, so the same announcement finds it again
+        clock.advance(Duration::from_secs(2));
+        assert!(client.run_once(Duration::ZERO).unwrap().is_empty());
+        peer_transport.send(&notify).unwrap();
+        let found = client.run_once(Duration::from_secs(1)).unwrap();
+        assert!(matches!(&found[..], [s] if s.port == 4113));
+    }
+}
@@ -1,10 +1,132 @@
-use std::net::IpAddr;
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    iter,
+    net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4},
+    path::PathBuf,
+    sync::Arc,
+    time::Duration,
+};
 
-use crate::{error::Error, sync::SyncUdis, Service, Udis};
+use log::trace;
+
+use crate::{
+    blocking::BlockingUdis,
+    clock::Clock,
+    config::{AddrFilterFn, Config, ExternalSocket, Hook, MetricsReporter, NAME_CONFLICT_LISTEN},
+    diagnostics::Metrics,
+    error::Error,
+    net::{names_in_use, Delivery, MULTICAST_PORT},
+    sync::SyncUdis,
+    transport::Transport,
+    HostEndpoint, Service, ServiceInfo, Udis, WILDCARD_KIND,
+};
 
 #[cfg(feature = "tokio")]
 use crate::async_tokio::AsyncUdis;
 
+/// Which IP versions a udis endpoint binds its discovery socket for.
+///
+/// By default only IPv4 is used, as this has the greatest support in most networks. Endpoints can
+/// only discover each other if they share an IP version, so a [`BindMode::DualStack`] endpoint
+/// can discover both [`BindMode::V4Only`] and [`BindMode::V6Only`] endpoints.
+///
+/// # Platform support
+///
+/// The IPv6 modes use the link-local multicast group `ff02::87` on the OS default interface.
+/// [`BindMode::DualStack`] relies on joining the IPv4 group from an IPv6 socket, which is
+/// supported on Linux but may not be on other platforms, in which case building the endpoint will
+/// fail with an IO error.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub enum BindMode {
+    /// Only use IPv4, binding on `0.0.0.0`
+    #[default]
+    V4Only,
+
+    /// Only use IPv6, binding on `::` with `IPV6_V6ONLY` set
+    V6Only,
+
+    /// Use both IPv4 and IPv6, binding on `::` with `IPV6_V6ONLY` cleared and joining both
+    /// multicast groups
+    DualStack,
+}
+
+/// How an endpoint decides whether a notify message comes from a peer it has already seen.
+///
+/// Services are only found once per peer, so this controls what happens when a known peer changes
+/// the services it announces.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum DedupKey {
+    /// Peers are identified by their name and address, so a peer which changes its services is
+    /// still the same peer and only the services it has added are found
+    #[default]
+    NameAddr,
+
+    /// Peers are identified by their whole notify message, so a peer which changes its services
+    /// is treated as a new peer and all of the services it hosts are found again
+    FullStruct,
+}
+
+/// What an endpoint does when it finds another endpoint on the discovery network already using its
+/// name, see [`Builder::on_name_conflict`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum NameConflict {
+    /// Use the name anyway, so the endpoints can't be told apart by name
+    #[default]
+    Allow,
+
+    /// Add the first free numeric suffix to the name, such as `name (2)`, like mDNS does
+    Rename,
+
+    /// Fail to build the endpoint with [`Error::NameConflict`]
+    Fail,
+}
+
+/// Extra options for a service hosted with [`Builder::host_with`].
+///
+/// The defaults are the same as a service hosted with [`Builder::host`], so only the options
+/// wanted need setting:
+///
+/// ```
+/// use std::time::Duration;
+///
+/// use udis::builder::HostOptions;
+///
+/// let options = HostOptions {
+///     ttl: Some(Duration::from_secs(30)),
+///     description: Some("Primary Postgres, us-east".into()),
+///     ..Default::default()
+/// };
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct HostOptions {
+    /// The priority of the service, where like DNS SRV records services with a lower priority
+    /// should be preferred, see [`crate::ServiceInfo::cmp_preference`] and
+    /// [`crate::sync::SyncUdis::find_best_service`]
+    pub priority: u8,
+
+    /// The weight of the service, where services with a higher weight should be preferred over
+    /// others with the same priority
+    pub weight: u16,
+
+    /// How long the service is valid for after each announcement, for example a service running
+    /// on an ephemeral lease.
+    ///
+    /// Searchers forget the service if they don't hear it announced again within the TTL, and get
+    /// a [`crate::ServiceEvent::Expired`] for it. The endpoint announces itself again halfway
+    /// through the TTL for as long as it's running, so the service only expires once it stops.
+    /// The TTL is sent in whole seconds, so is rounded down to a second or more.
+    pub ttl: Option<Duration>,
+
+    /// A human readable description of the service, such as "Primary Postgres, us-east", for
+    /// showing in discovery tools.
+    ///
+    /// Unlike metadata the description has its own field in [`crate::ServiceInfo::description`],
+    /// so tools can rely on where to find it.
+    pub description: Option<String>,
+}
+
 /// A builder struct for a udis endpoint.
 ///
 /// This struct allows you to configure the udis endpoint, see [`Udis`] for the configuration
@@ -13,7 +135,11 @@ use crate::async_tokio::AsyncUdis;
 pub struct Builder {
     name: String,
     addr: Option<IpAddr>,
+    extra_addrs: Vec<IpAddr>,
+    addr_filter: Option<Hook<AddrFilterFn>>,
     services: Vec<Service>,
+    extra: Arc<[u8]>,
+    config: Config,
 }
 
 impl Builder {
@@ -21,7 +147,11 @@ impl Builder {
         Self {
             name,
             addr: None,
+            extra_addrs: Vec::new(),
+            addr_filter: None,
             services: Vec::new(),
+            extra: Arc::default(),
+            config: Config::default(),
         }
     }
 
@@ -37,6 +167,35 @@ impl Builder {
         self
     }
 
+    /// Also advertise `ip` as an address this endpoint can be reached on, for endpoints reachable
+    /// several ways such as over both IPv4 and IPv6, or over a LAN and a VPN.
+    ///
+    /// Found services list every address in [`ServiceInfo::addrs`], in order of preference
+    /// starting with the main address from [`Builder::addr`], so searchers can try each in turn.
+    /// Extra addresses are listed in the order they're added. Peers running older versions of
+    /// udis only see the main address.
+    pub fn add_addr<I>(mut self, ip: I) -> Self
+    where
+        I: Into<IpAddr>,
+    {
+        self.extra_addrs.push(ip.into());
+        self
+    }
+
+    /// Choose the IP address this discovery endpoint will be visible on from the addresses of the
+    /// current machine's network interfaces, using the first one `filter` accepts.
+    ///
+    /// This is useful on machines with several interfaces where the address picked by default may
+    /// not be reachable by peers, e.g. to prefer addresses in `10.0.0.0/8`. This has no effect if
+    /// an address is set with [`Builder::addr`].
+    pub fn auto_addr_filter<F>(mut self, filter: F) -> Self
+    where
+        F: Fn(&IpAddr) -> bool + Send + Sync + 'static,
+    {
+        self.addr_filter = Some(Hook(Arc::new(filter)));
+        self
+    }
+
     /// Make a service available on this endpoint, i.e. say that we are hosting a service.
     ///
     /// `kind` is the name for the service type, which is hosted on this machine on the given
@@ -45,21 +204,155 @@ impl Builder {
     /// # Errors
     ///
     /// Can fail if the given `kind` or `port` are already hosted on this endpoint.
-    pub fn host<S: Into<String>>(mut self, kind: S, port: u16) -> Result<Self, Error> {
-        let kind = kind.into();
+    pub fn host<S: Into<String>>(self, kind: S, port: u16) -> Result<Self, Error> {
+        self.host_with_metadata(kind, port, BTreeMap::<String, String>::new())
+    }
 
-        if self.services.iter().any(|s| {
-            if let Service::Host { kind: k, port: p } = s {
-                *k == kind || *p == port
-            } else {
-                false
-            }
-        }) {
-            Err(Error::DuplicateService { kind, port })
-        } else {
-            self.services.push(Service::Host { kind, port });
-            Ok(self)
+    /// Make every `(kind, port)` service in `services` available on this endpoint, as if each was
+    /// passed to [`Builder::host`], for example when the services come from a config file.
+    ///
+    /// # Errors
+    ///
+    /// Fails on the first service whose `kind` or `port` is already hosted on this endpoint.
+    pub fn hosts<I, S>(self, services: I) -> Result<Self, Error>
+    where
+        I: IntoIterator<Item = (S, u16)>,
+        S: Into<String>,
+    {
+        services
+            .into_iter()
+            .try_fold(self, |builder, (kind, port)| builder.host(kind, port))
+    }
+
+    /// Make a service available on this endpoint along with some metadata describing it, for
+    /// example its version.
+    ///
+    /// The metadata is advertised with the service and is available to searchers in
+    /// [`crate::ServiceInfo::metadata`], and can be filtered on with
+    /// [`Builder::search_with_filter`].
+    ///
+    /// # Errors
+    ///
+    /// Can fail if the given `kind` or `port` are already hosted on this endpoint.
+    pub fn host_with_metadata<S, I, K, V>(
+        self,
+        kind: S,
+        port: u16,
+        metadata: I,
+    ) -> Result<Self, Error>
+    where
+        S: Into<String>,
+        I: IntoIterator<Item = (K, V)>,
+        K: Into<String>,
+        V: Into<String>,
+    {
+        self.host_endpoint(kind.into(), HostEndpoint::Tcp(port), None, metadata)
+    }
+
+    /// Make a service available on this endpoint with the extra `options` it's advertised with,
+    /// see [`HostOptions`] for what can be set.
+    ///
+    /// # Errors
+    ///
+    /// Can fail if the given `kind` or `port` are already hosted on this endpoint.
+    pub fn host_with<S: Into<String>>(
+        self,
+        kind: S,
+        port: u16,
+        options: HostOptions,
+    ) -> Result<Self, Error> {
+        let mut builder = self.host(kind, port)?;
+
+        if let Some(Service::Host {
+            priority,
+            weight,
+            ttl,
+            description,
+            ..
+        }) = builder.services.last_mut()
+        {
+            *priority = options.priority;
+            *weight = options.weight;
+            *ttl = options.ttl.map_or(0, |ttl| {
+                u32::try_from(ttl.as_secs()).unwrap_or(u32::MAX).max(1)
+            });
+            *description = options.description;
         }
+
+        Ok(builder)
+    }
+
+    /// Make a service available on this endpoint on a Unix domain socket at `path`.
+    ///
+    /// Peers get the path in [`crate::ServiceInfo::endpoint`], and as the socket is only
+    /// reachable from this machine it's only useful to peers running on the same machine.
+    ///
+    /// # Errors
+    ///
+    /// Can fail if the given `kind` or `path` are already hosted on this endpoint.
+    pub fn host_unix<S: Into<String>, P: Into<PathBuf>>(
+        self,
+        kind: S,
+        path: P,
+    ) -> Result<Self, Error> {
+        self.host_endpoint(
+            kind.into(),
+            HostEndpoint::Unix(path.into()),
+            None,
+            BTreeMap::<String, String>::new(),
+        )
+    }
+
+    /// Advertise a service which is really hosted by another machine at `backend`, as if this
+    /// endpoint hosted it, for example to bring services from a static list or another discovery
+    /// system onto the udis discovery network.
+    ///
+    /// Searchers get the backend's address and port in [`crate::ServiceInfo::addr`] and
+    /// [`crate::ServiceInfo::port`], so can connect to the service as usual, and this endpoint's
+    /// address in [`crate::ServiceInfo::proxied_by`]. This endpoint doesn't forward any traffic
+    /// to the backend, or check that it's up.
+    ///
+    /// # Errors
+    ///
+    /// Can fail if the given `kind` is already hosted on this endpoint, or `backend` is already
+    /// proxied by it.
+    pub fn host_proxy<S: Into<String>>(self, kind: S, backend: SocketAddr) -> Result<Self, Error> {
+        self.host_endpoint(
+            kind.into(),
+            HostEndpoint::Tcp(backend.port()),
+            Some(backend.ip()),
+            BTreeMap::<String, String>::new(),
+        )
+    }
+
+    fn host_endpoint<I, K, V>(
+        mut self,
+        kind: String,
+        endpoint: HostEndpoint,
+        proxy: Option<IpAddr>,
+        metadata: I,
+    ) -> Result<Self, Error>
+    where
+        I: IntoIterator<Item = (K, V)>,
+        K: Into<String>,
+        V: Into<String>,
+    {
+        check_duplicate(&self.services, &kind, &endpoint, proxy)?;
+
+        self.services.push(Service::Host {
+            kind,
+            endpoint,
+            metadata: metadata
+                .into_iter()
+                .map(|(k, v)| (k.into(), v.into()))
+                .collect(),
+            priority: 0,
+            weight: 0,
+            ttl: 0,
+            description: None,
+            proxy,
+        });
+        Ok(self)
     }
 
     /// Search for a service kind with this endpoint.
@@ -68,20 +361,805 @@ impl Builder {
         self
     }
 
+    /// Search for every service kind in `kinds` with this endpoint, as if each was passed to
+    /// [`Builder::search`].
+    pub fn searches<I, S>(self, kinds: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        kinds.into_iter().fold(self, Builder::search)
+    }
+
+    /// Search for every kind of service with this endpoint, for example to keep an inventory of
+    /// everything on the discovery network.
+    ///
+    /// Each service hosted by a peer is found once, even if its kind is also searched for with
+    /// [`Builder::search`], and services hosted by this endpoint are never found. Peers running
+    /// older versions of udis don't reply to this search, so their services are only found when
+    /// they announce themselves.
+    pub fn search_all(self) -> Self {
+        if self.services.iter().any(|s| s.is_search_all()) {
+            return self;
+        }
+
+        self.search(WILDCARD_KIND)
+    }
+
+    /// Search for a service kind with this endpoint, but only find services whose metadata passes
+    /// the `filter`.
+    ///
+    /// The filter is given the metadata the host advertised with the service, which is empty if
+    /// the host didn't advertise any, so any filter requiring a particular key will reject hosts
+    /// without metadata. Searching for the same kind again replaces the filter.
+    ///
+    /// The filter is called on the background worker, so it should return quickly.
+    pub fn search_with_filter<S, F>(mut self, kind: S, filter: F) -> Self
+    where
+        S: Into<String>,
+        F: Fn(&HashMap<String, String>) -> bool + Send + Sync + 'static,
+    {
+        let kind = kind.into();
+
+        self.config
+            .search_filters
+            .insert(kind.clone(), Hook(Arc::new(filter)));
+        self.search(kind)
+    }
+
+    /// Remove every service hosted or searched for by this endpoint so far, along with any search
+    /// filters.
+    ///
+    /// This is useful when cloning a builder as a template for several endpoints which each have
+    /// different services.
+    pub fn clear_services(mut self) -> Self {
+        self.services.clear();
+        self.config.search_filters.clear();
+        self
+    }
+
+    /// Register a hook which is called with every raw datagram received by the endpoint.
+    ///
+    /// The hook is given the raw bytes and the source address of the datagram, and is called
+    /// before the packet is deserialised, so it will also see packets which are not valid udis
+    /// notify messages. This is useful for protocol debugging, for example dumping all traffic to
+    /// a file.
+    ///
+    /// The hook is called on the background worker, so it should return quickly.
+    pub fn on_raw_packet<F>(mut self, hook: F) -> Self
+    where
+        F: Fn(&[u8], SocketAddr) + Send + Sync + 'static,
+    {
+        self.config.on_raw_packet = Some(Hook(Arc::new(hook)));
+        self
+    }
+
+    /// Set which IP versions the endpoint binds its discovery socket for, see [`BindMode`] for
+    /// details and platform caveats.
+    ///
+    /// Defaults to [`BindMode::V4Only`], and is ignored if [`Builder::use_broadcast`] is set.
+    pub fn bind_mode(mut self, bind_mode: BindMode) -> Self {
+        self.config.bind_mode = bind_mode;
+        self
+    }
+
+    /// Bind the endpoint's sockets to the network device called `device`, such as `eth1`, so it
+    /// only sends and receives on that interface.
+    ///
+    /// This is more reliable than choosing an interface by its address, for example on a machine
+    /// with several interfaces on overlapping subnets. The IPv4 multicast group is joined on the
+    /// device too. Note that this doesn't change the address advertised to peers, see
+    /// [`Builder::addr`] or [`Builder::auto_addr_filter`].
+    ///
+    /// This uses `SO_BINDTODEVICE`, which is only available on Linux and may need the
+    /// `CAP_NET_RAW` capability on older kernels. On other platforms building the endpoint fails
+    /// with [`Error::Unsupported`]. This is ignored when building with a custom
+    /// [`crate::transport::Transport`], and [`Builder::with_prepared_socket`] sockets must be
+    /// bound to the device already.
+    pub fn bind_device<S: Into<String>>(mut self, device: S) -> Self {
+        self.config.device = Some(device.into());
+        self
+    }
+
+    /// Mark the packets the endpoint sends with the DSCP value `dscp`, so networks with strict QoS
+    /// policies can prioritise discovery traffic appropriately against other traffic.
+    ///
+    /// DSCP values are six bits, so must be from 0 to 63, for example 8 for CS1 (low priority) or
+    /// 46 for EF (expedited forwarding). Building the endpoint fails with [`Error::InvalidDscp`]
+    /// for larger values. IPv4 packets are marked on all major platforms, though Windows ignores
+    /// the mark unless its QoS policies allow it, while marking IPv6 packets is only supported on
+    /// Linux, macOS and the BSDs and building fails with [`Error::Unsupported`] elsewhere. This is
+    /// ignored when building with a custom [`crate::transport::Transport`], and
+    /// [`Builder::with_prepared_socket`] sockets must be marked already.
+    pub fn dscp(mut self, dscp: u8) -> Self {
+        self.config.dscp = Some(dscp);
+        self
+    }
+
+    /// Check that the discovery network works when building the endpoint, failing with
+    /// [`Error::MulticastUnreachable`] if it doesn't.
+    ///
+    /// Some environments, such as containers without multicast support, let the multicast group
+    /// be joined but silently drop everything sent to it, leaving an endpoint which never finds
+    /// anything. With this set the endpoint sends a test message to the discovery network while
+    /// it's being built and waits up to half a second for multicast loopback to deliver it back,
+    /// so broken environments are caught straight away. Peers ignore the test message, but any
+    /// messages from them received while waiting for it are dropped.
+    ///
+    /// This blocks the thread building the endpoint while waiting, including when building an
+    /// async endpoint, and relies on multicast loopback which is enabled on the endpoint's sockets
+    /// where possible. It's ignored when building with a custom
+    /// [`crate::transport::Transport`].
+    pub fn verify_multicast(mut self) -> Self {
+        self.config.verify_multicast = true;
+        self
+    }
+
+    /// Choose what happens if another endpoint on the discovery network already uses this
+    /// endpoint's name, so that names can be relied on to tell endpoints apart.
+    ///
+    /// Unless the `policy` is [`NameConflict::Allow`], the default, the endpoint listens to the
+    /// discovery network for half a second while it's being built, before announcing itself,
+    /// and either picks a free name like `name (2)` or fails with [`Error::NameConflict`]. To
+    /// hear from other endpoints it queries the network for every kind of service, which hosts
+    /// reply to without remembering the query, so endpoints which only search for services are
+    /// only noticed if they announce themselves while it's listening.
+    ///
+    /// This blocks the thread building the endpoint while listening, including when building an
+    /// async endpoint. It's ignored when building with a custom [`crate::transport::Transport`].
+    pub fn on_name_conflict(mut self, policy: NameConflict) -> Self {
+        self.config.name_conflict = policy;
+        self
+    }
+
+    /// Send messages to the IPv4 broadcast address, `255.255.255.255`, instead of the udis
+    /// multicast group.
+    ///
+    /// Some cheap switches and cloud networks drop multicast traffic but still forward broadcast
+    /// traffic, in which case this lets endpoints find each other. Broadcast only exists in IPv4 so
+    /// the [`Builder::bind_mode`] is ignored, and endpoints only hear each other if they all use
+    /// broadcast. A socket given to [`Builder::with_prepared_socket`] must have `SO_BROADCAST` set
+    /// rather than being a member of the multicast group.
+    pub fn use_broadcast(mut self) -> Self {
+        self.config.delivery = Delivery::Broadcast;
+        self
+    }
+
+    /// Also join the IPv4 multicast group `addr`, sending notify messages to it and finding
+    /// services on it as well as on the udis discovery network.
+    ///
+    /// This can be called several times to join several groups, for example to observe or bridge
+    /// more than one discovery domain. Services found on the group have it as their
+    /// [`ServiceInfo::group`](crate::ServiceInfo::group). Each group is received on its own
+    /// socket bound to `port`, so the port must differ from the udis port (`8787`) and from the
+    /// ports of the other groups for the group a service came from to be known.
+    ///
+    /// Groups are IPv4 only so can't be joined with [`BindMode::V6Only`], and are ignored when
+    /// building with a custom [`crate::transport::Transport`]. [`Builder::validate`] and building
+    /// the endpoint fail with [`Error::NotAMulticastAddr`] if `addr` isn't a multicast address.
+    pub fn join_group(mut self, addr: Ipv4Addr, port: u16) -> Self {
+        self.config.groups.push(SocketAddrV4::new(addr, port));
+        self
+    }
+
+    /// Relay messages between the udis discovery networks on the interfaces with the addresses
+    /// `interfaces`, so peers on networks which don't route multicast between them can find each
+    /// other.
+    ///
+    /// This is for machines bridging separate networks, for example with one network card on
+    /// each. Every message received from the udis multicast group on one interface is sent on to
+    /// the group on all the others, marked as relayed so it's never relayed again and relays
+    /// can't loop. Peers reply to relayed messages through the relay rather than directly, and
+    /// ignore them if built with [`Builder::require_matching_source`] as they come from the relay.
+    /// Services are still found at their host's own address, so the networks must route unicast
+    /// traffic between them for found services to be reached.
+    ///
+    /// The endpoint relays on its own thread alongside its usual work, and at least two
+    /// interfaces must be given. Relaying relies on `IP_MULTICAST_ALL`, so it's only supported on
+    /// Linux, and building the endpoint fails with [`Error::Unsupported`] elsewhere. This is
+    /// ignored when building with a custom [`crate::transport::Transport`].
+    pub fn relay_between(mut self, interfaces: Vec<Ipv4Addr>) -> Self {
+        self.config.relay_between = interfaces;
+        self
+    }
+
+    /// Wait at most `timeout` for packets before checking the transport again.
+    ///
+    /// The sync and blocking workers wait for packets and commands together, so they usually
+    /// handle each as soon as it arrives and don't need this. A custom
+    /// [`crate::transport::Transport`] which can't wake the worker is instead polled every 100ms,
+    /// and a shorter timeout here reduces how late its packets are handled. Timeouts under 1ms are
+    /// rounded up so the worker doesn't spin. Defaults to no limit beyond that polling period.
+    pub fn read_timeout(mut self, timeout: Duration) -> Self {
+        self.config.read_timeout = Some(timeout.max(Duration::from_millis(1)));
+        self
+    }
+
+    /// Set how peers are deduplicated, see [`DedupKey`] for the options.
+    ///
+    /// Defaults to [`DedupKey::NameAddr`].
+    pub fn dedup_by(mut self, dedup_key: DedupKey) -> Self {
+        self.config.dedup_by = dedup_key;
+        self
+    }
+
+    /// Send from, and receive unicast replies on, a separate socket bound to `port`.
+    ///
+    /// By default the endpoint uses a single socket bound to the multicast port (`8787`), which
+    /// is shared with every other endpoint on the machine. With a unicast port the multicast
+    /// socket is only used to receive multicast traffic, while every message the endpoint sends
+    /// comes from the unicast port, so firewalls can expect a predictable source port. Peers
+    /// interested in our services are then replied to directly rather than by multicast, at the
+    /// address their notify message came from, and their replies to us arrive on the unicast port.
+    ///
+    /// If a peer doesn't have a unicast port itself its notify messages come from the shared
+    /// multicast port, so a direct reply may be received by a different endpoint on the peer's
+    /// machine. The port must be free, as unlike the multicast port it isn't shared.
+    ///
+    /// This is ignored when building with a custom [`crate::transport::Transport`].
+    pub fn unicast_port(mut self, port: u16) -> Self {
+        self.config.unicast_port = Some(port);
+        self
+    }
+
+    /// Send the endpoint's initial announcement `count` times, spaced a short time apart.
+    ///
+    /// A single announcement can be lost on a lossy network, in which case peers won't find the
+    /// endpoint until they next hear from it. Repeating the announcement makes this much less
+    /// likely, in the same way as SSDP and mDNS.
+    ///
+    /// Defaults to 3, a `count` of zero is treated as one.
+    pub fn announce_repeat(mut self, count: u8) -> Self {
+        self.config.announce_repeat = Some(count);
+        self
+    }
+
+    /// Delay the endpoint's initial announcement by a random amount of time up to `max_jitter`.
+    ///
+    /// When many endpoints start at the same time (e.g. a fleet of machines rebooting) they will
+    /// all announce themselves at once, which can cause a burst of multicast traffic and lost
+    /// packets. Adding a small random delay, e.g. 500 ms, spreads these announcements out.
+    ///
+    /// Defaults to no delay.
+    pub fn startup_jitter(mut self, max_jitter: Duration) -> Self {
+        self.config.startup_jitter = max_jitter;
+        self
+    }
+
+    /// Only trust peers whose advertised address matches the source address of their notify
+    /// messages.
+    ///
+    /// By default the address inside a peer's notify message is trusted, however this can be
+    /// wrong (e.g. behind NAT) or spoofed. When this is set any peer whose advertised address
+    /// differs from the address the packet was actually received from is ignored.
+    pub fn require_matching_source(mut self) -> Self {
+        self.config.require_matching_source = true;
+        self
+    }
+
+    /// Skip repeating the initial announcement if an identical notify message was sent to the
+    /// discovery network within the last repeat interval, e.g. when re-announcing to a peer that
+    /// just joined.
+    ///
+    /// This saves a little bandwidth on busy networks. The initial announcement, announcements
+    /// after a rename and re-announcements to new peers are always sent.
+    pub fn suppress_redundant_announcements(mut self, suppress: bool) -> Self {
+        self.config.suppress_redundant_announcements = suppress;
+        self
+    }
+
+    /// Stop the async endpoint's background task when `signal` finishes, for example
+    /// `token.cancelled_owned()` for a `tokio_util` `CancellationToken` that fires when the
+    /// application shuts down.
+    ///
+    /// This lets the endpoint take part in an application-wide graceful shutdown without being
+    /// kept around just to call `shutdown` on it. Once the task has stopped, waiting for services
+    /// returns [`Error::ServiceInfoChannelClosed`], and shutting the endpoint down still succeeds.
+    ///
+    /// Only the first endpoint built from this builder is stopped by the signal if the builder is
+    /// cloned, and sync endpoints ignore it.
+    ///
+    /// __Requires the `tokio` or `async-std` feature.__
+    #[cfg(any(feature = "tokio", feature = "async-std"))]
+    pub fn shutdown_on<F>(mut self, signal: F) -> Self
+    where
+        F: std::future::Future<Output = ()> + Send + 'static,
+    {
+        self.config.shutdown_signal = Some(Hook(Arc::new(std::sync::Mutex::new(Some(Box::pin(
+            signal,
+        ))))));
+        self
+    }
+
+    /// Don't announce the endpoint to the discovery network on startup if it doesn't host any
+    /// services, so pure clients don't add noise or reveal their presence.
+    ///
+    /// Hosts normally reply to a client's announcement straight away, so this makes discovery
+    /// slower: the client only finds hosts when they announce themselves, for example when they
+    /// start up or are renamed or resumed. Hosts which are already running when the client starts
+    /// won't be found until then. The client still acknowledges services it finds, and renaming
+    /// or resuming it still announces it. This has no effect on endpoints which host a service.
+    pub fn silent_client(mut self) -> Self {
+        self.config.silent_client = true;
+        self
+    }
+
+    /// Only accept peers advertising an address within `subnet`, given in CIDR notation such as
+    /// `192.168.0.0/24` or `fd00::/8`.
+    ///
+    /// This is useful on segmented networks where multicast leaks between segments, and services
+    /// advertised by peers on other segments can't be reached. Any peer whose advertised address
+    /// is outside the subnet is ignored, and any other addresses a peer advertises with
+    /// [`Builder::add_addr`] which are outside the subnet are left out of
+    /// [`crate::ServiceInfo::addrs`].
+    ///
+    /// # Errors
+    ///
+    /// Can fail if `subnet` isn't a valid subnet in CIDR notation.
+    pub fn restrict_to_subnet(mut self, subnet: &str) -> Result<Self, Error> {
+        self.config.subnet = Some(subnet.parse()?);
+        Ok(self)
+    }
+
+    /// Only accept peers which pass the `filter`, for policies the other filters can't express.
+    ///
+    /// The filter is given each peer's notify message and the address it was received from, after
+    /// the checks made by [`Builder::require_matching_source`] and
+    /// [`Builder::restrict_to_subnet`]. Any peer the filter returns false for is dropped entirely,
+    /// so it's never replied to and none of its services are found.
+    ///
+    /// The filter is called on the background worker, so it should return quickly.
+    pub fn peer_filter<F>(mut self, filter: F) -> Self
+    where
+        F: Fn(&Udis, SocketAddr) -> bool + Send + Sync + 'static,
+    {
+        self.config.peer_filter = Some(Hook(Arc::new(filter)));
+        self
+    }
+
+    /// Ignore any packet larger than `max_size` bytes without trying to deserialise it.
+    ///
+    /// This protects the endpoint from hostile or broken peers sending huge packets which are
+    /// expensive to parse. The default is 64 KiB, which is larger than any UDP packet, so only
+    /// matters for custom transports unless lowered.
+    ///
+    /// This endpoint's own notify message is kept within the same limit, and within the largest
+    /// UDP datagram, so peers configured alike can always read it. If the hosted services don't
+    /// fit, the least preferred ones are left out of the message and an error is logged for each.
+    pub fn max_message_size(mut self, max_size: usize) -> Self {
+        self.config.max_message_size = Some(max_size);
+        self
+    }
+
+    /// Attach `payload` to this endpoint's announcements, for application specific data which
+    /// udis doesn't understand itself, such as a protocol version or a public key.
+    ///
+    /// Peers get the payload as it was given in [`crate::ServiceInfo::extra`] for each service
+    /// they find, and parse it however they like. Peers running older versions of udis ignore it.
+    ///
+    /// The payload is sent base64 encoded, so takes up a third more than its length in the notify
+    /// message, which is kept within [`Builder::max_message_size`] and a single UDP datagram. If
+    /// the payload leaves no room for every hosted service, the least preferred are left out, and
+    /// building fails with [`Error::NotifyMsgTooLarge`] if the payload doesn't fit at all. Peers
+    /// with a lower limit drop the whole message, and messages larger than the network's MTU,
+    /// often around 1400 bytes, may be fragmented and are more likely to be lost, so payloads
+    /// should be kept to a few hundred bytes.
+    pub fn extra_payload<P: Into<Arc<[u8]>>>(mut self, payload: P) -> Self {
+        self.extra = payload.into();
+        self
+    }
+
+    /// Ignore any packet with JSON objects and arrays nested more than `max_depth` levels deep
+    /// without trying to deserialise it.
+    ///
+    /// This protects the endpoint from hostile or broken peers sending deeply nested packets which
+    /// are expensive to parse. The default of 16 is plenty for udis messages, which are only
+    /// nested a few levels deep.
+    pub fn max_message_depth(mut self, max_depth: usize) -> Self {
+        self.config.max_message_depth = Some(max_depth);
+        self
+    }
+
+    /// Sort the services found on a peer by kind before they are returned.
+    ///
+    /// By default services found in the same notify message are returned in the order the peer
+    /// listed them, which depends on how the peer was built. Enable this for a reproducible order,
+    /// e.g. in tests or UIs.
+    pub fn sort_results(mut self, sort: bool) -> Self {
+        self.config.sort_results = sort;
+        self
+    }
+
+    /// Periodically call `callback` with the endpoint's [`Metrics`], e.g. to feed a monitoring
+    /// dashboard.
+    ///
+    /// The callback is called every `interval` on the background worker, so it should return
+    /// quickly.
+    pub fn metrics<F>(mut self, interval: Duration, callback: F) -> Self
+    where
+        F: Fn(Metrics) + Send + Sync + 'static,
+    {
+        self.config.metrics = Some(MetricsReporter {
+            interval,
+            callback: Hook(Arc::new(callback)),
+        });
+        self
+    }
+
+    /// Keep a timeline of the last `capacity` things to happen on the endpoint, such as packets
+    /// received, peers added and announcements sent.
+    ///
+    /// This gives a trace of what the endpoint did for debugging intermittent problems, without
+    /// enabling trace logging globally. Get it with [`SyncUdis::recent_events`]. By default no
+    /// timeline is kept.
+    pub fn timeline(mut self, capacity: usize) -> Self {
+        self.config.timeline_capacity = capacity;
+        self
+    }
+
+    /// Pass events about the services this endpoint hosts on to the user, such as peers
+    /// acknowledging them, to be received with [`SyncUdis::next_host_event`].
+    ///
+    /// Host events are queued until they're received, so by default they aren't passed on at all
+    /// to stop the queue growing forever on endpoints which never look at them. Blocking endpoints
+    /// never pass them on, see [`BlockingUdis`].
+    pub fn host_events(mut self) -> Self {
+        self.config.host_events = true;
+        self
+    }
+
+    /// Stop the endpoint once it has found its first service.
+    ///
+    /// This is useful for tools which just need to resolve one service and exit. After the first
+    /// matching service is found the endpoint says goodbye to the discovery network and its
+    /// background worker stops, so there's no need to call shutdown. The first call to
+    /// `find_service` returns the service, after which it returns an error rather than blocking.
+    pub fn find_once(mut self) -> Self {
+        self.config.find_once = true;
+        self
+    }
+
+    /// Wait for `grace` when the endpoint is shut down, before its background worker stops, so
+    /// messages it has just sent can leave the machine.
+    ///
+    /// Messages are handed to the OS as soon as they're sent, but if the process exits straight
+    /// after shutting the endpoint down the OS may discard datagrams still waiting in the socket's
+    /// buffers. A short grace, such as 50 ms, avoids this at the cost of a slower shutdown.
+    ///
+    /// Defaults to no grace.
+    pub fn shutdown_grace(mut self, grace: Duration) -> Self {
+        self.config.shutdown_grace = grace;
+        self
+    }
+
+    /// Hold back replies to peers interested in this endpoint's services for up to `window`, so
+    /// that replies to several peers can be sent as one message.
+    ///
+    /// Usually each newly interested peer is sent its own copy of this endpoint's notify message,
+    /// which on a busy network with many searchers starting together multiplies the traffic. With
+    /// a window the first interested peer starts it, and when it's up a peer which is the only one
+    /// waiting is replied to directly, while several peers are replied to with a single
+    /// announcement to the whole discovery network. A peer is only ever replied to once per
+    /// window, and [`crate::HostEvent::Replied`] events are passed on once the reply is sent.
+    ///
+    /// The window is timed by the endpoint's [`Builder::clock`], and any replies still held back
+    /// are sent when the endpoint is paused or shut down. A short window, such as 50 ms, is
+    /// usually enough. Defaults to no window, so every reply is sent straight away.
+    pub fn reply_coalesce_window(mut self, window: Duration) -> Self {
+        self.config.reply_coalesce_window = window;
+        self
+    }
+
+    /// Use `clock` for the current time instead of the system's clock, for example a
+    /// [`crate::clock::MockClock`] to test time-based features such as service TTLs, repeat
+    /// announcements and lifetimes without waiting for them.
+    ///
+    /// With a custom clock the sync worker checks its timers every few milliseconds, so it notices
+    /// promptly when the clock is advanced. Async workers do the same for service expiry, but
+    /// otherwise sleep on their runtime's timers so don't notice the clock being advanced, for
+    /// them tokio's `time::pause` is more useful.
+    pub fn clock<C: Clock>(mut self, clock: C) -> Self {
+        self.config.clock = Some(Arc::new(clock));
+        self
+    }
+
+    /// Stop the endpoint after it has been running for `lifetime`.
+    ///
+    /// This time boxes the endpoint, which is useful for short-lived tools and tests. Once the
+    /// lifetime is up the endpoint says goodbye to the discovery network and its background
+    /// worker stops, so `find_service` returns an error rather than blocking and there's no need to
+    /// call shutdown.
+    pub fn lifetime(mut self, lifetime: Duration) -> Self {
+        self.config.lifetime = Some(lifetime);
+        self
+    }
+
+    /// Include every service hosted by a peer in the [`crate::ServiceInfo::peer_services`] of the
+    /// services found on it.
+    ///
+    /// This saves searching for the other services of a peer you've already found, but is off by
+    /// default to keep found services small.
+    pub fn include_peer_services(mut self) -> Self {
+        self.config.include_peer_services = true;
+        self
+    }
+
+    /// Use a socket you have created yourself instead of letting the endpoint build one.
+    ///
+    /// This is useful if the socket needs configuring in a way udis doesn't support, for example
+    /// binding to a device with `SO_BINDTODEVICE`, or creating it with elevated capabilities or in
+    /// a specific network namespace.
+    ///
+    /// The socket must be a UDP socket already bound to the udis discovery port (`8787`), with an
+    /// address family matching the [`Builder::bind_mode`]. The endpoint will set the socket to
+    /// non-blocking and join the udis multicast groups on it,
+    /// if you have already done this use [`Builder::with_prepared_socket`] instead.
+    pub fn with_socket(mut self, socket: socket2::Socket) -> Self {
+        self.config.socket = Some(ExternalSocket {
+            socket: Arc::new(socket),
+            prepared: false,
+        });
+        self
+    }
+
+    /// Use a socket you have created and fully configured yourself.
+    ///
+    /// Unlike [`Builder::with_socket`] the endpoint will use the socket as-is, so it must already
+    /// be bound to the udis discovery port (`8787`), be a member of the udis multicast groups for
+    /// the [`Builder::bind_mode`] (`224.0.0.87` for IPv4) and be non-blocking.
+    pub fn with_prepared_socket(mut self, socket: socket2::Socket) -> Self {
+        self.config.socket = Some(ExternalSocket {
+            socket: Arc::new(socket),
+            prepared: true,
+        });
+        self
+    }
+
+    /// Check the whole configuration of the endpoint without touching the network, returning the
+    /// first problem found.
+    ///
+    /// This is useful for checking configuration as it's parsed, or in tests, as the endpoint
+    /// doesn't have to be built. The local address isn't looked up, so building can still fail if
+    /// no address was given with [`Builder::addr`].
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if:
+    ///
+    /// - a service is hosted twice, or has an empty kind,
+    /// - the address given with [`Builder::addr`], or the backend of a service hosted with
+    ///   [`Builder::host_proxy`], can't be reached by peers, for example `0.0.0.0` or a multicast
+    ///   address,
+    /// - a group given with [`Builder::join_group`] isn't a multicast address,
+    /// - or options are set which can't work together, for example [`Builder::find_once`] without
+    ///   searching for any services.
+    pub fn validate(&self) -> Result<(), Error> {
+        for (i, service) in self.services.iter().enumerate() {
+            if service.kind().is_empty() {
+                return Err(Error::EmptyServiceKind);
+            }
+
+            if let Service::Host {
+                kind,
+                endpoint,
+                proxy,
+                ..
+            } = service
+            {
+                check_duplicate(&self.services[..i], kind, endpoint, *proxy)?;
+            }
+        }
+
+        let proxies = self.services.iter().filter_map(|s| match s {
+            Service::Host { proxy, .. } => proxy.as_ref(),
+            Service::Search { .. } => None,
+        });
+        for &addr in self.addr.iter().chain(&self.extra_addrs).chain(proxies) {
+            let broadcast = match addr {
+                IpAddr::V4(addr) => addr.is_broadcast(),
+                IpAddr::V6(_) => false,
+            };
+
+            if addr.is_unspecified() || addr.is_multicast() || broadcast {
+                return Err(Error::UnroutableAddr(addr));
+            }
+        }
+
+        let searching = self
+            .services
+            .iter()
+            .any(|s| matches!(s, Service::Search { .. }));
+        if self.config.find_once && !searching {
+            return Err(Error::ConflictingOptions(
+                "find_once is set but no services are searched for".into(),
+            ));
+        }
+
+        for (i, group) in self.config.groups.iter().enumerate() {
+            if !group.ip().is_multicast() {
+                return Err(Error::NotAMulticastAddr {
+                    addr: (*group.ip()).into(),
+                });
+            }
+
+            if self.config.bind_mode() == BindMode::V6Only {
+                return Err(Error::ConflictingOptions(format!(
+                    "the group {group} is IPv4 but the bind mode is V6Only"
+                )));
+            }
+
+            let port = group.port();
+            if port == MULTICAST_PORT || self.config.groups[..i].iter().any(|g| g.port() == port) {
+                return Err(Error::ConflictingOptions(format!(
+                    "the group {group} shares its port with the udis group or another extra group"
+                )));
+            }
+        }
+
+        if let Some(dscp) = self.config.dscp.filter(|&dscp| dscp > 63) {
+            return Err(Error::InvalidDscp(dscp));
+        }
+
+        let relay_between = &self.config.relay_between;
+        if !relay_between.is_empty() && relay_between.len() < 2 {
+            return Err(Error::ConflictingOptions(
+                "relaying needs at least two interfaces to relay between".into(),
+            ));
+        }
+        for (i, interface) in relay_between.iter().enumerate() {
+            if relay_between[..i].contains(interface) {
+                return Err(Error::ConflictingOptions(format!(
+                    "the interface {interface} is given more than once to relay between"
+                )));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Get the address the endpoint will be visible on, either the one the user set or a local one
+    fn resolve_addr(&self) -> Result<IpAddr, Error> {
+        if let Some(addr) = self.addr {
+            return Ok(addr);
+        }
+
+        let Some(filter) = &self.addr_filter else {
+            return Ok(local_ip_address::local_ip()?);
+        };
+
+        local_ip_address::list_afinet_netifas()?
+            .into_iter()
+            .map(|(_, addr)| addr)
+            .find(|addr| (filter.0)(addr))
+            .ok_or(Error::NoSuitableLocalAddr)
+    }
+
+    /// Get the name the endpoint will announce itself with, after checking it isn't already used
+    /// on the discovery network if the user wants it to be unique
+    fn resolve_name(&self, addr: IpAddr) -> Result<String, Error> {
+        let policy = self.config.name_conflict;
+        if policy == NameConflict::Allow {
+            return Ok(self.name.clone());
+        }
+
+        trace!("listening for other endpoints named `{}`", self.name);
+        let names = names_in_use(&self.config, addr, NAME_CONFLICT_LISTEN)?;
+        if !names.contains(&self.name) {
+            return Ok(self.name.clone());
+        }
+
+        if policy == NameConflict::Fail {
+            return Err(Error::NameConflict(self.name.clone()));
+        }
+
+        let name = free_name(&self.name, &names);
+        trace!("`{}` is already in use, using `{name}` instead", self.name);
+        Ok(name)
+    }
+
     /// Build a sync udis endpoint
     ///
     /// # Errors
     ///
     /// This function will return an error if you did not specify an address using
-    /// [`Builder::addr`] and the local IP address of this machine can't be determined.
+    /// [`Builder::addr`] and the local IP address of this machine can't be determined, or none of
+    /// the local addresses pass the [`Builder::auto_addr_filter`].
+    ///
+    /// It will also return an error if the socket used to join the discovery network can't be set
+    /// up, for example if the multicast group can't be joined.
     pub fn build_sync(self) -> Result<SyncUdis, Error> {
-        // If there is no addr use the local one
-        let addr = match self.addr {
-            Some(addr) => addr,
-            None => local_ip_address::local_ip()?,
-        };
+        let addr = self.resolve_addr()?;
+        let name = self.resolve_name(addr)?;
+
+        SyncUdis::build(
+            Udis::build(name, addr, self.services)
+                .with_extra_addrs(self.extra_addrs)
+                .with_extra(self.extra),
+            self.config,
+            None,
+        )
+    }
+
+    /// Build a sync udis endpoint and wait up to `timeout` for it to find a service, for the
+    /// common case of starting up and finding a dependency before carrying on.
+    ///
+    /// The endpoint is returned along with the first service found, or `None` if no service was
+    /// found in time, and carries on discovering services as normal.
+    ///
+    /// # Errors
+    ///
+    /// This function can fail in the same ways as [`Builder::build_sync`], or if the background
+    /// thread stops while waiting for a service.
+    pub fn build_sync_and_wait(
+        self,
+        timeout: Duration,
+    ) -> Result<(SyncUdis, Option<ServiceInfo>), Error> {
+        let udis = self.build_sync()?;
+        let serv_info = udis.find_service_where(timeout, true, |_| true)?;
 
-        Ok(SyncUdis::build(Udis::build(self.name, addr, self.services)))
+        Ok((udis, serv_info))
+    }
+
+    /// Build a sync udis endpoint which uses the given transport rather than UDP multicast.
+    ///
+    /// Any socket given with [`Builder::with_socket`] or [`Builder::with_prepared_socket`], and
+    /// the [`Builder::bind_mode`], are ignored as the transport is responsible for the network.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if you did not specify an address using
+    /// [`Builder::addr`] and the local IP address of this machine can't be determined, or none of
+    /// the local addresses pass the [`Builder::auto_addr_filter`].
+    pub fn build_sync_with_transport<T: Transport>(self, transport: T) -> Result<SyncUdis, Error> {
+        let addr = self.resolve_addr()?;
+
+        SyncUdis::build(
+            Udis::build(self.name, addr, self.services)
+                .with_extra_addrs(self.extra_addrs)
+                .with_extra(self.extra),
+            self.config,
+            Some(Box::new(transport)),
+        )
+    }
+
+    /// Build a blocking udis endpoint, which runs on the caller's thread whenever
+    /// [`BlockingUdis::run_once`] is called rather than starting a background thread.
+    ///
+    /// # Errors
+    ///
+    /// This function can fail in the same ways as [`Builder::build_sync`].
+    pub fn build_blocking(self) -> Result<BlockingUdis, Error> {
+        let addr = self.resolve_addr()?;
+        let name = self.resolve_name(addr)?;
+
+        BlockingUdis::build(
+            Udis::build(name, addr, self.services)
+                .with_extra_addrs(self.extra_addrs)
+                .with_extra(self.extra),
+            self.config,
+            None,
+        )
+    }
+
+    /// Build a blocking udis endpoint which uses the given transport rather than UDP multicast,
+    /// see [`Builder::build_blocking`] and [`Builder::build_sync_with_transport`].
+    ///
+    /// # Errors
+    ///
+    /// This function can fail in the same ways as [`Builder::build_sync_with_transport`].
+    pub fn build_blocking_with_transport<T: Transport>(
+        self,
+        transport: T,
+    ) -> Result<BlockingUdis, Error> {
+        let addr = self.resolve_addr()?;
+
+        BlockingUdis::build(
+            Udis::build(self.name, addr, self.services)
+                .with_extra_addrs(self.extra_addrs)
+                .with_extra(self.extra),
+            self.config,
+            Some(Box::new(transport)),
+        )
     }
 
     /// Build an async udis endpoint
@@ -91,19 +1169,330 @@ impl Builder {
     /// # Errors
     ///
     /// This function will return an error if you did not specify an address using
-    /// [`Builder::addr`] and the local IP address of this machine can't be determined.
+    /// [`Builder::addr`] and the local IP address of this machine can't be determined, or none of
+    /// the local addresses pass the [`Builder::auto_addr_filter`].
+    ///
+    /// It will also return an error if the socket used to join the discovery network can't be set
+    /// up, for example if the multicast group can't be joined.
+    ///
+    /// # Panics
+    ///
+    /// This function will panic if called from outside of a tokio runtime, use
+    /// [`Builder::build_async_on`] to build the endpoint on an explicit runtime instead.
     #[cfg(feature = "tokio")]
     pub fn build_async(self) -> Result<AsyncUdis, Error> {
-        // If there is no addr use the local one
-        let addr = match self.addr {
-            Some(addr) => addr,
-            None => local_ip_address::local_ip()?,
+        self.build_async_on(&tokio::runtime::Handle::current())
+    }
+
+    /// Build an async udis endpoint, spawning the background task on the given runtime.
+    ///
+    /// Unlike [`Builder::build_async`] this can be called from outside of a tokio runtime, for
+    /// example during application setup before entering any async code.
+    ///
+    /// __Requires the `tokio` feature.__
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if you did not specify an address using
+    /// [`Builder::addr`] and the local IP address of this machine can't be determined, or none of
+    /// the local addresses pass the [`Builder::auto_addr_filter`].
+    ///
+    /// It will also return an error if the socket used to join the discovery network can't be set
+    /// up, for example if the multicast group can't be joined.
+    #[cfg(feature = "tokio")]
+    pub fn build_async_on(self, handle: &tokio::runtime::Handle) -> Result<AsyncUdis, Error> {
+        let addr = self.resolve_addr()?;
+        let name = self.resolve_name(addr)?;
+
+        AsyncUdis::build(
+            Udis::build(name, addr, self.services)
+                .with_extra_addrs(self.extra_addrs)
+                .with_extra(self.extra),
+            self.config,
+            None,
+            handle,
+        )
+    }
+
+    /// Build an async udis endpoint which uses the given transport rather than UDP multicast.
+    ///
+    /// Any socket given with [`Builder::with_socket`] or [`Builder::with_prepared_socket`], and
+    /// the [`Builder::bind_mode`], are ignored as the transport is responsible for the network.
+    ///
+    /// __Requires the `tokio` feature.__
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if you did not specify an address using
+    /// [`Builder::addr`] and the local IP address of this machine can't be determined, or none of
+    /// the local addresses pass the [`Builder::auto_addr_filter`].
+    ///
+    /// # Panics
+    ///
+    /// This function will panic if called from outside of a tokio runtime.
+    #[cfg(feature = "tokio")]
+    pub fn build_async_with_transport<T: Transport>(
+        self,
+        transport: T,
+    ) -> Result<AsyncUdis, Error> {
+        let addr = self.resolve_addr()?;
+
+        AsyncUdis::build(
+            Udis::build(self.name, addr, self.services)
+                .with_extra_addrs(self.extra_addrs)
+                .with_extra(self.extra),
+            self.config,
+            Some(Box::new(transport)),
+            &tokio::runtime::Handle::current(),
+        )
+    }
+
+    /// Build an async udis endpoint which runs its background task on the async-std runtime.
+    ///
+    /// __Requires the `async-std` feature.__
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if you did not specify an address using
+    /// [`Builder::addr`] and the local IP address of this machine can't be determined, or none of
+    /// the local addresses pass the [`Builder::auto_addr_filter`], or if the discovery socket
+    /// can't be set up.
+    #[cfg(feature = "async-std")]
+    pub fn build_async_std(self) -> Result<crate::async_std_rt::AsyncUdis, Error> {
+        let addr = self.resolve_addr()?;
+        let name = self.resolve_name(addr)?;
+
+        crate::async_std_rt::AsyncUdis::build(
+            Udis::build(name, addr, self.services)
+                .with_extra_addrs(self.extra_addrs)
+                .with_extra(self.extra),
+            self.config,
+            None,
+        )
+    }
+
+    /// Build an async udis endpoint on the async-std runtime which uses the given transport
+    /// rather than UDP multicast.
+    ///
+    /// Any socket given with [`Builder::with_socket`] or [`Builder::with_prepared_socket`], and
+    /// the [`Builder::bind_mode`], are ignored as the transport is responsible for the network.
+    ///
+    /// __Requires the `async-std` feature.__
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if you did not specify an address using
+    /// [`Builder::addr`] and the local IP address of this machine can't be determined, or none of
+    /// the local addresses pass the [`Builder::auto_addr_filter`].
+    #[cfg(feature = "async-std")]
+    pub fn build_async_std_with_transport<T: Transport>(
+        self,
+        transport: T,
+    ) -> Result<crate::async_std_rt::AsyncUdis, Error> {
+        let addr = self.resolve_addr()?;
+
+        crate::async_std_rt::AsyncUdis::build(
+            Udis::build(self.name, addr, self.services)
+                .with_extra_addrs(self.extra_addrs)
+                .with_extra(self.extra),
+            self.config,
+            Some(Box::new(transport)),
+        )
+    }
+}
+
+/// Check that a service hosted with `kind` at `endpoint` doesn't share either with one of
+/// `services`, where a proxied service's endpoint is on its `proxy` rather than this endpoint
+fn check_duplicate(
+    services: &[Service],
+    kind: &str,
+    endpoint: &HostEndpoint,
+    proxy: Option<IpAddr>,
+) -> Result<(), Error> {
+    let duplicate = services.iter().any(|s| match s {
+        Service::Host {
+            kind: k,
+            endpoint: e,
+            proxy: p,
+            ..
+        } => k == kind || (e == endpoint && *p == proxy),
+        Service::Search { .. } => false,
+    });
+
+    if !duplicate {
+        return Ok(());
+    }
+
+    Err(match endpoint {
+        HostEndpoint::Tcp(port) => Error::DuplicateService {
+            kind: kind.to_owned(),
+            port: *port,
+        },
+        HostEndpoint::Unix(path) => Error::DuplicateUnixService {
+            kind: kind.to_owned(),
+            path: path.clone(),
+        },
+    })
+}
+
+/// The first of `name`, `name (2)`, `name (3)` and so on which isn't one of the `names` in use
+fn free_name(name: &str, names: &HashSet<String>) -> String {
+    iter::once(name.to_owned())
+        .chain((2..).map(|n| format!("{name} ({n})")))
+        .find(|candidate| !names.contains(candidate))
+        .unwrap_or_else(|| name.to_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::HashSet,
+        net::{IpAddr, Ipv4Addr, SocketAddr},
+    };
+
+    use super::{free_name, BindMode, Builder};
+    use crate::{error::Error, Udis};
+
+    #[test]
+    fn test_free_name() {
+        let names = |names: &[&str]| names.iter().map(|&n| n.to_owned()).collect::<HashSet<_>>();
+
+        // A free name is kept, otherwise the first free suffix is taken, even if there are gaps
+        assert_eq!(free_name("server", &names(&[])), "server");
+        assert_eq!(free_name("server", &names(&["other"])), "server");
+        assert_eq!(free_name("server", &names(&["server"])), "server (2)");
+        assert_eq!(
+            free_name("server", &names(&["server", "server (2)", "server (4)"])),
+            "server (3)"
+        );
+
+        // Names which already look suffixed get another suffix rather than being renumbered
+        assert_eq!(
+            free_name("server (2)", &names(&["server (2)"])),
+            "server (2) (2)"
+        );
+    }
+
+    #[test]
+    fn test_clear_services() {
+        let base = Udis::new("base")
+            .addr(Ipv4Addr::new(10, 0, 0, 1))
+            .host("hello", 4112)
+            .unwrap()
+            .search_with_filter("goodbye", |_| true);
+
+        // Clearing a clone leaves it with nothing hosted, searched for or filtered, and doesn't
+        // touch the base
+        let cleared = base.clone().clear_services().search("other");
+        assert_eq!(cleared.services.len(), 1);
+        assert_eq!(cleared.services[0].kind(), "other");
+        assert!(cleared.config.search_filters.is_empty());
+        assert_eq!(cleared.addr, base.addr);
+
+        assert_eq!(base.services.len(), 2);
+        assert!(base.config.search_filters.contains_key("goodbye"));
+    }
+
+    #[test]
+    fn test_validate() {
+        let base = || Udis::new("server").addr(Ipv4Addr::new(10, 0, 0, 1));
+        let proxy = |ip: Ipv4Addr| {
+            base()
+                .host_proxy("hello", SocketAddr::new(ip.into(), 4112))
+                .unwrap()
+        };
+        let unroutable = |builder: Builder| match builder.validate() {
+            Err(Error::UnroutableAddr(addr)) => Some(addr),
+            _ => None,
         };
+        let conflicting =
+            |builder: Builder| matches!(builder.validate(), Err(Error::ConflictingOptions(_)));
+
+        assert!(base().host("hello", 4112).unwrap().validate().is_ok());
+
+        // Empty kinds, whether hosted or searched for
+        assert!(matches!(
+            base().host("", 4112).unwrap().validate(),
+            Err(Error::EmptyServiceKind)
+        ));
+        assert!(matches!(
+            base().search("").validate(),
+            Err(Error::EmptyServiceKind)
+        ));
+
+        // Duplicate hosts which got past `host`, e.g. after editing the services directly
+        let mut duplicated = base().host("hello", 4112).unwrap();
+        duplicated.services.push(duplicated.services[0].clone());
+        assert!(matches!(
+            duplicated.validate(),
+            Err(Error::DuplicateService { port: 4112, .. })
+        ));
+
+        // Addresses peers can't reach, whether our own or a proxied backend's
+        for ip in [
+            Ipv4Addr::UNSPECIFIED,
+            Ipv4Addr::new(239, 255, 0, 1),
+            Ipv4Addr::BROADCAST,
+        ] {
+            assert_eq!(unroutable(base().addr(ip)), Some(IpAddr::V4(ip)));
+            assert_eq!(unroutable(base().add_addr(ip)), Some(IpAddr::V4(ip)));
+            assert_eq!(unroutable(proxy(ip)), Some(IpAddr::V4(ip)));
+        }
+        assert_eq!(unroutable(proxy(Ipv4Addr::new(10, 0, 0, 2))), None);
+
+        // Stopping after the first service found when nothing is searched for
+        assert!(conflicting(base().host("hello", 4112).unwrap().find_once()));
+        assert!(base().search("hello").find_once().validate().is_ok());
+
+        // Extra groups which aren't multicast, are IPv4 when bound to IPv6 only, or share a port
+        assert!(matches!(
+            base()
+                .join_group(Ipv4Addr::new(10, 0, 0, 9), 9000)
+                .validate(),
+            Err(Error::NotAMulticastAddr { .. })
+        ));
+        let group = Ipv4Addr::new(239, 255, 0, 1);
+        assert!(conflicting(
+            base().bind_mode(BindMode::V6Only).join_group(group, 9000)
+        ));
+        assert!(conflicting(base().join_group(group, 8787)));
+        assert!(conflicting(
+            base().join_group(group, 9000).join_group(group, 9000)
+        ));
+
+        // DSCP values which don't fit in six bits
+        assert!(base().dscp(63).validate().is_ok());
+        assert!(matches!(
+            base().dscp(64).validate(),
+            Err(Error::InvalidDscp(64))
+        ));
+
+        // Relaying needs at least two different interfaces
+        let a = Ipv4Addr::new(10, 0, 0, 1);
+        let b = Ipv4Addr::new(192, 168, 0, 1);
+        assert!(conflicting(base().relay_between(vec![a])));
+        assert!(conflicting(base().relay_between(vec![a, b, a])));
+        assert!(base().relay_between(vec![a, b]).validate().is_ok());
+    }
+
+    #[test]
+    fn test_hosts() {
+        let builder = Udis::new("server")
+            .hosts([("hello", 4112), ("goodbye", 4113)])
+            .unwrap();
+        let kinds = builder
+            .services
+            .iter()
+            .map(|s| s.kind())
+            .collect::<Vec<_>>();
+        assert_eq!(kinds, ["hello", "goodbye"]);
 
-        Ok(AsyncUdis::build(Udis::build(
-            self.name,
-            addr,
-            self.services,
-        )))
+        // The first duplicate stops the rest being added
+        let result =
+            Udis::new("server").hosts([("hello", 4112), ("hello", 4113), ("goodbye", 4112)]);
+        assert!(matches!(
+            result,
+            Err(Error::DuplicateService { ref kind, port: 4113 }) if kind == "hello"
+        ));
     }
 }
@@ -0,0 +1,80 @@
+use std::{
+    fmt,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+/// A source of the current time for udis endpoints.
+///
+/// Endpoints use the [`SystemClock`] unless given another with
+/// [`crate::builder::Builder::clock`], such as a [`MockClock`] which lets tests move time forward
+/// to trigger service expiry, repeat announcements or lifetimes without waiting for them.
+pub trait Clock: fmt::Debug + Send + Sync + 'static {
+    /// The current time
+    fn now(&self) -> Instant;
+}
+
+/// The system's monotonic clock, used by endpoints by default
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+/// A clock which only moves when it's advanced, for testing.
+///
+/// Clones of the clock share the same time, so one can be given to an endpoint while the test
+/// keeps another to advance.
+///
+/// # Examples
+///
+/// ```
+/// use std::time::Duration;
+///
+/// use udis::clock::{Clock, MockClock};
+///
+/// let clock = MockClock::new();
+/// let start = clock.now();
+///
+/// clock.advance(Duration::from_secs(60));
+/// assert_eq!(clock.now() - start, Duration::from_secs(60));
+/// ```
+#[derive(Debug, Clone)]
+pub struct MockClock {
+    now: Arc<Mutex<Instant>>,
+}
+
+impl MockClock {
+    /// Create a clock which starts at the current time
+    pub fn new() -> Self {
+        Self {
+            now: Arc::new(Mutex::new(Instant::now())),
+        }
+    }
+
+    /// Move the clock, and every clone of it, forward by `by`
+    pub fn advance(&self, by: Duration) {
+        match self.now.lock() {
+            Ok(mut now) => *now += by,
+            Err(poisoned) => *poisoned.into_inner() += by,
+        }
+    }
+}
+
+impl Default for MockClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Clock for MockClock {
+    fn now(&self) -> Instant {
+        match self.now.lock() {
+            Ok(now) => *now,
+            Err(poisoned) => *poisoned.into_inner(),
+        }
+    }
+}
@@ -0,0 +1,277 @@
+use std::{
+    collections::{hash_map::RandomState, HashMap},
+    fmt,
+    hash::{BuildHasher, Hasher},
+    net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4},
+    sync::Arc,
+    time::Duration,
+};
+
+use socket2::Socket;
+
+use crate::{
+    builder::{BindMode, DedupKey, NameConflict},
+    clock::{Clock, SystemClock},
+    diagnostics::Metrics,
+    net::{Delivery, Subnet, MAX_DATAGRAM_SIZE},
+    Udis,
+};
+
+/// How many times the initial announcement is sent by default
+pub(crate) const DEFAULT_ANNOUNCE_REPEAT: u8 = 3;
+
+/// Time between repeats of the initial announcement
+pub(crate) const ANNOUNCE_REPEAT_INTERVAL: Duration = Duration::from_millis(250);
+
+/// How often workers check their timers when the endpoint has a custom clock
+pub(crate) const CLOCK_SWEEP_PERIOD: Duration = Duration::from_millis(10);
+
+/// How long to wait for the test message sent when verifying the discovery network to come back
+pub(crate) const VERIFY_MULTICAST_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// How long to listen for other endpoints using our name before announcing ourselves
+pub(crate) const NAME_CONFLICT_LISTEN: Duration = Duration::from_millis(500);
+
+/// Largest packet that will be deserialised by default, in bytes
+pub(crate) const DEFAULT_MAX_MESSAGE_SIZE: usize = 64 * 1024;
+
+/// Deepest nesting of JSON objects and arrays that will be deserialised by default
+pub(crate) const DEFAULT_MAX_MESSAGE_DEPTH: usize = 16;
+
+/// Configuration for the background worker, collected by the [`crate::builder::Builder`].
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Config {
+    /// Hook invoked with every raw datagram received, before it is deserialised
+    pub(crate) on_raw_packet: Option<Hook<RawPacketFn>>,
+
+    /// Drop peers whose advertised address doesn't match the source of their notify message
+    pub(crate) require_matching_source: bool,
+
+    /// Drop peers whose advertised address is outside this subnet
+    pub(crate) subnet: Option<Subnet>,
+
+    /// Drop peers which the user's filter rejects
+    pub(crate) peer_filter: Option<Hook<PeerFilterFn>>,
+
+    /// Sort services found on a peer by kind before sending them to the user
+    pub(crate) sort_results: bool,
+
+    /// Leave the discovery network and stop the worker once the first service is found
+    pub(crate) find_once: bool,
+
+    /// Fill in every service hosted by the peer in found services
+    pub(crate) include_peer_services: bool,
+
+    /// Filters on the metadata of found services, by service kind
+    pub(crate) search_filters: HashMap<String, Hook<SearchFilterFn>>,
+
+    /// How peers are deduplicated
+    pub(crate) dedup_by: DedupKey,
+
+    /// Which IP versions the discovery socket is bound for, use [`Config::bind_mode`] to account
+    /// for the delivery
+    pub(crate) bind_mode: BindMode,
+
+    /// How messages are delivered to the discovery network
+    pub(crate) delivery: Delivery,
+
+    /// Network device the sockets are bound to, if any
+    pub(crate) device: Option<String>,
+
+    /// DSCP value packets are marked with, if any
+    pub(crate) dscp: Option<u8>,
+
+    /// Extra multicast groups joined alongside the discovery network
+    pub(crate) groups: Vec<SocketAddrV4>,
+
+    /// Addresses of the interfaces messages are relayed between, none if empty
+    pub(crate) relay_between: Vec<Ipv4Addr>,
+
+    /// Socket supplied by the user to use instead of building one
+    pub(crate) socket: Option<ExternalSocket>,
+
+    /// Maximum random delay before the initial announcement
+    pub(crate) startup_jitter: Duration,
+
+    /// Signal which stops the async worker, taken by the first worker built with it
+    #[cfg(any(feature = "tokio", feature = "async-std"))]
+    pub(crate) shutdown_signal: Option<Hook<std::sync::Mutex<Option<ShutdownSignal>>>>,
+
+    /// Callback periodically given the worker's metrics
+    pub(crate) metrics: Option<MetricsReporter>,
+
+    /// Pass events about hosted services on to the user, see [`crate::HostEvent`]
+    pub(crate) host_events: bool,
+
+    /// How many entries the worker keeps in its timeline, none if zero
+    pub(crate) timeline_capacity: usize,
+
+    /// Port to send from and receive unicast replies on, separately from the multicast port
+    pub(crate) unicast_port: Option<u16>,
+
+    /// How many times to send the initial announcement, if not the default
+    pub(crate) announce_repeat: Option<u8>,
+
+    /// Skip repeats of the initial announcement if the same notify message was just sent
+    pub(crate) suppress_redundant_announcements: bool,
+
+    /// Don't announce the endpoint on startup if it doesn't host any services
+    pub(crate) silent_client: bool,
+
+    /// How long the worker waits for sent messages to leave the machine when shutting down
+    pub(crate) shutdown_grace: Duration,
+
+    /// How long replies to interested peers are held back so they can be sent together, where
+    /// zero means they're sent straight away
+    pub(crate) reply_coalesce_window: Duration,
+
+    /// Clock used instead of the system's, if any
+    pub(crate) clock: Option<Arc<dyn Clock>>,
+
+    /// How long the worker runs for before leaving the discovery network and stopping
+    pub(crate) lifetime: Option<Duration>,
+
+    /// Largest packet to deserialise, if not the default
+    pub(crate) max_message_size: Option<usize>,
+
+    /// Deepest nesting of JSON to deserialise, if not the default
+    pub(crate) max_message_depth: Option<usize>,
+
+    /// Check that messages sent to the discovery network come back before starting the worker
+    pub(crate) verify_multicast: bool,
+
+    /// What to do if another endpoint is already using our name
+    pub(crate) name_conflict: NameConflict,
+
+    /// Longest the worker waits for packets before checking its transport again, if set
+    pub(crate) read_timeout: Option<Duration>,
+}
+
+impl Config {
+    /// How many times the initial announcement should be sent, always at least once
+    pub(crate) fn announce_repeat(&self) -> u8 {
+        self.announce_repeat
+            .unwrap_or(DEFAULT_ANNOUNCE_REPEAT)
+            .max(1)
+    }
+
+    /// Which IP versions the discovery socket should be bound for, which is always IPv4 when
+    /// broadcasting
+    pub(crate) fn bind_mode(&self) -> BindMode {
+        match self.delivery {
+            Delivery::Multicast => self.bind_mode,
+            Delivery::Broadcast => BindMode::V4Only,
+        }
+    }
+
+    /// Take the signal which stops the async worker, if there is one and another worker built
+    /// from the same builder hasn't already taken it
+    #[cfg(any(feature = "tokio", feature = "async-std"))]
+    pub(crate) fn take_shutdown_signal(&self) -> Option<ShutdownSignal> {
+        let signal = self.shutdown_signal.as_ref()?;
+
+        match signal.0.lock() {
+            Ok(mut signal) => signal.take(),
+            Err(poisoned) => poisoned.into_inner().take(),
+        }
+    }
+
+    /// The clock the worker should use for the current time
+    pub(crate) fn clock(&self) -> Arc<dyn Clock> {
+        match &self.clock {
+            Some(clock) => clock.clone(),
+            None => Arc::new(SystemClock),
+        }
+    }
+
+    /// Largest packet that should be deserialised
+    pub(crate) fn max_message_size(&self) -> usize {
+        self.max_message_size.unwrap_or(DEFAULT_MAX_MESSAGE_SIZE)
+    }
+
+    /// Size of the buffer packets should be received into, one byte more than the largest packet
+    /// that should be deserialised so larger ones are spotted rather than cut short, but no larger
+    /// than any UDP datagram
+    pub(crate) fn recv_buffer_size(&self) -> usize {
+        self.max_message_size()
+            .saturating_add(1)
+            .min(usize::from(u16::MAX))
+    }
+
+    /// Largest notify message that should be sent, which peers with the same limit can read and
+    /// which fits in a single datagram
+    pub(crate) fn max_send_size(&self) -> usize {
+        self.max_message_size().min(MAX_DATAGRAM_SIZE)
+    }
+
+    /// Deepest nesting of JSON objects and arrays that should be deserialised
+    pub(crate) fn max_message_depth(&self) -> usize {
+        self.max_message_depth.unwrap_or(DEFAULT_MAX_MESSAGE_DEPTH)
+    }
+
+    /// Pick a random delay between zero and the configured startup jitter to wait before the
+    /// initial announcement
+    pub(crate) fn startup_delay(&self) -> Duration {
+        if self.startup_jitter.is_zero() {
+            return Duration::ZERO;
+        }
+
+        // The std hasher is randomly seeded, which is plenty random enough for jitter
+        let random = RandomState::new().build_hasher().finish();
+
+        self.startup_jitter.mul_f64(random as f64 / u64::MAX as f64)
+    }
+}
+
+/// A socket supplied by the user with [`crate::builder::Builder::with_socket`]
+#[derive(Debug, Clone)]
+pub(crate) struct ExternalSocket {
+    pub(crate) socket: Arc<Socket>,
+
+    /// If true the user has already joined the multicast group and set the socket non-blocking
+    pub(crate) prepared: bool,
+}
+
+/// A callback to periodically report metrics to, see [`crate::builder::Builder::metrics`]
+#[derive(Debug, Clone)]
+pub(crate) struct MetricsReporter {
+    /// How often the metrics are reported
+    pub(crate) interval: Duration,
+
+    pub(crate) callback: Hook<MetricsFn>,
+}
+
+/// Signature of the raw packet hook, see [`crate::builder::Builder::on_raw_packet`]
+pub(crate) type RawPacketFn = dyn Fn(&[u8], SocketAddr) + Send + Sync;
+
+/// Signature of the metrics callback, see [`crate::builder::Builder::metrics`]
+pub(crate) type MetricsFn = dyn Fn(Metrics) + Send + Sync;
+
+/// Signature of the local address filter, see [`crate::builder::Builder::auto_addr_filter`]
+pub(crate) type AddrFilterFn = dyn Fn(&IpAddr) -> bool + Send + Sync;
+
+/// Signature of the peer filter, see [`crate::builder::Builder::peer_filter`]
+pub(crate) type PeerFilterFn = dyn Fn(&Udis, SocketAddr) -> bool + Send + Sync;
+
+/// Signature of a search filter, see [`crate::builder::Builder::search_with_filter`]
+pub(crate) type SearchFilterFn = dyn Fn(&HashMap<String, String>) -> bool + Send + Sync;
+
+/// A future which stops the async worker when it finishes, see
+/// [`crate::builder::Builder::shutdown_on`]
+#[cfg(any(feature = "tokio", feature = "async-std"))]
+pub(crate) type ShutdownSignal = std::pin::Pin<Box<dyn std::future::Future<Output = ()> + Send>>;
+
+/// A user supplied callback, shared between the builder and the worker.
+pub(crate) struct Hook<F: ?Sized>(pub(crate) Arc<F>);
+
+impl<F: ?Sized> Clone for Hook<F> {
+    fn clone(&self) -> Self {
+        Self(self.0.clone())
+    }
+}
+
+impl<F: ?Sized> fmt::Debug for Hook<F> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Hook(..)")
+    }
+}
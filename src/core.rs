@@ -0,0 +1,1778 @@
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap, HashSet, VecDeque},
+    hash::{Hash, Hasher},
+    net::{IpAddr, SocketAddr, SocketAddrV4},
+    sync::Arc,
+    time::{Duration, Instant, SystemTime},
+};
+
+use log::{error, trace, warn};
+
+use crate::{
+    builder::DedupKey,
+    clock::Clock,
+    config::{Config, ANNOUNCE_REPEAT_INTERVAL},
+    diagnostics::{Metrics, SharedDiagnostics, TimelineEntry, TimelineEvent},
+    error::Error,
+    net::{canonical_addr, RX_LOG_TARGET, TX_LOG_TARGET},
+    Ack, HostEvent, Message, PeerId, Probe, Service, ServiceEvent, ServiceInfo, Udis,
+};
+
+/// The network logic shared by the sync and async workers.
+///
+/// The processor holds the registry of peers and decides what to do with each packet received
+/// from the discovery network, leaving the worker to do the actual sending and receiving.
+#[derive(Debug)]
+pub(crate) struct Processor {
+    udis: Udis,
+
+    config: Config,
+
+    /// Where the current time comes from
+    clock: Arc<dyn Clock>,
+
+    /// When the processor was created, which found services are timed from
+    started: Instant,
+
+    /// Registry of udis peers, the address we last heard from them at and the extra group we heard
+    /// them on if any, by their identity
+    registry: HashMap<PeerId, (Udis, SocketAddr, Option<SocketAddrV4>)>,
+
+    /// When each found service with a TTL expires, by the identity of its host and its kind
+    expiries: HashMap<(PeerId, String), (Instant, ServiceInfo)>,
+
+    /// Our serialised notify message
+    notify_message: Vec<u8>,
+
+    /// Our serialised notify message flagged as a reply, sent straight to interested peers
+    reply_message: Vec<u8>,
+
+    /// Our serialised notify message flagged as a query, asking hosts to reply to it even if they
+    /// already know about us
+    query_message: Vec<u8>,
+
+    /// Whether the endpoint was refreshed while paused, so should query hosts on resuming
+    refresh_pending: bool,
+
+    /// Hash of the last notify message sent to the discovery network, and when it was sent
+    last_announcement: Option<(u64, Instant)>,
+
+    /// Addresses of the peers we've probed which haven't replied yet
+    probes: HashSet<IpAddr>,
+
+    /// The number of services passed to the user
+    services_found: u64,
+
+    /// The number of packets which couldn't be deserialised
+    deserialise_failures: u64,
+
+    /// The number of packets in a row which couldn't be deserialised
+    consecutive_deserialise_failures: u32,
+
+    /// Whether we've warned that the discovery port seems to be shared with another application
+    warned_shared_port: bool,
+
+    /// The error from the last packet which couldn't be deserialised, until the worker takes it to
+    /// show the user
+    last_error: Option<Error>,
+
+    /// Replies held back to be sent together once the reply coalescing window is up
+    held_replies: Option<HeldReplies>,
+
+    /// Whether the user has paused the endpoint
+    paused: bool,
+
+    /// The most recent things to happen on the endpoint, if the user wants them
+    timeline: VecDeque<TimelineEntry>,
+}
+
+/// How many packets in a row can fail to deserialise before we warn that something other than
+/// udis is using the discovery port
+const SHARED_PORT_WARN_THRESHOLD: u32 = 10;
+
+/// Replies to interested peers held back so they can be sent as one message, see
+/// [`crate::builder::Builder::reply_coalesce_window`]
+#[derive(Debug)]
+struct HeldReplies {
+    /// When the replies must be sent, by the endpoint's clock
+    due: Instant,
+
+    /// The peers waiting for a reply straight to them, without duplicates
+    targets: Vec<SocketAddr>,
+
+    /// Whether a peer is waiting for us to announce ourselves to the whole discovery network
+    announce: bool,
+
+    /// Events to pass to the user once the replies have been sent
+    events: Vec<HostEvent>,
+}
+
+/// Something the worker must do as a result of processing a packet
+#[derive(Debug)]
+pub(crate) enum Output {
+    /// Send the message to the discovery network
+    Send(Vec<u8>),
+
+    /// Send the message directly to the endpoint at the address
+    Reply(Vec<u8>, SocketAddr),
+
+    /// Pass a found service to the user
+    Service(Box<ServiceInfo>),
+
+    /// Pass an event relating to our hosted services to the user
+    HostEvent(HostEvent),
+
+    /// Pass every service hosted by a peer at the address to whoever probed it
+    ProbeReply(IpAddr, Vec<ServiceInfo>),
+
+    /// Stop the worker
+    Shutdown,
+}
+
+impl Processor {
+    pub(crate) fn new(udis: Udis, config: Config) -> Result<Self, Error> {
+        trace!("starting endpoint {udis}");
+
+        // Build the notify message
+        let (notify_message, reply_message, query_message) =
+            encode_notify(&udis, config.max_send_size())?;
+
+        Ok(Self {
+            udis,
+            started: config.clock().now(),
+            clock: config.clock(),
+            config,
+            registry: HashMap::new(),
+            expiries: HashMap::new(),
+            notify_message,
+            reply_message,
+            query_message,
+            refresh_pending: false,
+            last_announcement: None,
+            probes: HashSet::new(),
+            services_found: 0,
+            deserialise_failures: 0,
+            consecutive_deserialise_failures: 0,
+            warned_shared_port: false,
+            last_error: None,
+            held_replies: None,
+            paused: false,
+            timeline: VecDeque::new(),
+        })
+    }
+
+    /// The name we announce ourselves with
+    pub(crate) fn name(&self) -> &str {
+        &self.udis.name
+    }
+
+    /// How large a buffer packets should be received into
+    pub(crate) fn recv_buffer_size(&self) -> usize {
+        self.config.recv_buffer_size()
+    }
+
+    /// Our serialised notify message
+    pub(crate) fn notify_message(&self) -> &[u8] {
+        &self.notify_message
+    }
+
+    /// Our serialised notify message flagged as a query, which hosts reply to even if they
+    /// already know about us
+    pub(crate) fn query_message(&self) -> &[u8] {
+        &self.query_message
+    }
+
+    /// Stop processing packets until resumed, while paused the worker shouldn't announce itself
+    pub(crate) fn pause(&mut self) {
+        trace!("pausing endpoint `{}`", self.udis.name);
+        self.paused = true;
+    }
+
+    /// Start processing packets again, returning the message the worker should announce itself
+    /// with afterwards
+    pub(crate) fn resume(&mut self) -> &[u8] {
+        trace!("resuming endpoint `{}`", self.udis.name);
+        self.paused = false;
+
+        // If we were refreshed while paused the hosts still need asking to reply
+        if std::mem::take(&mut self.refresh_pending) {
+            &self.query_message
+        } else {
+            &self.notify_message
+        }
+    }
+
+    /// Whether the endpoint is paused
+    pub(crate) fn is_paused(&self) -> bool {
+        self.paused
+    }
+
+    /// Whether the endpoint is a silent client, which doesn't announce itself on startup
+    pub(crate) fn is_silent(&self) -> bool {
+        self.config.silent_client
+            && !self
+                .udis
+                .services
+                .iter()
+                .any(|s| matches!(s, Service::Host { .. }))
+    }
+
+    /// Record that our notify message has just been sent to the discovery network
+    pub(crate) fn announced(&mut self) {
+        self.last_announcement = Some((self.notify_hash(), self.clock.now()));
+        self.record(TimelineEvent::Announced);
+    }
+
+    /// Add the event to the timeline, dropping the oldest entry if it's full
+    fn record(&mut self, event: TimelineEvent) {
+        let capacity = self.config.timeline_capacity;
+        if capacity == 0 {
+            return;
+        }
+
+        if self.timeline.len() == capacity {
+            self.timeline.pop_front();
+        }
+        self.timeline.push_back(TimelineEntry {
+            at: SystemTime::now(),
+            event,
+        });
+    }
+
+    /// The entries in the timeline, oldest first
+    pub(crate) fn timeline(&self) -> Vec<TimelineEntry> {
+        self.timeline.iter().cloned().collect()
+    }
+
+    /// Whether repeating our announcement now would be redundant, because redundant
+    /// announcements are being suppressed and the same notify message was sent recently.
+    ///
+    /// Only repeats of the initial announcement should be skipped, every other announcement is
+    /// always sent.
+    pub(crate) fn announcement_is_redundant(&self) -> bool {
+        self.config.suppress_redundant_announcements
+            && self.last_announcement.is_some_and(|(hash, at)| {
+                hash == self.notify_hash()
+                    && self.clock.now().saturating_duration_since(at) < ANNOUNCE_REPEAT_INTERVAL
+            })
+    }
+
+    fn notify_hash(&self) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        self.notify_message.hash(&mut hasher);
+        hasher.finish()
+    }
+
+    /// Change the name we announce ourselves with, the worker should announce the new notify
+    /// message afterwards
+    pub(crate) fn rename(&mut self, name: String) -> Result<(), Error> {
+        trace!("renaming endpoint `{}` to `{}`", self.udis.name, name);
+        self.udis.name = name;
+        (self.notify_message, self.reply_message, self.query_message) =
+            encode_notify(&self.udis, self.config.max_send_size())?;
+
+        Ok(())
+    }
+
+    /// Forget every peer and found service so they're found again, the worker should send our
+    /// query message afterwards so hosts reply to us, see [`Processor::query_message`].
+    ///
+    /// Peers don't forget us, as saying goodbye would make every endpoint on the network find
+    /// the services we host again. If we're paused the query is sent on resuming instead.
+    pub(crate) fn refresh(&mut self) {
+        trace!(
+            "refreshing endpoint `{}`, forgetting {} peers",
+            self.udis.name,
+            self.registry.len()
+        );
+        #[cfg(feature = "metrics")]
+        metrics::gauge!("udis_peers_known").decrement(self.registry.len() as f64);
+        self.registry.clear();
+        self.expiries.clear();
+        self.refresh_pending = self.paused;
+    }
+
+    /// Start probing the peer at `addr`, returning the probe message the worker should send both
+    /// to the discovery network and straight to `addr` on the discovery port.
+    ///
+    /// Every endpoint at `addr` which hosts services replies to the probe with all of them, and
+    /// the first reply is passed on in an [`Output::ProbeReply`]. Sending the probe straight to
+    /// `addr` reaches the peer where multicast doesn't, but every endpoint on the peer's machine
+    /// shares the discovery port so only one of them would receive it, hence also sending it to
+    /// the whole network.
+    pub(crate) fn probe(&mut self, addr: IpAddr) -> Result<Vec<u8>, Error> {
+        trace!(target: TX_LOG_TARGET, "probing the peer at {addr}");
+        self.probes.insert(addr);
+
+        Message::Probe(Probe {
+            name: self.udis.name.clone(),
+            addr: self.udis.addr,
+            target: addr,
+        })
+        .encode()
+        .map_err(Error::FailedToSerialiseNotifyMsg)
+    }
+
+    /// Stop waiting for a reply to our probe of the peer at `addr`, once nobody wants it any more
+    pub(crate) fn forget_probe(&mut self, addr: IpAddr) {
+        self.probes.remove(&addr);
+    }
+
+    /// How often metrics should be reported, if the user wants them
+    pub(crate) fn metrics_interval(&self) -> Option<Duration> {
+        self.config.metrics.as_ref().map(|m| m.interval)
+    }
+
+    /// Take the last error the processor carried on from, if there's been one since this was last
+    /// called
+    pub(crate) fn take_error(&mut self) -> Option<Error> {
+        self.last_error.take()
+    }
+
+    /// Give the current metrics to the user's callback, if there is one
+    pub(crate) fn report_metrics(&self, diag: &SharedDiagnostics) {
+        let Some(reporter) = &self.config.metrics else {
+            return;
+        };
+
+        let diag = diag.get();
+
+        (reporter.callback.0)(Metrics {
+            packets_sent: diag.packets_sent,
+            packets_received: diag.packets_received,
+            peers_known: self.registry.len(),
+            services_found: self.services_found,
+            deserialise_failures: self.deserialise_failures,
+        });
+    }
+
+    /// Build the message telling the discovery network that we're leaving
+    pub(crate) fn goodbye_message(&self) -> Result<Vec<u8>, Error> {
+        let goodbye = Message::Goodbye {
+            goodbye: self.udis.id(),
+        };
+
+        goodbye.encode().map_err(Error::FailedToSerialiseGoodbyeMsg)
+    }
+
+    /// Process a packet received from `src`, returning what the worker should do in response in
+    /// the order it should be done.
+    ///
+    /// `group` is the extra multicast group the packet was received on, or `None` if it came from
+    /// the discovery network or directly from the peer.
+    pub(crate) fn process_packet(
+        &mut self,
+        bytes: &[u8],
+        src: SocketAddr,
+        group: Option<SocketAddrV4>,
+    ) -> Result<Vec<Output>, Error> {
+        let mut outputs = Vec::new();
+
+        // Packets from IPv4 peers on a dual-stack socket will have an IPv4-mapped source
+        let src = canonical_addr(src);
+
+        // Pass the raw packet to the user's hook, if there is one
+        if let Some(hook) = &self.config.on_raw_packet {
+            (hook.0)(bytes, src);
+        }
+        self.record(TimelineEvent::PacketReceived {
+            from: src,
+            len: bytes.len(),
+        });
+
+        // While paused packets are still received so they don't build up, but are dropped
+        if self.paused {
+            trace!(target: RX_LOG_TARGET, "paused, dropping packet from {src}");
+            return Ok(outputs);
+        }
+
+        // Decode into a udis message, a bad packet from one peer shouldn't stop us hearing from
+        // the others so just skip it
+        let msg = match Message::decode(
+            bytes,
+            self.config.max_message_size(),
+            self.config.max_message_depth(),
+        ) {
+            Ok(msg) => msg,
+            Err(source) => {
+                let reason = source.to_string();
+                let e = Error::FailedToDeserialiseNotifyMsg {
+                    src,
+                    preview: packet_preview(bytes),
+                    source,
+                };
+                error!(target: RX_LOG_TARGET, "{e} (will continue): {reason}");
+                self.deserialise_failures += 1;
+                #[cfg(feature = "metrics")]
+                metrics::counter!("udis_deserialise_failures_total").increment(1);
+                self.consecutive_deserialise_failures =
+                    self.consecutive_deserialise_failures.saturating_add(1);
+
+                // Lots of bad packets in a row probably means another application is sending to
+                // the same group and port, which is worth knowing about but only once
+                if self.consecutive_deserialise_failures >= SHARED_PORT_WARN_THRESHOLD
+                    && !self.warned_shared_port
+                {
+                    warn!(
+                        target: RX_LOG_TARGET,
+                        "{} packets in a row couldn't be deserialised, the udis port appears \
+                        shared with non-udis traffic",
+                        self.consecutive_deserialise_failures
+                    );
+                    self.warned_shared_port = true;
+                }
+
+                self.last_error = Some(e);
+                return Ok(outputs);
+            }
+        };
+        self.consecutive_deserialise_failures = 0;
+
+        // Relayed messages come from the endpoint relaying them rather than the peer, so the peer
+        // can't be replied to directly
+        let (msg, relayed) = match msg {
+            Message::Relayed(msg) => (*msg, true),
+            msg => (msg, false),
+        };
+
+        let (mut peer, reply, query) = match msg {
+            Message::Notify(peer) => (peer, false, false),
+            Message::Reply(peer) => (peer, true, false),
+            Message::Query(peer) => (peer, false, true),
+            Message::Ack { ack } => {
+                // Only acknowledgements of services we host are interesting, and only if the user
+                // wants to hear about them
+                if self.config.host_events
+                    && ack.host == self.udis.name
+                    && self.udis.hosts(&ack.kind)
+                {
+                    trace!(
+                        target: RX_LOG_TARGET,
+                        "peer `{}` acknowledged our service `{}`",
+                        ack.name,
+                        ack.kind
+                    );
+
+                    outputs.push(Output::HostEvent(HostEvent::Acknowledged {
+                        by: ack.name,
+                        kind: ack.kind,
+                    }));
+                }
+                return Ok(outputs);
+            }
+            Message::Goodbye { goodbye } => {
+                // Forget the peer so that we find its services again if it comes back
+                self.expiries.retain(|(id, _), _| *id != goodbye);
+                if self.registry.remove(&goodbye).is_some() {
+                    trace!(target: RX_LOG_TARGET, "peer `{}` said goodbye", goodbye.name);
+                    #[cfg(feature = "metrics")]
+                    metrics::gauge!("udis_peers_known").decrement(1);
+                    self.record(TimelineEvent::PeerRemoved {
+                        name: goodbye.name,
+                        addr: goodbye.addr,
+                    });
+                }
+                return Ok(outputs);
+            }
+            Message::Probe(probe) => {
+                // Only endpoints at the probed address answer, and only if they host anything
+                let hosting = self
+                    .udis
+                    .services
+                    .iter()
+                    .any(|s| matches!(s, Service::Host { .. }));
+                let ours = probe.name == self.udis.name && probe.addr == self.udis.addr;
+                if hosting && !ours && self.udis.addrs().contains(&probe.target) {
+                    trace!(target: RX_LOG_TARGET, "peer `{}` probed us", probe.name);
+                    let reply_to = (self.config.unicast_port.is_some() && !relayed).then_some(src);
+                    outputs.push(self.reply(reply_to));
+                }
+                return Ok(outputs);
+            }
+            // Messages are only relayed once, so are never decoded as relayed twice over
+            Message::Relayed(_) => return Ok(outputs),
+        };
+
+        // If its our own notify message ignore it, including the one we probe peers with
+        if peer.id() == self.udis.id() {
+            return Ok(outputs);
+        }
+
+        // If the peer isn't where it says it is ignore it
+        if self.config.require_matching_source && peer.addr != src.ip() {
+            trace!(
+                target: RX_LOG_TARGET,
+                "ignoring peer `{}` advertising {} but received from {}",
+                peer.name,
+                peer.addr,
+                src
+            );
+            return Ok(outputs);
+        }
+
+        // If the peer is outside the subnet we're restricted to ignore it
+        if let Some(subnet) = &self.config.subnet {
+            if !subnet.contains(peer.addr) {
+                trace!(
+                    target: RX_LOG_TARGET,
+                    "ignoring peer `{}` advertising {} outside of our subnet",
+                    peer.name,
+                    peer.addr
+                );
+                return Ok(outputs);
+            }
+
+            // Its other addresses outside the subnet can't be reached either, so drop them
+            peer.extra_addrs.retain(|addr| subnet.contains(*addr));
+        }
+
+        // If the user's filter rejects the peer ignore it
+        if let Some(filter) = &self.config.peer_filter {
+            if !(filter.0)(&peer, src) {
+                trace!(
+                    target: RX_LOG_TARGET,
+                    "ignoring peer `{}` rejected by our peer filter",
+                    peer.name
+                );
+                return Ok(outputs);
+            }
+        }
+
+        // A query isn't an announcement, so doesn't change what we know about the peer, but it
+        // wants a reply from every host of a service it searches for as if it were new
+        if query {
+            let wanted: Vec<_> = self
+                .udis
+                .get_services_newly_wanted_by(&peer, None)
+                .map(|s| s.kind().to_owned())
+                .collect();
+            if !wanted.is_empty() {
+                trace!(target: RX_LOG_TARGET, "peer `{}` queried for our services", peer.name);
+                self.queue_reply(&peer, wanted, src, relayed, &mut outputs);
+            }
+            return Ok(outputs);
+        }
+
+        // Add the peer to the registry, getting its previous notify message if we've seen it
+        let mut prev = self
+            .registry
+            .insert(peer.id(), (peer.clone(), src, group))
+            .map(|(prev, ..)| prev);
+        if prev.is_none() {
+            trace!(target: RX_LOG_TARGET, "new peer {peer}");
+
+            #[cfg(feature = "metrics")]
+            {
+                metrics::counter!("udis_peers_seen_total").increment(1);
+                metrics::gauge!("udis_peers_known").increment(1);
+            }
+            self.record(TimelineEvent::PeerAdded {
+                name: peer.name.clone(),
+                addr: peer.addr,
+            });
+        }
+
+        // Answer any probe of the peer with every service it hosts, even if nothing has changed.
+        // Only hosts answer probes, so an endpoint at the same address which just searches isn't
+        // the reply, for example when it repeats its announcement before the host has answered.
+        let hosts = peer
+            .services
+            .iter()
+            .any(|service| matches!(service, Service::Host { .. }));
+        let probed: Vec<_> = [peer.addr, src.ip()]
+            .into_iter()
+            .filter(|addr| hosts && self.probes.remove(addr))
+            .collect();
+        if !probed.is_empty() {
+            trace!(target: RX_LOG_TARGET, "peer `{}` replied to our probe", peer.name);
+            let hosted: Vec<_> = peer
+                .services
+                .iter()
+                .filter_map(|service| match service {
+                    Service::Host { .. } => self.service_info(&peer, service, src, group),
+                    Service::Search { .. } => None,
+                })
+                .collect();
+            outputs.extend(
+                probed
+                    .into_iter()
+                    .map(|addr| Output::ProbeReply(addr, hosted.clone())),
+            );
+        }
+
+        // If peers are deduplicated by their whole notify message a changed peer is a new one
+        if self.config.dedup_by == DedupKey::FullStruct && prev.as_ref() != Some(&peer) {
+            prev = None;
+        }
+
+        self.renew_expiries(&peer);
+
+        // If nothing has changed since we last heard from the peer ignore it
+        if prev.as_ref() == Some(&peer) {
+            return Ok(outputs);
+        }
+
+        // Diff the services the peer searches for against its previous notify message, to find
+        // which of the services we're offering it's newly interested in
+        let new_interest: Vec<_> = self
+            .udis
+            .get_services_newly_wanted_by(&peer, prev.as_ref())
+            .map(|s| s.kind().to_owned())
+            .collect();
+
+        // If the peer is newly interested in one of the services we're offering notify it
+        if !new_interest.is_empty() {
+            for kind in &new_interest {
+                trace!(
+                    target: RX_LOG_TARGET,
+                    "notified of peer `{}` that wants our service `{}`",
+                    peer.name,
+                    kind
+                );
+
+                if self.config.host_events {
+                    outputs.push(Output::HostEvent(HostEvent::NewInterest {
+                        peer: peer.name.clone(),
+                        kind: kind.clone(),
+                    }));
+                }
+            }
+
+            self.queue_reply(&peer, new_interest, src, relayed, &mut outputs);
+        }
+
+        // Find the services the peer has started hosting that we're interested in, sorted by
+        // kind if asked so the order doesn't depend on how the peer listed them
+        let mut found: Vec<_> = peer
+            .get_wanted_services_added_since(prev.as_ref(), &self.udis)
+            .cloned()
+            .collect();
+        if self.config.sort_results {
+            found.sort_by(|a, b| a.kind().cmp(b.kind()));
+        }
+
+        for service in &found {
+            let Some(mut serv_info) = self.service_info(&peer, service, src, group) else {
+                continue;
+            };
+            serv_info.seq = self.services_found;
+            serv_info.discovered_after = self.clock.now().saturating_duration_since(self.started);
+            serv_info.reply = reply;
+            let kind = serv_info.kind.clone();
+
+            trace!(
+                target: RX_LOG_TARGET,
+                "found peer `{}` that hosts a service we want `{}` at {} on {}",
+                peer.name,
+                kind,
+                peer.addr,
+                serv_info.endpoint
+            );
+
+            // Remember when the service expires if the host gave it a TTL
+            if let Some(ttl) = serv_info.ttl {
+                self.expiries.insert(
+                    (peer.id(), kind.clone()),
+                    (self.clock.now() + ttl, serv_info.clone()),
+                );
+            }
+
+            self.record(TimelineEvent::ServiceFound {
+                name: peer.name.clone(),
+                kind: kind.clone(),
+            });
+            outputs.push(Output::Service(Box::new(serv_info)));
+            self.services_found += 1;
+            #[cfg(feature = "metrics")]
+            metrics::counter!("udis_services_found_total").increment(1);
+
+            // Let the host know we've received its service. This goes to the whole discovery
+            // network rather than directly to the host, as every endpoint on the host's machine
+            // shares the discovery port so there's no guarantee the right one would receive it.
+            let ack = Message::Ack {
+                ack: Ack {
+                    name: self.udis.name.clone(),
+                    host: peer.name.clone(),
+                    kind: kind.clone(),
+                },
+            };
+            let ack_message = ack.encode().map_err(Error::FailedToSerialiseAckMsg)?;
+
+            trace!(
+                target: TX_LOG_TARGET,
+                "acknowledging `{}` hosted by `{}`",
+                kind,
+                peer.name
+            );
+            outputs.push(Output::Send(ack_message));
+
+            // If we only wanted one service leave the network now we've found it
+            if self.config.find_once {
+                trace!("found a service in find once mode, leaving the discovery network");
+
+                trace!(target: TX_LOG_TARGET, "saying goodbye from `{}`", self.udis.name);
+                outputs.push(Output::Send(self.goodbye_message()?));
+                outputs.push(Output::Shutdown);
+                break;
+            }
+        }
+
+        Ok(outputs)
+    }
+
+    /// Reply to a peer interested in the services of `kinds`, or hold the reply back to be sent
+    /// with others if replies are coalesced
+    fn queue_reply(
+        &mut self,
+        peer: &Udis,
+        kinds: Vec<String>,
+        src: SocketAddr,
+        relayed: bool,
+        outputs: &mut Vec<Output>,
+    ) {
+        // Reply directly to the peer if we have a unicast port for it to reply back to
+        let reply_to = (self.config.unicast_port.is_some() && !relayed).then_some(src);
+        let host_events = self.config.host_events;
+        let replied = kinds
+            .into_iter()
+            .filter(|_| host_events)
+            .map(|to_kind| HostEvent::Replied {
+                peer: peer.name.clone(),
+                to_kind,
+            });
+
+        // Let the user know once the reply has gone out
+        let window = self.config.reply_coalesce_window;
+        if window.is_zero() {
+            outputs.push(self.reply(reply_to));
+            outputs.extend(replied.map(Output::HostEvent));
+        } else {
+            trace!(
+                target: TX_LOG_TARGET,
+                "holding back reply to `{}` for up to {window:?}",
+                peer.name
+            );
+            let held = self.held_replies.get_or_insert_with(|| HeldReplies {
+                due: self.clock.now() + window,
+                targets: Vec::new(),
+                announce: false,
+                events: Vec::new(),
+            });
+            match reply_to {
+                Some(to) if !held.targets.contains(&to) => held.targets.push(to),
+                Some(_) => (),
+                None => held.announce = true,
+            }
+            held.events.extend(replied);
+        }
+    }
+
+    /// Reply to an interested peer straight to it at `to`, or by announcing ourselves to the whole
+    /// discovery network if there's no address to reply to
+    fn reply(&mut self, to: Option<SocketAddr>) -> Output {
+        match to {
+            Some(to) => {
+                trace!(target: TX_LOG_TARGET, "replying to {to} with `{}`", self.udis.name);
+                self.record(TimelineEvent::Replied { to });
+                Output::Reply(self.reply_message.clone(), to)
+            }
+            None => {
+                trace!(target: TX_LOG_TARGET, "re-announcing `{}`", self.udis.name);
+                self.announced();
+                Output::Send(self.notify_message.clone())
+            }
+        }
+    }
+
+    /// When the held back replies must be sent, if there are any
+    pub(crate) fn next_reply_flush(&self) -> Option<Instant> {
+        self.held_replies.as_ref().map(|held| held.due)
+    }
+
+    /// Whether it's time to send the held back replies, see [`Processor::next_reply_flush`]
+    pub(crate) fn replies_due(&self) -> bool {
+        self.next_reply_flush()
+            .is_some_and(|at| self.clock.now() >= at)
+    }
+
+    /// When the worker should next check whether to send the held back replies, by the system's
+    /// clock, in the same way as [`Processor::next_sweep`]
+    #[cfg(any(feature = "tokio", feature = "async-std"))]
+    pub(crate) fn next_reply_flush_check(&self) -> Option<Instant> {
+        self.next_reply_flush().map(|at| self.system_time(at))
+    }
+
+    /// Send the held back replies now, straight to the peer if only one is waiting, or as a single
+    /// announcement to the whole discovery network if several are, followed by the events for
+    /// them
+    pub(crate) fn flush_replies(&mut self) -> Vec<Output> {
+        let Some(held) = self.held_replies.take() else {
+            return Vec::new();
+        };
+
+        let to = match held.targets[..] {
+            [to] if !held.announce => Some(to),
+            _ => {
+                trace!(target: TX_LOG_TARGET, "coalescing held back replies into one announcement");
+                None
+            }
+        };
+
+        std::iter::once(self.reply(to))
+            .chain(held.events.into_iter().map(Output::HostEvent))
+            .collect()
+    }
+
+    /// When the next found service expires, if any have a TTL
+    pub(crate) fn next_expiry(&self) -> Option<Instant> {
+        self.expiries.values().map(|(at, _)| *at).min()
+    }
+
+    /// When the worker should next check for expired services, by the system's clock.
+    ///
+    /// A custom clock may jump forward or never move at all, so its expiries can't be slept until.
+    /// Instead the worker checks every [`crate::config::CLOCK_SWEEP_PERIOD`] while any found
+    /// service has a TTL.
+    #[cfg(any(feature = "tokio", feature = "async-std"))]
+    pub(crate) fn next_sweep(&self) -> Option<Instant> {
+        self.next_expiry().map(|at| self.system_time(at))
+    }
+
+    /// When we must announce ourselves again so that searchers don't forget the services we host
+    /// with a TTL, which is halfway through the shortest TTL since our last announcement
+    pub(crate) fn next_reannounce(&self) -> Option<Instant> {
+        if self.paused {
+            return None;
+        }
+
+        let ttl = self
+            .udis
+            .services
+            .iter()
+            .filter_map(|s| match s {
+                Service::Host { ttl, .. } if *ttl > 0 => Some(*ttl),
+                _ => None,
+            })
+            .min()?;
+        let (_, at) = self.last_announcement?;
+
+        Some(at + Duration::from_secs(ttl.into()) / 2)
+    }
+
+    /// Whether it's time to announce ourselves again, see [`Processor::next_reannounce`]
+    pub(crate) fn reannounce_due(&self) -> bool {
+        self.next_reannounce()
+            .is_some_and(|at| self.clock.now() >= at)
+    }
+
+    /// When the worker should next check whether to announce ourselves again, by the system's
+    /// clock, in the same way as [`Processor::next_sweep`]
+    #[cfg(any(feature = "tokio", feature = "async-std"))]
+    pub(crate) fn next_reannounce_check(&self) -> Option<Instant> {
+        self.next_reannounce().map(|at| self.system_time(at))
+    }
+
+    /// When a timer due at `at` by the endpoint's clock should be checked, by the system's clock
+    #[cfg(any(feature = "tokio", feature = "async-std"))]
+    fn system_time(&self, at: Instant) -> Instant {
+        match self.config.clock {
+            Some(_) => Instant::now() + crate::config::CLOCK_SWEEP_PERIOD,
+            None => at,
+        }
+    }
+
+    /// Forget every found service whose TTL has run out, returning an event for each
+    pub(crate) fn expire_services(&mut self) -> Vec<ServiceEvent> {
+        let now = self.clock.now();
+        let expired: Vec<_> = self
+            .expiries
+            .iter()
+            .filter(|(_, (at, _))| *at <= now)
+            .map(|(key, _)| key.clone())
+            .collect();
+
+        let mut events = Vec::new();
+        for key in expired {
+            let Some((_, serv_info)) = self.expiries.remove(&key) else {
+                continue;
+            };
+            let (id, kind) = key;
+
+            // Forget that the peer hosts the service, so it's found again if the peer announces
+            // it again
+            if let Some((peer, ..)) = self.registry.get_mut(&id) {
+                peer.services
+                    .retain(|s| !matches!(s, Service::Host { kind: k, .. } if *k == kind));
+            }
+
+            trace!(
+                target: RX_LOG_TARGET,
+                "service `{}` hosted by `{}` expired",
+                kind,
+                serv_info.name
+            );
+            events.push(ServiceEvent::Expired(serv_info));
+        }
+
+        events
+    }
+
+    /// Renew the expiry of the found services hosted by `peer` now we've heard from it, forgetting
+    /// those it no longer hosts with a TTL
+    fn renew_expiries(&mut self, peer: &Udis) {
+        let id = peer.id();
+        let now = self.clock.now();
+
+        self.expiries.retain(|(peer_id, kind), (at, _)| {
+            if *peer_id != id {
+                return true;
+            }
+
+            let ttl = peer.services.iter().find_map(|s| match s {
+                Service::Host { kind: k, ttl, .. } if k == kind && *ttl > 0 => Some(*ttl),
+                _ => None,
+            });
+
+            match ttl {
+                Some(ttl) => {
+                    *at = now + Duration::from_secs(ttl.into());
+                    true
+                }
+                None => false,
+            }
+        });
+    }
+
+    /// Every service we're searching for hosted by the peers in the registry, grouped by the name
+    /// of the peer hosting them
+    pub(crate) fn peers_with_services(&self) -> HashMap<String, Vec<ServiceInfo>> {
+        let mut peers: HashMap<String, Vec<ServiceInfo>> = HashMap::new();
+
+        for (peer, src, group) in self.registry.values() {
+            let services: Vec<_> = peer
+                .get_wanted_services(&self.udis)
+                .filter_map(|service| self.service_info(peer, service, *src, *group))
+                .collect();
+
+            if !services.is_empty() {
+                peers.entry(peer.name.clone()).or_default().extend(services);
+            }
+        }
+
+        peers
+    }
+
+    /// Build the info passed to the user for a service hosted by `peer`, if it's a hosted service
+    /// whose metadata passes our search filter
+    fn service_info(
+        &self,
+        peer: &Udis,
+        service: &Service,
+        src: SocketAddr,
+        group: Option<SocketAddrV4>,
+    ) -> Option<ServiceInfo> {
+        let Service::Host {
+            kind,
+            endpoint,
+            metadata,
+            priority,
+            weight,
+            ttl,
+            description,
+            proxy,
+        } = service
+        else {
+            trace!(
+                target: RX_LOG_TARGET,
+                "Non-host service returned by get_wanted_services, skipping"
+            );
+            return None;
+        };
+
+        // If the service's metadata doesn't pass the search filter we don't want it
+        if let Some(filter) = self.config.search_filters.get(kind) {
+            let metadata = metadata.clone().into_iter().collect();
+
+            if !(filter.0)(&metadata) {
+                trace!(
+                    target: RX_LOG_TARGET,
+                    "peer `{}` hosts `{}` but its metadata doesn't pass our filter",
+                    peer.name,
+                    kind
+                );
+                return None;
+            }
+        }
+
+        Some(ServiceInfo {
+            name: peer.name.clone(),
+            kind: kind.clone(),
+            addr: proxy.unwrap_or(peer.addr),
+            addrs: proxy.map_or_else(|| peer.addrs(), |proxy| vec![proxy]),
+            port: endpoint.port().unwrap_or(0),
+            endpoint: endpoint.clone(),
+            observed_from: src,
+            peer_services: if self.config.include_peer_services {
+                peer.hosted_services()
+            } else {
+                Vec::new()
+            },
+            metadata: metadata.clone(),
+            priority: *priority,
+            weight: *weight,
+            ttl: (*ttl > 0).then(|| Duration::from_secs((*ttl).into())),
+            group,
+            description: description.clone(),
+            proxied_by: proxy.map(|_| peer.addr),
+            seq: 0,
+            discovered_after: Duration::ZERO,
+            reply: false,
+            extra: peer.extra.clone(),
+        })
+    }
+}
+
+// Peers known by an endpoint which has shut down shouldn't count towards the known peers
+#[cfg(feature = "metrics")]
+impl Drop for Processor {
+    fn drop(&mut self) {
+        metrics::gauge!("udis_peers_known").decrement(self.registry.len() as f64);
+    }
+}
+
+/// How many bytes of a packet which couldn't be deserialised are shown in the logs
+const PACKET_PREVIEW_LEN: usize = 64;
+
+/// A short preview of a packet for logging, as quoted text if it's UTF-8 and hex otherwise, so
+/// whatever sent a bad packet can be tracked down
+fn packet_preview(bytes: &[u8]) -> String {
+    let shown = &bytes[..bytes.len().min(PACKET_PREVIEW_LEN)];
+
+    // The preview may have cut a character in half, which is fine
+    let text = match std::str::from_utf8(shown) {
+        Ok(text) => Some(text),
+        Err(e) if e.error_len().is_none() => std::str::from_utf8(&shown[..e.valid_up_to()]).ok(),
+        Err(_) => None,
+    };
+    let mut preview = match text {
+        Some(text) => format!("{text:?}"),
+        None => shown.iter().map(|b| format!("{b:02x}")).collect(),
+    };
+
+    if shown.len() < bytes.len() {
+        preview.push_str(&format!("... ({} bytes)", bytes.len()));
+    }
+    preview
+}
+
+/// Serialise the notify message for `udis` and the same message flagged as a reply and as a query,
+/// leaving services out until all of them are no more than `max_size` bytes.
+///
+/// A notify message that doesn't fit in a datagram can't be sent at all, so the least preferred
+/// hosted services are dropped first, then searches, and each one dropped is logged so the user
+/// can find out why peers can't see it.
+#[allow(clippy::type_complexity)]
+fn encode_notify(udis: &Udis, max_size: usize) -> Result<(Vec<u8>, Vec<u8>, Vec<u8>), Error> {
+    let mut udis = udis.clone();
+
+    loop {
+        // The reply and query are always larger than the notify message as they have an extra
+        // flag, and are the same size as each other
+        let message = Message::Reply(udis.clone())
+            .encode()
+            .map_err(Error::FailedToSerialiseNotifyMsg)?;
+        if message.len() <= max_size {
+            let query = Message::Query(udis.clone())
+                .encode()
+                .map_err(Error::FailedToSerialiseNotifyMsg)?;
+            let notify = Message::Notify(udis)
+                .encode()
+                .map_err(Error::FailedToSerialiseNotifyMsg)?;
+            return Ok((notify, message, query));
+        }
+
+        // Drop the service with the highest priority value, then the lowest weight
+        let worst = udis
+            .services
+            .iter()
+            .enumerate()
+            .max_by_key(|(_, service)| match service {
+                Service::Host {
+                    priority, weight, ..
+                } => (1, *priority, std::cmp::Reverse(*weight)),
+                Service::Search { .. } => (0, 0, std::cmp::Reverse(0)),
+            })
+            .map(|(i, _)| i);
+
+        match worst {
+            Some(i) => {
+                let service = udis.services.remove(i);
+                error!(
+                    target: TX_LOG_TARGET,
+                    "notify message from `{}` is {} bytes, more than the limit of {}, leaving \
+                    out the service {}",
+                    udis.name,
+                    message.len(),
+                    max_size,
+                    service
+                );
+            }
+            None => {
+                return Err(Error::NotifyMsgTooLarge {
+                    size: message.len(),
+                    max: max_size,
+                })
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::HashSet,
+        net::{IpAddr, Ipv4Addr, SocketAddr},
+        sync::Arc,
+        time::Duration,
+    };
+
+    use crate::{
+        builder::DedupKey,
+        clock::MockClock,
+        config::{Config, Hook},
+        core::{packet_preview, Output, Processor, SHARED_PORT_WARN_THRESHOLD},
+        tcp_host, HostEndpoint, HostEvent, Message, Service, ServiceEvent, Udis, WILDCARD_KIND,
+    };
+
+    #[test]
+    fn test_process_host_notify() {
+        let client = Udis::build(
+            "client".into(),
+            Ipv4Addr::new(10, 0, 0, 2).into(),
+            vec![Service::Search {
+                kind: "hello".into(),
+            }],
+        );
+        let mut processor = Processor::new(client.clone(), Config::default()).unwrap();
+
+        let server = Udis::build(
+            "server".into(),
+            Ipv4Addr::new(10, 0, 0, 1).into(),
+            vec![tcp_host("hello", 4112)],
+        );
+        let notify = Message::Notify(server.clone()).encode().unwrap();
+        let src = SocketAddr::from((Ipv4Addr::new(10, 0, 0, 1), 8787));
+
+        // Finding the service should pass it to the user and acknowledge it
+        let outputs = processor.process_packet(&notify, src, None).unwrap();
+        assert!(matches!(
+            &outputs[..],
+            [Output::Service(serv_info), Output::Send(_)]
+                if serv_info.port == 4112 && !serv_info.reply
+        ));
+
+        // Hearing the same notify message again shouldn't find the service twice
+        let outputs = processor.process_packet(&notify, src, None).unwrap();
+        assert!(outputs.is_empty());
+
+        // Services found in a reply to our search should be marked as solicited
+        let mut processor = Processor::new(client, Config::default()).unwrap();
+        let reply = Message::Reply(server).encode().unwrap();
+        let outputs = processor.process_packet(&reply, src, None).unwrap();
+        assert!(matches!(
+            &outputs[..],
+            [Output::Service(serv_info), Output::Send(_)] if serv_info.reply
+        ));
+    }
+
+    #[test]
+    fn test_new_interest() {
+        let server = Udis::build(
+            "server".into(),
+            Ipv4Addr::new(10, 0, 0, 1).into(),
+            vec![tcp_host("hello", 4112)],
+        );
+        let config = Config {
+            host_events: true,
+            ..Default::default()
+        };
+        let mut processor = Processor::new(server.clone(), config).unwrap();
+        let src = SocketAddr::from((Ipv4Addr::new(10, 0, 0, 2), 8787));
+
+        // A peer which doesn't want anything we host shouldn't be replied to
+        let mut client = Udis::build("client".into(), Ipv4Addr::new(10, 0, 0, 2).into(), vec![]);
+        let notify = Message::Notify(client.clone()).encode().unwrap();
+        let outputs = processor.process_packet(&notify, src, None).unwrap();
+        assert!(outputs.is_empty());
+
+        // Once the same peer starts searching for our service it should be
+        client.services.push(Service::Search {
+            kind: "hello".into(),
+        });
+        let notify = Message::Notify(client.clone()).encode().unwrap();
+        let outputs = processor.process_packet(&notify, src, None).unwrap();
+        assert!(matches!(
+            &outputs[..],
+            [
+                Output::HostEvent(HostEvent::NewInterest { peer, kind }),
+                Output::Send(_),
+                Output::HostEvent(HostEvent::Replied { peer: replied, to_kind })
+            ] if peer == "client" && kind == "hello" && replied == "client" && to_kind == "hello"
+        ));
+
+        // Without host events the peer should still be replied to, but the user not told
+        let mut processor = Processor::new(server.clone(), Config::default()).unwrap();
+        let outputs = processor.process_packet(&notify, src, None).unwrap();
+        assert!(matches!(&outputs[..], [Output::Send(_)]));
+
+        // Peers heard through a relay can't be replied to directly, even with a unicast port
+        let config = Config {
+            unicast_port: Some(9000),
+            host_events: true,
+            ..Default::default()
+        };
+        let mut processor = Processor::new(server, config).unwrap();
+        let relayed = Message::Relayed(Box::new(Message::Notify(client)))
+            .encode()
+            .unwrap();
+        let outputs = processor.process_packet(&relayed, src, None).unwrap();
+        assert!(matches!(
+            &outputs[..],
+            [Output::HostEvent(_), Output::Send(_), Output::HostEvent(_)]
+        ));
+    }
+
+    #[test]
+    fn test_query() {
+        let server = Udis::build(
+            "server".into(),
+            Ipv4Addr::new(10, 0, 0, 1).into(),
+            vec![tcp_host("hello", 4112)],
+        );
+        let client = Udis::build(
+            "client".into(),
+            Ipv4Addr::new(10, 0, 0, 2).into(),
+            vec![Service::Search {
+                kind: "hello".into(),
+            }],
+        );
+        let config = Config {
+            host_events: true,
+            ..Default::default()
+        };
+        let mut processor = Processor::new(server, config).unwrap();
+        let src = SocketAddr::from((Ipv4Addr::new(10, 0, 0, 2), 8787));
+
+        // Once the client is known hearing from it again isn't replied to
+        let notify = Message::Notify(client.clone()).encode().unwrap();
+        processor.process_packet(&notify, src, None).unwrap();
+        let outputs = processor.process_packet(&notify, src, None).unwrap();
+        assert!(outputs.is_empty());
+
+        // Unless it queries, which is replied to without the client being newly interested
+        let mut refreshed = Processor::new(client, Config::default()).unwrap();
+        refreshed.refresh();
+        let query = refreshed.query_message();
+        let outputs = processor.process_packet(query, src, None).unwrap();
+        assert!(matches!(
+            &outputs[..],
+            [
+                Output::Send(_),
+                Output::HostEvent(HostEvent::Replied { peer, to_kind })
+            ] if peer == "client" && to_kind == "hello"
+        ));
+
+        // The query doesn't change what's known about the client either
+        let outputs = processor.process_packet(&notify, src, None).unwrap();
+        assert!(outputs.is_empty());
+    }
+
+    #[test]
+    fn test_probe() {
+        let server = Udis::build(
+            "server".into(),
+            Ipv4Addr::new(10, 0, 0, 1).into(),
+            vec![tcp_host("hello", 4112)],
+        );
+        let config = Config {
+            host_events: true,
+            ..Default::default()
+        };
+        let mut processor = Processor::new(server, config).unwrap();
+        let mut client = Processor::new(
+            Udis::build("client".into(), Ipv4Addr::new(10, 0, 0, 2).into(), vec![]),
+            Config::default(),
+        )
+        .unwrap();
+        let src = SocketAddr::from((Ipv4Addr::new(10, 0, 0, 2), 8787));
+
+        // A probe of somewhere else is ignored
+        let probe = client.probe(Ipv4Addr::new(10, 0, 0, 3).into()).unwrap();
+        let outputs = processor.process_packet(&probe, src, None).unwrap();
+        assert!(outputs.is_empty());
+
+        // A probe of our address is answered by announcing ourselves, without any host events
+        let probe = client.probe(Ipv4Addr::new(10, 0, 0, 1).into()).unwrap();
+        let outputs = processor.process_packet(&probe, src, None).unwrap();
+        let [Output::Send(reply)] = &outputs[..] else {
+            panic!("expected the probe to be answered: {outputs:?}");
+        };
+
+        // A searcher at the probed address isn't the reply, even though it's heard first
+        let src = SocketAddr::from((Ipv4Addr::new(10, 0, 0, 1), 8787));
+        let searcher = Udis::build(
+            "searcher".into(),
+            Ipv4Addr::new(10, 0, 0, 1).into(),
+            vec![Service::Search {
+                kind: "hello".into(),
+            }],
+        );
+        let searcher = Message::Notify(searcher).encode().unwrap();
+        let outputs = client.process_packet(&searcher, src, None).unwrap();
+        assert!(!outputs
+            .iter()
+            .any(|output| matches!(output, Output::ProbeReply(..))));
+
+        // But the host's reply is passed on as the reply to the probe
+        let outputs = client.process_packet(reply, src, None).unwrap();
+        assert!(matches!(
+            &outputs[..],
+            [Output::ProbeReply(addr, hosted)]
+                if *addr == src.ip() && hosted.len() == 1 && hosted[0].port == 4112
+        ));
+    }
+
+    #[test]
+    fn test_dedup_by() {
+        let client = Udis::build(
+            "client".into(),
+            Ipv4Addr::new(10, 0, 0, 2).into(),
+            vec![Service::Search {
+                kind: "hello".into(),
+            }],
+        );
+        let mut server = Udis::build(
+            "server".into(),
+            Ipv4Addr::new(10, 0, 0, 1).into(),
+            vec![tcp_host("hello", 4112)],
+        );
+        let notify = Message::Notify(server.clone()).encode().unwrap();
+        server.services.push(Service::Search {
+            kind: "world".into(),
+        });
+        let changed = Message::Notify(server).encode().unwrap();
+        let src = SocketAddr::from((Ipv4Addr::new(10, 0, 0, 1), 8787));
+
+        // By default a peer changing its services is the same peer, so nothing new is found
+        let mut processor = Processor::new(client.clone(), Config::default()).unwrap();
+        processor.process_packet(&notify, src, None).unwrap();
+        let outputs = processor.process_packet(&changed, src, None).unwrap();
+        assert!(outputs.is_empty());
+
+        // Deduplicating by the whole notify message finds the service again
+        let config = Config {
+            dedup_by: DedupKey::FullStruct,
+            ..Default::default()
+        };
+        let mut processor = Processor::new(client, config).unwrap();
+        let outputs = processor.process_packet(&notify, src, None).unwrap();
+        let [Output::Service(first), ..] = &outputs[..] else {
+            panic!("service wasn't found: {outputs:?}");
+        };
+        let outputs = processor.process_packet(&changed, src, None).unwrap();
+        let [Output::Service(again), Output::Send(_)] = &outputs[..] else {
+            panic!("service wasn't found again: {outputs:?}");
+        };
+
+        // It's still the same service though, so it should compare and hash the same
+        assert_ne!(first.seq, again.seq);
+        assert_eq!(first, again);
+        let found: HashSet<_> = [first, again].into_iter().collect();
+        assert_eq!(found.len(), 1);
+    }
+
+    #[test]
+    fn test_service_expiry() {
+        let client = Udis::build(
+            "client".into(),
+            Ipv4Addr::new(10, 0, 0, 2).into(),
+            vec![Service::Search {
+                kind: "hello".into(),
+            }],
+        );
+        let mut hello = tcp_host("hello", 4112);
+        if let Service::Host { ttl, .. } = &mut hello {
+            *ttl = 10;
+        }
+        let server = Udis::build(
+            "server".into(),
+            Ipv4Addr::new(10, 0, 0, 1).into(),
+            vec![hello],
+        );
+        let notify = Message::Notify(server).encode().unwrap();
+        let src = SocketAddr::from((Ipv4Addr::new(10, 0, 0, 1), 8787));
+
+        let clock = MockClock::new();
+        let config = Config {
+            clock: Some(Arc::new(clock.clone())),
+            ..Default::default()
+        };
+        let mut processor = Processor::new(client, config).unwrap();
+        processor.process_packet(&notify, src, None).unwrap();
+
+        // Hearing from the host again renews the service
+        clock.advance(Duration::from_secs(8));
+        processor.process_packet(&notify, src, None).unwrap();
+        clock.advance(Duration::from_secs(8));
+        assert!(processor.expire_services().is_empty());
+
+        clock.advance(Duration::from_secs(2));
+        let events = processor.expire_services();
+        assert!(matches!(&events[..], [ServiceEvent::Expired(info)] if info.kind == "hello"));
+
+        // Once expired the service is found again when the host next announces it
+        let outputs = processor.process_packet(&notify, src, None).unwrap();
+        assert!(matches!(outputs.first(), Some(Output::Service(info))
+            if info.seq == 1 && info.discovered_after == Duration::from_secs(18)));
+    }
+
+    #[test]
+    fn test_oversize_notify() {
+        let host = |kind: &str, p: u8, d: &str| {
+            let mut service = tcp_host(kind, 4112);
+            if let Service::Host {
+                priority,
+                description,
+                ..
+            } = &mut service
+            {
+                *priority = p;
+                *description = Some(d.into());
+            }
+            service
+        };
+        let server = Udis::build(
+            "server".into(),
+            Ipv4Addr::new(10, 0, 0, 1).into(),
+            vec![host("hello", 0, "short"), host("bulk", 5, &"x".repeat(512))],
+        );
+        let config = Config {
+            max_message_size: Some(256),
+            ..Default::default()
+        };
+
+        // The least preferred service should be left out so the rest still fit
+        let processor = Processor::new(server.clone(), config.clone()).unwrap();
+        let notify = processor.notify_message();
+        assert!(notify.len() <= 256);
+        let Message::Notify(sent) = Message::decode(notify, usize::MAX, usize::MAX).unwrap() else {
+            panic!("expected a notify message");
+        };
+        assert_eq!(sent.hosted_services(), vec![("hello".to_string(), 4112)]);
+
+        // If even the name doesn't fit there's nothing that can be sent
+        let mut renamed = server;
+        renamed.name = "x".repeat(512);
+        assert!(Processor::new(renamed, config).is_err());
+    }
+
+    #[test]
+    fn test_search_all() {
+        let client = Udis::build(
+            "client".into(),
+            Ipv4Addr::new(10, 0, 0, 2).into(),
+            vec![
+                Service::Search {
+                    kind: WILDCARD_KIND.into(),
+                },
+                Service::Search {
+                    kind: "hello".into(),
+                },
+            ],
+        );
+        let mut processor = Processor::new(client, Config::default()).unwrap();
+
+        let server = Udis::build(
+            "server".into(),
+            Ipv4Addr::new(10, 0, 0, 1).into(),
+            vec![tcp_host("hello", 4112), tcp_host("world", 4113)],
+        );
+        let notify = Message::Notify(server).encode().unwrap();
+        let src = SocketAddr::from((Ipv4Addr::new(10, 0, 0, 1), 8787));
+
+        // Every service should be found, once each even if also searched for by kind
+        let outputs = processor.process_packet(&notify, src, None).unwrap();
+        let mut found: Vec<_> = outputs
+            .iter()
+            .filter_map(|output| match output {
+                Output::Service(serv_info) => Some(serv_info.kind.as_str()),
+                _ => None,
+            })
+            .collect();
+        found.sort();
+        assert_eq!(found, ["hello", "world"]);
+    }
+
+    #[test]
+    fn test_shared_port_warning() {
+        let client = Udis::build("client".into(), Ipv4Addr::new(10, 0, 0, 2).into(), vec![]);
+        let mut processor = Processor::new(client.clone(), Config::default()).unwrap();
+        let src = SocketAddr::from((Ipv4Addr::new(10, 0, 0, 1), 8787));
+
+        // A few bad packets are just skipped, as are ones broken up by a good packet
+        let notify = Message::Notify(client).encode().unwrap();
+        for _ in 1..SHARED_PORT_WARN_THRESHOLD {
+            processor.process_packet(b"not udis", src, None).unwrap();
+        }
+        processor.process_packet(&notify, src, None).unwrap();
+        processor.process_packet(b"not udis", src, None).unwrap();
+        assert!(!processor.warned_shared_port);
+
+        // But enough in a row should be warned about
+        for _ in 1..SHARED_PORT_WARN_THRESHOLD {
+            processor.process_packet(b"not udis", src, None).unwrap();
+        }
+        assert!(processor.warned_shared_port);
+    }
+
+    #[test]
+    fn test_packet_preview() {
+        assert_eq!(packet_preview(br#"{"name""#), r#""{\"name\"""#);
+        assert_eq!(packet_preview(&[0xff, 0x00, 0x10]), "ff0010");
+
+        let long = packet_preview(&[b'a'; 100]);
+        assert!(long.starts_with(&format!("\"{}\"", "a".repeat(64))));
+        assert!(long.ends_with("... (100 bytes)"));
+    }
+
+    #[test]
+    fn test_peer_filter() {
+        let client = Udis::build(
+            "client".into(),
+            Ipv4Addr::new(10, 0, 0, 2).into(),
+            vec![Service::Search {
+                kind: "hello".into(),
+            }],
+        );
+        let config = Config {
+            peer_filter: Some(Hook(Arc::new(|peer: &Udis, _| peer.name() != "rogue"))),
+            ..Default::default()
+        };
+        let mut processor = Processor::new(client, config).unwrap();
+
+        let src = SocketAddr::from((Ipv4Addr::new(10, 0, 0, 1), 8787));
+        let notify = |name: &str| {
+            let server = Udis::build(
+                name.into(),
+                Ipv4Addr::new(10, 0, 0, 1).into(),
+                vec![tcp_host("hello", 4112)],
+            );
+            Message::Notify(server).encode().unwrap()
+        };
+
+        // Peers rejected by the filter should be dropped entirely
+        let outputs = processor
+            .process_packet(&notify("rogue"), src, None)
+            .unwrap();
+        assert!(outputs.is_empty());
+        assert!(processor.peers_with_services().is_empty());
+
+        let outputs = processor
+            .process_packet(&notify("server"), src, None)
+            .unwrap();
+        assert!(matches!(
+            &outputs[..],
+            [Output::Service(_), Output::Send(_)]
+        ));
+    }
+
+    #[test]
+    fn test_display() {
+        let udis = Udis::build(
+            "server".into(),
+            Ipv4Addr::new(10, 0, 0, 1).into(),
+            vec![
+                tcp_host("a", 1),
+                Service::Host {
+                    kind: "b".into(),
+                    endpoint: HostEndpoint::Unix("/run/b.sock".into()),
+                    metadata: Default::default(),
+                    priority: 0,
+                    weight: 0,
+                    ttl: 0,
+                    description: None,
+                    proxy: None,
+                },
+                Service::Search { kind: "c".into() },
+            ],
+        );
+        assert_eq!(
+            udis.to_string(),
+            "server@10.0.0.1 hosting [a:1, b:/run/b.sock] searching [c]"
+        );
+        assert_eq!(udis.services[0].to_string(), "hosting a:1");
+        assert_eq!(udis.services[2].to_string(), "searching c");
+
+        // Endpoints which don't host or search for anything are just their name and address
+        let udis = Udis::build("client".into(), "fd00::1".parse().unwrap(), vec![]);
+        assert_eq!(udis.to_string(), "client@fd00::1");
+    }
+
+    #[test]
+    fn test_proxied_service() {
+        let client = Udis::build(
+            "client".into(),
+            Ipv4Addr::new(10, 0, 0, 2).into(),
+            vec![Service::Search {
+                kind: "hello".into(),
+            }],
+        );
+        let mut processor = Processor::new(client, Config::default()).unwrap();
+
+        let mut hello = tcp_host("hello", 4112);
+        if let Service::Host { proxy, .. } = &mut hello {
+            *proxy = Some(Ipv4Addr::new(10, 0, 1, 5).into());
+        }
+        let server = Udis::build(
+            "gateway".into(),
+            Ipv4Addr::new(10, 0, 0, 1).into(),
+            vec![hello],
+        );
+        assert_eq!(
+            server.to_string(),
+            "gateway@10.0.0.1 hosting [hello:10.0.1.5:4112]"
+        );
+        let notify = Message::Notify(server).encode().unwrap();
+        let src = SocketAddr::from((Ipv4Addr::new(10, 0, 0, 1), 8787));
+
+        // The service should be found at its backend rather than the endpoint advertising it
+        let outputs = processor.process_packet(&notify, src, None).unwrap();
+        let Some(Output::Service(info)) = outputs.first() else {
+            panic!("expected the proxied service to be found");
+        };
+        assert_eq!(info.addr, Ipv4Addr::new(10, 0, 1, 5));
+        assert_eq!(info.addrs, [info.addr]);
+        assert_eq!(info.port, 4112);
+        assert_eq!(info.proxied_by, Some(Ipv4Addr::new(10, 0, 0, 1).into()));
+    }
+
+    #[test]
+    fn test_restrict_to_subnet() {
+        let client = Udis::build(
+            "client".into(),
+            Ipv4Addr::new(10, 0, 0, 2).into(),
+            vec![Service::Search {
+                kind: "hello".into(),
+            }],
+        );
+        let config = Config {
+            subnet: Some("10.0.0.0/24".parse().unwrap()),
+            ..Default::default()
+        };
+        let mut processor = Processor::new(client, config).unwrap();
+
+        let server = |addr: Ipv4Addr| {
+            Udis::build("server".into(), addr.into(), vec![tcp_host("hello", 4112)])
+                .with_extra_addrs(vec![
+                    Ipv4Addr::new(10, 0, 0, 5).into(),
+                    Ipv4Addr::new(192, 168, 0, 5).into(),
+                ])
+        };
+        let src = SocketAddr::from((Ipv4Addr::new(10, 0, 0, 1), 8787));
+
+        // A peer advertising an address outside the subnet should be ignored
+        let notify = Message::Notify(server(Ipv4Addr::new(10, 0, 1, 1)))
+            .encode()
+            .unwrap();
+        let outputs = processor.process_packet(&notify, src, None).unwrap();
+        assert!(outputs.is_empty());
+
+        // A peer inside it should be found, but only at its addresses inside the subnet
+        let notify = Message::Notify(server(Ipv4Addr::new(10, 0, 0, 1)))
+            .encode()
+            .unwrap();
+        let outputs = processor.process_packet(&notify, src, None).unwrap();
+        let Some(Output::Service(info)) = outputs.first() else {
+            panic!("expected the service to be found");
+        };
+        assert_eq!(
+            info.addrs,
+            [
+                IpAddr::from(Ipv4Addr::new(10, 0, 0, 1)),
+                Ipv4Addr::new(10, 0, 0, 5).into()
+            ]
+        );
+    }
+
+    #[test]
+    fn test_reply_coalescing() {
+        let server = Udis::build(
+            "server".into(),
+            Ipv4Addr::new(10, 0, 0, 1).into(),
+            vec![tcp_host("hello", 4112)],
+        );
+        let config = Config {
+            unicast_port: Some(9000),
+            reply_coalesce_window: Duration::from_millis(50),
+            host_events: true,
+            ..Default::default()
+        };
+        let notify = |name: &str| {
+            let client = Udis::build(
+                name.into(),
+                Ipv4Addr::new(10, 0, 0, 2).into(),
+                vec![Service::Search {
+                    kind: "hello".into(),
+                }],
+            );
+            Message::Notify(client).encode().unwrap()
+        };
+        let replied = |outputs: &[Output]| {
+            outputs
+                .iter()
+                .filter(|output| matches!(output, Output::HostEvent(HostEvent::Replied { .. })))
+                .count()
+        };
+
+        // Replies should be held back until the window is up, and peers sharing an address
+        // replied to once
+        let mut processor = Processor::new(server.clone(), config.clone()).unwrap();
+        let src = SocketAddr::from((Ipv4Addr::new(10, 0, 0, 2), 8787));
+        for name in ["a", "b"] {
+            let outputs = processor.process_packet(&notify(name), src, None).unwrap();
+            assert!(matches!(
+                &outputs[..],
+                [Output::HostEvent(HostEvent::NewInterest { .. })]
+            ));
+        }
+        assert!(processor.next_reply_flush().is_some());
+        let outputs = processor.flush_replies();
+        assert!(matches!(outputs.first(), Some(Output::Reply(_, to)) if *to == src));
+        assert_eq!(outputs.len(), 3);
+        assert_eq!(replied(&outputs), 2);
+        assert!(processor.next_reply_flush().is_none());
+
+        // The window should be timed by the endpoint's clock
+        let clock = MockClock::new();
+        let clocked = Config {
+            clock: Some(Arc::new(clock.clone())),
+            ..config.clone()
+        };
+        let mut processor = Processor::new(server.clone(), clocked).unwrap();
+        processor.process_packet(&notify("a"), src, None).unwrap();
+        assert!(!processor.replies_due());
+        clock.advance(Duration::from_millis(50));
+        assert!(processor.replies_due());
+
+        // Peers at several addresses should all be answered by one announcement
+        let mut processor = Processor::new(server, config).unwrap();
+        for (name, port) in [("a", 8787), ("b", 8788)] {
+            let src = SocketAddr::from((Ipv4Addr::new(10, 0, 0, 2), port));
+            processor.process_packet(&notify(name), src, None).unwrap();
+        }
+        let outputs = processor.flush_replies();
+        assert!(matches!(outputs.first(), Some(Output::Send(_))));
+        assert_eq!(outputs.len(), 3);
+        assert_eq!(replied(&outputs), 2);
+    }
+}
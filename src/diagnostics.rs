@@ -0,0 +1,199 @@
+use std::{
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    sync::{Arc, Mutex},
+    time::SystemTime,
+};
+
+use socket2::Socket;
+
+use crate::error::Error;
+
+/// Diagnostic information about the state of a udis endpoint's connection to the discovery
+/// network.
+///
+/// This is intended to help troubleshoot endpoints which aren't finding any services, for example
+/// by checking that the multicast group was joined and that packets are being received at all.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Diagnostics {
+    /// The multicast groups the endpoint has joined, empty if it hasn't joined any yet
+    pub groups: Vec<SocketAddr>,
+
+    /// The local address the endpoint's socket is bound to
+    pub bound_addr: Option<SocketAddr>,
+
+    /// The interface multicast packets are sent on, [`Ipv4Addr::UNSPECIFIED`] means the OS
+    /// default interface is used
+    pub interface: Option<Ipv4Addr>,
+
+    /// Whether multicast loopback is enabled, i.e. whether endpoints on the same machine can see
+    /// each other
+    pub multicast_loop: Option<bool>,
+
+    /// When a notify message was last sent by the endpoint
+    pub last_send: Option<SystemTime>,
+
+    /// When a packet was last received by the endpoint
+    pub last_recv: Option<SystemTime>,
+
+    /// The number of packets sent by the endpoint
+    pub packets_sent: u64,
+
+    /// The number of packets received by the endpoint
+    pub packets_received: u64,
+}
+
+/// Counters describing the activity of a udis endpoint, reported periodically to the callback
+/// given to [`crate::builder::Builder::metrics`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Metrics {
+    /// The number of packets sent by the endpoint
+    pub packets_sent: u64,
+
+    /// The number of packets received by the endpoint
+    pub packets_received: u64,
+
+    /// The number of peers the endpoint currently knows about
+    pub peers_known: usize,
+
+    /// The number of services the endpoint has found
+    pub services_found: u64,
+
+    /// The number of received packets which couldn't be deserialised
+    pub deserialise_failures: u64,
+}
+
+/// Something that happened on a udis endpoint, recorded in its timeline if it was built with
+/// [`crate::builder::Builder::timeline`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TimelineEntry {
+    /// When it happened
+    pub at: SystemTime,
+
+    /// What happened
+    pub event: TimelineEvent,
+}
+
+/// The kinds of things recorded in an endpoint's timeline
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum TimelineEvent {
+    /// A packet was received from the discovery network
+    PacketReceived {
+        /// The source address of the packet
+        from: SocketAddr,
+
+        /// The size of the packet in bytes
+        len: usize,
+    },
+
+    /// A peer was heard from for the first time
+    PeerAdded {
+        /// The name of the peer
+        name: String,
+
+        /// The address the peer advertises
+        addr: IpAddr,
+    },
+
+    /// A peer said goodbye and was forgotten
+    PeerRemoved {
+        /// The name of the peer
+        name: String,
+
+        /// The address the peer advertised
+        addr: IpAddr,
+    },
+
+    /// The endpoint announced itself to the discovery network
+    Announced,
+
+    /// The endpoint replied directly to a peer interested in one of its services
+    Replied {
+        /// The address the reply was sent to
+        to: SocketAddr,
+    },
+
+    /// The endpoint found a service it was searching for
+    ServiceFound {
+        /// The name of the peer hosting the service
+        name: String,
+
+        /// The kind of the service
+        kind: String,
+    },
+}
+
+/// [`Diagnostics`] shared between an endpoint and its background worker
+#[derive(Debug, Clone, Default)]
+pub(crate) struct SharedDiagnostics {
+    diag: Arc<Mutex<Diagnostics>>,
+
+    /// The last error the worker carried on from, which isn't part of [`Diagnostics`] as errors
+    /// can't be cloned so it's taken when the user reads it
+    last_error: Arc<Mutex<Option<Error>>>,
+}
+
+impl SharedDiagnostics {
+    /// Get a copy of the current diagnostics
+    pub(crate) fn get(&self) -> Diagnostics {
+        match self.diag.lock() {
+            Ok(diag) => diag.clone(),
+            Err(poisoned) => poisoned.into_inner().clone(),
+        }
+    }
+
+    /// Record an error the worker logged and carried on from, replacing any not yet taken
+    pub(crate) fn record_error(&self, e: Error) {
+        match self.last_error.lock() {
+            Ok(mut last_error) => *last_error = Some(e),
+            Err(poisoned) => *poisoned.into_inner() = Some(e),
+        }
+    }
+
+    /// Take the last error recorded by the worker, if there's been one since this was last called
+    pub(crate) fn take_error(&self) -> Option<Error> {
+        match self.last_error.lock() {
+            Ok(mut last_error) => last_error.take(),
+            Err(poisoned) => poisoned.into_inner().take(),
+        }
+    }
+
+    /// Record the details of the socket the worker has joined the discovery network with
+    pub(crate) fn record_socket(&self, groups: &[SocketAddr], socket: &Socket) {
+        self.update(|diag| {
+            diag.groups = groups.to_vec();
+            diag.bound_addr = socket.local_addr().ok().and_then(|a| a.as_socket());
+            diag.interface = socket.multicast_if_v4().ok();
+            diag.multicast_loop = socket.multicast_loop_v4().ok();
+        });
+    }
+
+    /// Record that a notify message was just sent
+    pub(crate) fn record_send(&self) {
+        self.update(|diag| {
+            diag.last_send = Some(SystemTime::now());
+            diag.packets_sent += 1;
+        });
+
+        #[cfg(feature = "metrics")]
+        metrics::counter!("udis_packets_sent_total").increment(1);
+    }
+
+    /// Record that a packet was just received
+    pub(crate) fn record_recv(&self) {
+        self.update(|diag| {
+            diag.last_recv = Some(SystemTime::now());
+            diag.packets_received += 1;
+        });
+
+        #[cfg(feature = "metrics")]
+        metrics::counter!("udis_packets_received_total").increment(1);
+    }
+
+    fn update<F: FnOnce(&mut Diagnostics)>(&self, f: F) {
+        match self.diag.lock() {
+            Ok(mut diag) => f(&mut diag),
+            Err(poisoned) => f(&mut poisoned.into_inner()),
+        }
+    }
+}
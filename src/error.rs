@@ -1,4 +1,6 @@
-use crate::ServiceInfo;
+use std::{net::SocketAddr, path::PathBuf};
+
+use crate::{HostEvent, ServiceInfo};
 
 /// Enum of errors that might occur in udis usage
 #[derive(Debug, thiserror::Error)]
@@ -13,9 +15,44 @@ pub enum Error {
     #[error("The service `{kind}` on port {port} is a duplicate service, either the kind or port are already in use on this endpoint")]
     DuplicateService { kind: String, port: u16 },
 
+    #[error("The service `{kind}` at {path:?} is a duplicate service, either the kind or path are already in use on this endpoint")]
+    DuplicateUnixService { kind: String, path: PathBuf },
+
+    #[error("A service has an empty kind")]
+    EmptyServiceKind,
+
+    #[error("The address {0} can't be reached by peers")]
+    UnroutableAddr(std::net::IpAddr),
+
+    #[error(
+        "{addr} is not a multicast address, multicast groups must be in 224.0.0.0/4 or ff00::/8"
+    )]
+    NotAMulticastAddr { addr: std::net::IpAddr },
+
+    #[error("{0} is not a valid DSCP value, which must be from 0 to 63")]
+    InvalidDscp(u8),
+
+    #[error("Conflicting options: {0}")]
+    ConflictingOptions(String),
+
+    #[error("A test message sent to the discovery network never came back, is multicast blocked?")]
+    MulticastUnreachable,
+
+    #[error("Another endpoint on the discovery network is already named `{0}`")]
+    NameConflict(String),
+
     #[error("Could not get the local IP address")]
     LocalAddrError(#[from] local_ip_address::Error),
 
+    #[error("None of the local IP addresses passed the address filter")]
+    NoSuitableLocalAddr,
+
+    #[error("`{0}` is not a valid subnet, expected CIDR notation such as `192.168.0.0/24`")]
+    InvalidSubnet(String),
+
+    #[error("{0} is not supported on this platform")]
+    Unsupported(&'static str),
+
     #[error("Action cannot be performed, the background udis thread shutdown")]
     BackgroundThreadShutdown,
 
@@ -25,15 +62,46 @@ pub enum Error {
     #[error("Failed to serialise udis notify message")]
     FailedToSerialiseNotifyMsg(#[source] serde_json::Error),
 
-    #[error("Failed to deserialise udis notify message")]
-    FailedToDeserialiseNotifyMsg(#[source] serde_json::Error),
+    #[error(
+        "The notify message is {size} bytes without any services, more than the limit of {max}"
+    )]
+    NotifyMsgTooLarge { size: usize, max: usize },
+
+    #[error("Failed to serialise udis acknowledgement message")]
+    FailedToSerialiseAckMsg(#[source] serde_json::Error),
+
+    #[error("Failed to serialise udis goodbye message")]
+    FailedToSerialiseGoodbyeMsg(#[source] serde_json::Error),
+
+    #[error("Failed to deserialise udis packet from {src}, starting {preview}")]
+    FailedToDeserialiseNotifyMsg {
+        src: SocketAddr,
+        preview: String,
+        #[source]
+        source: serde_json::Error,
+    },
 
     #[error("Failed to send service information to the main thread")]
-    FailedToSendServiceInfo(#[from] std::sync::mpsc::SendError<ServiceInfo>),
+    FailedToSendServiceInfo(#[source] Box<std::sync::mpsc::SendError<ServiceInfo>>),
 
     #[cfg(feature = "tokio")]
     #[error("Failed to send service information to the main thread")]
-    FailedToSendServiceInfoTokio(#[from] tokio::sync::mpsc::error::SendError<ServiceInfo>),
+    FailedToSendServiceInfoTokio(#[source] Box<tokio::sync::mpsc::error::SendError<ServiceInfo>>),
+
+    #[cfg(feature = "async-std")]
+    #[error("Failed to send service information to the main task")]
+    FailedToSendServiceInfoAsyncStd(#[source] Box<async_std::channel::SendError<ServiceInfo>>),
+
+    #[error("Failed to send a host event to the main thread")]
+    FailedToSendHostEvent(#[from] std::sync::mpsc::SendError<HostEvent>),
+
+    #[cfg(feature = "tokio")]
+    #[error("Failed to send a host event to the main thread")]
+    FailedToSendHostEventTokio(#[from] tokio::sync::mpsc::error::SendError<HostEvent>),
+
+    #[cfg(feature = "async-std")]
+    #[error("Failed to send a host event to the main task")]
+    FailedToSendHostEventAsyncStd(#[from] async_std::channel::SendError<HostEvent>),
 
     #[error("Failed to shutdown the udis background thread")]
     FailedToShutdownUdisThread,
@@ -48,4 +116,102 @@ pub enum Error {
 
     #[error("Service info channel closed, the udis task has stopped")]
     ServiceInfoChannelClosed,
+
+    #[error("A service callback is registered, so services can't be found manually")]
+    ServiceCallbackRegistered,
+
+    #[error("Host events weren't enabled with `Builder::host_events`")]
+    HostEventsDisabled,
+
+    #[error("Endpoints built with a custom transport can't be restarted")]
+    CustomTransportNotRestartable,
+}
+
+impl Error {
+    /// Whether the error is fatal, meaning that retrying whatever caused it won't help.
+    ///
+    /// Errors are fatal if the endpoint's background worker has stopped, so no more services
+    /// will be found, or if the endpoint was configured in a way that can never work. Other
+    /// errors, like failing to find a local address while the network is still coming up, may go
+    /// away if the operation is retried.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use udis::error::Error;
+    ///
+    /// assert!(Error::ServiceInfoChannelClosed.is_fatal());
+    /// assert!(!Error::NoSuitableLocalAddr.is_fatal());
+    /// ```
+    pub fn is_fatal(&self) -> bool {
+        match self {
+            // Transient conditions, such as network errors or bad packets from a peer
+            Error::FmtError(_)
+            | Error::IoError(_)
+            | Error::LocalAddrError(_)
+            | Error::NoSuitableLocalAddr
+            | Error::MulticastUnreachable
+            | Error::NameConflict(_)
+            | Error::FailedToDeserialiseNotifyMsg { .. } => false,
+
+            // Invalid configuration, which will fail the same way every time
+            Error::DuplicateService { .. }
+            | Error::DuplicateUnixService { .. }
+            | Error::InvalidSubnet(_)
+            | Error::EmptyServiceKind
+            | Error::UnroutableAddr(_)
+            | Error::NotAMulticastAddr { .. }
+            | Error::InvalidDscp(_)
+            | Error::ConflictingOptions(_)
+            | Error::Unsupported(_)
+            | Error::FailedToSerialiseNotifyMsg(_)
+            | Error::NotifyMsgTooLarge { .. }
+            | Error::FailedToSerialiseAckMsg(_)
+            | Error::FailedToSerialiseGoodbyeMsg(_)
+            | Error::ServiceCallbackRegistered
+            | Error::HostEventsDisabled
+            | Error::CustomTransportNotRestartable => true,
+
+            // The background worker or the user's end of its channels has gone away
+            Error::BackgroundThreadShutdown
+            | Error::ServiceInfoRecvError(_)
+            | Error::ServiceInfoChannelClosed
+            | Error::FailedToSendServiceInfo(_)
+            | Error::FailedToSendHostEvent(_)
+            | Error::FailedToShutdownUdisThread => true,
+
+            #[cfg(feature = "tokio")]
+            Error::FailedToSendServiceInfoTokio(_)
+            | Error::FailedToSendHostEventTokio(_)
+            | Error::FailedToShutdownUdisTask
+            | Error::FailedToJoinUdisTask(_) => true,
+
+            #[cfg(feature = "async-std")]
+            Error::FailedToSendServiceInfoAsyncStd(_) | Error::FailedToSendHostEventAsyncStd(_) => {
+                true
+            }
+        }
+    }
+}
+
+// The unsent service info is boxed to keep the error small, so these can't be derived
+
+impl From<std::sync::mpsc::SendError<ServiceInfo>> for Error {
+    fn from(e: std::sync::mpsc::SendError<ServiceInfo>) -> Self {
+        Self::FailedToSendServiceInfo(Box::new(e))
+    }
+}
+
+#[cfg(feature = "tokio")]
+impl From<tokio::sync::mpsc::error::SendError<ServiceInfo>> for Error {
+    fn from(e: tokio::sync::mpsc::error::SendError<ServiceInfo>) -> Self {
+        Self::FailedToSendServiceInfoTokio(Box::new(e))
+    }
+}
+
+#[cfg(feature = "async-std")]
+impl From<async_std::channel::SendError<ServiceInfo>> for Error {
+    fn from(e: async_std::channel::SendError<ServiceInfo>) -> Self {
+        Self::FailedToSendServiceInfoAsyncStd(Box::new(e))
+    }
 }
@@ -7,26 +7,103 @@
     clippy::missing_errors_doc
 )]
 
-use std::net::IpAddr;
+use std::{
+    cmp::Ordering,
+    collections::{BTreeMap, HashSet},
+    fmt,
+    hash::{Hash, Hasher},
+    net::{IpAddr, SocketAddr, SocketAddrV4},
+    path::PathBuf,
+    str::FromStr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
 use builder::Builder;
 use serde::{Deserialize, Serialize};
 
+/// Implementation of the async udis endpoint on the async-std runtime, __Requires the `async-std`
+/// feature__
+#[cfg(feature = "async-std")]
+pub mod async_std_rt;
+
 /// Implementation of the async udis endpoint, __Requires the `tokio` feature__
 #[cfg(feature = "tokio")]
 pub mod async_tokio;
 
+/// Implementation of the blocking udis endpoint, which runs on the caller's thread
+pub mod blocking;
+
 /// Builder struct for the [`Udis`] type
 pub mod builder;
 
+/// Clocks used by udis endpoints for time-based features
+pub mod clock;
+
+mod config;
+
+mod core;
+
+/// Diagnostic information about udis endpoints
+pub mod diagnostics;
+
 /// Defines errors that can occur
 pub mod error;
 
 mod net;
 
+mod relay;
+
 /// Implementation of the sync udis endpoint
 pub mod sync;
 
+/// Transports connecting udis endpoints to the discovery network
+pub mod transport;
+
+mod wire;
+
+/// The kind searched for by [`Builder::search_all`], which matches every kind of service
+pub(crate) const WILDCARD_KIND: &str = "*";
+
+/// Find every service of `kind` on the discovery network within `timeout`, like a DNS lookup.
+///
+/// This builds a short-lived endpoint searching for `kind`, collects the services it finds until
+/// the timeout, and shuts it down again. Use a [`Builder`] instead to keep discovering services
+/// or to configure the endpoint.
+///
+/// # Examples
+///
+/// ```no_run
+/// use std::time::Duration;
+///
+/// let services = udis::resolve("hello", Duration::from_secs(1)).expect("Failed to resolve");
+///
+/// for service in services {
+///     println!("`{}` hosts hello at {}:{}", service.name, service.addr, service.port);
+/// }
+/// ```
+///
+/// # Errors
+///
+/// This function can fail in the same ways as [`Builder::build_sync`], or if the endpoint's
+/// background thread stops while collecting services.
+pub fn resolve(kind: &str, timeout: Duration) -> Result<Vec<ServiceInfo>, error::Error> {
+    let udis = Udis::new("udis-resolve").search(kind).build_sync()?;
+    let deadline = Instant::now() + timeout;
+
+    let mut found = Vec::new();
+    while let Some(serv_info) = udis.find_service_where(
+        deadline.saturating_duration_since(Instant::now()),
+        false,
+        |_| true,
+    )? {
+        found.push(serv_info);
+    }
+
+    udis.shutdown()?;
+    Ok(found)
+}
+
 /// The main interface to the udis system.
 ///
 /// This type provides a builder which lets you define:
@@ -72,10 +149,31 @@ pub struct Udis {
     name: String,
     addr: IpAddr,
     services: Vec<Service>,
+
+    /// Other addresses the endpoint can be reached on, in order of preference after `addr`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    extra_addrs: Vec<IpAddr>,
+
+    /// Application specific data attached to the endpoint's announcements, which only the current
+    /// wire format can carry
+    #[serde(skip)]
+    extra: Arc<[u8]>,
 }
 
-/// Contains information on a single discovered service
-#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+/// Contains information on a single discovered service.
+///
+/// This can be serialised, e.g. to forward found services elsewhere, with addresses serialised as
+/// strings in human readable formats such as JSON.
+///
+/// New fields may be added in future, so outside of udis this should be built with
+/// [`ServiceInfo::new`], for example when testing code which consumes found services.
+///
+/// Two infos are equal if they describe the same service, so the fields which only say how this
+/// copy was found (`seq`, `discovered_after` and `reply`) are left out of comparisons and hashes,
+/// as is the host's `extra` payload, which may be large and describes the host rather than the
+/// service. This lets found services be deduplicated with a `HashSet`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[non_exhaustive]
 pub struct ServiceInfo {
     /// The name of the udis endpoint hosting the service
     pub name: String,
@@ -86,14 +184,451 @@ pub struct ServiceInfo {
     /// The address of the endpoint hosting the service
     pub addr: IpAddr,
 
-    /// The port number the service is hosted on
+    /// Every address the endpoint hosting the service advertised, in order of preference
+    /// starting with `addr`, see [`Builder::add_addr`]
+    pub addrs: Vec<IpAddr>,
+
+    /// The port number the service is hosted on, or `0` if it's hosted on a Unix domain socket
     pub port: u16,
+
+    /// Where the service is hosted, either a port or a Unix domain socket path
+    pub endpoint: HostEndpoint,
+
+    /// The source address of the notify message the service was discovered from.
+    ///
+    /// This is where the packet actually came from, which may differ from the advertised `addr`,
+    /// for example if the peer is behind NAT or is misconfigured.
+    pub observed_from: SocketAddr,
+
+    /// Every service hosted by the endpoint on a port, as `(kind, port)` pairs, including this one.
+    ///
+    /// This is only filled in if the endpoint was built with
+    /// [`Builder::include_peer_services`], otherwise it is empty.
+    pub peer_services: Vec<(String, u16)>,
+
+    /// The metadata the endpoint advertises with the service, see [`Builder::host_with_metadata`]
+    pub metadata: BTreeMap<String, String>,
+
+    /// The priority of the service, where services with a lower priority should be preferred,
+    /// see [`builder::HostOptions::priority`]
+    pub priority: u8,
+
+    /// The weight of the service, where services with a higher weight should be preferred over
+    /// others with the same priority
+    pub weight: u16,
+
+    /// How long the service is valid for after the host last announced it, if the host gave it a
+    /// TTL with [`builder::HostOptions::ttl`]
+    pub ttl: Option<Duration>,
+
+    /// The extra multicast group the service was found on, see [`Builder::join_group`], or `None`
+    /// if it was found on the udis discovery network
+    pub group: Option<SocketAddrV4>,
+
+    /// A human readable description of the service, for showing in discovery tools, if the host
+    /// gave it one with [`builder::HostOptions::description`]
+    pub description: Option<String>,
+
+    /// The address of the endpoint advertising the service, if it only proxies the service for
+    /// another machine, see [`Builder::host_proxy`].
+    ///
+    /// For proxied services `addr`, `addrs` and `port` are where the service is really hosted, so
+    /// they can be connected to as usual, and this is `None` for every other service.
+    pub proxied_by: Option<IpAddr>,
+
+    /// Sequence number of the service, counting up from zero in the order the endpoint found its
+    /// services, so gaps or reordering can be spotted when services are forwarded elsewhere.
+    ///
+    /// This is always zero for services listed by [`sync::SyncUdis::peers_with_services`], as they
+    /// weren't just found.
+    pub seq: u64,
+
+    /// How long after the endpoint was built the service was found, for spotting slow peers or
+    /// network delays. Like `seq` this is zero for services listed by
+    /// [`sync::SyncUdis::peers_with_services`].
+    pub discovered_after: Duration,
+
+    /// Whether the service was found in a reply the host sent straight to us because we search
+    /// for one of its services, rather than in an announcement sent to the whole discovery
+    /// network. Solicited announcements are useful for measuring how quickly hosts respond.
+    pub reply: bool,
+
+    /// The application specific data the host attaches to its announcements, see
+    /// [`Builder::extra_payload`], which is empty if it doesn't attach any. The payload is shared
+    /// between every service found in the same announcement rather than copied.
+    pub extra: Arc<[u8]>,
+}
+
+impl ServiceInfo {
+    /// Create the info for a service of `kind` hosted by the endpoint `name` at `addr:port`.
+    ///
+    /// The service is assumed to have been observed from the endpoint's address on the udis
+    /// discovery port, and has no peer services or metadata. The fields can be changed after it's
+    /// created if needed.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// let info = udis::ServiceInfo::new("server", "hello", [192, 168, 0, 1].into(), 4112);
+    ///
+    /// assert_eq!(info.port, 4112);
+    /// assert!(info.metadata.is_empty());
+    /// ```
+    pub fn new<N: Into<String>, K: Into<String>>(
+        name: N,
+        kind: K,
+        addr: IpAddr,
+        port: u16,
+    ) -> Self {
+        Self {
+            name: name.into(),
+            kind: kind.into(),
+            addr,
+            addrs: vec![addr],
+            port,
+            endpoint: HostEndpoint::Tcp(port),
+            observed_from: SocketAddr::new(addr, net::MULTICAST_PORT),
+            peer_services: Vec::new(),
+            metadata: BTreeMap::new(),
+            priority: 0,
+            weight: 0,
+            ttl: None,
+            group: None,
+            description: None,
+            proxied_by: None,
+            seq: 0,
+            discovered_after: Duration::ZERO,
+            reply: false,
+            extra: Arc::default(),
+        }
+    }
+
+    /// Compare how preferable this service is to `other`, from their priority and weight.
+    ///
+    /// Services with a lower priority are preferred, and between services with the same priority
+    /// the one with the higher weight is preferred, like DNS SRV records. A service which compares
+    /// as [`Ordering::Less`] is preferred, so sorting a list of services with this puts the most
+    /// preferable first.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// let mut primary = udis::ServiceInfo::new("primary", "hello", [192, 168, 0, 1].into(), 4112);
+    /// let mut backup = udis::ServiceInfo::new("backup", "hello", [192, 168, 0, 2].into(), 4112);
+    /// backup.priority = 10;
+    ///
+    /// let mut services = vec![backup, primary];
+    /// services.sort_by(|a, b| a.cmp_preference(b));
+    /// assert_eq!(services[0].name, "primary");
+    /// ```
+    pub fn cmp_preference(&self, other: &Self) -> Ordering {
+        self.priority
+            .cmp(&other.priority)
+            .then(other.weight.cmp(&self.weight))
+    }
+
+    /// Parse the kind of this service, for example into an enum of the kinds known to the
+    /// application.
+    ///
+    /// Kinds are always sent as strings, so an enum of kinds can be passed to [`Builder::host`]
+    /// and [`Builder::search`] by converting it into a `String`, and read back with this.
+    ///
+    /// # Errors
+    ///
+    /// Returns the error from parsing the kind if it isn't a known kind.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use std::str::FromStr;
+    ///
+    /// #[derive(Debug, PartialEq)]
+    /// enum Kind {
+    ///     Hello,
+    ///     Goodbye,
+    /// }
+    ///
+    /// impl From<Kind> for String {
+    ///     fn from(kind: Kind) -> Self {
+    ///         match kind {
+    ///             Kind::Hello => "hello".into(),
+    ///             Kind::Goodbye => "goodbye".into(),
+    ///         }
+    ///     }
+    /// }
+    ///
+    /// impl FromStr for Kind {
+    ///     type Err = String;
+    ///
+    ///     fn from_str(s: &str) -> Result<Self, Self::Err> {
+    ///         match s {
+    ///             "hello" => Ok(Kind::Hello),
+    ///             "goodbye" => Ok(Kind::Goodbye),
+    ///             _ => Err(format!("unknown kind `{s}`")),
+    ///         }
+    ///     }
+    /// }
+    ///
+    /// let builder = udis::Udis::new("client").search(Kind::Hello);
+    ///
+    /// let info = udis::ServiceInfo::new("server", Kind::Hello, [192, 168, 0, 1].into(), 4112);
+    /// assert_eq!(info.kind_as::<Kind>(), Ok(Kind::Hello));
+    /// ```
+    pub fn kind_as<K: FromStr>(&self) -> Result<K, K::Err> {
+        self.kind.parse()
+    }
+
+    /// The fields which identify the service, which are compared and hashed
+    #[allow(clippy::type_complexity)]
+    fn identity(
+        &self,
+    ) -> (
+        (&str, &str),
+        (&IpAddr, &[IpAddr], u16, &HostEndpoint, &SocketAddr),
+        &[(String, u16)],
+        &BTreeMap<String, String>,
+        (u8, u16, &Option<Duration>),
+        (&Option<SocketAddrV4>, &Option<String>, &Option<IpAddr>),
+    ) {
+        // Destructure so that new fields have to be added here or deliberately left out
+        let Self {
+            name,
+            kind,
+            addr,
+            addrs,
+            port,
+            endpoint,
+            observed_from,
+            peer_services,
+            metadata,
+            priority,
+            weight,
+            ttl,
+            group,
+            description,
+            proxied_by,
+            seq: _,
+            discovered_after: _,
+            reply: _,
+            extra: _,
+        } = self;
+
+        (
+            (name, kind),
+            (addr, addrs, *port, endpoint, observed_from),
+            peer_services,
+            metadata,
+            (*priority, *weight, ttl),
+            (group, description, proxied_by),
+        )
+    }
+}
+
+impl PartialEq for ServiceInfo {
+    fn eq(&self, other: &Self) -> bool {
+        self.identity() == other.identity()
+    }
+}
+
+impl Eq for ServiceInfo {}
+
+impl Hash for ServiceInfo {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.identity().hash(state);
+    }
+}
+
+/// Where a service is hosted
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, Hash)]
+#[serde(from = "wire::WireEndpoint", into = "wire::WireEndpoint")]
+pub enum HostEndpoint {
+    /// The service is hosted on this port of the endpoint's address
+    Tcp(u16),
+
+    /// The service is hosted on a Unix domain socket at this path, so is only reachable from the
+    /// endpoint's own machine
+    Unix(PathBuf),
+}
+
+impl HostEndpoint {
+    /// Get the port the service is hosted on, if it is hosted on one
+    pub fn port(&self) -> Option<u16> {
+        match self {
+            HostEndpoint::Tcp(port) => Some(*port),
+            HostEndpoint::Unix(_) => None,
+        }
+    }
+}
+
+impl fmt::Display for HostEndpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HostEndpoint::Tcp(port) => write!(f, "port {port}"),
+            HostEndpoint::Unix(path) => write!(f, "{}", path.display()),
+        }
+    }
+}
+
+/// Events relating to the services hosted by a udis endpoint, only passed on by endpoints built
+/// with [`Builder::host_events`]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum HostEvent {
+    /// A peer has acknowledged that it received one of our hosted services. Acknowledgements are
+    /// multicast to the whole discovery network, naming the host they're meant for.
+    Acknowledged {
+        /// The name of the peer which acknowledged the service
+        by: String,
+
+        /// The kind of the service which was acknowledged
+        kind: String,
+    },
+
+    /// A peer has started searching for one of our hosted services, either because it's new to
+    /// the discovery network or because it has changed what it searches for. The endpoint replies
+    /// to the peer automatically.
+    NewInterest {
+        /// The name of the peer which wants the service
+        peer: String,
+
+        /// The kind of the service the peer wants
+        kind: String,
+    },
+
+    /// The endpoint has replied to a peer that wants one of our hosted services, either directly
+    /// if a [`Builder::unicast_port`] is set or by re-announcing to the discovery network.
+    ///
+    /// This follows the [`HostEvent::NewInterest`] for the same peer and service.
+    Replied {
+        /// The name of the peer which was replied to
+        peer: String,
+
+        /// The kind of the service the peer wants
+        to_kind: String,
+    },
+}
+
+/// Events relating to the services found by a udis endpoint
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum ServiceEvent {
+    /// A found service's TTL ran out without its host announcing it again, so it should no longer
+    /// be used. If the host announces it again later it will be found again.
+    Expired(ServiceInfo),
+}
+
+/// A message sent over the discovery network, see [`Message::encode`] for how it is sent.
+///
+/// The serde representation is the format used before the wire format was versioned, which is
+/// still accepted from older endpoints. This is untagged so that notify messages are just the
+/// serialised [`Udis`] struct.
+#[derive(Debug, Clone, Deserialize, PartialEq, Eq)]
+#[serde(untagged)]
+pub(crate) enum Message {
+    /// Announces an endpoint and the services it hosts and searches for
+    Notify(Udis),
+
+    /// A notify message sent straight to a peer which searches for one of our services, in reply
+    /// to its announcement
+    #[serde(skip)]
+    Reply(Udis),
+
+    /// A notify message asking every host of a service the endpoint searches for to reply to it,
+    /// even if the host already knows about it, see [`sync::SyncUdis::refresh`]
+    #[serde(skip)]
+    Query(Udis),
+
+    /// A message relayed from another network by an endpoint bridging the networks, see
+    /// [`builder::Builder::relay_between`]
+    #[serde(skip)]
+    Relayed(Box<Message>),
+
+    /// Acknowledges that a peer received a service hosted by another endpoint
+    Ack { ack: Ack },
+
+    /// Sent by an endpoint when it leaves the discovery network
+    Goodbye { goodbye: PeerId },
+
+    /// Asks the endpoints at an address which services they host, see [`sync::SyncUdis::probe`]
+    #[serde(skip)]
+    Probe(Probe),
+}
+
+/// Sent to the whole discovery network by a peer when it discovers one of a host's services, which
+/// only the host named in it pays attention to
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub(crate) struct Ack {
+    /// The name of the endpoint acknowledging the service
+    pub(crate) name: String,
+
+    /// The name of the endpoint hosting the service
+    pub(crate) host: String,
+
+    /// The kind of the service being acknowledged
+    pub(crate) kind: String,
+}
+
+/// Sent to the whole discovery network by an endpoint probing a peer, which only the endpoints at
+/// the probed address reply to
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Probe {
+    /// The name of the endpoint probing the peer
+    pub(crate) name: String,
+
+    /// The address of the endpoint probing the peer
+    pub(crate) addr: IpAddr,
+
+    /// The address of the peer being probed
+    pub(crate) target: IpAddr,
+}
+
+/// The stable identity of a udis endpoint, used to recognise the same peer across notify messages
+/// even if the services it hosts or searches for have changed.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, Hash)]
+pub(crate) struct PeerId {
+    name: String,
+    addr: IpAddr,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, Hash)]
 enum Service {
-    Host { kind: String, port: u16 },
-    Search { kind: String },
+    Host {
+        kind: String,
+
+        /// Flattened so that services hosted on a port look the same as they always have
+        #[serde(flatten)]
+        endpoint: HostEndpoint,
+
+        /// Left out of the notify message when empty, so endpoints without metadata look the same
+        /// as they always have
+        #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+        metadata: BTreeMap<String, String>,
+
+        /// Left out of the notify message when zero, for the same reason as the metadata
+        #[serde(default, skip_serializing_if = "wire::is_zero")]
+        priority: u8,
+
+        #[serde(default, skip_serializing_if = "wire::is_zero")]
+        weight: u16,
+
+        /// How many seconds the service is valid for after each announcement, where zero means
+        /// forever
+        #[serde(default, skip_serializing_if = "wire::is_zero")]
+        ttl: u32,
+
+        /// Human readable description of the service, left out of the notify message when there
+        /// isn't one
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        description: Option<String>,
+
+        /// Address of the machine which really hosts the service, if the endpoint only advertises
+        /// it on the machine's behalf, left out of the notify message otherwise
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        proxy: Option<IpAddr>,
+    },
+    Search {
+        kind: String,
+    },
 }
 
 impl Udis {
@@ -104,36 +639,224 @@ impl Udis {
         Builder::new(name.into())
     }
 
+    /// The name the endpoint advertises itself with
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The address the endpoint advertises itself on
+    pub fn addr(&self) -> IpAddr {
+        self.addr
+    }
+
     pub(crate) fn build(name: String, addr: IpAddr, services: Vec<Service>) -> Self {
         Self {
             name,
             addr,
             services,
+            extra_addrs: Vec::new(),
+            extra: Arc::default(),
         }
     }
 
+    pub(crate) fn with_extra_addrs(mut self, extra_addrs: Vec<IpAddr>) -> Self {
+        self.extra_addrs = extra_addrs;
+        self
+    }
+
+    pub(crate) fn with_extra(mut self, extra: Arc<[u8]>) -> Self {
+        self.extra = extra;
+        self
+    }
+
+    /// Every address the endpoint advertises, in order of preference
+    pub(crate) fn addrs(&self) -> Vec<IpAddr> {
+        std::iter::once(self.addr)
+            .chain(self.extra_addrs.iter().copied())
+            .collect()
+    }
+
     pub(crate) fn get_wanted_services<'a>(
         &'a self,
         peer: &'a Udis,
     ) -> impl Iterator<Item = &'a Service> {
+        // Collect the kinds the peer searches for first, so endpoints hosting lots of services
+        // don't compare every one against every search
+        let searched = peer.searched_kinds();
+        let all = searched.contains(WILDCARD_KIND);
+
+        self.services.iter().filter(move |s| {
+            matches!(s, Service::Host { kind, .. } if all || searched.contains(kind.as_str()))
+        })
+    }
+
+    pub(crate) fn id(&self) -> PeerId {
+        PeerId {
+            name: self.name.clone(),
+            addr: self.addr,
+        }
+    }
+
+    /// Name for the endpoint's background worker, so it can be picked out in a debugger. Thread
+    /// names can't contain nul characters so any in the endpoint's name are left out.
+    pub(crate) fn worker_name(&self) -> String {
+        format!("udis-{}", self.name.replace('\0', ""))
+    }
+
+    /// Get the kind and port of every service hosted by this endpoint
+    pub(crate) fn hosted_services(&self) -> Vec<(String, u16)> {
+        self.services
+            .iter()
+            .filter_map(|s| match s {
+                Service::Host { kind, endpoint, .. } => {
+                    endpoint.port().map(|port| (kind.clone(), port))
+                }
+                Service::Search { .. } => None,
+            })
+            .collect()
+    }
+
+    /// Whether the endpoint hosts a service of `kind`
+    pub fn hosts(&self, kind: &str) -> bool {
+        self.services
+            .iter()
+            .any(|s| matches!(s, Service::Host { kind: k, .. } if k == kind))
+    }
+
+    /// Get the services of this endpoint wanted by `peer` which weren't already wanted by the
+    /// previous notify message we received from that peer, `prev_peer`.
+    pub(crate) fn get_services_newly_wanted_by<'a>(
+        &'a self,
+        peer: &'a Udis,
+        prev_peer: Option<&'a Udis>,
+    ) -> impl Iterator<Item = &'a Service> {
+        let prev_searched = prev_peer.map(Udis::searched_kinds).unwrap_or_default();
+        let prev_all = prev_searched.contains(WILDCARD_KIND);
+
+        self.get_wanted_services(peer)
+            .filter(move |s| !prev_all && !prev_searched.contains(s.kind()))
+    }
+
+    /// The kinds of service this endpoint searches for
+    fn searched_kinds(&self) -> HashSet<&str> {
         self.services
             .iter()
-            .filter(|s| peer.services.iter().any(|p| s.wanted_by(p)))
+            .filter_map(|s| match s {
+                Service::Search { kind } => Some(kind.as_str()),
+                Service::Host { .. } => None,
+            })
+            .collect()
+    }
+
+    /// Get the services of this endpoint wanted by `peer` which weren't in the previous notify
+    /// message we received from this endpoint, `prev`.
+    pub(crate) fn get_wanted_services_added_since<'a>(
+        &'a self,
+        prev: Option<&'a Udis>,
+        peer: &'a Udis,
+    ) -> impl Iterator<Item = &'a Service> {
+        self.get_wanted_services(peer)
+            .filter(move |s| !prev.is_some_and(|prev| prev.services.contains(s)))
     }
 }
 
 impl Service {
-    fn wanted_by(&self, peer_service: &Service) -> bool {
-        if let (
-            Service::Host { kind, .. },
-            Service::Search {
-                kind: peer_wanted_kind,
-            },
-        ) = (self, peer_service)
-        {
-            kind == peer_wanted_kind
-        } else {
-            false
+    /// The kind of the service, whether hosted or searched for
+    fn kind(&self) -> &str {
+        match self {
+            Service::Host { kind, .. } | Service::Search { kind } => kind,
+        }
+    }
+
+    /// Whether this is a search for every kind of service
+    fn is_search_all(&self) -> bool {
+        matches!(self, Service::Search { kind } if kind == WILDCARD_KIND)
+    }
+}
+
+/// A service hosted on TCP `port` with nothing else set, for building peers in tests
+#[cfg(test)]
+pub(crate) fn tcp_host(kind: &str, port: u16) -> Service {
+    Service::Host {
+        kind: kind.into(),
+        endpoint: HostEndpoint::Tcp(port),
+        metadata: Default::default(),
+        priority: 0,
+        weight: 0,
+        ttl: 0,
+        description: None,
+        proxy: None,
+    }
+}
+
+/// Shows the endpoint as `name@addr hosting [kind:port, ...] searching [kind, ...]`, leaving out
+/// the hosted or searched services if there are none
+impl fmt::Display for Udis {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}@{}", self.name, self.addr)?;
+
+        let hosted: Vec<_> = self
+            .services
+            .iter()
+            .filter_map(|service| match service {
+                Service::Host {
+                    kind,
+                    endpoint,
+                    proxy,
+                    ..
+                } => Some(ServiceTarget(kind, endpoint, *proxy)),
+                Service::Search { .. } => None,
+            })
+            .map(|target| target.to_string())
+            .collect();
+        if !hosted.is_empty() {
+            write!(f, " hosting [{}]", hosted.join(", "))?;
+        }
+
+        let searched: Vec<_> = self
+            .services
+            .iter()
+            .filter_map(|service| match service {
+                Service::Search { kind } => Some(kind.as_str()),
+                Service::Host { .. } => None,
+            })
+            .collect();
+        if !searched.is_empty() {
+            write!(f, " searching [{}]", searched.join(", "))?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Shows hosted services as `hosting kind:port` or `hosting kind:path`, and searches as
+/// `searching kind`
+impl fmt::Display for Service {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Service::Host {
+                kind,
+                endpoint,
+                proxy,
+                ..
+            } => write!(f, "hosting {}", ServiceTarget(kind, endpoint, *proxy)),
+            Service::Search { kind } => write!(f, "searching {kind}"),
+        }
+    }
+}
+
+/// A hosted service's kind and where it's hosted, shown as `kind:port` or `kind:path`, or as
+/// `kind:addr:port` for proxied services
+struct ServiceTarget<'a>(&'a str, &'a HostEndpoint, Option<IpAddr>);
+
+impl fmt::Display for ServiceTarget<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (self.1, self.2) {
+            (HostEndpoint::Tcp(port), Some(proxy)) => {
+                write!(f, "{}:{}", self.0, SocketAddr::new(proxy, *port))
+            }
+            (HostEndpoint::Tcp(port), None) => write!(f, "{}:{port}", self.0),
+            (HostEndpoint::Unix(path), _) => write!(f, "{}:{}", self.0, path.display()),
         }
     }
 }
@@ -1,8 +1,25 @@
-use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::{
+    collections::{hash_map::RandomState, HashSet},
+    hash::{BuildHasher, Hasher},
+    io::{self, ErrorKind},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6},
+    str::FromStr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
+use log::{error, trace, warn};
 use socket2::{Domain, Protocol, Socket, Type};
 
-use crate::error::Error;
+use crate::{
+    builder::BindMode,
+    config::{Config, ExternalSocket},
+    diagnostics::SharedDiagnostics,
+    error::Error,
+    sync::POLL_PERIOD,
+    transport::recv_from,
+    Message, PeerId, Service, Udis, WILDCARD_KIND,
+};
 
 /// Multicast port used for udis traffic
 pub const MULTICAST_PORT: u16 = 8787;
@@ -11,27 +28,708 @@ pub const MULTICAST_PORT: u16 = 8787;
 /// networks.
 pub static MULTICAST_ADDR: Ipv4Addr = Ipv4Addr::new(224, 0, 0, 87);
 
-/// Build the multicast socket for use in udis endpoints
-pub fn build_multicast_socket() -> Result<(SocketAddr, Socket), Error> {
+/// IPv6 multicast address used for udis traffic when IPv6 is enabled by the [`BindMode`], this is
+/// the link-local scoped equivalent of [`MULTICAST_ADDR`].
+pub static MULTICAST_ADDR_V6: Ipv6Addr = Ipv6Addr::new(0xff02, 0, 0, 0, 0, 0, 0, 0x87);
+
+/// How messages are delivered to the rest of the discovery network
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Delivery {
+    /// Send to the udis multicast groups, which are joined to receive messages
+    #[default]
+    Multicast,
+
+    /// Send to the IPv4 broadcast address, for networks which drop multicast traffic
+    Broadcast,
+}
+
+/// Log target used for everything on the receive side of the udis network
+pub const RX_LOG_TARGET: &str = "udis::net::rx";
+
+/// Log target used for everything on the send side of the udis network
+pub const TX_LOG_TARGET: &str = "udis::net::tx";
+
+/// Largest payload that fits in a single UDP datagram over IPv4, in bytes
+pub(crate) const MAX_DATAGRAM_SIZE: usize = 65_507;
+
+/// Build the multicast socket for use in udis endpoints, returning the addresses of the discovery
+/// network groups notify messages should be sent to along with the socket.
+pub fn build_multicast_socket(
+    bind_mode: BindMode,
+    device: Option<&str>,
+    dscp: Option<u8>,
+) -> Result<(Vec<SocketAddr>, Socket), Error> {
     // Get the addresses
-    let disc_addr = SocketAddrV4::new(MULTICAST_ADDR, MULTICAST_PORT);
+    let (domain, bind_addr) = bind_domain(bind_mode);
 
     // Build the multicast socket
+    let socket = Socket::new(domain, Type::DGRAM, Some(Protocol::UDP))?;
+    socket.set_reuse_address(true)?;
+    if domain == Domain::IPV6 {
+        socket.set_only_v6(bind_mode == BindMode::V6Only)?;
+    }
+    bind_device(&socket, device)?;
+    set_dscp(&socket, bind_mode, dscp)?;
+    join_multicast_group(&socket, bind_mode, device)?;
+    socket.bind(&SocketAddr::new(bind_addr, MULTICAST_PORT).into())?;
+
+    Ok((discovery_addrs(bind_mode, Delivery::Multicast), socket))
+}
+
+/// Build the broadcast socket for use in udis endpoints which can't use multicast, returning the
+/// broadcast address notify messages should be sent to along with the socket.
+///
+/// Broadcast only exists in IPv4, so this is always an IPv4 socket.
+pub fn build_broadcast_socket(
+    device: Option<&str>,
+    dscp: Option<u8>,
+) -> Result<(Vec<SocketAddr>, Socket), Error> {
+    let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+    socket.set_reuse_address(true)?;
+    bind_device(&socket, device)?;
+    set_dscp(&socket, BindMode::V4Only, dscp)?;
+    enable_broadcast(&socket)?;
+    socket.bind(&SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), MULTICAST_PORT).into())?;
+
+    Ok((
+        discovery_addrs(BindMode::V4Only, Delivery::Broadcast),
+        socket,
+    ))
+}
+
+/// Build the non-blocking socket used for unicast traffic when the endpoint has a unicast port.
+///
+/// Unlike the multicast socket this doesn't allow address reuse, as the port should belong to this
+/// endpoint alone. As messages to the whole discovery network are sent from this socket it needs
+/// to be able to broadcast if that's how messages are delivered.
+pub fn build_unicast_socket(
+    port: u16,
+    bind_mode: BindMode,
+    delivery: Delivery,
+    device: Option<&str>,
+    dscp: Option<u8>,
+) -> Result<Socket, Error> {
+    let (domain, bind_addr) = bind_domain(bind_mode);
+
+    let socket = Socket::new(domain, Type::DGRAM, Some(Protocol::UDP))?;
+    if domain == Domain::IPV6 {
+        socket.set_only_v6(bind_mode == BindMode::V6Only)?;
+    }
+    match delivery {
+        Delivery::Multicast => enable_multicast_loop(&socket, bind_mode),
+        Delivery::Broadcast => socket.set_broadcast(true)?,
+    }
+    bind_device(&socket, device)?;
+    set_dscp(&socket, bind_mode, dscp)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&SocketAddr::new(bind_addr, port).into())?;
+
+    Ok(socket)
+}
+
+/// Build the non-blocking socket used to receive messages sent to an extra multicast `group`, on
+/// the network device if there is one.
+///
+/// The socket is bound to the group's port, so it receives messages sent to that port on any group
+/// this machine has joined. Extra groups should each have their own port so the group a message
+/// came from is known.
+pub fn build_group_socket(group: SocketAddrV4, device: Option<&str>) -> Result<Socket, Error> {
+    check_multicast((*group.ip()).into())?;
+
+    let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+    socket.set_reuse_address(true)?;
+    bind_device(&socket, device)?;
+    socket.set_nonblocking(true)?;
+
+    let interface = device
+        .and_then(device_addr_v4)
+        .unwrap_or(Ipv4Addr::UNSPECIFIED);
+    socket.join_multicast_v4(group.ip(), &interface)?;
+    socket.bind(&SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), group.port()).into())?;
+
+    Ok(socket)
+}
+
+/// Build the non-blocking socket used to relay messages to and from the udis multicast group on
+/// the interface with the address `interface`.
+///
+/// The socket only receives messages from the group which arrive on the interface, and only sends
+/// on it, so a relay can tell which network a message came from. It's bound to the group's
+/// address rather than the unspecified one, so it never receives unicast messages sent to the udis
+/// port, which are meant for the machine's endpoints rather than the relay.
+#[cfg(target_os = "linux")]
+pub fn build_relay_socket(interface: Ipv4Addr, dscp: Option<u8>) -> Result<Socket, Error> {
     let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
     socket.set_reuse_address(true)?;
     socket.set_nonblocking(true)?;
-    socket.join_multicast_v4(&MULTICAST_ADDR, &Ipv4Addr::UNSPECIFIED)?;
-    socket.bind(&SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, MULTICAST_PORT).into())?;
+    set_dscp(&socket, BindMode::V4Only, dscp)?;
+
+    // Otherwise the socket receives messages from the group on every interface any socket on this
+    // machine has joined it on
+    socket.set_multicast_all_v4(false)?;
+    socket.join_multicast_v4(&MULTICAST_ADDR, &interface)?;
+    socket.set_multicast_if_v4(&interface)?;
+
+    // Messages relayed onto the interface don't need to come back to this machine, as they were
+    // received here in the first place
+    socket.set_multicast_loop_v4(false)?;
+
+    // Every endpoint on this machine shares the udis port, and a unicast message to it is only
+    // received by one of the sockets bound to it, which mustn't be the relay's. Peers always reply
+    // to relayed messages by multicast, so the relay doesn't need unicast messages itself.
+    socket.bind(&SocketAddr::new(MULTICAST_ADDR.into(), MULTICAST_PORT).into())?;
+
+    Ok(socket)
+}
+
+/// Relaying needs `IP_MULTICAST_ALL`, which is only supported on Linux
+#[cfg(not(target_os = "linux"))]
+pub fn build_relay_socket(_interface: Ipv4Addr, _dscp: Option<u8>) -> Result<Socket, Error> {
+    Err(Error::Unsupported("relaying messages between interfaces"))
+}
+
+/// Get the socket domain and unspecified address to bind to for the bind mode
+fn bind_domain(bind_mode: BindMode) -> (Domain, IpAddr) {
+    match bind_mode {
+        BindMode::V4Only => (Domain::IPV4, IpAddr::from(Ipv4Addr::UNSPECIFIED)),
+        BindMode::V6Only | BindMode::DualStack => {
+            (Domain::IPV6, IpAddr::from(Ipv6Addr::UNSPECIFIED))
+        }
+    }
+}
+
+/// Make the socket non-blocking and join the udis multicast groups for the bind mode on it, on
+/// the network device if there is one
+pub fn join_multicast_group(
+    socket: &Socket,
+    bind_mode: BindMode,
+    device: Option<&str>,
+) -> Result<(), Error> {
+    socket.set_nonblocking(true)?;
+
+    if bind_mode != BindMode::V6Only {
+        // Otherwise the group would be joined on the default interface, which may not be the
+        // device the socket is bound to
+        let interface = device
+            .and_then(device_addr_v4)
+            .unwrap_or(Ipv4Addr::UNSPECIFIED);
+        socket.join_multicast_v4(&MULTICAST_ADDR, &interface)?;
+    }
+
+    if bind_mode != BindMode::V4Only {
+        socket.join_multicast_v6(&MULTICAST_ADDR_V6, 0)?;
+    }
+
+    enable_multicast_loop(socket, bind_mode);
+
+    Ok(())
+}
+
+/// Check that `addr` is a multicast address before joining it, as the OS only gives an opaque
+/// error for joining a unicast address
+fn check_multicast(addr: IpAddr) -> Result<(), Error> {
+    if addr.is_multicast() {
+        Ok(())
+    } else {
+        Err(Error::NotAMulticastAddr { addr })
+    }
+}
+
+/// Make sure multicast messages sent from the socket are delivered back to this machine, so
+/// endpoints in the same process or on the same machine can find each other.
+///
+/// This is the default on most platforms but isn't guaranteed. Failing to enable it isn't fatal,
+/// as only endpoints on other machines are affected.
+fn enable_multicast_loop(socket: &Socket, bind_mode: BindMode) {
+    if bind_mode != BindMode::V6Only {
+        if let Err(e) = socket.set_multicast_loop_v4(true) {
+            warn!("couldn't enable IPv4 multicast loopback, local endpoints may not be found: {e}");
+        }
+    }
+
+    if bind_mode != BindMode::V4Only {
+        if let Err(e) = socket.set_multicast_loop_v6(true) {
+            warn!("couldn't enable IPv6 multicast loopback, local endpoints may not be found: {e}");
+        }
+    }
+}
+
+/// Bind the socket to the network device if there is one, so it only sends and receives on that
+/// interface
+#[cfg(any(target_os = "android", target_os = "fuchsia", target_os = "linux"))]
+pub fn bind_device(socket: &Socket, device: Option<&str>) -> Result<(), Error> {
+    if let Some(device) = device {
+        socket.bind_device(Some(device.as_bytes()))?;
+    }
+
+    Ok(())
+}
+
+/// Binding to a network device is only supported on Linux-like platforms
+#[cfg(not(any(target_os = "android", target_os = "fuchsia", target_os = "linux")))]
+pub fn bind_device(_socket: &Socket, device: Option<&str>) -> Result<(), Error> {
+    match device {
+        Some(_) => Err(Error::Unsupported("binding to a network device")),
+        None => Ok(()),
+    }
+}
+
+/// Mark packets sent from the socket with the DSCP value `dscp` if there is one, so networks with
+/// QoS policies can tell discovery traffic apart.
+///
+/// The DSCP is the top six bits of the IPv4 ToS or IPv6 traffic class, the bottom two being left
+/// for ECN.
+pub fn set_dscp(socket: &Socket, bind_mode: BindMode, dscp: Option<u8>) -> Result<(), Error> {
+    let Some(dscp) = dscp else {
+        return Ok(());
+    };
+    if dscp > 63 {
+        return Err(Error::InvalidDscp(dscp));
+    }
+    let tos = u32::from(dscp) << 2;
+
+    if bind_mode != BindMode::V6Only {
+        set_tos_v4(socket, tos)?;
+    }
+    if bind_mode != BindMode::V4Only {
+        set_tclass_v6(socket, tos)?;
+    }
+
+    Ok(())
+}
+
+#[cfg(not(any(
+    target_os = "fuchsia",
+    target_os = "redox",
+    target_os = "solaris",
+    target_os = "illumos",
+    target_os = "haiku",
+)))]
+fn set_tos_v4(socket: &Socket, tos: u32) -> Result<(), Error> {
+    socket.set_tos(tos)?;
+    Ok(())
+}
+
+/// Setting the IPv4 ToS isn't supported on every platform
+#[cfg(any(
+    target_os = "fuchsia",
+    target_os = "redox",
+    target_os = "solaris",
+    target_os = "illumos",
+    target_os = "haiku",
+))]
+fn set_tos_v4(_socket: &Socket, _tos: u32) -> Result<(), Error> {
+    Err(Error::Unsupported("setting the DSCP of IPv4 packets"))
+}
+
+#[cfg(any(
+    target_os = "android",
+    target_os = "dragonfly",
+    target_os = "freebsd",
+    target_os = "fuchsia",
+    target_os = "linux",
+    target_os = "macos",
+    target_os = "netbsd",
+    target_os = "openbsd",
+))]
+fn set_tclass_v6(socket: &Socket, tclass: u32) -> Result<(), Error> {
+    socket.set_tclass_v6(tclass)?;
+    Ok(())
+}
 
-    Ok((disc_addr.into(), socket))
+/// Setting the IPv6 traffic class isn't supported on every platform
+#[cfg(not(any(
+    target_os = "android",
+    target_os = "dragonfly",
+    target_os = "freebsd",
+    target_os = "fuchsia",
+    target_os = "linux",
+    target_os = "macos",
+    target_os = "netbsd",
+    target_os = "openbsd",
+)))]
+fn set_tclass_v6(_socket: &Socket, _tclass: u32) -> Result<(), Error> {
+    Err(Error::Unsupported("setting the DSCP of IPv6 packets"))
+}
+
+/// Get the IPv4 address of the network device called `device`, if it has one
+fn device_addr_v4(device: &str) -> Option<Ipv4Addr> {
+    local_ip_address::list_afinet_netifas()
+        .ok()?
+        .into_iter()
+        .find_map(|(name, addr)| match addr {
+            IpAddr::V4(addr) if name == device => Some(addr),
+            _ => None,
+        })
+}
+
+/// Make the socket non-blocking and allow it to send to the broadcast address
+pub fn enable_broadcast(socket: &Socket) -> Result<(), Error> {
+    socket.set_nonblocking(true)?;
+    socket.set_broadcast(true)?;
+
+    Ok(())
+}
+
+/// Get the addresses of the discovery network groups notify messages are sent to
+pub fn discovery_addrs(bind_mode: BindMode, delivery: Delivery) -> Vec<SocketAddr> {
+    if delivery == Delivery::Broadcast {
+        return vec![SocketAddrV4::new(Ipv4Addr::BROADCAST, MULTICAST_PORT).into()];
+    }
+
+    let v4 = SocketAddrV4::new(MULTICAST_ADDR, MULTICAST_PORT).into();
+    let v6 = SocketAddrV6::new(MULTICAST_ADDR_V6, MULTICAST_PORT, 0, 0).into();
+
+    match bind_mode {
+        BindMode::V4Only => vec![v4],
+        BindMode::V6Only => vec![v6],
+        // IPv4 traffic is sent from an IPv6 socket using the IPv4-mapped address
+        BindMode::DualStack => vec![
+            SocketAddrV6::new(MULTICAST_ADDR.to_ipv6_mapped(), MULTICAST_PORT, 0, 0).into(),
+            v6,
+        ],
+    }
+}
+
+/// Get the socket for the worker to use, either the one supplied by the user or a newly built one
+pub fn discovery_socket(
+    external: Option<ExternalSocket>,
+    bind_mode: BindMode,
+    delivery: Delivery,
+    device: Option<&str>,
+    dscp: Option<u8>,
+) -> Result<(Vec<SocketAddr>, Socket), Error> {
+    let Some(external) = external else {
+        return match delivery {
+            Delivery::Multicast => build_multicast_socket(bind_mode, device, dscp),
+            Delivery::Broadcast => build_broadcast_socket(device, dscp),
+        };
+    };
+
+    // If the builder was cloned the socket may be shared with another endpoint, in which case use
+    // a duplicate of it
+    let socket = Arc::try_unwrap(external.socket).or_else(|socket| socket.try_clone())?;
+
+    if !external.prepared {
+        bind_device(&socket, device)?;
+        set_dscp(&socket, bind_mode, dscp)?;
+
+        match delivery {
+            Delivery::Multicast => join_multicast_group(&socket, bind_mode, device)?,
+            Delivery::Broadcast => enable_broadcast(&socket)?,
+        }
+    }
+
+    Ok((discovery_addrs(bind_mode, delivery), socket))
+}
+
+/// Check that messages sent to the discovery network from `send_socket` reach it, by sending a
+/// goodbye from a made up endpoint to `disc_addrs` and waiting up to `timeout` for loopback to
+/// deliver it back to `socket`.
+///
+/// Peers ignore goodbyes from endpoints they don't know, so the test message doesn't disturb them.
+/// Any other messages received while waiting are dropped.
+pub fn verify_discovery(
+    socket: &Socket,
+    send_socket: &Socket,
+    disc_addrs: &[SocketAddr],
+    timeout: Duration,
+) -> Result<(), Error> {
+    // The std hasher is randomly seeded, so this won't clash with any other endpoint verifying
+    let nonce = RandomState::new().build_hasher().finish();
+    let test = Message::Goodbye {
+        goodbye: PeerId {
+            name: format!("udis-verify-{nonce:016x}"),
+            addr: Ipv4Addr::UNSPECIFIED.into(),
+        },
+    }
+    .encode()
+    .map_err(Error::FailedToSerialiseGoodbyeMsg)?;
+
+    for disc_addr in disc_addrs {
+        send_socket.send_to(&test, &(*disc_addr).into())?;
+    }
+
+    let deadline = Instant::now() + timeout;
+    let mut buf = Vec::with_capacity(MAX_DATAGRAM_SIZE);
+    while Instant::now() < deadline {
+        match recv_from(socket, &mut buf) {
+            Ok(_) if buf == test => {
+                trace!("test message came back from the discovery network");
+                return Ok(());
+            }
+            Ok(src) => trace!(target: RX_LOG_TARGET, "dropping packet from {src} while verifying"),
+            Err(e) if e.kind() == ErrorKind::WouldBlock => std::thread::sleep(POLL_PERIOD),
+            Err(e) => return Err(e.into()),
+        }
+    }
+
+    Err(Error::MulticastUnreachable)
+}
+
+/// Find the names of the endpoints on the discovery network, by querying for every kind of service
+/// from `addr` and listening for `timeout` for hosts to reply.
+///
+/// Peers answer queries without remembering who sent them, so there's nothing to say goodbye to
+/// afterwards. Only hosts reply, so endpoints which just search for services are only found if
+/// they announce themselves while listening.
+pub fn names_in_use(
+    config: &Config,
+    addr: IpAddr,
+    timeout: Duration,
+) -> Result<HashSet<String>, Error> {
+    let (disc_addrs, socket) = discovery_socket(
+        None,
+        config.bind_mode(),
+        config.delivery,
+        config.device.as_deref(),
+        config.dscp,
+    )?;
+
+    // The std hasher is randomly seeded, so this won't clash with any other endpoint listening
+    let nonce = RandomState::new().build_hasher().finish();
+    let probe = Udis::build(
+        format!("udis-probe-{nonce:016x}"),
+        addr,
+        vec![Service::Search {
+            kind: WILDCARD_KIND.into(),
+        }],
+    );
+    let query = Message::Query(probe.clone())
+        .encode()
+        .map_err(Error::FailedToSerialiseNotifyMsg)?;
+
+    // The endpoint being built doesn't have diagnostics yet, so transient errors are only logged
+    let diag = SharedDiagnostics::default();
+    for disc_addr in &disc_addrs {
+        if let Err(e) = socket.send_to(&query, &(*disc_addr).into()) {
+            ignore_transient_send_error(e, &diag)?;
+        }
+    }
+
+    let deadline = Instant::now() + timeout;
+    let mut names = HashSet::new();
+    let mut buf = Vec::with_capacity(config.recv_buffer_size());
+    while Instant::now() < deadline {
+        let src = match recv_from(&socket, &mut buf) {
+            Ok(src) => src,
+            Err(e) if e.kind() == ErrorKind::WouldBlock => {
+                std::thread::sleep(POLL_PERIOD);
+                continue;
+            }
+            Err(e) => return Err(e.into()),
+        };
+
+        let peer =
+            match Message::decode(&buf, config.max_message_size(), config.max_message_depth()) {
+                Ok(Message::Notify(peer) | Message::Reply(peer)) => peer,
+                Ok(Message::Relayed(msg)) => match *msg {
+                    Message::Notify(peer) | Message::Reply(peer) => peer,
+                    _ => continue,
+                },
+                Ok(_) => continue,
+                Err(e) => {
+                    trace!(target: RX_LOG_TARGET, "dropping bad packet from {src}: {e}");
+                    continue;
+                }
+            };
+
+        if peer.name != probe.name {
+            trace!(target: RX_LOG_TARGET, "heard from `{}` at {src}", peer.name);
+            names.insert(peer.name);
+        }
+    }
+
+    Ok(names)
+}
+
+/// Whether an error from the socket is likely to go away by itself, such as the network being
+/// briefly unreachable, in which case the worker should carry on
+pub fn is_transient(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        ErrorKind::WouldBlock
+            | ErrorKind::TimedOut
+            | ErrorKind::Interrupted
+            | ErrorKind::ConnectionRefused
+            | ErrorKind::ConnectionReset
+            | ErrorKind::NetworkUnreachable
+            | ErrorKind::HostUnreachable
+            | ErrorKind::NetworkDown
+            | ErrorKind::AddrNotAvailable
+    )
+}
+
+/// Log an error sending a message and carry on if it's transient, recording it in `diag`,
+/// otherwise return it so the worker stops
+pub fn ignore_transient_send_error(e: io::Error, diag: &SharedDiagnostics) -> Result<(), Error> {
+    if !is_transient(&e) {
+        return Err(e.into());
+    }
+
+    error!(
+        target: TX_LOG_TARGET,
+        "Error while sending udis message (will continue): ({:?}) {e}",
+        e.kind()
+    );
+    diag.record_error(e.into());
+    Ok(())
+}
+
+/// Get the address to send a packet for `addr` to on a socket bound for the bind mode, as IPv4
+/// addresses must be IPv4-mapped on a dual-stack socket.
+pub fn send_addr(addr: SocketAddr, bind_mode: BindMode) -> SocketAddr {
+    match addr {
+        SocketAddr::V4(addr) if bind_mode == BindMode::DualStack => {
+            SocketAddrV6::new(addr.ip().to_ipv6_mapped(), addr.port(), 0, 0).into()
+        }
+        addr => addr,
+    }
+}
+
+/// Convert a source address received on a dual-stack socket back to IPv4 if it was IPv4-mapped,
+/// so it can be compared with the addresses peers advertise.
+pub fn canonical_addr(addr: SocketAddr) -> SocketAddr {
+    SocketAddr::new(addr.ip().to_canonical(), addr.port())
+}
+
+/// A range of IP addresses, written in CIDR notation such as `192.168.0.0/24`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Subnet {
+    addr: IpAddr,
+    prefix_len: u8,
+}
+
+impl Subnet {
+    /// Whether the address is inside the subnet, IPv4-mapped addresses are treated as IPv4
+    pub fn contains(&self, addr: IpAddr) -> bool {
+        match (self.addr, addr.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(addr)) => {
+                let mask = u32::MAX
+                    .checked_shl(32 - self.prefix_len as u32)
+                    .unwrap_or(0);
+                u32::from(net) & mask == u32::from(addr) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(addr)) => {
+                let mask = u128::MAX
+                    .checked_shl(128 - self.prefix_len as u32)
+                    .unwrap_or(0);
+                u128::from(net) & mask == u128::from(addr) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for Subnet {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || Error::InvalidSubnet(s.to_owned());
+
+        let (addr, prefix_len) = s.split_once('/').ok_or_else(invalid)?;
+        let addr: IpAddr = addr.parse().map_err(|_| invalid())?;
+        let prefix_len: u8 = prefix_len.parse().map_err(|_| invalid())?;
+
+        let max_prefix_len = if addr.is_ipv4() { 32 } else { 128 };
+        if prefix_len > max_prefix_len {
+            return Err(invalid());
+        }
+
+        Ok(Self { addr, prefix_len })
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::net::MULTICAST_ADDR;
+    use std::{
+        net::{Ipv4Addr, SocketAddr, SocketAddrV4},
+        time::Duration,
+    };
+
+    use socket2::{Domain, Protocol, Socket, Type};
+
+    use crate::{
+        builder::BindMode,
+        error::Error,
+        net::{
+            build_group_socket, build_multicast_socket, check_multicast, discovery_addrs, set_dscp,
+            verify_discovery, Delivery, Subnet, MULTICAST_ADDR, MULTICAST_ADDR_V6,
+        },
+        Udis,
+    };
 
     #[test]
     fn test_multicast() {
         assert!(MULTICAST_ADDR.is_multicast());
+        assert!(MULTICAST_ADDR_V6.is_multicast());
+
+        // Unicast groups should be rejected before asking the OS to join them
+        let unicast = Ipv4Addr::new(192, 168, 0, 1);
+        assert!(matches!(
+            check_multicast(unicast.into()),
+            Err(Error::NotAMulticastAddr { addr }) if addr == unicast
+        ));
+        assert!(matches!(
+            build_group_socket(SocketAddrV4::new(unicast, 8788), None),
+            Err(Error::NotAMulticastAddr { .. })
+        ));
+        assert!(matches!(
+            Udis::new("grouped").join_group(unicast, 8788).validate(),
+            Err(Error::NotAMulticastAddr { addr }) if addr == unicast
+        ));
+    }
+
+    #[test]
+    fn test_dscp() {
+        let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP)).unwrap();
+        set_dscp(&socket, BindMode::V4Only, Some(46)).unwrap();
+        assert_eq!(socket.tos().unwrap(), 46 << 2);
+
+        // Only six bits are available for the DSCP
+        assert!(matches!(
+            set_dscp(&socket, BindMode::V4Only, Some(64)),
+            Err(Error::InvalidDscp(64))
+        ));
+    }
+
+    #[test]
+    #[ignore = "uses the real multicast network"]
+    fn test_verify_discovery() {
+        let disc_addrs = discovery_addrs(BindMode::V4Only, Delivery::Multicast);
+
+        // The test message should come back to a socket on the discovery network
+        let (_, socket) = build_multicast_socket(BindMode::V4Only, None, None).unwrap();
+        verify_discovery(&socket, &socket, &disc_addrs, Duration::from_secs(2)).unwrap();
+
+        // But not to a socket which isn't
+        let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP)).unwrap();
+        socket.set_nonblocking(true).unwrap();
+        socket
+            .bind(&SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)).into())
+            .unwrap();
+        assert!(matches!(
+            verify_discovery(&socket, &socket, &disc_addrs, Duration::from_millis(100)),
+            Err(Error::MulticastUnreachable)
+        ));
+    }
+
+    #[test]
+    fn test_subnet() {
+        let subnet: Subnet = "192.168.0.0/24".parse().unwrap();
+        assert!(subnet.contains([192, 168, 0, 42].into()));
+        assert!(!subnet.contains([192, 168, 1, 42].into()));
+        assert!(!subnet.contains("fe80::1".parse().unwrap()));
+        assert!(subnet.contains("::ffff:192.168.0.42".parse().unwrap()));
+
+        let everything: Subnet = "0.0.0.0/0".parse().unwrap();
+        assert!(everything.contains([10, 0, 0, 1].into()));
+
+        let subnet: Subnet = "fd00::/8".parse().unwrap();
+        assert!(subnet.contains("fd12::1".parse().unwrap()));
+        assert!(!subnet.contains("fe80::1".parse().unwrap()));
+
+        assert!("192.168.0.0/33".parse::<Subnet>().is_err());
+        assert!("192.168.0.0".parse::<Subnet>().is_err());
     }
 }
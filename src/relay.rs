@@ -0,0 +1,304 @@
+use std::{
+    io::{self, ErrorKind},
+    net::{Ipv4Addr, SocketAddr},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread::JoinHandle,
+};
+
+use log::{error, trace};
+use polling::{Event, Events, PollMode, Poller};
+use socket2::Socket;
+
+use crate::{
+    config::Config,
+    error::Error,
+    net::{build_relay_socket, MULTICAST_ADDR, MULTICAST_PORT, RX_LOG_TARGET, TX_LOG_TARGET},
+    sync::POLL_PERIOD,
+    transport::{recv_from, Transport},
+    Message,
+};
+
+/// Relays messages between the udis discovery networks on several interfaces, see
+/// [`crate::builder::Builder::relay_between`].
+///
+/// The relay runs on its own thread, so it works the same whichever kind of endpoint starts it,
+/// and stops when dropped.
+#[derive(Debug)]
+pub(crate) struct Relay {
+    /// Set to tell the relay thread to stop
+    stop: Arc<AtomicBool>,
+
+    /// The poller the relay thread waits on
+    poller: Arc<Poller>,
+
+    jh: Option<JoinHandle<()>>,
+}
+
+impl Relay {
+    /// Start relaying between the interfaces in the config, if there are any
+    pub(crate) fn start(config: &Config) -> Result<Option<Self>, Error> {
+        if config.relay_between.is_empty() {
+            return Ok(None);
+        }
+
+        // Sockets are registered level-triggered like the endpoint's own, and polled on platforms
+        // which don't support that
+        let poller = Arc::new(Poller::new()?);
+        let polls = !poller.supports_level();
+
+        let links = config
+            .relay_between
+            .iter()
+            .map(|&interface| {
+                trace!("relaying messages on interface {interface}");
+                let socket = RelaySocket {
+                    socket: build_relay_socket(interface, config.dscp)?,
+                    poller: None,
+                };
+                let socket = socket.register(&poller, polls)?;
+                Ok((interface, Box::new(socket) as Box<dyn Transport>))
+            })
+            .collect::<Result<Vec<_>, Error>>()?;
+
+        Self::spawn(config, links, poller, polls)
+    }
+
+    /// Start relaying between the transports in `links`, which are polled for messages as they
+    /// can't wake the relay thread.
+    #[cfg(test)]
+    pub(crate) fn start_with_transports(
+        config: &Config,
+        links: Vec<(Ipv4Addr, Box<dyn Transport>)>,
+    ) -> Result<Self, Error> {
+        Self::spawn(config, links, Arc::new(Poller::new()?), true)
+            .map(|relay| relay.expect("relay always starts"))
+    }
+
+    /// Start the relay thread relaying between `links`, waiting for messages on `poller`
+    fn spawn(
+        config: &Config,
+        links: Vec<(Ipv4Addr, Box<dyn Transport>)>,
+        poller: Arc<Poller>,
+        polls: bool,
+    ) -> Result<Option<Self>, Error> {
+        let stop = Arc::new(AtomicBool::new(false));
+        let mut relay = RelayThread {
+            links,
+            poller: poller.clone(),
+            stop: stop.clone(),
+            polls,
+            buf: Vec::with_capacity(config.recv_buffer_size()),
+            max_size: config.max_message_size(),
+            max_depth: config.max_message_depth(),
+        };
+
+        let jh = std::thread::Builder::new()
+            .name("udis-relay".into())
+            .spawn(move || relay.run())?;
+
+        Ok(Some(Self {
+            stop,
+            poller,
+            jh: Some(jh),
+        }))
+    }
+}
+
+impl Drop for Relay {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        let _ = self.poller.notify();
+
+        if let Some(jh) = self.jh.take() {
+            let _ = jh.join();
+        }
+    }
+}
+
+/// A socket relaying messages to and from the udis multicast group on one interface
+struct RelaySocket {
+    socket: Socket,
+
+    /// Poller the socket is registered with, if any, which it's removed from on drop
+    poller: Option<Arc<Poller>>,
+}
+
+impl RelaySocket {
+    /// Register the socket with `poller` unless the relay thread `polls` instead
+    fn register(mut self, poller: &Arc<Poller>, polls: bool) -> io::Result<Self> {
+        if !polls {
+            // SAFETY: the socket is deleted from the poller when it's dropped
+            unsafe { poller.add_with_mode(&self.socket, Event::readable(0), PollMode::Level)? };
+            self.poller = Some(poller.clone());
+        }
+
+        Ok(self)
+    }
+}
+
+impl Transport for RelaySocket {
+    fn send(&mut self, msg: &[u8]) -> io::Result<()> {
+        let group = SocketAddr::from((MULTICAST_ADDR, MULTICAST_PORT));
+        self.socket.send_to(msg, &group.into())?;
+
+        Ok(())
+    }
+
+    fn recv(&mut self, buf: &mut Vec<u8>) -> io::Result<SocketAddr> {
+        recv_from(&self.socket, buf)
+    }
+}
+
+impl Drop for RelaySocket {
+    fn drop(&mut self) {
+        if let Some(poller) = &self.poller {
+            let _ = poller.delete(&self.socket);
+        }
+    }
+}
+
+/// The state of the relay thread
+struct RelayThread {
+    /// The transport onto each network, by the address of the interface it's on
+    links: Vec<(Ipv4Addr, Box<dyn Transport>)>,
+
+    poller: Arc<Poller>,
+
+    stop: Arc<AtomicBool>,
+
+    /// Whether the sockets must be polled, as they couldn't be registered with the poller
+    polls: bool,
+
+    /// Receive buffer, reused for every packet
+    buf: Vec<u8>,
+
+    max_size: usize,
+
+    max_depth: usize,
+}
+
+impl RelayThread {
+    fn run(&mut self) {
+        let mut events = Events::new();
+
+        while !self.stop.load(Ordering::Relaxed) {
+            for i in 0..self.links.len() {
+                self.relay_from(i);
+            }
+
+            events.clear();
+            let timeout = self.polls.then_some(POLL_PERIOD);
+            match self.poller.wait(&mut events, timeout) {
+                Ok(_) => (),
+                Err(e) if e.kind() == ErrorKind::Interrupted => (),
+                Err(e) => {
+                    error!("Error while waiting for messages to relay, stopping the relay: {e}");
+                    break;
+                }
+            }
+        }
+    }
+
+    /// Relay every message waiting on the link at `from` onto every other interface
+    fn relay_from(&mut self, from: usize) {
+        loop {
+            let interface = self.links[from].0;
+            let src = match self.links[from].1.recv(&mut self.buf) {
+                Ok(src) => src,
+                Err(e) => {
+                    if e.kind() != ErrorKind::WouldBlock {
+                        error!(
+                            target: RX_LOG_TARGET,
+                            "Error while receiving messages to relay from {interface} (will continue): {e}"
+                        );
+                    }
+                    return;
+                }
+            };
+
+            // Messages are decoded and encoded again rather than forwarded as they are, so that
+            // they're marked as relayed and anything which isn't a udis message is dropped
+            let relayed = match Message::decode(&self.buf, self.max_size, self.max_depth) {
+                Ok(Message::Relayed(_)) => {
+                    trace!(target: RX_LOG_TARGET, "not relaying relayed message from {src}");
+                    continue;
+                }
+                Ok(msg) => match Message::Relayed(Box::new(msg)).encode() {
+                    Ok(relayed) => relayed,
+                    Err(e) => {
+                        error!(target: TX_LOG_TARGET, "Error while encoding relayed message: {e}");
+                        continue;
+                    }
+                },
+                Err(e) => {
+                    trace!(target: RX_LOG_TARGET, "not relaying bad packet from {src}: {e}");
+                    continue;
+                }
+            };
+
+            for (to, link) in self.links.iter_mut().filter(|(to, _)| *to != interface) {
+                trace!(target: TX_LOG_TARGET, "relaying message from {src} onto {to}");
+                if let Err(e) = link.send(&relayed) {
+                    error!(
+                        target: TX_LOG_TARGET,
+                        "Error while relaying message from {src} onto {to} (will continue): {e}"
+                    );
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::Ipv4Addr, time::Duration};
+
+    use super::Relay;
+    use crate::{
+        config::Config,
+        transport::{ChannelNetwork, Transport},
+        Udis,
+    };
+
+    #[test]
+    fn test_relay_between_networks() {
+        let (near, far) = (ChannelNetwork::new(), ChannelNetwork::new());
+        let links: Vec<(Ipv4Addr, Box<dyn Transport>)> = vec![
+            (
+                Ipv4Addr::new(10, 0, 0, 254),
+                Box::new(near.transport((Ipv4Addr::new(10, 0, 0, 254), 8787))),
+            ),
+            (
+                Ipv4Addr::new(10, 0, 1, 254),
+                Box::new(far.transport((Ipv4Addr::new(10, 0, 1, 254), 8787))),
+            ),
+        ];
+        let _relay = Relay::start_with_transports(&Config::default(), links).unwrap();
+
+        let server = Udis::new("server")
+            .addr(Ipv4Addr::new(10, 0, 0, 1))
+            .host("hello", 4112)
+            .unwrap()
+            .build_sync_with_transport(near.transport((Ipv4Addr::new(10, 0, 0, 1), 8787)))
+            .unwrap();
+        let client = Udis::new("client")
+            .addr(Ipv4Addr::new(10, 0, 1, 1))
+            .search("hello")
+            .build_sync_with_transport(far.transport((Ipv4Addr::new(10, 0, 1, 1), 8787)))
+            .unwrap();
+
+        // The client only hears the server through the relay, but finds it at the server's address
+        let service = client
+            .find_service_where(Duration::from_secs(5), false, |_| true)
+            .unwrap()
+            .expect("the server should be found through the relay");
+        assert_eq!(service.name, "server");
+        assert_eq!(service.addr, Ipv4Addr::new(10, 0, 0, 1));
+
+        client.shutdown().unwrap();
+        server.shutdown().unwrap();
+    }
+}
@@ -1,14 +1,28 @@
 use std::{
-    collections::HashSet,
+    collections::{HashMap, VecDeque},
     io::ErrorKind,
-    sync::mpsc::{channel, Receiver, RecvError, Sender, TryRecvError},
+    mem,
+    net::{IpAddr, SocketAddr},
+    sync::{
+        mpsc::{channel, Receiver, RecvError, RecvTimeoutError, SendError, Sender, TryRecvError},
+        Arc, Condvar, Mutex, MutexGuard, TryLockError,
+    },
     thread::JoinHandle,
-    time::Duration,
+    time::{Duration, Instant},
 };
 
 use log::{error, trace};
+use polling::{Events, Poller};
 
-use crate::{error::Error, net::build_multicast_socket, Service, ServiceInfo, Udis};
+use crate::{
+    config::{Config, ANNOUNCE_REPEAT_INTERVAL, CLOCK_SWEEP_PERIOD},
+    core::{Output, Processor},
+    diagnostics::{Diagnostics, SharedDiagnostics, TimelineEntry},
+    error::Error,
+    net::{ignore_transient_send_error, MULTICAST_PORT, RX_LOG_TARGET, TX_LOG_TARGET},
+    transport::{Transport, UdpTransport},
+    HostEvent, ServiceEvent, ServiceInfo, Udis,
+};
 
 /// A synchronous udis endpoint.
 ///
@@ -16,63 +30,306 @@ use crate::{error::Error, net::build_multicast_socket, Service, ServiceInfo, Udi
 /// communicate any observed services back to the main thread via channels.
 ///
 /// To retrieve services found by this endpoint use the [`SyncUdis::find_service`] or
-/// [`SyncUdis::try_find_service`] functions.
+/// [`SyncUdis::try_find_service`] functions, or register a callback with
+/// [`SyncUdis::on_service`].
 ///
 /// When finished using the endpoint be sure to call [`SyncUdis::shutdown`] to close the background
 /// thread.
+///
+/// If the endpoint is dropped without being shut down the background thread stops by itself, as
+/// nobody is listening for services any more. This is not treated as an error.
+///
+/// The endpoint can be shared between threads, for example in an [`Arc`], and any number of them
+/// can find services at once. Each service found is returned to exactly one of the callers, so
+/// the threads share out the services between them rather than each seeing every service.
 #[derive(Debug)]
 pub struct SyncUdis {
     /// The common udis info
-    _udis: Udis,
+    udis: Udis,
+
+    /// The configuration the background thread is restarted with, or `None` if the endpoint was
+    /// built with a custom transport which can't be rebuilt
+    config: Option<Config>,
 
     /// Join handle for the background thread
     bg_thread_jh: JoinHandle<Result<(), Error>>,
 
     /// Channel for sending commands to the bg thread
-    cmd_tx: Sender<Cmd>,
+    cmd_tx: CmdSender,
+
+    /// Services found by the BG thread which haven't been returned yet. This is taken by the
+    /// dispatcher thread if a callback is registered with [`SyncUdis::on_service`].
+    found: Option<Arc<FoundServices>>,
+
+    /// Host event receive channel, the BG thread will send events relating to the services we host
+    /// over this channel
+    host_event_rx: Mutex<Receiver<HostEvent>>,
+
+    /// Whether the BG thread passes on host events, see [`crate::builder::Builder::host_events`]
+    host_events: bool,
+
+    /// Service event receive channel, the BG thread will send events relating to the services
+    /// we've found over this channel
+    service_event_rx: Mutex<Receiver<ServiceEvent>>,
+
+    /// Diagnostics updated by the bg thread
+    diag: SharedDiagnostics,
+
+    /// Join handle for the thread dispatching services to the user's callback, if there is one
+    dispatch_thread_jh: Option<JoinHandle<()>>,
+}
+
+/// Services found by the BG thread which haven't been returned to the user yet.
+///
+/// This is shared by the BG thread and every caller finding services. Callers only hold the lock
+/// while taking services out, so one caller waiting for a service never holds up the others.
+#[derive(Debug, Default)]
+struct FoundServices {
+    state: Mutex<FoundState>,
+
+    /// Notified whenever a service is found or the BG thread stops
+    changed: Condvar,
+}
 
-    /// Service info receive channel, the BG thread will send discovered services over this channel
-    /// back to the [`SyncUdis`] endpoint
-    serv_info_rx: Receiver<ServiceInfo>,
+#[derive(Debug, Default)]
+struct FoundState {
+    /// Services in the order they were found, after any put back by
+    /// [`SyncUdis::find_service_where`]
+    queue: VecDeque<ServiceInfo>,
+
+    /// Whether the BG thread has stopped, so no more services will be found
+    closed: bool,
+}
+
+impl FoundServices {
+    /// Take the next service, waiting until `deadline` for one if there's a deadline or forever
+    /// otherwise. Returns `Ok(None)` if the deadline passes first.
+    fn next(&self, deadline: Option<Instant>) -> Result<Option<ServiceInfo>, Error> {
+        let mut state = lock(&self.state);
+
+        loop {
+            if let Some(serv_info) = state.queue.pop_front() {
+                return Ok(Some(serv_info));
+            }
+            // Services found before the BG thread stopped are still returned
+            if state.closed {
+                return Err(Error::ServiceInfoRecvError(RecvError));
+            }
+
+            state = match deadline {
+                Some(deadline) => {
+                    let timeout = deadline.saturating_duration_since(Instant::now());
+                    if timeout.is_zero() {
+                        return Ok(None);
+                    }
+                    match self.changed.wait_timeout(state, timeout) {
+                        Ok((state, _)) => state,
+                        Err(poisoned) => poisoned.into_inner().0,
+                    }
+                }
+                None => match self.changed.wait(state) {
+                    Ok(state) => state,
+                    Err(poisoned) => poisoned.into_inner(),
+                },
+            };
+        }
+    }
+
+    /// Put services back to be returned before any others, in the same order
+    fn put_back(&self, services: VecDeque<ServiceInfo>) {
+        if services.is_empty() {
+            return;
+        }
+
+        let mut state = lock(&self.state);
+        let queue = mem::replace(&mut state.queue, services);
+        state.queue.extend(queue);
+        drop(state);
+        self.changed.notify_all();
+    }
+}
+
+/// Passes the services the BG thread finds to the [`SyncUdis`] endpoint, marking that no more will
+/// be found once dropped
+#[derive(Debug)]
+struct ServiceSender(Arc<FoundServices>);
+
+impl ServiceSender {
+    /// Pass a service to the endpoint, failing if the endpoint has been dropped so nobody will
+    /// ever receive it
+    fn send(&self, serv_info: ServiceInfo) -> Result<(), ()> {
+        if Arc::strong_count(&self.0) == 1 {
+            return Err(());
+        }
+
+        lock(&self.0.state).queue.push_back(serv_info);
+        self.0.changed.notify_all();
+        Ok(())
+    }
+}
+
+impl Drop for ServiceSender {
+    fn drop(&mut self) {
+        lock(&self.0.state).closed = true;
+        self.0.changed.notify_all();
+    }
 }
 
 enum Cmd {
+    /// Wait for the shutdown grace so messages already sent leave the machine
+    Flush,
     Shutdown,
+    Rename(String),
+    Refresh,
+    Pause,
+    Resume,
+    GetPayload(Sender<Vec<u8>>),
+    Snapshot(Sender<HashMap<String, Vec<ServiceInfo>>>),
+    GetTimeline(Sender<Vec<TimelineEntry>>),
+    Probe {
+        addr: IpAddr,
+
+        /// When the caller stops waiting for the reply, by the system's clock
+        deadline: Instant,
+        reply_tx: Sender<Vec<ServiceInfo>>,
+    },
+
+    /// Pass a service to the user as if it had been found
+    #[cfg(feature = "test-util")]
+    Inject(Box<ServiceInfo>),
+}
+
+/// Someone waiting for the reply to a probe, and when they stop waiting by the system's clock
+type ProbeWaiter = (Instant, Sender<Vec<ServiceInfo>>);
+
+/// Sends commands to the bg thread, waking it up so they're handled straight away
+#[derive(Debug)]
+struct CmdSender {
+    tx: Sender<Cmd>,
+
+    /// The poller the bg thread waits on
+    poller: Arc<Poller>,
+}
+
+impl CmdSender {
+    fn send(&self, cmd: Cmd) -> Result<(), SendError<Cmd>> {
+        self.tx.send(cmd)?;
+
+        // If the thread can't be woken it still sees the command the next time it wakes up
+        let _ = self.poller.notify();
+        Ok(())
+    }
+}
+
+impl Drop for CmdSender {
+    fn drop(&mut self) {
+        // Disconnect the channel before waking the thread, so it sees the endpoint has gone
+        drop(mem::replace(&mut self.tx, channel().0));
+        let _ = self.poller.notify();
+    }
+}
+
+/// A running background thread and the channels connected to it
+struct Worker {
+    jh: JoinHandle<Result<(), Error>>,
+    cmd_tx: CmdSender,
+    found: Arc<FoundServices>,
+    host_event_rx: Receiver<HostEvent>,
+    service_event_rx: Receiver<ServiceEvent>,
 }
 
 impl SyncUdis {
-    pub(crate) fn build(udis: Udis) -> Self {
-        let (cmd_tx, cmd_rx) = channel();
-        let (serv_info_tx, serv_info_rx) = channel();
+    pub(crate) fn build(
+        udis: Udis,
+        config: Config,
+        transport: Option<Box<dyn Transport>>,
+    ) -> Result<Self, Error> {
+        let diag = SharedDiagnostics::default();
 
-        let udis_bg = udis.clone();
+        // Keep the configuration before the transport takes anything out of it, so the worker can
+        // be restarted with it
+        let restart_config = transport.is_none().then(|| config.clone());
+        let host_events = config.host_events;
+        let worker = spawn_worker(&udis, config, transport, &diag)?;
 
-        let bg_thread_jh =
-            std::thread::spawn(move || sync_bg_thread(udis_bg, cmd_rx, serv_info_tx));
+        Ok(Self {
+            udis,
+            config: restart_config,
+            bg_thread_jh: worker.jh,
+            cmd_tx: worker.cmd_tx,
+            found: Some(worker.found),
+            host_event_rx: Mutex::new(worker.host_event_rx),
+            host_events,
+            service_event_rx: Mutex::new(worker.service_event_rx),
+            diag,
+            dispatch_thread_jh: None,
+        })
+    }
 
-        Self {
-            _udis: udis,
-            bg_thread_jh,
-            cmd_tx,
-            serv_info_rx,
+    /// Restart the background thread if it has stopped, for example after a fatal socket error,
+    /// without having to build the endpoint again.
+    ///
+    /// The new thread rejoins the discovery network with the same configuration the endpoint was
+    /// built with, including the name it was built with rather than any given to
+    /// [`SyncUdis::rename`], and isn't paused. Services and events the old thread found which
+    /// hadn't been returned yet are lost, and services already found are found again. If the
+    /// background thread is still running this does nothing.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the endpoint was built with a custom transport or
+    /// has a callback registered with [`SyncUdis::on_service`], which can't be carried over to a
+    /// new thread, or if rejoining the discovery network fails.
+    pub fn restart(&mut self) -> Result<(), Error> {
+        if !self.bg_thread_jh.is_finished() {
+            return Ok(());
+        }
+        if self.found.is_none() {
+            return Err(Error::ServiceCallbackRegistered);
         }
+        let config = self
+            .config
+            .clone()
+            .ok_or(Error::CustomTransportNotRestartable)?;
+
+        let worker = spawn_worker(&self.udis, config, None, &self.diag)?;
+
+        match mem::replace(&mut self.bg_thread_jh, worker.jh).join() {
+            Ok(Ok(())) => trace!(
+                "restarting the udis background thread of `{}`",
+                self.udis.name
+            ),
+            Ok(Err(e)) => error!(
+                "restarting the udis background thread of `{}`, which stopped with: {e}",
+                self.udis.name
+            ),
+            Err(_) => error!(
+                "restarting the udis background thread of `{}`, which panicked",
+                self.udis.name
+            ),
+        }
+
+        self.cmd_tx = worker.cmd_tx;
+        self.found = Some(worker.found);
+        self.host_event_rx = Mutex::new(worker.host_event_rx);
+        self.service_event_rx = Mutex::new(worker.service_event_rx);
+
+        Ok(())
     }
 
     /// Find the next service discovered by this udis endpoint.
     ///
-    /// This function will block until a service is found.
+    /// This function will block until a service is found, or until another thread finding a service
+    /// on this endpoint has been given one.
     ///
     /// # Errors
     ///
     /// This function can return an error if the background thread closes for an unexpected reason.
     pub fn find_service(&self) -> Result<ServiceInfo, Error> {
-        if self.bg_thread_jh.is_finished() {
-            return Err(Error::BackgroundThreadShutdown);
-        }
-
-        let serv_info = self.serv_info_rx.recv()?;
-
-        Ok(serv_info)
+        // Without a deadline this only returns once a service is found or the thread stops
+        self.found()?
+            .next(None)?
+            .ok_or(Error::ServiceInfoRecvError(RecvError))
     }
 
     /// Try to find the next service discovered by the udis endpoint.
@@ -83,155 +340,940 @@ impl SyncUdis {
     ///
     /// This function can return an error if the background thread closes for an unexpected reason.
     pub fn try_find_service(&self) -> Result<Option<ServiceInfo>, Error> {
-        if self.bg_thread_jh.is_finished() {
-            return Err(Error::BackgroundThreadShutdown);
+        self.found()?.next(Some(Instant::now()))
+    }
+
+    /// Find the next service discovered by this endpoint for which `predicate` returns true,
+    /// waiting up to `timeout` for one.
+    ///
+    /// This is useful when matching on the kind of service isn't enough, for example to only
+    /// accept services with some metadata. Services which don't match are put back to be returned
+    /// by later calls if `requeue` is true, in the order they were found, otherwise they're
+    /// discarded. Returns `Ok(None)` if no matching service was found before the timeout.
+    ///
+    /// # Errors
+    ///
+    /// This function can return an error if the background thread closes before a matching
+    /// service is found.
+    pub fn find_service_where<F>(
+        &self,
+        timeout: Duration,
+        requeue: bool,
+        predicate: F,
+    ) -> Result<Option<ServiceInfo>, Error>
+    where
+        F: Fn(&ServiceInfo) -> bool,
+    {
+        let found = self.found()?;
+        let deadline = Instant::now() + timeout;
+        let mut skipped = VecDeque::new();
+
+        let result = loop {
+            let serv_info = match found.next(Some(deadline)) {
+                Ok(Some(serv_info)) => serv_info,
+                Ok(None) => break Ok(None),
+                Err(e) => break Err(e),
+            };
+
+            if predicate(&serv_info) {
+                break Ok(Some(serv_info));
+            }
+            if requeue {
+                skipped.push_back(serv_info);
+            }
+        };
+
+        found.put_back(skipped);
+
+        result
+    }
+
+    /// Collect every service discovered by this endpoint until `deadline`.
+    ///
+    /// Services put back by [`SyncUdis::find_service_where`] are returned first, followed by those
+    /// found before the deadline. Like the other methods which collect services over time, a
+    /// timeout isn't an error: whatever has been found when the deadline passes is returned, which
+    /// may be nothing. If the background thread stops before the deadline the services found until
+    /// then are returned.
+    ///
+    /// # Errors
+    ///
+    /// This function can return an error if the background thread closes before any service is
+    /// found.
+    pub fn find_all_services(&self, deadline: Instant) -> Result<Vec<ServiceInfo>, Error> {
+        let found = self.found()?;
+        let mut services = Vec::new();
+
+        loop {
+            match found.next(Some(deadline)) {
+                Ok(Some(serv_info)) => services.push(serv_info),
+                Ok(None) => break,
+                Err(_) if !services.is_empty() => break,
+                Err(e) => return Err(e),
+            }
+        }
+
+        Ok(services)
+    }
+
+    /// Find the most preferable service of `kind` discovered within `window`, according to
+    /// [`ServiceInfo::cmp_preference`].
+    ///
+    /// This blocks for the whole window while collecting candidates, and returns `Ok(None)` if no
+    /// service of `kind` was found. Services of other kinds, and the less preferable services of
+    /// `kind`, are put back to be returned by later calls in the order they were found.
+    ///
+    /// # Errors
+    ///
+    /// This function can return an error if the background thread closes before any service of
+    /// `kind` is found.
+    pub fn find_best_service(
+        &self,
+        kind: &str,
+        window: Duration,
+    ) -> Result<Option<ServiceInfo>, Error> {
+        self.find_best_service_inner(kind, window, None)
+    }
+
+    /// Find the most preferable service of `kind` discovered within `window` like
+    /// [`SyncUdis::find_best_service`], but stick with `previous` if it's found again.
+    ///
+    /// `previous` is a service found before, for example by an earlier run of the application. If
+    /// the same endpoint, by name and address, is found hosting `kind` within the window it's
+    /// returned straight away, regardless of its preference, so clients don't needlessly switch
+    /// hosts when restarting. Otherwise the most preferable service is returned once the window
+    /// is up.
+    ///
+    /// # Errors
+    ///
+    /// This function can return an error if the background thread closes before any service of
+    /// `kind` is found.
+    pub fn find_best_service_sticky(
+        &self,
+        kind: &str,
+        window: Duration,
+        previous: &ServiceInfo,
+    ) -> Result<Option<ServiceInfo>, Error> {
+        self.find_best_service_inner(kind, window, Some(previous))
+    }
+
+    fn find_best_service_inner(
+        &self,
+        kind: &str,
+        window: Duration,
+        previous: Option<&ServiceInfo>,
+    ) -> Result<Option<ServiceInfo>, Error> {
+        let found = self.found()?;
+        let deadline = Instant::now() + window;
+        let mut best: Option<ServiceInfo> = None;
+        let mut skipped = VecDeque::new();
+
+        let result = loop {
+            let serv_info = match found.next(Some(deadline)) {
+                Ok(Some(serv_info)) => serv_info,
+                Ok(None) => break Ok(best),
+                Err(_) if best.is_some() => break Ok(best),
+                Err(e) => break Err(e),
+            };
+
+            if serv_info.kind != kind {
+                skipped.push_back(serv_info);
+                continue;
+            }
+
+            // Return the previous service as soon as it comes back, putting back the best so far
+            if previous.is_some_and(|p| p.name == serv_info.name && p.addr == serv_info.addr) {
+                skipped.extend(best);
+                break Ok(Some(serv_info));
+            }
+
+            match &best {
+                Some(b) if serv_info.cmp_preference(b).is_ge() => skipped.push_back(serv_info),
+                _ => skipped.extend(best.replace(serv_info)),
+            }
+        };
+
+        found.put_back(skipped);
+
+        result
+    }
+
+    /// Discard any services which have been found but not yet returned by
+    /// [`SyncUdis::find_service`], returning how many were discarded.
+    ///
+    /// This is useful if you only want to know about services found from now on.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if a callback is registered with
+    /// [`SyncUdis::on_service`], as found services are passed to it instead.
+    pub fn drain(&self) -> Result<usize, Error> {
+        let mut state = lock(&self.found()?.state);
+
+        Ok(mem::take(&mut state.queue).len())
+    }
+
+    /// Get the next event relating to the services hosted by this endpoint, for example a peer
+    /// acknowledging that it has received one of our services.
+    ///
+    /// This function will block until an event occurs.
+    ///
+    /// # Errors
+    ///
+    /// This function can return an error if the endpoint wasn't built with
+    /// [`crate::builder::Builder::host_events`], or if the background thread closes for an
+    /// unexpected reason.
+    pub fn next_host_event(&self) -> Result<HostEvent, Error> {
+        if !self.host_events {
+            return Err(Error::HostEventsDisabled);
         }
 
-        match self.serv_info_rx.try_recv() {
-            Ok(serv_info) => Ok(Some(serv_info)),
+        let host_event = lock(&self.host_event_rx)
+            .recv()
+            .map_err(|_| Error::BackgroundThreadShutdown)?;
+
+        Ok(host_event)
+    }
+
+    /// Try to get the next event relating to the services hosted by this endpoint.
+    ///
+    /// This function will not block, if no event has occurred `Ok(None)` will be returned.
+    ///
+    /// # Errors
+    ///
+    /// This function can return an error if the endpoint wasn't built with
+    /// [`crate::builder::Builder::host_events`], or if the background thread closes for an
+    /// unexpected reason.
+    pub fn try_next_host_event(&self) -> Result<Option<HostEvent>, Error> {
+        if !self.host_events {
+            return Err(Error::HostEventsDisabled);
+        }
+
+        let Some(host_event_rx) = try_lock(&self.host_event_rx) else {
+            return Ok(None);
+        };
+
+        match host_event_rx.try_recv() {
+            Ok(host_event) => Ok(Some(host_event)),
             Err(TryRecvError::Empty) => Ok(None),
-            Err(TryRecvError::Disconnected) => Err(Error::ServiceInfoRecvError(RecvError)),
+            Err(TryRecvError::Disconnected) => Err(Error::BackgroundThreadShutdown),
         }
     }
 
-    /// Shutdown this endpoint
+    /// Get the next event relating to the services found by this endpoint, for example a service
+    /// expiring because its host didn't announce it again within its TTL.
+    ///
+    /// This function will block until an event occurs.
     ///
     /// # Errors
     ///
     /// This function can return an error if the background thread closes for an unexpected reason.
-    pub fn shutdown(self) -> Result<(), Error> {
+    pub fn next_service_event(&self) -> Result<ServiceEvent, Error> {
+        let service_event = lock(&self.service_event_rx)
+            .recv()
+            .map_err(|_| Error::BackgroundThreadShutdown)?;
+
+        Ok(service_event)
+    }
+
+    /// Try to get the next event relating to the services found by this endpoint.
+    ///
+    /// This function will not block, if no event has occurred `Ok(None)` will be returned.
+    ///
+    /// # Errors
+    ///
+    /// This function can return an error if the background thread closes for an unexpected reason.
+    pub fn try_next_service_event(&self) -> Result<Option<ServiceEvent>, Error> {
+        let Some(service_event_rx) = try_lock(&self.service_event_rx) else {
+            return Ok(None);
+        };
+
+        match service_event_rx.try_recv() {
+            Ok(service_event) => Ok(Some(service_event)),
+            Err(TryRecvError::Empty) => Ok(None),
+            Err(TryRecvError::Disconnected) => Err(Error::BackgroundThreadShutdown),
+        }
+    }
+
+    /// Register a callback which is called with every service discovered by this endpoint.
+    ///
+    /// The callback is run on a separate dispatcher thread, which lets the endpoint be used in
+    /// event driven code rather than polling for services. Once a callback is registered
+    /// [`SyncUdis::find_service`] and [`SyncUdis::try_find_service`] can no longer be used, as the
+    /// callback receives every discovered service.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if a callback has already been registered, or if the
+    /// dispatcher thread can't be started.
+    pub fn on_service<F>(&mut self, mut callback: F) -> Result<(), Error>
+    where
+        F: FnMut(ServiceInfo) + Send + 'static,
+    {
+        let found = self.found.take().ok_or(Error::ServiceCallbackRegistered)?;
+
+        // The dispatcher will stop once the background thread shuts down and every service it
+        // found has been passed on
+        self.dispatch_thread_jh = Some(
+            std::thread::Builder::new()
+                .name("udis-dispatch".into())
+                .spawn(move || {
+                    while let Ok(Some(serv_info)) = found.next(None) {
+                        callback(serv_info);
+                    }
+                })?,
+        );
+
+        Ok(())
+    }
+
+    /// Get diagnostic information about this endpoint's connection to the discovery network.
+    ///
+    /// This is useful for troubleshooting an endpoint which isn't finding any services, see
+    /// [`Diagnostics`] for the information available.
+    pub fn diagnostics(&self) -> Diagnostics {
+        self.diag.get()
+    }
+
+    /// Take the most recent error the background thread logged and carried on from, such as a
+    /// failure to receive a packet or a packet which couldn't be deserialised.
+    ///
+    /// The error is cleared once taken, so this returns `None` until the thread hits another
+    /// error, and only the latest is kept if there were several. Errors which stop the thread are
+    /// returned by [`SyncUdis::shutdown`] instead.
+    pub fn last_error(&self) -> Option<Error> {
+        self.diag.take_error()
+    }
+
+    /// Change the name this endpoint advertises to the discovery network.
+    ///
+    /// The background thread keeps its membership of the discovery network and immediately
+    /// re-announces itself under the new name. Peers will see the renamed endpoint as a new peer.
+    ///
+    /// # Errors
+    ///
+    /// This function can return an error if the background thread closes for an unexpected reason.
+    pub fn rename<S: Into<String>>(&self, name: S) -> Result<(), Error> {
+        self.cmd_tx
+            .send(Cmd::Rename(name.into()))
+            .map_err(|_| Error::BackgroundThreadShutdown)
+    }
+
+    /// Find every service again straight away, for example after the network has changed.
+    ///
+    /// The background thread forgets the peers and services it knows about, then queries the
+    /// discovery network so that hosts reply to it again. Services found before the refresh are
+    /// found again, so the same [`ServiceInfo`] may be returned more than once. Peers don't forget
+    /// the services this endpoint hosts.
+    ///
+    /// While paused the hosts aren't queried until the endpoint is resumed.
+    ///
+    /// # Errors
+    ///
+    /// This function can return an error if the background thread closes for an unexpected reason.
+    pub fn refresh(&self) -> Result<(), Error> {
+        self.cmd_tx
+            .send(Cmd::Refresh)
+            .map_err(|_| Error::BackgroundThreadShutdown)
+    }
+
+    /// Pass `serv_info` to the user as if the endpoint had found it, so code which reacts to found
+    /// services can be tested without a network.
+    ///
+    /// The service is returned by [`SyncUdis::find_service`], or passed to the callback registered
+    /// with [`SyncUdis::on_service`], in order with any services the endpoint really finds.
+    ///
+    /// __Requires the `test-util` feature.__
+    ///
+    /// # Errors
+    ///
+    /// This function can return an error if the background thread closes for an unexpected reason.
+    #[cfg(feature = "test-util")]
+    pub fn inject_service(&self, serv_info: ServiceInfo) -> Result<(), Error> {
+        self.cmd_tx
+            .send(Cmd::Inject(Box::new(serv_info)))
+            .map_err(|_| Error::BackgroundThreadShutdown)
+    }
+
+    /// Pause the endpoint without shutting it down, for example during maintenance.
+    ///
+    /// While paused the endpoint doesn't announce itself, reply to peers or find any services,
+    /// though the background thread keeps receiving packets so they don't build up. Services which
+    /// peers announce while the endpoint is paused are missed.
+    ///
+    /// # Errors
+    ///
+    /// This function can return an error if the background thread closes for an unexpected reason.
+    pub fn pause(&self) -> Result<(), Error> {
+        self.cmd_tx
+            .send(Cmd::Pause)
+            .map_err(|_| Error::BackgroundThreadShutdown)
+    }
+
+    /// Resume the endpoint after [`SyncUdis::pause`], re-announcing it to the discovery network.
+    ///
+    /// # Errors
+    ///
+    /// This function can return an error if the background thread closes for an unexpected reason.
+    pub fn resume(&self) -> Result<(), Error> {
+        self.cmd_tx
+            .send(Cmd::Resume)
+            .map_err(|_| Error::BackgroundThreadShutdown)
+    }
+
+    /// Ask the peer at `addr` directly which services it hosts, waiting up to `timeout` for it to
+    /// reply.
+    ///
+    /// The probe is sent straight to `addr` on the udis port as well as to the discovery network,
+    /// which is useful when the peer's address is known some other way but multicast doesn't
+    /// reach it, and reaches every endpoint at `addr` where multicast does. Every service the peer
+    /// hosts is returned, not just the kinds this endpoint searches for, and the services aren't
+    /// also returned by [`SyncUdis::find_service`] unless they're newly found. If several endpoints
+    /// at `addr` host services, the services of whichever replies first are returned. An empty
+    /// list is returned if the peer doesn't reply in time, and straight away without probing
+    /// anything while the endpoint is paused.
+    ///
+    /// The peer replies directly if it has a unicast port, otherwise to the whole discovery
+    /// network. Peers running older versions of udis don't reply to probes.
+    ///
+    /// # Errors
+    ///
+    /// This function can return an error if the background thread closes for an unexpected reason.
+    pub fn probe(&self, addr: IpAddr, timeout: Duration) -> Result<Vec<ServiceInfo>, Error> {
+        let (reply_tx, reply_rx) = channel();
+
+        self.cmd_tx
+            .send(Cmd::Probe {
+                addr,
+                deadline: Instant::now() + timeout,
+                reply_tx,
+            })
+            .map_err(|_| Error::BackgroundThreadShutdown)?;
+
+        match reply_rx.recv_timeout(timeout) {
+            Ok(hosted) => Ok(hosted),
+            Err(RecvTimeoutError::Timeout) => Ok(Vec::new()),
+            Err(RecvTimeoutError::Disconnected) => Err(Error::BackgroundThreadShutdown),
+        }
+    }
+
+    /// Get the notify message payload this endpoint broadcasts to the discovery network.
+    ///
+    /// These are the exact bytes sent by the background thread, which is useful for asserting on
+    /// the wire format in tests.
+    ///
+    /// # Errors
+    ///
+    /// This function can return an error if the background thread closes for an unexpected reason.
+    pub fn notify_payload(&self) -> Result<Vec<u8>, Error> {
+        let (payload_tx, payload_rx) = channel();
+
+        self.cmd_tx
+            .send(Cmd::GetPayload(payload_tx))
+            .map_err(|_| Error::BackgroundThreadShutdown)?;
+
+        payload_rx
+            .recv()
+            .map_err(|_| Error::BackgroundThreadShutdown)
+    }
+
+    /// Get every service this endpoint is searching for which is hosted by a known peer, grouped
+    /// by the name of the peer hosting them.
+    ///
+    /// Unlike [`SyncUdis::find_service`] this doesn't consume anything, so it's useful for showing
+    /// the current topology of the discovery network. Peers which don't host any of the services
+    /// we're searching for are left out, and peers which have said goodbye are forgotten.
+    ///
+    /// # Errors
+    ///
+    /// This function can return an error if the background thread closes for an unexpected reason.
+    pub fn peers_with_services(&self) -> Result<HashMap<String, Vec<ServiceInfo>>, Error> {
+        let (snapshot_tx, snapshot_rx) = channel();
+
+        self.cmd_tx
+            .send(Cmd::Snapshot(snapshot_tx))
+            .map_err(|_| Error::BackgroundThreadShutdown)?;
+
+        snapshot_rx
+            .recv()
+            .map_err(|_| Error::BackgroundThreadShutdown)
+    }
+
+    /// Get the most recent things to happen on this endpoint, oldest first, if it was built with
+    /// [`crate::builder::Builder::timeline`].
+    ///
+    /// # Errors
+    ///
+    /// This function can return an error if the background thread closes for an unexpected reason.
+    pub fn recent_events(&self) -> Result<Vec<TimelineEntry>, Error> {
+        let (timeline_tx, timeline_rx) = channel();
+
         self.cmd_tx
-            .send(Cmd::Shutdown)
-            .map_err(|_| Error::FailedToShutdownUdisThread)?;
+            .send(Cmd::GetTimeline(timeline_tx))
+            .map_err(|_| Error::BackgroundThreadShutdown)?;
+
+        timeline_rx
+            .recv()
+            .map_err(|_| Error::BackgroundThreadShutdown)
+    }
+
+    /// Shutdown this endpoint
+    ///
+    /// If the endpoint was built with a [`crate::builder::Builder::shutdown_grace`] the background
+    /// thread waits for it before stopping, so messages it has sent can leave the machine.
+    ///
+    /// This is safe to call after the background thread has already stopped, for example after
+    /// finding a service with [`crate::builder::Builder::find_once`] or once its
+    /// [`crate::builder::Builder::lifetime`] is up, and succeeds if the thread stopped cleanly.
+    ///
+    /// # Errors
+    ///
+    /// This function returns the error the background thread stopped with, if it stopped because
+    /// of one, or [`Error::FailedToShutdownUdisThread`] if it panicked.
+    pub fn shutdown(self) -> Result<(), Error> {
+        // If the thread has already stopped, e.g. after finding a service with
+        // `Builder::find_once`, these sends fail and are ignored, and it only needs joining to find
+        // out how it stopped
+        let _ = self.cmd_tx.send(Cmd::Flush);
+        let _ = self.cmd_tx.send(Cmd::Shutdown);
 
         self.bg_thread_jh
             .join()
             .map_err(|_| Error::FailedToShutdownUdisThread)??;
 
+        if let Some(dispatch_thread_jh) = self.dispatch_thread_jh {
+            dispatch_thread_jh
+                .join()
+                .map_err(|_| Error::FailedToShutdownUdisThread)?;
+        }
+
         Ok(())
     }
+
+    /// Shutdown this endpoint without waiting for the background thread to finish.
+    ///
+    /// Unlike [`SyncUdis::shutdown`] this returns immediately, leaving the background thread to
+    /// stop on its own shortly afterwards. This means any messages the thread is in the middle of
+    /// sending may not have been sent by the time this returns, and any error the thread stopped
+    /// with is discarded.
+    pub fn shutdown_detached(self) {
+        // If the thread has already stopped there's nothing to do, and dropping the join handle
+        // detaches the thread
+        let _ = self.cmd_tx.send(Cmd::Shutdown);
+    }
+
+    fn found(&self) -> Result<&FoundServices, Error> {
+        self.found
+            .as_deref()
+            .ok_or(Error::ServiceCallbackRegistered)
+    }
+}
+
+/// Connect to the discovery network and start a background thread for `udis`, returning the
+/// channels connected to it
+fn spawn_worker(
+    udis: &Udis,
+    mut config: Config,
+    transport: Option<Box<dyn Transport>>,
+    diag: &SharedDiagnostics,
+) -> Result<Worker, Error> {
+    let (cmd_tx, cmd_rx) = channel();
+    let found = Arc::new(FoundServices::default());
+    let serv_info_tx = ServiceSender(found.clone());
+    let (host_event_tx, host_event_rx) = channel();
+    let (service_event_tx, service_event_rx) = channel();
+
+    let poller = Arc::new(Poller::new()?);
+
+    // Connect to the multicast network here rather than on the bg thread, so that any errors
+    // are returned when building the endpoint
+    let (transport, waits_on_transport): (Box<dyn Transport>, _) = match transport {
+        // Custom transports can't wake the poller, so the worker must keep polling them
+        Some(transport) => (transport, false),
+        None => {
+            let mut transport = UdpTransport::from_config(&mut config, diag)?;
+            let registered = transport.register(poller.clone())?;
+            (Box::new(transport), registered)
+        }
+    };
+
+    let udis_bg = udis.clone();
+    let diag_bg = diag.clone();
+    let poller_bg = poller.clone();
+
+    let jh = std::thread::Builder::new()
+        .name(udis.worker_name())
+        .spawn(move || {
+            sync_bg_thread(
+                udis_bg,
+                config,
+                transport,
+                poller_bg,
+                waits_on_transport,
+                diag_bg,
+                cmd_rx,
+                serv_info_tx,
+                host_event_tx,
+                service_event_tx,
+            )
+        })?;
+
+    Ok(Worker {
+        jh,
+        cmd_tx: CmdSender { tx: cmd_tx, poller },
+        found,
+        host_event_rx,
+        service_event_rx,
+    })
+}
+
+/// Lock `mutex`, even if another thread panicked while holding it as what it guards is still
+/// usable
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    match mutex.lock() {
+        Ok(guard) => guard,
+        Err(poisoned) => poisoned.into_inner(),
+    }
+}
+
+/// Lock `mutex` if no other thread holds it
+fn try_lock<T>(mutex: &Mutex<T>) -> Option<MutexGuard<'_, T>> {
+    match mutex.try_lock() {
+        Ok(guard) => Some(guard),
+        Err(TryLockError::Poisoned(poisoned)) => Some(poisoned.into_inner()),
+        Err(TryLockError::WouldBlock) => None,
+    }
+}
+
+/// How long to wait between polls of a transport which can't wake the background worker
+pub(crate) const POLL_PERIOD: Duration = Duration::from_millis(100);
+
+/// How often a worker waiting on a poller must wake up by itself, if at all.
+///
+/// Packets only wake the poller if `waits_on_transport` is set, otherwise the transport is polled
+/// every [`POLL_PERIOD`]. A custom clock can jump forward at any moment, so its timers are checked
+/// every [`CLOCK_SWEEP_PERIOD`]. Either is shortened to the configured read timeout.
+pub(crate) fn poll_period(config: &Config, waits_on_transport: bool) -> Option<Duration> {
+    let period = match (&config.clock, waits_on_transport) {
+        (Some(_), _) => Some(CLOCK_SWEEP_PERIOD),
+        (None, false) => Some(POLL_PERIOD),
+        (None, true) => None,
+    };
+
+    match (period, config.read_timeout) {
+        (Some(period), Some(timeout)) => Some(period.min(timeout)),
+        (period, timeout) => period.or(timeout),
+    }
 }
 
-/// Background thread for the [`SyncUdis`] endpoint
+/// Background thread for the [`SyncUdis`] endpoint.
+///
+/// The thread waits on `poller` until a command or packet arrives or something is due to happen,
+/// waking by itself every [`poll_period`] if packets or timers can't wake it.
+#[allow(clippy::too_many_arguments)]
 fn sync_bg_thread(
     udis: Udis,
+    config: Config,
+    mut transport: Box<dyn Transport>,
+    poller: Arc<Poller>,
+    waits_on_transport: bool,
+    diag: SharedDiagnostics,
     cmd_rx: Receiver<Cmd>,
-    serv_info_tx: Sender<ServiceInfo>,
+    serv_info_tx: ServiceSender,
+    host_event_tx: Sender<HostEvent>,
+    service_event_tx: Sender<ServiceEvent>,
 ) -> Result<(), Error> {
-    // Build the multicast socket
-    let (disc_addr, socket) = build_multicast_socket()?;
-    trace!("joined udis notify network on {disc_addr}");
+    let clock = config.clock();
+    let startup_delay = config.startup_delay();
+    let deadline = config.lifetime.map(|lifetime| clock.now() + lifetime);
 
-    for service in &udis.services {
-        match service {
-            Service::Host { kind, port } => {
-                trace!("hosting service `{}` on port {}", kind, port);
-            }
-            Service::Search { kind } => {
-                trace!("searching for service `{}`", kind);
-            }
-        }
+    let poll_period = poll_period(&config, waits_on_transport);
+    let mut repeats_left = config.announce_repeat() - 1;
+    let shutdown_grace = config.shutdown_grace;
+
+    let mut processor = Processor::new(udis, config)?;
+
+    // Wait a random amount of time so endpoints starting together don't all announce at once
+    if !startup_delay.is_zero() {
+        trace!(target: TX_LOG_TARGET, "delaying initial announcement by {startup_delay:?}");
+        std::thread::sleep(startup_delay);
+    }
+
+    // Send our notify message as we're joining the network, unless we're a silent client in
+    // which case hosts find out about us when they announce themselves
+    if processor.is_silent() {
+        trace!(target: TX_LOG_TARGET, "silent client, not announcing `{}`", processor.name());
+        repeats_left = 0;
+    } else {
+        trace!(target: TX_LOG_TARGET, "announcing `{}`", processor.name());
+        send_notify(&mut *transport, processor.notify_message(), &diag)?;
+        processor.announced();
     }
+    let mut next_repeat = clock.now() + ANNOUNCE_REPEAT_INTERVAL;
 
-    // Build the registry of udis peers
-    let mut registry = HashSet::<Udis>::new();
+    // Receive buffer, reused for every packet
+    let mut buf = Vec::with_capacity(processor.recv_buffer_size());
 
-    // Build the notify message
-    let notify_message = serde_json::to_vec(&udis).map_err(Error::FailedToSerialiseNotifyMsg)?;
+    // Events the poller woke up for, which aren't needed as everything is checked on each wake
+    let mut events = Events::new();
 
-    // Send our notify message as we're joining the network
-    socket.send_to(&notify_message[..], &disc_addr.into())?;
+    // When metrics were last reported to the user
+    let mut last_metrics = clock.now();
 
-    // Receive buffer
-    let mut buf = Vec::with_capacity(1024);
+    // Whoever is waiting for a reply from each probed peer and until when, by the peer's address
+    let mut probes: HashMap<IpAddr, Vec<ProbeWaiter>> = HashMap::new();
 
     // Main loop
-    loop {
-        // Check if there's a command
-        match cmd_rx.try_recv() {
-            Ok(cmd) => match cmd {
-                Cmd::Shutdown => break,
-            },
-            Err(TryRecvError::Empty) => (),
-            Err(TryRecvError::Disconnected) => break,
-        }
-
-        // Wait so we're not busy blocking the thread
-        std::thread::sleep(Duration::from_millis(100));
-
-        // Try to receive a packet on the discovery socket
-        let received = match socket.recv(buf.spare_capacity_mut()) {
-            Ok(a) => a,
-            Err(e) => {
-                match e.kind() {
-                    ErrorKind::TimedOut | ErrorKind::WouldBlock => (),
-                    k => error!(
-                        "Error while receiving udis notify messages (will continue): ({k:?}) {e}"
-                    ),
+    'main: loop {
+        // Handle every command waiting
+        loop {
+            let cmd = match cmd_rx.try_recv() {
+                Ok(cmd) => cmd,
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => break 'main,
+            };
+
+            match cmd {
+                Cmd::Flush => {
+                    // Replies held back are still owed to the peers that asked for them
+                    if !send_held_replies(&mut processor, &mut *transport, &diag, &host_event_tx)? {
+                        break 'main;
+                    }
+                    if !shutdown_grace.is_zero() {
+                        trace!("waiting {shutdown_grace:?} for sent messages to leave");
+                        std::thread::sleep(shutdown_grace);
+                    }
                 }
-                continue;
-            }
-        };
-        // SAFETY: just received into the `buffer`.
-        unsafe {
-            buf.set_len(received);
-        }
+                Cmd::Shutdown => break 'main,
+                Cmd::Rename(name) => {
+                    processor.rename(name)?;
 
-        // Decode into a udis struct
-        let peer: Udis =
-            serde_json::from_slice(&buf[..]).map_err(Error::FailedToDeserialiseNotifyMsg)?;
+                    // If paused the new name is announced on resuming
+                    if !processor.is_paused() {
+                        trace!(target: TX_LOG_TARGET, "announcing `{}`", processor.name());
+                        send_notify(&mut *transport, processor.notify_message(), &diag)?;
+                        processor.announced();
+                    }
+                }
+                Cmd::Refresh => {
+                    processor.refresh();
 
-        // Clear the buffer
-        buf.clear();
+                    // If paused hosts are queried on resuming
+                    if !processor.is_paused() {
+                        trace!(target: TX_LOG_TARGET, "querying hosts for `{}`", processor.name());
+                        send_notify(&mut *transport, processor.query_message(), &diag)?;
+                        processor.announced();
+                    }
+                }
+                Cmd::Pause => {
+                    if !send_held_replies(&mut processor, &mut *transport, &diag, &host_event_tx)? {
+                        break 'main;
+                    }
+                    processor.pause();
+                }
+                Cmd::Resume => {
+                    trace!(target: TX_LOG_TARGET, "announcing `{}`", processor.name());
+                    send_notify(&mut *transport, processor.resume(), &diag)?;
+                    processor.announced();
+                }
+                Cmd::GetPayload(payload_tx) => {
+                    // If the requester has gone away there's nobody to tell
+                    let _ = payload_tx.send(processor.notify_message().to_vec());
+                }
+                Cmd::Snapshot(snapshot_tx) => {
+                    let _ = snapshot_tx.send(processor.peers_with_services());
+                }
+                Cmd::GetTimeline(timeline_tx) => {
+                    let _ = timeline_tx.send(processor.timeline());
+                }
+                Cmd::Probe {
+                    addr,
+                    deadline,
+                    reply_tx,
+                } => {
+                    // A paused endpoint mustn't make itself known, so has nobody to ask
+                    if processor.is_paused() {
+                        let _ = reply_tx.send(Vec::new());
+                        continue;
+                    }
 
-        // If its our own notify message ignore it
-        if peer == udis {
-            continue;
+                    let probe = processor.probe(addr)?;
+                    send_notify(&mut *transport, &probe, &diag)?;
+                    let to = SocketAddr::new(addr, MULTICAST_PORT);
+                    send_reply(&mut *transport, &probe, to, &diag)?;
+                    probes.entry(addr).or_default().push((deadline, reply_tx));
+                }
+                #[cfg(feature = "test-util")]
+                Cmd::Inject(serv_info) => {
+                    if serv_info_tx.send(*serv_info).is_err() {
+                        trace!("service receiver dropped, stopping");
+                        break 'main;
+                    }
+                }
+            }
         }
 
-        // If its already in the registry ignore it
-        if registry.contains(&peer) {
-            continue;
+        // Repeat our initial announcement in case it was lost
+        if repeats_left > 0 && clock.now() >= next_repeat {
+            repeats_left -= 1;
+            next_repeat += ANNOUNCE_REPEAT_INTERVAL;
+
+            if processor.is_paused() {
+                trace!(target: TX_LOG_TARGET, "paused, skipping repeat announcement");
+            } else if processor.announcement_is_redundant() {
+                trace!(target: TX_LOG_TARGET, "skipping redundant repeat announcement");
+            } else {
+                trace!(target: TX_LOG_TARGET, "repeating announcement of `{}`", processor.name());
+                send_notify(&mut *transport, processor.notify_message(), &diag)?;
+                processor.announced();
+            }
         }
 
-        // Add the peer to the registry
-        registry.insert(peer.clone());
+        // Announce ourselves again before searchers forget the services we host with a TTL
+        if processor.reannounce_due() {
+            trace!(target: TX_LOG_TARGET, "re-announcing `{}` within its TTL", processor.name());
+            send_notify(&mut *transport, processor.notify_message(), &diag)?;
+            processor.announced();
+        }
 
-        // If the peer is interested in one of the services we're offering notify it directly
-        if udis.get_wanted_services(&peer).count() > 0 {
+        // Leave the discovery network once our lifetime is up
+        if deadline.is_some_and(|deadline| clock.now() >= deadline) {
             trace!(
-                "notified of peer `{}` that wants one of our services",
-                peer.name
+                target: TX_LOG_TARGET,
+                "lifetime expired, saying goodbye from `{}`",
+                processor.name()
             );
+            send_notify(&mut *transport, &processor.goodbye_message()?, &diag)?;
+            break;
+        }
 
-            socket.send_to(&notify_message[..], &disc_addr.into())?;
+        // Report metrics if it's time to
+        if processor
+            .metrics_interval()
+            .is_some_and(|interval| clock.now().saturating_duration_since(last_metrics) >= interval)
+        {
+            processor.report_metrics(&diag);
+            last_metrics = clock.now();
         }
 
-        // If the peer has one of the services we're interested in
-        for service in peer.get_wanted_services(&udis) {
-            let Service::Host { kind, port } = service else {
-                trace!("Non-host service returned by get_wanted_services, skipping");
-                continue;
-            };
+        // Tell the user about found services whose TTL has run out
+        if processor
+            .next_expiry()
+            .is_some_and(|expiry| clock.now() >= expiry)
+        {
+            for event in processor.expire_services() {
+                if service_event_tx.send(event).is_err() {
+                    trace!("service event receiver dropped, stopping");
+                    break 'main;
+                }
+            }
+        }
 
-            trace!(
-                "found peer `{}` that hosts a service we want `{}` at {}:{}",
-                peer.name,
-                kind,
-                peer.addr,
-                port
-            );
+        // Forget anyone whose probe has timed out, telling them nothing replied rather than leaving
+        // them to think the thread has stopped, and any probe nobody is waiting for any more
+        let now = Instant::now();
+        probes.retain(|&addr, waiting| {
+            waiting.retain(|(deadline, reply_tx)| {
+                let waiting = *deadline > now;
+                if !waiting {
+                    let _ = reply_tx.send(Vec::new());
+                }
+                waiting
+            });
+            if waiting.is_empty() {
+                processor.forget_probe(addr);
+            }
+            !waiting.is_empty()
+        });
+
+        // Send replies held back to be coalesced once their window is up
+        if processor.replies_due()
+            && !send_held_replies(&mut processor, &mut *transport, &diag, &host_event_tx)?
+        {
+            break 'main;
+        }
 
-            // Build service info struct
-            let serv_info = ServiceInfo {
-                name: peer.name.clone(),
-                kind: kind.clone(),
-                addr: peer.addr,
-                port: *port,
+        // Receive all packets waiting on the transport, so that bursts of notify messages are
+        // handled promptly rather than one per loop
+        loop {
+            let (src, group) = match transport.recv_with_group(&mut buf) {
+                Ok(received) => {
+                    diag.record_recv();
+                    received
+                }
+                Err(e) => {
+                    match e.kind() {
+                        ErrorKind::TimedOut | ErrorKind::WouldBlock => (),
+                        k => {
+                            error!(
+                                target: RX_LOG_TARGET,
+                                "Error while receiving udis notify messages (will continue): ({k:?}) {e}"
+                            );
+                            diag.record_error(e.into());
+                        }
+                    }
+                    break;
+                }
             };
 
-            // Send to the main thread
-            serv_info_tx.send(serv_info)?;
+            let outputs = processor.process_packet(&buf, src, group)?;
+            if let Some(e) = processor.take_error() {
+                diag.record_error(e);
+            }
+
+            for output in outputs {
+                match output {
+                    Output::Send(msg) => send_notify(&mut *transport, &msg, &diag)?,
+                    Output::Reply(msg, addr) => send_reply(&mut *transport, &msg, addr, &diag)?,
+                    // A closed channel means the endpoint was dropped without being shut down,
+                    // so nobody is listening any more and the thread should just stop
+                    Output::Service(serv_info) => {
+                        if serv_info_tx.send(*serv_info).is_err() {
+                            trace!("service receiver dropped, stopping");
+                            break 'main;
+                        }
+                    }
+                    Output::HostEvent(event) => {
+                        if host_event_tx.send(event).is_err() {
+                            trace!("host event receiver dropped, stopping");
+                            break 'main;
+                        }
+                    }
+                    // Anyone who stopped waiting has dropped their receiver, so can be ignored
+                    Output::ProbeReply(addr, hosted) => {
+                        for (_, reply_tx) in probes.remove(&addr).unwrap_or_default() {
+                            let _ = reply_tx.send(hosted.clone());
+                        }
+                    }
+                    Output::Shutdown => break 'main,
+                }
+            }
+        }
+
+        // Sleep until there's a command or packet to handle, or the next thing is due to happen
+        let next_wake = [
+            (repeats_left > 0).then_some(next_repeat),
+            processor.next_reannounce(),
+            deadline,
+            processor
+                .metrics_interval()
+                .map(|interval| last_metrics + interval),
+            processor.next_expiry(),
+            processor.next_reply_flush(),
+        ]
+        .into_iter()
+        .flatten()
+        .min();
+        let mut timeout = next_wake.map(|at| at.saturating_duration_since(clock.now()));
+        if let Some(period) = poll_period {
+            timeout = Some(timeout.map_or(period, |timeout| timeout.min(period)));
+        }
+
+        events.clear();
+        match poller.wait(&mut events, timeout) {
+            Ok(_) => (),
+            Err(e) if e.kind() == ErrorKind::Interrupted => (),
+            Err(e) => return Err(e.into()),
         }
     }
 
@@ -239,3 +1281,808 @@ fn sync_bg_thread(
 
     Ok(())
 }
+
+/// Send the replies held back to be coalesced, returning false if the host event receiver has been
+/// dropped so the thread should stop
+fn send_held_replies(
+    processor: &mut Processor,
+    transport: &mut dyn Transport,
+    diag: &SharedDiagnostics,
+    host_event_tx: &Sender<HostEvent>,
+) -> Result<bool, Error> {
+    for output in processor.flush_replies() {
+        match output {
+            Output::Send(msg) => send_notify(transport, &msg, diag)?,
+            Output::Reply(msg, addr) => send_reply(transport, &msg, addr, diag)?,
+            Output::HostEvent(event) => {
+                if host_event_tx.send(event).is_err() {
+                    trace!("host event receiver dropped, stopping");
+                    return Ok(false);
+                }
+            }
+            // Only replies and the events for them are held back
+            Output::Service(_) | Output::ProbeReply(..) | Output::Shutdown => (),
+        }
+    }
+
+    Ok(true)
+}
+
+/// Send the notify message to the discovery network
+pub(crate) fn send_notify(
+    transport: &mut dyn Transport,
+    notify_message: &[u8],
+    diag: &SharedDiagnostics,
+) -> Result<(), Error> {
+    match transport.send(notify_message) {
+        Ok(()) => diag.record_send(),
+        Err(e) => ignore_transient_send_error(e, diag)?,
+    }
+
+    Ok(())
+}
+
+/// Send a message directly to a peer
+pub(crate) fn send_reply(
+    transport: &mut dyn Transport,
+    message: &[u8],
+    addr: SocketAddr,
+    diag: &SharedDiagnostics,
+) -> Result<(), Error> {
+    match transport.send_to(message, addr) {
+        Ok(()) => diag.record_send(),
+        Err(e) => ignore_transient_send_error(e, diag)?,
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{self, ErrorKind},
+        net::{Ipv4Addr, SocketAddr},
+        sync::{
+            mpsc::{channel, Sender},
+            Arc,
+        },
+        time::{Duration, Instant},
+    };
+
+    use super::{poll_period, POLL_PERIOD};
+    use crate::{
+        builder::{HostOptions, NameConflict},
+        clock::MockClock,
+        config::Config,
+        error::Error,
+        tcp_host,
+        transport::{ChannelNetwork, Transport},
+        HostEvent, Message, Service, ServiceInfo, Udis,
+    };
+
+    /// A transport which fails to send every message with the given kind of error, letting the
+    /// test know each time it tries
+    struct FailingTransport {
+        kind: ErrorKind,
+        attempts_tx: Sender<()>,
+    }
+
+    impl Transport for FailingTransport {
+        fn send(&mut self, _msg: &[u8]) -> io::Result<()> {
+            let _ = self.attempts_tx.send(());
+            Err(self.kind.into())
+        }
+
+        fn recv(&mut self, _buf: &mut Vec<u8>) -> io::Result<SocketAddr> {
+            Err(ErrorKind::WouldBlock.into())
+        }
+    }
+
+    #[test]
+    fn test_burst_of_announcements() {
+        const NUM_PEERS: u16 = 50;
+
+        let network = ChannelNetwork::new();
+        let udis = Udis::new("burst-client")
+            .addr(Ipv4Addr::new(10, 0, 0, 1))
+            .search("burst-test")
+            .build_sync_with_transport(network.transport((Ipv4Addr::new(10, 0, 0, 1), 8787)))
+            .unwrap();
+
+        // Send lots of announcements as quickly as possible
+        let mut peers = network.transport((Ipv4Addr::new(10, 0, 0, 2), 8787));
+        for i in 0..NUM_PEERS {
+            let peer = Udis::build(
+                format!("burst-server-{i}"),
+                Ipv4Addr::new(10, 0, 0, 2).into(),
+                vec![tcp_host("burst-test", 5000 + i)],
+            );
+
+            peers
+                .send(&Message::Notify(peer).encode().unwrap())
+                .unwrap();
+        }
+
+        // All of them should be discovered well within the time it would take to process one
+        // packet per loop
+        let start = Instant::now();
+        let mut found = 0;
+        while found < NUM_PEERS && start.elapsed() < Duration::from_secs(2) {
+            match udis.try_find_service().unwrap() {
+                Some(_) => found += 1,
+                None => std::thread::sleep(Duration::from_millis(10)),
+            }
+        }
+
+        udis.shutdown().unwrap();
+
+        assert_eq!(found, NUM_PEERS);
+    }
+
+    #[test]
+    #[ignore = "uses the real multicast network"]
+    fn test_same_process() {
+        // Endpoints in one process share the discovery port, and must still see each other
+        let server = Udis::new("same-process-server")
+            .addr(Ipv4Addr::LOCALHOST)
+            .host("same-process-test", 4112)
+            .unwrap()
+            .build_sync()
+            .unwrap();
+        let client = Udis::new("same-process-client")
+            .addr(Ipv4Addr::LOCALHOST)
+            .search("same-process-test")
+            .build_sync()
+            .unwrap();
+
+        let found = client
+            .find_service_where(Duration::from_secs(2), false, |s| {
+                s.name == "same-process-server"
+            })
+            .unwrap();
+        assert_eq!(found.map(|s| s.port), Some(4112));
+        assert_eq!(client.diagnostics().multicast_loop, Some(true));
+
+        client.shutdown().unwrap();
+        server.shutdown().unwrap();
+    }
+
+    #[test]
+    fn test_send_failures() {
+        // A transient failure to send shouldn't stop the background thread
+        let (attempts_tx, attempts_rx) = channel();
+        let udis = Udis::new("flaky")
+            .addr(Ipv4Addr::LOCALHOST)
+            .build_sync_with_transport(FailingTransport {
+                kind: ErrorKind::NetworkUnreachable,
+                attempts_tx,
+            })
+            .unwrap();
+        attempts_rx.recv().unwrap();
+        assert!(udis.notify_payload().is_ok());
+
+        // The failure should be kept for the user, until they've seen it
+        assert!(matches!(udis.last_error(), Some(Error::IoError(e))
+            if e.kind() == ErrorKind::NetworkUnreachable));
+        assert!(udis.last_error().is_none());
+        udis.shutdown().unwrap();
+
+        // But any other failure should
+        let (attempts_tx, attempts_rx) = channel();
+        let udis = Udis::new("broken")
+            .addr(Ipv4Addr::LOCALHOST)
+            .build_sync_with_transport(FailingTransport {
+                kind: ErrorKind::InvalidInput,
+                attempts_tx,
+            })
+            .unwrap();
+        attempts_rx.recv().unwrap();
+        assert!(udis.notify_payload().is_err());
+        assert!(matches!(udis.shutdown(), Err(Error::IoError(_))));
+    }
+
+    #[test]
+    fn test_find_service_where() {
+        let network = ChannelNetwork::new();
+        let servers = (1..=2)
+            .map(|i| {
+                Udis::new(format!("server-{i}"))
+                    .addr(Ipv4Addr::new(10, 0, 0, i))
+                    .host("hello", 4110 + u16::from(i))
+                    .unwrap()
+                    .build_sync_with_transport(
+                        network.transport((Ipv4Addr::new(10, 0, 0, i), 8787)),
+                    )
+                    .unwrap()
+            })
+            .collect::<Vec<_>>();
+        let client = Udis::new("client")
+            .addr(Ipv4Addr::new(10, 0, 0, 3))
+            .search("hello")
+            .build_sync_with_transport(network.transport((Ipv4Addr::new(10, 0, 0, 3), 8787)))
+            .unwrap();
+
+        // Services which don't match should be put back for later
+        let timeout = Duration::from_secs(2);
+        let even = client
+            .find_service_where(timeout, true, |s| s.port % 2 == 0)
+            .unwrap();
+        assert_eq!(even.map(|s| s.port), Some(4112));
+        assert_eq!(client.find_service().unwrap().port, 4111);
+
+        // Or discarded if not wanted
+        let none = client
+            .find_service_where(Duration::from_millis(100), false, |_| false)
+            .unwrap();
+        assert!(none.is_none());
+        assert_eq!(client.drain().unwrap(), 0);
+
+        client.shutdown().unwrap();
+        for server in servers {
+            server.shutdown().unwrap();
+        }
+    }
+
+    #[test]
+    fn test_refresh() {
+        let network = ChannelNetwork::new();
+        let server = Udis::new("server")
+            .addr(Ipv4Addr::new(10, 0, 0, 1))
+            .host("hello", 4112)
+            .unwrap()
+            .build_sync_with_transport(network.transport((Ipv4Addr::new(10, 0, 0, 1), 8787)))
+            .unwrap();
+        let client = Udis::new("client")
+            .addr(Ipv4Addr::new(10, 0, 0, 2))
+            .search("hello")
+            .build_sync_with_transport(network.transport((Ipv4Addr::new(10, 0, 0, 2), 8787)))
+            .unwrap();
+
+        let timeout = Duration::from_secs(2);
+        let found = client.find_service_where(timeout, false, |_| true).unwrap();
+        assert_eq!(found.map(|s| s.port), Some(4112));
+
+        // Refreshing should find the same service again
+        client.refresh().unwrap();
+        let found = client.find_service_where(timeout, false, |_| true).unwrap();
+        assert_eq!(found.map(|s| s.port), Some(4112));
+
+        client.shutdown().unwrap();
+        server.shutdown().unwrap();
+    }
+
+    #[test]
+    fn test_timed_find_while_blocked() {
+        let network = ChannelNetwork::new();
+        let client = Arc::new(
+            Udis::new("client")
+                .addr(Ipv4Addr::new(10, 0, 0, 2))
+                .search("hello")
+                .build_sync_with_transport(network.transport((Ipv4Addr::new(10, 0, 0, 2), 8787)))
+                .unwrap(),
+        );
+
+        // One thread waiting for a service without a timeout shouldn't hold up timed finds
+        let blocked = {
+            let client = client.clone();
+            std::thread::spawn(move || client.find_service())
+        };
+        std::thread::sleep(Duration::from_millis(50));
+        let started = Instant::now();
+        let found = client
+            .find_service_where(Duration::from_millis(100), true, |_| true)
+            .unwrap();
+        assert!(found.is_none());
+        assert!(started.elapsed() < Duration::from_secs(1));
+        assert!(client.try_find_service().unwrap().is_none());
+
+        // The waiting thread should still be given the next service found
+        let server = Udis::new("server")
+            .addr(Ipv4Addr::new(10, 0, 0, 1))
+            .host("hello", 4112)
+            .unwrap()
+            .build_sync_with_transport(network.transport((Ipv4Addr::new(10, 0, 0, 1), 8787)))
+            .unwrap();
+        assert_eq!(blocked.join().unwrap().unwrap().port, 4112);
+
+        Arc::into_inner(client).unwrap().shutdown().unwrap();
+        server.shutdown().unwrap();
+    }
+
+    #[test]
+    fn test_shared_between_threads() {
+        const NUM_SERVERS: u8 = 4;
+
+        let network = ChannelNetwork::new();
+        let servers = (1..=NUM_SERVERS)
+            .map(|i| {
+                Udis::new(format!("server-{i}"))
+                    .addr(Ipv4Addr::new(10, 0, 0, i))
+                    .host("hello", 4110 + u16::from(i))
+                    .unwrap()
+                    .build_sync_with_transport(
+                        network.transport((Ipv4Addr::new(10, 0, 0, i), 8787)),
+                    )
+                    .unwrap()
+            })
+            .collect::<Vec<_>>();
+        let client = Arc::new(
+            Udis::new("client")
+                .addr(Ipv4Addr::new(10, 0, 0, 100))
+                .search("hello")
+                .build_sync_with_transport(network.transport((Ipv4Addr::new(10, 0, 0, 100), 8787)))
+                .unwrap(),
+        );
+
+        // Every service should go to exactly one of the threads
+        let workers = (0..2)
+            .map(|_| {
+                let client = client.clone();
+                std::thread::spawn(move || {
+                    let mut ports = Vec::new();
+                    while let Some(serv_info) = client
+                        .find_service_where(Duration::from_millis(500), false, |_| true)
+                        .unwrap()
+                    {
+                        ports.push(serv_info.port);
+                    }
+                    ports
+                })
+            })
+            .collect::<Vec<_>>();
+        let mut ports = workers
+            .into_iter()
+            .flat_map(|worker| worker.join().unwrap())
+            .collect::<Vec<_>>();
+        ports.sort_unstable();
+        assert_eq!(ports, vec![4111, 4112, 4113, 4114]);
+
+        Arc::into_inner(client).unwrap().shutdown().unwrap();
+        for server in servers {
+            server.shutdown().unwrap();
+        }
+    }
+
+    #[test]
+    fn test_probe() {
+        let network = ChannelNetwork::new();
+        let server = Udis::new("server")
+            .addr(Ipv4Addr::new(10, 0, 0, 1))
+            .host("hello", 4112)
+            .unwrap()
+            .host("goodbye", 4113)
+            .unwrap()
+            .build_sync_with_transport(network.transport((Ipv4Addr::new(10, 0, 0, 1), 8787)))
+            .unwrap();
+        let client = Udis::new("client")
+            .addr(Ipv4Addr::new(10, 0, 0, 2))
+            .search("hello")
+            .build_sync_with_transport(network.transport((Ipv4Addr::new(10, 0, 0, 2), 8787)))
+            .unwrap();
+
+        // Every service the peer hosts should be returned, not just the ones searched for, and
+        // probing again should get another reply
+        let timeout = Duration::from_secs(2);
+        for _ in 0..2 {
+            let mut ports = client
+                .probe(Ipv4Addr::new(10, 0, 0, 1).into(), timeout)
+                .unwrap()
+                .into_iter()
+                .map(|s| s.port)
+                .collect::<Vec<_>>();
+            ports.sort_unstable();
+            assert_eq!(ports, vec![4112, 4113]);
+        }
+
+        // Nobody is at the address so there's no reply
+        let hosted = client
+            .probe(
+                Ipv4Addr::new(10, 0, 0, 3).into(),
+                Duration::from_millis(100),
+            )
+            .unwrap();
+        assert!(hosted.is_empty());
+
+        // A paused endpoint gives up straight away without sending the probe
+        let mut observer = network.transport((Ipv4Addr::new(10, 0, 0, 9), 8787));
+        client.pause().unwrap();
+        let start = Instant::now();
+        let hosted = client
+            .probe(Ipv4Addr::new(10, 0, 0, 1).into(), Duration::from_secs(5))
+            .unwrap();
+        assert!(hosted.is_empty());
+        assert!(start.elapsed() < Duration::from_secs(1));
+        let mut buf = Vec::new();
+        while observer.recv(&mut buf).is_ok() {
+            let msg = Message::decode(&buf, usize::MAX, usize::MAX);
+            assert!(!matches!(msg, Ok(Message::Probe(_))));
+        }
+
+        client.shutdown().unwrap();
+        server.shutdown().unwrap();
+    }
+
+    #[test]
+    fn test_ttl_reannounce() {
+        let network = ChannelNetwork::new();
+        let clock = MockClock::new();
+        let server = Udis::new("server")
+            .addr(Ipv4Addr::new(10, 0, 0, 1))
+            .host_with(
+                "hello",
+                4112,
+                HostOptions {
+                    ttl: Some(Duration::from_secs(10)),
+                    ..Default::default()
+                },
+            )
+            .unwrap()
+            .clock(clock.clone())
+            .build_sync_with_transport(network.transport((Ipv4Addr::new(10, 0, 0, 1), 8787)))
+            .unwrap();
+        let client = Udis::new("client")
+            .addr(Ipv4Addr::new(10, 0, 0, 2))
+            .search("hello")
+            .clock(clock.clone())
+            .build_sync_with_transport(network.transport((Ipv4Addr::new(10, 0, 0, 2), 8787)))
+            .unwrap();
+
+        let found = client
+            .find_service_where(Duration::from_secs(2), false, |_| true)
+            .unwrap();
+        assert_eq!(found.map(|s| s.port), Some(4112));
+
+        // A host which is still running announces itself halfway through its TTL, so its service
+        // never expires however long it runs for
+        let wait_for = |done: &dyn Fn() -> bool| {
+            let deadline = Instant::now() + Duration::from_secs(2);
+            while !done() && Instant::now() < deadline {
+                std::thread::sleep(Duration::from_millis(10));
+            }
+            assert!(done());
+        };
+        for _ in 0..4 {
+            let sent = server.diagnostics().packets_sent;
+            clock.advance(Duration::from_secs(5));
+
+            // Every packet on the network, including the client's own, reaches the client
+            wait_for(&|| server.diagnostics().packets_sent > sent);
+            wait_for(&|| {
+                let sent = server.diagnostics().packets_sent + client.diagnostics().packets_sent;
+                client.diagnostics().packets_received >= sent
+            });
+            assert!(client.try_next_service_event().unwrap().is_none());
+        }
+
+        client.shutdown().unwrap();
+        server.shutdown().unwrap();
+    }
+
+    #[test]
+    fn test_find_best_service() {
+        let network = ChannelNetwork::new();
+        let servers = [
+            ("backup", "hello", 10),
+            ("primary", "hello", 0),
+            ("other", "world", 0),
+        ]
+        .into_iter()
+        .zip(1..)
+        .map(|((name, kind, priority), i)| {
+            Udis::new(name)
+                .addr(Ipv4Addr::new(10, 0, 0, i))
+                .host_with(
+                    kind,
+                    4112,
+                    HostOptions {
+                        priority,
+                        ..Default::default()
+                    },
+                )
+                .unwrap()
+                .build_sync_with_transport(network.transport((Ipv4Addr::new(10, 0, 0, i), 8787)))
+                .unwrap()
+        })
+        .collect::<Vec<_>>();
+        let client = Udis::new("client")
+            .addr(Ipv4Addr::new(10, 0, 0, 4))
+            .search("hello")
+            .search("world")
+            .build_sync_with_transport(network.transport((Ipv4Addr::new(10, 0, 0, 4), 8787)))
+            .unwrap();
+
+        let found = client
+            .find_best_service("hello", Duration::from_millis(500))
+            .unwrap();
+        assert_eq!(found.map(|s| s.name), Some("primary".into()));
+
+        // The services which weren't picked should be put back for later
+        let mut rest = client
+            .find_all_services(Instant::now() + Duration::from_millis(100))
+            .unwrap()
+            .into_iter()
+            .map(|s| s.name)
+            .collect::<Vec<_>>();
+        rest.sort();
+        assert_eq!(rest, ["backup", "other"]);
+
+        client.shutdown().unwrap();
+        for server in servers {
+            server.shutdown().unwrap();
+        }
+    }
+
+    #[test]
+    fn test_find_best_service_sticky() {
+        let network = ChannelNetwork::new();
+        let servers = [("primary", 0), ("backup", 10)]
+            .into_iter()
+            .zip(1..)
+            .map(|((name, priority), i)| {
+                Udis::new(name)
+                    .addr(Ipv4Addr::new(10, 0, 0, i))
+                    .host_with(
+                        "hello",
+                        4112,
+                        HostOptions {
+                            priority,
+                            ..Default::default()
+                        },
+                    )
+                    .unwrap()
+                    .build_sync_with_transport(
+                        network.transport((Ipv4Addr::new(10, 0, 0, i), 8787)),
+                    )
+                    .unwrap()
+            })
+            .collect::<Vec<_>>();
+        let client = Udis::new("client")
+            .addr(Ipv4Addr::new(10, 0, 0, 3))
+            .search("hello")
+            .build_sync_with_transport(network.transport((Ipv4Addr::new(10, 0, 0, 3), 8787)))
+            .unwrap();
+
+        // The previous host should be kept even though the other one is preferable
+        let previous = ServiceInfo::new("backup", "hello", Ipv4Addr::new(10, 0, 0, 2).into(), 4112);
+        let found = client
+            .find_best_service_sticky("hello", Duration::from_secs(2), &previous)
+            .unwrap();
+        assert_eq!(found.map(|s| s.name), Some("backup".into()));
+
+        client.shutdown().unwrap();
+        for server in servers {
+            server.shutdown().unwrap();
+        }
+    }
+
+    #[test]
+    #[ignore = "uses the real multicast network"]
+    fn test_restart() {
+        let server = Udis::new("restart-server")
+            .addr(Ipv4Addr::LOCALHOST)
+            .host("restart-test", 4112)
+            .unwrap()
+            .build_sync()
+            .unwrap();
+        let mut client = Udis::new("restart-client")
+            .addr(Ipv4Addr::LOCALHOST)
+            .search("restart-test")
+            .find_once()
+            .build_sync()
+            .unwrap();
+
+        // Restarting a running endpoint does nothing
+        client.restart().unwrap();
+
+        let timeout = Duration::from_secs(2);
+        let found = client.find_service_where(timeout, false, |_| true).unwrap();
+        assert_eq!(found.map(|s| s.port), Some(4112));
+
+        // Once the background thread has stopped it can be restarted to find the service again
+        let deadline = Instant::now() + timeout;
+        while !client.bg_thread_jh.is_finished() && Instant::now() < deadline {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        assert!(client.recent_events().is_err());
+
+        client.restart().unwrap();
+        let found = client.find_service_where(timeout, false, |_| true).unwrap();
+        assert_eq!(found.map(|s| s.port), Some(4112));
+
+        client.shutdown().unwrap();
+        server.shutdown().unwrap();
+    }
+
+    #[test]
+    fn test_restart_custom_transport() {
+        // Custom transports can't be rebuilt
+        let network = ChannelNetwork::new();
+        let mut udis = Udis::new("custom")
+            .addr(Ipv4Addr::new(10, 0, 0, 1))
+            .lifetime(Duration::ZERO)
+            .build_sync_with_transport(network.transport((Ipv4Addr::new(10, 0, 0, 1), 8787)))
+            .unwrap();
+        while !udis.bg_thread_jh.is_finished() {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        assert!(matches!(
+            udis.restart(),
+            Err(Error::CustomTransportNotRestartable)
+        ));
+    }
+
+    #[test]
+    fn test_extra_payload() {
+        let network = ChannelNetwork::new();
+        let server = Udis::new("server")
+            .addr(Ipv4Addr::new(10, 0, 0, 1))
+            .host("hello", 4112)
+            .unwrap()
+            .host("goodbye", 4113)
+            .unwrap()
+            .extra_payload(vec![0x00, 0xab, 0x10, 0xff])
+            .build_sync_with_transport(network.transport((Ipv4Addr::new(10, 0, 0, 1), 8787)))
+            .unwrap();
+        let client = Udis::new("client")
+            .addr(Ipv4Addr::new(10, 0, 0, 2))
+            .searches(["hello", "goodbye"])
+            .build_sync_with_transport(network.transport((Ipv4Addr::new(10, 0, 0, 2), 8787)))
+            .unwrap();
+
+        // Every service from the announcement should carry the same payload, without copying it
+        let first = client.find_service().unwrap();
+        let second = client.find_service().unwrap();
+        assert_eq!(&first.extra[..], [0x00, 0xab, 0x10, 0xff]);
+        assert!(Arc::ptr_eq(&first.extra, &second.extra));
+
+        client.shutdown().unwrap();
+        server.shutdown().unwrap();
+    }
+
+    #[test]
+    fn test_find_all_services() {
+        let network = ChannelNetwork::new();
+        let server = Udis::new("server")
+            .addr(Ipv4Addr::new(10, 0, 0, 1))
+            .host("hello", 4112)
+            .unwrap()
+            .build_sync_with_transport(network.transport((Ipv4Addr::new(10, 0, 0, 1), 8787)))
+            .unwrap();
+        let client = Udis::new("client")
+            .addr(Ipv4Addr::new(10, 0, 0, 2))
+            .search("hello")
+            .search("world")
+            .build_sync_with_transport(network.transport((Ipv4Addr::new(10, 0, 0, 2), 8787)))
+            .unwrap();
+
+        // Nobody hosts `world`, so the deadline passes with only some of the services found
+        let deadline = Instant::now() + Duration::from_millis(500);
+        let found = client.find_all_services(deadline).unwrap();
+        assert!(Instant::now() >= deadline);
+        assert_eq!(
+            found.iter().map(|s| s.kind.as_str()).collect::<Vec<_>>(),
+            ["hello"]
+        );
+
+        // Finding nothing before the deadline isn't an error either
+        let found = client
+            .find_all_services(Instant::now() + Duration::from_millis(100))
+            .unwrap();
+        assert!(found.is_empty());
+
+        client.shutdown().unwrap();
+        server.shutdown().unwrap();
+    }
+
+    #[test]
+    #[ignore = "uses the real multicast network"]
+    fn test_name_conflict() {
+        let server = Udis::new("name-conflict")
+            .addr(Ipv4Addr::LOCALHOST)
+            .host("name-conflict-test", 4112)
+            .unwrap()
+            .build_sync()
+            .unwrap();
+
+        // Another endpoint wanting the same name should be renamed
+        let renamed = Udis::new("name-conflict")
+            .addr(Ipv4Addr::LOCALHOST)
+            .search("name-conflict-test")
+            .on_name_conflict(NameConflict::Rename)
+            .build_sync()
+            .unwrap();
+        let payload = String::from_utf8(renamed.notify_payload().unwrap()).unwrap();
+        assert!(payload.contains(r#""name":"name-conflict (2)""#));
+
+        // Or fail to build if it would rather
+        let failed = Udis::new("name-conflict")
+            .addr(Ipv4Addr::LOCALHOST)
+            .on_name_conflict(NameConflict::Fail)
+            .build_sync();
+        assert!(matches!(failed, Err(Error::NameConflict(name)) if name == "name-conflict"));
+
+        renamed.shutdown().unwrap();
+        server.shutdown().unwrap();
+    }
+
+    #[test]
+    fn test_shutdown_sends_held_replies() {
+        let network = ChannelNetwork::new();
+        let server = Udis::new("server")
+            .addr(Ipv4Addr::new(10, 0, 0, 1))
+            .host("hello", 4112)
+            .unwrap()
+            .host_events()
+            .reply_coalesce_window(Duration::from_secs(60))
+            .build_sync_with_transport(network.transport((Ipv4Addr::new(10, 0, 0, 1), 8787)))
+            .unwrap();
+
+        let client = Udis::build(
+            "client".into(),
+            Ipv4Addr::new(10, 0, 0, 2).into(),
+            vec![Service::Search {
+                kind: "hello".into(),
+            }],
+        );
+        let mut client_transport = network.transport((Ipv4Addr::new(10, 0, 0, 2), 8787));
+        client_transport
+            .send(&Message::Notify(client).encode().unwrap())
+            .unwrap();
+        assert!(matches!(
+            server.next_host_event().unwrap(),
+            HostEvent::NewInterest { peer, .. } if peer == "client"
+        ));
+
+        // The reply is held back well past the test, so it's only sent because of the shutdown
+        let mut buf = Vec::new();
+        while client_transport.recv(&mut buf).is_ok() {}
+        server.shutdown().unwrap();
+
+        let mut replies = 0;
+        while client_transport.recv(&mut buf).is_ok() {
+            if let Ok(Message::Notify(udis)) = Message::decode(&buf, usize::MAX, usize::MAX) {
+                assert_eq!(udis.name, "server");
+                replies += 1;
+            }
+        }
+        assert_eq!(replies, 1);
+    }
+
+    #[test]
+    fn test_shutdown_after_stopping() {
+        // An endpoint whose thread stopped cleanly by itself should still shut down cleanly
+        let network = ChannelNetwork::new();
+        let udis = Udis::new("short-lived")
+            .addr(Ipv4Addr::LOCALHOST)
+            .lifetime(Duration::from_millis(50))
+            .build_sync_with_transport(network.transport((Ipv4Addr::LOCALHOST, 8787)))
+            .unwrap();
+
+        let deadline = Instant::now() + Duration::from_secs(2);
+        while !udis.bg_thread_jh.is_finished() && Instant::now() < deadline {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        assert!(udis.bg_thread_jh.is_finished());
+        udis.shutdown().unwrap();
+    }
+
+    #[test]
+    fn test_read_timeout_caps_poll_period() {
+        let config = |timeout: Option<u64>| Config {
+            read_timeout: timeout.map(Duration::from_millis),
+            ..Default::default()
+        };
+
+        assert_eq!(poll_period(&config(None), true), None);
+        assert_eq!(poll_period(&config(None), false), Some(POLL_PERIOD));
+        assert_eq!(
+            poll_period(&config(Some(20)), true),
+            Some(Duration::from_millis(20))
+        );
+        assert_eq!(
+            poll_period(&config(Some(20)), false),
+            Some(Duration::from_millis(20))
+        );
+        assert_eq!(poll_period(&config(Some(500)), false), Some(POLL_PERIOD));
+    }
+}
@@ -0,0 +1,375 @@
+use std::{
+    io::{self, ErrorKind},
+    iter,
+    net::{SocketAddr, SocketAddrV4},
+    sync::{
+        mpsc::{channel, Receiver, Sender, TryRecvError},
+        Arc, Mutex,
+    },
+};
+
+use log::trace;
+use polling::{Event, PollMode, Poller};
+use socket2::Socket;
+
+use crate::{
+    builder::BindMode,
+    config::{Config, VERIFY_MULTICAST_TIMEOUT},
+    diagnostics::SharedDiagnostics,
+    error::Error,
+    net::{
+        build_group_socket, build_unicast_socket, discovery_socket, send_addr, verify_discovery,
+    },
+    relay::Relay,
+};
+
+/// A transport connects a udis endpoint to the discovery network.
+///
+/// By default endpoints use UDP multicast, but a custom transport can be supplied with
+/// [`crate::builder::Builder::build_sync_with_transport`], for example a [`ChannelTransport`] to
+/// connect several endpoints in memory for testing.
+pub trait Transport: Send + 'static {
+    /// Send a message to every endpoint on the discovery network.
+    ///
+    /// # Errors
+    ///
+    /// This function should return an error if the message could not be sent.
+    fn send(&mut self, msg: &[u8]) -> io::Result<()>;
+
+    /// Receive the next message from the discovery network into `buf` without blocking,
+    /// returning the source address of the message.
+    ///
+    /// Any existing contents of `buf` should be replaced by the message.
+    ///
+    /// # Errors
+    ///
+    /// If there is no message waiting this function must return an error of kind
+    /// [`ErrorKind::WouldBlock`], any other error is logged by the endpoint and ignored.
+    fn recv(&mut self, buf: &mut Vec<u8>) -> io::Result<SocketAddr>;
+
+    /// Receive the next message like [`Transport::recv`], also returning the extra multicast group
+    /// it was received on if it wasn't received on the discovery network, see
+    /// [`crate::builder::Builder::join_group`].
+    ///
+    /// By default no extra groups are joined, so this never returns a group.
+    ///
+    /// # Errors
+    ///
+    /// This function must return errors in the same way as [`Transport::recv`].
+    fn recv_with_group(
+        &mut self,
+        buf: &mut Vec<u8>,
+    ) -> io::Result<(SocketAddr, Option<SocketAddrV4>)> {
+        self.recv(buf).map(|src| (src, None))
+    }
+
+    /// Send a message directly to the endpoint at `addr`, which is the source address of a
+    /// message previously received by [`Transport::recv`].
+    ///
+    /// By default this sends the message to every endpoint, as [`Transport::send`] does.
+    ///
+    /// # Errors
+    ///
+    /// This function should return an error if the message could not be sent.
+    fn send_to(&mut self, msg: &[u8], addr: SocketAddr) -> io::Result<()> {
+        let _ = addr;
+        self.send(msg)
+    }
+}
+
+/// The default UDP multicast transport
+#[derive(Debug)]
+pub(crate) struct UdpTransport {
+    socket: Socket,
+
+    /// Socket bound to the unicast port, if there is one, which all messages are sent from
+    unicast: Option<Socket>,
+
+    /// Addresses of the discovery network groups messages are sent to
+    disc_addrs: Vec<SocketAddr>,
+
+    /// Extra multicast groups messages are also sent to, and the sockets receiving from them
+    groups: Vec<(SocketAddrV4, Socket)>,
+
+    bind_mode: BindMode,
+
+    /// Poller the sockets are registered with, if any, which they're removed from on drop
+    poller: Option<Arc<Poller>>,
+
+    /// Relays messages between interfaces while the transport is in use, if asked to
+    _relay: Option<Relay>,
+}
+
+impl UdpTransport {
+    /// Build the multicast socket described by the config, or use the one the user gave us
+    pub(crate) fn from_config(
+        config: &mut Config,
+        diag: &SharedDiagnostics,
+    ) -> Result<Self, Error> {
+        let (disc_addrs, socket) = discovery_socket(
+            config.socket.take(),
+            config.bind_mode(),
+            config.delivery,
+            config.device.as_deref(),
+            config.dscp,
+        )?;
+        trace!("joined udis notify network on {disc_addrs:?}");
+        diag.record_socket(&disc_addrs, &socket);
+
+        let unicast = config
+            .unicast_port
+            .map(|port| {
+                trace!("using unicast port {port}");
+                build_unicast_socket(
+                    port,
+                    config.bind_mode(),
+                    config.delivery,
+                    config.device.as_deref(),
+                    config.dscp,
+                )
+            })
+            .transpose()?;
+
+        if config.verify_multicast {
+            trace!("verifying the udis notify network");
+            verify_discovery(
+                &socket,
+                unicast.as_ref().unwrap_or(&socket),
+                &disc_addrs,
+                VERIFY_MULTICAST_TIMEOUT,
+            )?;
+        }
+
+        let groups = config
+            .groups
+            .iter()
+            .map(|&group| {
+                trace!("joining extra group {group}");
+                build_group_socket(group, config.device.as_deref()).map(|socket| (group, socket))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(Self {
+            socket,
+            unicast,
+            disc_addrs,
+            groups,
+            bind_mode: config.bind_mode(),
+            poller: None,
+            _relay: Relay::start(config)?,
+        })
+    }
+
+    /// Register every socket with `poller`, so that waiting on it wakes when a message can be
+    /// received, returning whether they were registered.
+    ///
+    /// The sockets are registered level-triggered so they don't need re-arming after each wake,
+    /// which means they aren't registered on platforms where the poller doesn't support that.
+    pub(crate) fn register(&mut self, poller: Arc<Poller>) -> io::Result<bool> {
+        if !poller.supports_level() {
+            return Ok(false);
+        }
+
+        self.poller = Some(poller.clone());
+        for socket in self.sockets() {
+            // SAFETY: the sockets are deleted from the poller when the transport is dropped
+            unsafe { poller.add_with_mode(socket, Event::readable(0), PollMode::Level)? };
+        }
+
+        Ok(true)
+    }
+
+    /// Every socket messages are received on
+    fn sockets(&self) -> impl Iterator<Item = &Socket> {
+        iter::once(&self.socket)
+            .chain(&self.unicast)
+            .chain(self.groups.iter().map(|(_, socket)| socket))
+    }
+
+    /// The socket messages are sent from
+    fn send_socket(&self) -> &Socket {
+        self.unicast.as_ref().unwrap_or(&self.socket)
+    }
+}
+
+impl Transport for UdpTransport {
+    fn send(&mut self, msg: &[u8]) -> io::Result<()> {
+        for disc_addr in &self.disc_addrs {
+            self.send_socket().send_to(msg, &(*disc_addr).into())?;
+        }
+
+        for (group, _) in &self.groups {
+            let addr = send_addr((*group).into(), self.bind_mode);
+            self.send_socket().send_to(msg, &addr.into())?;
+        }
+
+        Ok(())
+    }
+
+    fn recv(&mut self, buf: &mut Vec<u8>) -> io::Result<SocketAddr> {
+        self.recv_with_group(buf).map(|(src, _)| src)
+    }
+
+    fn recv_with_group(
+        &mut self,
+        buf: &mut Vec<u8>,
+    ) -> io::Result<(SocketAddr, Option<SocketAddrV4>)> {
+        let mut res = match (recv_from(&self.socket, buf), &self.unicast) {
+            (Err(e), Some(unicast)) if e.kind() == ErrorKind::WouldBlock => recv_from(unicast, buf),
+            (res, _) => res,
+        }
+        .map(|src| (src, None));
+
+        // Only check the extra groups once nothing is waiting on the discovery network
+        for (group, socket) in &self.groups {
+            match &res {
+                Err(e) if e.kind() == ErrorKind::WouldBlock => {
+                    res = recv_from(socket, buf).map(|src| (src, Some(*group)));
+                }
+                _ => break,
+            }
+        }
+
+        res
+    }
+
+    fn send_to(&mut self, msg: &[u8], addr: SocketAddr) -> io::Result<()> {
+        self.send_socket()
+            .send_to(msg, &send_addr(addr, self.bind_mode).into())?;
+
+        Ok(())
+    }
+}
+
+impl Drop for UdpTransport {
+    fn drop(&mut self) {
+        if let Some(poller) = &self.poller {
+            for socket in self.sockets() {
+                // Sockets which failed to register aren't in the poller, which is fine
+                let _ = poller.delete(socket);
+            }
+        }
+    }
+}
+
+/// Receive a packet on the socket into `buf`
+pub(crate) fn recv_from(socket: &Socket, buf: &mut Vec<u8>) -> io::Result<SocketAddr> {
+    buf.clear();
+
+    let (received, src) = socket.recv_from(buf.spare_capacity_mut())?;
+
+    // SAFETY: just received into the `buffer`.
+    unsafe {
+        buf.set_len(received);
+    }
+
+    // The socket is always an IP one, so the source should always be an IP address
+    src.as_socket()
+        .ok_or_else(|| io::Error::new(ErrorKind::InvalidData, "non-IP source address"))
+}
+
+/// A message on a [`ChannelNetwork`] and the address of the transport that sent it
+type Packet = (Vec<u8>, SocketAddr);
+
+/// An in-memory discovery network, which [`ChannelTransport`]s can be connected to.
+///
+/// This lets several endpoints discover each other without touching the real network, which is
+/// useful for testing code that uses udis.
+///
+/// # Examples
+///
+/// ```
+/// use std::net::{Ipv4Addr, SocketAddr};
+///
+/// let network = udis::transport::ChannelNetwork::new();
+///
+/// let server = udis::Udis::new("server")
+///     .addr(Ipv4Addr::new(10, 0, 0, 1))
+///     .host("hello", 4112)
+///     .unwrap()
+///     .build_sync_with_transport(network.transport((Ipv4Addr::new(10, 0, 0, 1), 8787)))
+///     .unwrap();
+///
+/// let client = udis::Udis::new("client")
+///     .addr(Ipv4Addr::new(10, 0, 0, 2))
+///     .search("hello")
+///     .build_sync_with_transport(network.transport((Ipv4Addr::new(10, 0, 0, 2), 8787)))
+///     .unwrap();
+///
+/// let service = client.find_service().unwrap();
+/// assert_eq!(service.name, "server");
+///
+/// client.shutdown().unwrap();
+/// server.shutdown().unwrap();
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct ChannelNetwork {
+    /// Senders for every transport connected to the network
+    transports: Arc<Mutex<Vec<Sender<Packet>>>>,
+}
+
+impl ChannelNetwork {
+    /// Create a new, empty, in-memory discovery network
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Create a transport connected to this network. Messages sent by the transport will appear to
+    /// come from `addr`.
+    pub fn transport<A: Into<SocketAddr>>(&self, addr: A) -> ChannelTransport {
+        let (tx, rx) = channel();
+
+        match self.transports.lock() {
+            Ok(mut transports) => transports.push(tx),
+            Err(poisoned) => poisoned.into_inner().push(tx),
+        }
+
+        ChannelTransport {
+            addr: addr.into(),
+            network: self.clone(),
+            rx,
+        }
+    }
+}
+
+/// A transport connected to an in-memory [`ChannelNetwork`].
+///
+/// Like multicast, every message sent by a transport is received by every transport on the
+/// network, including the sender.
+#[derive(Debug)]
+pub struct ChannelTransport {
+    /// The address messages from this transport appear to come from
+    addr: SocketAddr,
+
+    network: ChannelNetwork,
+
+    rx: Receiver<Packet>,
+}
+
+impl Transport for ChannelTransport {
+    fn send(&mut self, msg: &[u8]) -> io::Result<()> {
+        let mut transports = match self.network.transports.lock() {
+            Ok(transports) => transports,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+
+        // Forget about any transports which have been dropped
+        transports.retain(|tx| tx.send((msg.to_vec(), self.addr)).is_ok());
+
+        Ok(())
+    }
+
+    fn recv(&mut self, buf: &mut Vec<u8>) -> io::Result<SocketAddr> {
+        match self.rx.try_recv() {
+            Ok((msg, src)) => {
+                buf.clear();
+                buf.extend_from_slice(&msg);
+                Ok(src)
+            }
+            // We always hold a sender to ourselves through the network, so this can't disconnect
+            Err(TryRecvError::Empty | TryRecvError::Disconnected) => {
+                Err(ErrorKind::WouldBlock.into())
+            }
+        }
+    }
+}
@@ -0,0 +1,592 @@
+use std::{collections::BTreeMap, net::IpAddr, path::PathBuf, sync::Arc};
+
+use serde::{de::Error as _, Deserialize, Serialize};
+
+use crate::{Ack, HostEndpoint, Message, PeerId, Probe, Service, Udis};
+
+/// Version of the wire format, sent in every message so the format can change in future
+pub(crate) const WIRE_VERSION: u32 = 1;
+
+/// A message as it is sent over the discovery network.
+///
+/// This is kept separate from the types used internally so that the wire format is stable and
+/// easy to read from other languages, see the readme for a description of it.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+struct WireMessage {
+    version: u32,
+
+    #[serde(flatten)]
+    body: WireBody,
+
+    /// Whether the message was relayed from another network, so it mustn't be relayed again
+    #[serde(default, skip_serializing_if = "is_false")]
+    relayed: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum WireBody {
+    Notify {
+        name: String,
+        addr: IpAddr,
+        services: Vec<WireService>,
+
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        extra_addrs: Vec<IpAddr>,
+
+        #[serde(default, skip_serializing_if = "is_false")]
+        reply: bool,
+
+        #[serde(default, skip_serializing_if = "is_false")]
+        query: bool,
+
+        #[serde(default, skip_serializing_if = "<[u8]>::is_empty", with = "base64")]
+        extra: Arc<[u8]>,
+    },
+    Ack {
+        name: String,
+        host: String,
+        kind: String,
+    },
+    Goodbye {
+        name: String,
+        addr: IpAddr,
+    },
+    Probe {
+        name: String,
+        addr: IpAddr,
+        target: IpAddr,
+    },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(tag = "role", rename_all = "snake_case")]
+enum WireService {
+    Host {
+        kind: String,
+
+        #[serde(flatten)]
+        endpoint: HostEndpoint,
+
+        #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+        metadata: BTreeMap<String, String>,
+
+        #[serde(default, skip_serializing_if = "is_zero")]
+        priority: u8,
+
+        #[serde(default, skip_serializing_if = "is_zero")]
+        weight: u16,
+
+        #[serde(default, skip_serializing_if = "is_zero")]
+        ttl: u32,
+
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        description: Option<String>,
+
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        proxy: Option<IpAddr>,
+    },
+    Search {
+        kind: String,
+    },
+}
+
+/// How a [`HostEndpoint`] is represented, either `"port": 4112` or `"path": "/run/hello.sock"`
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(untagged)]
+pub(crate) enum WireEndpoint {
+    Tcp { port: u16 },
+    Unix { path: PathBuf },
+}
+
+impl From<WireEndpoint> for HostEndpoint {
+    fn from(endpoint: WireEndpoint) -> Self {
+        match endpoint {
+            WireEndpoint::Tcp { port } => HostEndpoint::Tcp(port),
+            WireEndpoint::Unix { path } => HostEndpoint::Unix(path),
+        }
+    }
+}
+
+impl From<HostEndpoint> for WireEndpoint {
+    fn from(endpoint: HostEndpoint) -> Self {
+        match endpoint {
+            HostEndpoint::Tcp(port) => WireEndpoint::Tcp { port },
+            HostEndpoint::Unix(path) => WireEndpoint::Unix { path },
+        }
+    }
+}
+
+impl Message {
+    /// Serialise the message into the wire format
+    pub(crate) fn encode(&self) -> Result<Vec<u8>, serde_json::Error> {
+        serde_json::to_vec(&WireMessage {
+            version: WIRE_VERSION,
+            body: self.wire_body(),
+            relayed: matches!(self, Message::Relayed(_)),
+        })
+    }
+
+    /// The body of the message in the wire format
+    fn wire_body(&self) -> WireBody {
+        match self {
+            Message::Notify(udis) | Message::Reply(udis) | Message::Query(udis) => {
+                WireBody::Notify {
+                    name: udis.name.clone(),
+                    addr: udis.addr,
+                    services: udis.services.iter().cloned().map(Into::into).collect(),
+                    extra_addrs: udis.extra_addrs.clone(),
+                    reply: matches!(self, Message::Reply(_)),
+                    query: matches!(self, Message::Query(_)),
+                    extra: udis.extra.clone(),
+                }
+            }
+            Message::Ack { ack } => WireBody::Ack {
+                name: ack.name.clone(),
+                host: ack.host.clone(),
+                kind: ack.kind.clone(),
+            },
+            Message::Goodbye { goodbye } => WireBody::Goodbye {
+                name: goodbye.name.clone(),
+                addr: goodbye.addr,
+            },
+            Message::Probe(probe) => WireBody::Probe {
+                name: probe.name.clone(),
+                addr: probe.addr,
+                target: probe.target,
+            },
+            Message::Relayed(msg) => msg.wire_body(),
+        }
+    }
+
+    /// Deserialise a message from the wire format.
+    ///
+    /// Messages in the format used before the wire format was versioned are also accepted, so
+    /// endpoints running older versions of udis can still be found.
+    ///
+    /// Any message larger than `max_size` bytes or with JSON nested deeper than `max_depth` is
+    /// refused before doing the work of parsing it, so hostile peers can't make us do lots of work.
+    pub(crate) fn decode(
+        bytes: &[u8],
+        max_size: usize,
+        max_depth: usize,
+    ) -> Result<Self, serde_json::Error> {
+        if bytes.len() > max_size {
+            return Err(serde_json::Error::custom(format!(
+                "message is {} bytes, more than the limit of {max_size}",
+                bytes.len()
+            )));
+        }
+        if exceeds_depth(bytes, max_depth) {
+            return Err(serde_json::Error::custom(format!(
+                "message is nested more than {max_depth} levels deep"
+            )));
+        }
+
+        let wire: WireMessage = match serde_json::from_slice(bytes) {
+            Ok(wire) => wire,
+            Err(e) => return serde_json::from_slice(bytes).map_err(|_| e),
+        };
+
+        let msg = match wire.body {
+            WireBody::Notify {
+                name,
+                addr,
+                services,
+                extra_addrs,
+                reply,
+                query,
+                extra,
+            } => {
+                let udis = Udis {
+                    name,
+                    addr,
+                    services: services.into_iter().map(Into::into).collect(),
+                    extra_addrs,
+                    extra,
+                };
+                if reply {
+                    Message::Reply(udis)
+                } else if query {
+                    Message::Query(udis)
+                } else {
+                    Message::Notify(udis)
+                }
+            }
+            WireBody::Ack { name, host, kind } => Message::Ack {
+                ack: Ack { name, host, kind },
+            },
+            WireBody::Goodbye { name, addr } => Message::Goodbye {
+                goodbye: PeerId { name, addr },
+            },
+            WireBody::Probe { name, addr, target } => Message::Probe(Probe { name, addr, target }),
+        };
+
+        Ok(if wire.relayed {
+            Message::Relayed(Box::new(msg))
+        } else {
+            msg
+        })
+    }
+}
+
+/// Whether a number is zero, for leaving zero fields out of messages
+pub(crate) fn is_zero<T: Default + PartialEq>(value: &T) -> bool {
+    *value == T::default()
+}
+
+/// Whether a flag is unset, for leaving false flags out of messages
+fn is_false(value: &bool) -> bool {
+    !*value
+}
+
+/// Serialises bytes as a padded base64 string with the standard alphabet, so they can be carried
+/// in JSON compactly and read easily from other languages
+mod base64 {
+    use std::sync::Arc;
+
+    use serde::{de::Error as _, Deserialize, Deserializer, Serializer};
+
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+    pub(super) fn serialize<S: Serializer>(bytes: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        let mut base64 = String::with_capacity(bytes.len().div_ceil(3) * 4);
+        for chunk in bytes.chunks(3) {
+            let group = chunk.iter().enumerate().fold(0u32, |group, (i, &byte)| {
+                group | u32::from(byte) << (16 - 8 * i)
+            });
+
+            // Each byte in the chunk needs another character, and the rest are padding
+            for i in 0..4 {
+                match i <= chunk.len() {
+                    true => base64.push(char::from(
+                        ALPHABET[(group >> (18 - 6 * i)) as usize & 0x3f],
+                    )),
+                    false => base64.push('='),
+                }
+            }
+        }
+        serializer.serialize_str(&base64)
+    }
+
+    pub(super) fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Arc<[u8]>, D::Error> {
+        let base64 = String::deserialize(deserializer)?;
+        if base64.len() % 4 != 0 {
+            return Err(D::Error::custom(
+                "base64 string isn't a multiple of four characters long",
+            ));
+        }
+        let invalid = || D::Error::custom(format!("invalid base64 string `{base64}`"));
+
+        let chunks = base64.len() / 4;
+        let mut bytes = Vec::with_capacity(chunks * 3);
+        for (n, chunk) in base64.as_bytes().chunks(4).enumerate() {
+            // Padding can only end the string, in place of at most two characters
+            let padding = chunk.iter().rev().take_while(|&&c| c == b'=').count();
+            if padding > 2 || (padding > 0 && n + 1 != chunks) {
+                return Err(invalid());
+            }
+
+            let mut group = 0u32;
+            for (i, c) in chunk[..4 - padding].iter().enumerate() {
+                let value = ALPHABET.iter().position(|a| a == c).ok_or_else(invalid)?;
+                group |= (value as u32) << (18 - 6 * i);
+            }
+            bytes.extend_from_slice(&group.to_be_bytes()[1..4 - padding]);
+        }
+
+        Ok(bytes.into())
+    }
+}
+
+/// Whether the JSON objects and arrays in `bytes` are nested deeper than `max_depth`.
+///
+/// This only looks at brackets outside of strings so it's much cheaper than parsing, and doesn't
+/// care whether the JSON is otherwise valid.
+fn exceeds_depth(bytes: &[u8], max_depth: usize) -> bool {
+    let mut depth = 0usize;
+    let mut in_string = false;
+    let mut escaped = false;
+
+    for &byte in bytes {
+        if in_string {
+            match byte {
+                _ if escaped => escaped = false,
+                b'\\' => escaped = true,
+                b'"' => in_string = false,
+                _ => (),
+            }
+            continue;
+        }
+
+        match byte {
+            b'"' => in_string = true,
+            b'{' | b'[' => {
+                depth += 1;
+                if depth > max_depth {
+                    return true;
+                }
+            }
+            b'}' | b']' => depth = depth.saturating_sub(1),
+            _ => (),
+        }
+    }
+
+    false
+}
+
+impl From<Service> for WireService {
+    fn from(service: Service) -> Self {
+        match service {
+            Service::Host {
+                kind,
+                endpoint,
+                metadata,
+                priority,
+                weight,
+                ttl,
+                description,
+                proxy,
+            } => WireService::Host {
+                kind,
+                endpoint,
+                metadata,
+                priority,
+                weight,
+                ttl,
+                description,
+                proxy,
+            },
+            Service::Search { kind } => WireService::Search { kind },
+        }
+    }
+}
+
+impl From<WireService> for Service {
+    fn from(service: WireService) -> Self {
+        match service {
+            WireService::Host {
+                kind,
+                endpoint,
+                metadata,
+                priority,
+                weight,
+                ttl,
+                description,
+                proxy,
+            } => Service::Host {
+                kind,
+                endpoint,
+                metadata,
+                priority,
+                weight,
+                ttl,
+                description,
+                proxy,
+            },
+            WireService::Search { kind } => Service::Search { kind },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use crate::{
+        tcp_host,
+        wire::{base64, exceeds_depth},
+        HostEndpoint, Message, Service, Udis,
+    };
+
+    #[test]
+    fn test_wire_format() {
+        let udis = Udis::build(
+            "server".into(),
+            Ipv4Addr::new(192, 168, 0, 1).into(),
+            vec![tcp_host("hello", 4112)],
+        );
+        let msg = Message::Notify(udis);
+
+        let bytes = msg.encode().unwrap();
+        assert_eq!(
+            std::str::from_utf8(&bytes).unwrap(),
+            r#"{"version":1,"type":"notify","name":"server","addr":"192.168.0.1","services":[{"role":"host","kind":"hello","port":4112}]}"#
+        );
+        assert_eq!(
+            Message::decode(&bytes, usize::MAX, usize::MAX).unwrap(),
+            msg
+        );
+
+        // Notify messages from older endpoints should still be understood
+        let legacy = br#"{"name":"server","addr":"192.168.0.1","services":[{"Host":{"kind":"hello","port":4112}}]}"#;
+        assert_eq!(
+            Message::decode(legacy, usize::MAX, usize::MAX).unwrap(),
+            msg
+        );
+
+        // Services hosted on a Unix domain socket have a path instead of a port
+        let udis = Udis::build(
+            "server".into(),
+            Ipv4Addr::new(192, 168, 0, 1).into(),
+            vec![Service::Host {
+                kind: "hello".into(),
+                endpoint: HostEndpoint::Unix("/run/hello.sock".into()),
+                metadata: Default::default(),
+                priority: 0,
+                weight: 0,
+                ttl: 0,
+                description: None,
+                proxy: None,
+            }],
+        );
+        let msg = Message::Notify(udis);
+
+        let bytes = msg.encode().unwrap();
+        assert_eq!(
+            std::str::from_utf8(&bytes).unwrap(),
+            r#"{"version":1,"type":"notify","name":"server","addr":"192.168.0.1","services":[{"role":"host","kind":"hello","path":"/run/hello.sock"}]}"#
+        );
+        assert_eq!(
+            Message::decode(&bytes, usize::MAX, usize::MAX).unwrap(),
+            msg
+        );
+
+        // Descriptions are only sent when the service has one
+        let mut hello = tcp_host("hello", 4112);
+        if let Service::Host { description, .. } = &mut hello {
+            *description = Some("Primary hello".into());
+        }
+        let udis = Udis::build(
+            "server".into(),
+            Ipv4Addr::new(192, 168, 0, 1).into(),
+            vec![hello],
+        );
+        let described = Message::Notify(udis);
+
+        let described_bytes = described.encode().unwrap();
+        assert_eq!(
+            std::str::from_utf8(&described_bytes).unwrap(),
+            r#"{"version":1,"type":"notify","name":"server","addr":"192.168.0.1","services":[{"role":"host","kind":"hello","port":4112,"description":"Primary hello"}]}"#
+        );
+        assert_eq!(
+            Message::decode(&described_bytes, usize::MAX, usize::MAX).unwrap(),
+            described
+        );
+
+        // Extra addresses are only sent when the endpoint has some
+        let udis = Udis::build(
+            "server".into(),
+            Ipv4Addr::new(192, 168, 0, 1).into(),
+            vec![],
+        )
+        .with_extra_addrs(vec!["fd00::1".parse().unwrap()]);
+        let multihomed = Message::Notify(udis);
+
+        let multihomed_bytes = multihomed.encode().unwrap();
+        assert_eq!(
+            std::str::from_utf8(&multihomed_bytes).unwrap(),
+            r#"{"version":1,"type":"notify","name":"server","addr":"192.168.0.1","services":[],"extra_addrs":["fd00::1"]}"#
+        );
+        assert_eq!(
+            Message::decode(&multihomed_bytes, usize::MAX, usize::MAX).unwrap(),
+            multihomed
+        );
+
+        // Extra payloads are sent as base64, and only when the endpoint has one
+        for (payload, base64) in [
+            (&[0x00, 0xab, 0x10, 0xff][..], "AKsQ/w=="),
+            (&[0x00, 0xab, 0x10, 0xff, 0x7e][..], "AKsQ/34="),
+            (&[0x00, 0xab, 0x10][..], "AKsQ"),
+        ] {
+            let udis = Udis::build(
+                "server".into(),
+                Ipv4Addr::new(192, 168, 0, 1).into(),
+                vec![],
+            )
+            .with_extra(payload.into());
+            let extra = Message::Notify(udis);
+
+            let extra_bytes = extra.encode().unwrap();
+            assert_eq!(
+                std::str::from_utf8(&extra_bytes).unwrap(),
+                format!(
+                    r#"{{"version":1,"type":"notify","name":"server","addr":"192.168.0.1","services":[],"extra":"{base64}"}}"#
+                )
+            );
+            assert_eq!(
+                Message::decode(&extra_bytes, usize::MAX, usize::MAX).unwrap(),
+                extra
+            );
+        }
+
+        // Payloads which aren't valid base64 are rejected
+        for invalid in [
+            r#""AKsQ/w=""#,
+            r#""AK=Q""#,
+            r#""AKsQ/w==AKsQ""#,
+            r#""AKs*""#,
+        ] {
+            let mut de = serde_json::Deserializer::from_str(invalid);
+            assert!(base64::deserialize(&mut de).is_err());
+        }
+
+        // Replies to interested peers are flagged, other notify messages leave the flag out
+        let reply = Message::Reply(Udis::build(
+            "server".into(),
+            Ipv4Addr::new(192, 168, 0, 1).into(),
+            vec![],
+        ));
+
+        let reply_bytes = reply.encode().unwrap();
+        assert_eq!(
+            std::str::from_utf8(&reply_bytes).unwrap(),
+            r#"{"version":1,"type":"notify","name":"server","addr":"192.168.0.1","services":[],"reply":true}"#
+        );
+        assert_eq!(
+            Message::decode(&reply_bytes, usize::MAX, usize::MAX).unwrap(),
+            reply
+        );
+
+        // As are queries asking hosts to reply
+        let query = Message::Query(Udis::build(
+            "client".into(),
+            Ipv4Addr::new(192, 168, 0, 2).into(),
+            vec![],
+        ));
+
+        let query_bytes = query.encode().unwrap();
+        assert_eq!(
+            std::str::from_utf8(&query_bytes).unwrap(),
+            r#"{"version":1,"type":"notify","name":"client","addr":"192.168.0.2","services":[],"query":true}"#
+        );
+        assert_eq!(
+            Message::decode(&query_bytes, usize::MAX, usize::MAX).unwrap(),
+            query
+        );
+
+        // Relayed messages are marked so they aren't relayed again
+        let relayed = Message::Relayed(Box::new(multihomed));
+
+        let relayed_bytes = relayed.encode().unwrap();
+        assert_eq!(
+            std::str::from_utf8(&relayed_bytes).unwrap(),
+            r#"{"version":1,"type":"notify","name":"server","addr":"192.168.0.1","services":[],"extra_addrs":["fd00::1"],"relayed":true}"#
+        );
+        assert_eq!(
+            Message::decode(&relayed_bytes, usize::MAX, usize::MAX).unwrap(),
+            relayed
+        );
+
+        // Messages over the limits shouldn't be parsed
+        assert!(Message::decode(&bytes, bytes.len() - 1, usize::MAX).is_err());
+        assert!(Message::decode(&bytes, usize::MAX, 2).is_err());
+        assert!(Message::decode(&bytes, usize::MAX, 3).is_ok());
+
+        // Brackets inside strings don't count towards the depth
+        assert!(!exceeds_depth(br#"{"name":"[[[\"]]"}"#, 1));
+        assert!(exceeds_depth(br#"{"name":"\\",[]}"#, 1));
+    }
+}